@@ -15,12 +15,12 @@ DATA_DIR2=$(mktemp --directory)
 DIR=$(mktemp --directory)
 DIR2=$(mktemp --directory)
 cargo build --release
-cargo run --release -- --port 3300 --data-dir $DATA_DIR --peers 127.0.0.1:3301  &
-cargo run --release -- --port 3301 --data-dir $DATA_DIR2 --peers 127.0.0.1:3300 &
+cargo run --release -- server --port 3300 --data-dir $DATA_DIR --peers 127.0.0.1:3301  &
+cargo run --release -- server --port 3301 --data-dir $DATA_DIR2 --peers 127.0.0.1:3300 &
 sleep 0.5
-cargo run -- --server-ip-port 127.0.0.1:3300 --get-leader
-cargo run --release -- --server-ip-port 127.0.0.1:3300 --mount-point $DIR &
-cargo run --release -- --server-ip-port 127.0.0.1:3301 --mount-point $DIR2 &
+cargo run -- --server-ip-port 127.0.0.1:3300 get-leader
+cargo run --release -- --server-ip-port 127.0.0.1:3300 mount $DIR &
+cargo run --release -- --server-ip-port 127.0.0.1:3301 mount $DIR2 &
 sleep 0.5
 
 echo "mounting at $DIR"
@@ -0,0 +1,278 @@
+use crate::base::{ErrorCode, UserContext};
+use byteorder::{ByteOrder, LittleEndian};
+use redb::{TypeName, Value};
+
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+// Entry tags, with the same values that Linux uses in the ACL xattr format
+pub const ACL_USER_OBJ: u16 = 0x01;
+pub const ACL_USER: u16 = 0x02;
+pub const ACL_GROUP_OBJ: u16 = 0x04;
+pub const ACL_GROUP: u16 = 0x08;
+pub const ACL_MASK: u16 = 0x10;
+pub const ACL_OTHER: u16 = 0x20;
+
+const ACL_XATTR_VERSION: u32 = 2;
+const ACL_XATTR_HEADER_SIZE: usize = 4;
+const ACL_XATTR_ENTRY_SIZE: usize = 8;
+// The id stored in entries that don't refer to a specific user or group
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AclEntry {
+    pub tag: u16,
+    // rwx bits
+    pub perm: u16,
+    // uid or gid for ACL_USER and ACL_GROUP entries
+    pub id: u32,
+}
+
+// A POSIX access or default ACL. Entries are kept sorted by tag and id, and always include the
+// ACL_USER_OBJ, ACL_GROUP_OBJ, and ACL_OTHER entries
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PosixAcl {
+    entries: Vec<AclEntry>,
+}
+
+// Stored in the same format as the xattr
+impl Value for PosixAcl {
+    type SelfType<'a> = PosixAcl;
+    type AsBytes<'a> = Vec<u8>;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        PosixAcl::from_xattr(data).expect("Stored ACL is invalid")
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        value.to_xattr()
+    }
+
+    fn type_name() -> TypeName {
+        TypeName::new("fleetfs::PosixAcl")
+    }
+}
+
+impl PosixAcl {
+    // Parses the format used by the system.posix_acl_* xattrs
+    pub fn from_xattr(data: &[u8]) -> Result<PosixAcl, ErrorCode> {
+        if data.len() < ACL_XATTR_HEADER_SIZE
+            || !(data.len() - ACL_XATTR_HEADER_SIZE).is_multiple_of(ACL_XATTR_ENTRY_SIZE)
+            || LittleEndian::read_u32(data) != ACL_XATTR_VERSION
+        {
+            return Err(ErrorCode::InvalidArgument);
+        }
+
+        let mut entries: Vec<AclEntry> = data[ACL_XATTR_HEADER_SIZE..]
+            .chunks(ACL_XATTR_ENTRY_SIZE)
+            .map(|entry| AclEntry {
+                tag: LittleEndian::read_u16(entry),
+                perm: LittleEndian::read_u16(&entry[2..]),
+                id: LittleEndian::read_u32(&entry[4..]),
+            })
+            .collect();
+        for entry in entries.iter_mut() {
+            if entry.perm & !0o7 != 0 {
+                return Err(ErrorCode::InvalidArgument);
+            }
+            match entry.tag {
+                ACL_USER | ACL_GROUP => {}
+                ACL_USER_OBJ | ACL_GROUP_OBJ | ACL_MASK | ACL_OTHER => {
+                    entry.id = ACL_UNDEFINED_ID;
+                }
+                _ => return Err(ErrorCode::InvalidArgument),
+            }
+        }
+        entries.sort_by_key(|entry| (entry.tag, entry.id));
+        if entries
+            .windows(2)
+            .any(|pair| (pair[0].tag, pair[0].id) == (pair[1].tag, pair[1].id))
+        {
+            return Err(ErrorCode::InvalidArgument);
+        }
+
+        let acl = PosixAcl { entries };
+        let has_named_entries = acl
+            .entries
+            .iter()
+            .any(|entry| entry.tag == ACL_USER || entry.tag == ACL_GROUP);
+        if acl.find(ACL_USER_OBJ).is_none()
+            || acl.find(ACL_GROUP_OBJ).is_none()
+            || acl.find(ACL_OTHER).is_none()
+            || (has_named_entries && acl.find(ACL_MASK).is_none())
+        {
+            return Err(ErrorCode::InvalidArgument);
+        }
+
+        Ok(acl)
+    }
+
+    pub fn to_xattr(&self) -> Vec<u8> {
+        let mut data = vec![0; ACL_XATTR_HEADER_SIZE + ACL_XATTR_ENTRY_SIZE * self.entries.len()];
+        LittleEndian::write_u32(&mut data, ACL_XATTR_VERSION);
+        for (entry, buffer) in self
+            .entries
+            .iter()
+            .zip(data[ACL_XATTR_HEADER_SIZE..].chunks_mut(ACL_XATTR_ENTRY_SIZE))
+        {
+            LittleEndian::write_u16(buffer, entry.tag);
+            LittleEndian::write_u16(&mut buffer[2..], entry.perm);
+            LittleEndian::write_u32(&mut buffer[4..], entry.id);
+        }
+
+        data
+    }
+
+    fn find(&self, tag: u16) -> Option<&AclEntry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    fn find_mut(&mut self, tag: u16) -> Option<&mut AclEntry> {
+        self.entries.iter_mut().find(|entry| entry.tag == tag)
+    }
+
+    // True if the ACL can be represented exactly by the mode bits
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    // The permission bits of the file mode, which mirror the ACL
+    pub fn mode_bits(&self) -> u16 {
+        let user = self.find(ACL_USER_OBJ).unwrap().perm;
+        let group = self
+            .find(ACL_MASK)
+            .or_else(|| self.find(ACL_GROUP_OBJ))
+            .unwrap()
+            .perm;
+        let other = self.find(ACL_OTHER).unwrap().perm;
+
+        (user << 6) | (group << 3) | other
+    }
+
+    // Updates the ACL to match new permission bits, as done by chmod()
+    pub fn set_mode_bits(&mut self, mode: u16) {
+        self.find_mut(ACL_USER_OBJ).unwrap().perm = (mode >> 6) & 0o7;
+        if let Some(mask) = self.find_mut(ACL_MASK) {
+            mask.perm = (mode >> 3) & 0o7;
+        } else {
+            self.find_mut(ACL_GROUP_OBJ).unwrap().perm = (mode >> 3) & 0o7;
+        }
+        self.find_mut(ACL_OTHER).unwrap().perm = mode & 0o7;
+    }
+
+    // The ACL that a new inode inherits from its parent's default ACL, given the mode it was
+    // created with
+    pub fn inherit(&self, mode: u16) -> PosixAcl {
+        let mut acl = self.clone();
+        let inherited = acl.mode_bits() & mode;
+        acl.set_mode_bits(inherited);
+
+        acl
+    }
+
+    // Returns true if the ACL grants all the access in access_mask. Root is handled by the caller
+    pub fn check_access(
+        &self,
+        file_uid: u32,
+        file_gid: u32,
+        context: &UserContext,
+        access_mask: i32,
+    ) -> bool {
+        let access_mask = access_mask as u16;
+        let uid = context.uid();
+        let mask = self.find(ACL_MASK).map_or(0o7, |entry| entry.perm);
+        if uid == file_uid {
+            return self.find(ACL_USER_OBJ).unwrap().perm & access_mask == access_mask;
+        }
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.tag == ACL_USER && entry.id == uid)
+        {
+            return entry.perm & mask & access_mask == access_mask;
+        }
+
+        let mut group_matched = false;
+        for entry in self.entries.iter() {
+            let matches = match entry.tag {
+                ACL_GROUP_OBJ => context.in_group(file_gid),
+                ACL_GROUP => context.in_group(entry.id),
+                _ => false,
+            };
+            if matches {
+                if entry.perm & mask & access_mask == access_mask {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+
+        self.find(ACL_OTHER).unwrap().perm & access_mask == access_mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::UserContext;
+    use crate::base::acl::{ACL_GROUP, ACL_GROUP_OBJ, ACL_MASK, ACL_OTHER, ACL_USER_OBJ, PosixAcl};
+    use byteorder::{ByteOrder, LittleEndian};
+
+    fn to_xattr(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut data = vec![0; 4 + 8 * entries.len()];
+        LittleEndian::write_u32(&mut data, 2);
+        for (i, (tag, perm, id)) in entries.iter().enumerate() {
+            LittleEndian::write_u16(&mut data[4 + 8 * i..], *tag);
+            LittleEndian::write_u16(&mut data[6 + 8 * i..], *perm);
+            LittleEndian::write_u32(&mut data[8 + 8 * i..], *id);
+        }
+        data
+    }
+
+    #[test]
+    fn named_group_is_limited_by_mask() {
+        let acl = PosixAcl::from_xattr(&to_xattr(&[
+            (ACL_USER_OBJ, 0o7, u32::MAX),
+            (ACL_GROUP_OBJ, 0o5, u32::MAX),
+            (ACL_GROUP, 0o7, 1000),
+            (ACL_MASK, 0o5, u32::MAX),
+            (ACL_OTHER, 0o0, u32::MAX),
+        ]))
+        .unwrap();
+        assert_eq!(acl.mode_bits(), 0o750);
+        assert!(acl.check_access(0, 0, &UserContext::new(1, 1000), libc::R_OK));
+        assert!(!acl.check_access(0, 0, &UserContext::new(1, 1000), libc::W_OK));
+        assert!(!acl.check_access(0, 0, &UserContext::new(1, 1001), libc::R_OK));
+        assert_eq!(PosixAcl::from_xattr(&acl.to_xattr()).unwrap(), acl);
+
+        let inherited = acl.inherit(0o640);
+        assert_eq!(inherited.mode_bits(), 0o640);
+        assert!(!inherited.check_access(0, 0, &UserContext::new(1, 1000), libc::X_OK));
+    }
+
+    #[test]
+    fn named_entries_require_mask() {
+        assert!(
+            PosixAcl::from_xattr(&to_xattr(&[
+                (ACL_USER_OBJ, 0o7, u32::MAX),
+                (ACL_GROUP_OBJ, 0o5, u32::MAX),
+                (ACL_GROUP, 0o7, 1000),
+                (ACL_OTHER, 0o0, u32::MAX),
+            ]))
+            .is_err()
+        );
+    }
+}
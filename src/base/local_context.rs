@@ -1,6 +1,18 @@
 use crate::base::utils::node_id_from_address;
 use std::net::SocketAddr;
 
+// Controls when reads update the access time of inodes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtimeMode {
+    // Access times are never updated by reads
+    NoAtime,
+    // Access times are updated if they're older than the modify or change time, or more than a
+    // day old. Updates are batched in the background, rather than made as part of the read
+    Relatime,
+    // Every read updates the access time, which requires a consensus round per read
+    StrictAtime,
+}
+
 #[derive(Clone)]
 pub struct LocalContext {
     pub data_dir: String,
@@ -8,6 +20,7 @@ pub struct LocalContext {
     pub peers: Vec<SocketAddr>,
     pub node_id: u64,
     pub replicas_per_raft_group: usize,
+    pub atime_mode: AtimeMode,
 }
 
 impl LocalContext {
@@ -17,6 +30,7 @@ impl LocalContext {
         peers: Vec<SocketAddr>,
         node_id: u64,
         replicas_per_raft_group: usize,
+        atime_mode: AtimeMode,
     ) -> LocalContext {
         LocalContext {
             data_dir: data_dir.to_string(),
@@ -24,6 +38,7 @@ impl LocalContext {
             peers,
             node_id,
             replicas_per_raft_group,
+            atime_mode,
         }
     }
 
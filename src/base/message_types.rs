@@ -9,6 +9,7 @@ use zerialize::{List, ListView, Zerializable, zerializable};
 pub struct RequestMetaInfo {
     pub raft_group: Option<u16>,
     pub inode: Option<u64>, // Some if the request accesses a single inode (i.e. None for Rename)
+    pub entry: Option<String>, // Some if the request accesses a single directory entry of inode
     pub lock_id: Option<u64>,
     pub access_type: AccessType, // Used to determine locks to acquire
     pub distribution_requirement: DistributionRequirement,
@@ -17,7 +18,7 @@ pub struct RequestMetaInfo {
 pub enum AccessType {
     ReadData,
     ReadMetadata,
-    LockMetadata,
+    LockMetadata(LockMode),
     WriteMetadata,
     WriteDataAndMetadata,
     NoAccess,
@@ -63,8 +64,38 @@ pub enum ErrorCode {
     InvalidXattrNamespace,
     #[variant(14)]
     Uncategorized,
+    // A conflicting advisory lock is held, and the request asked not to wait for it
+    #[variant(15)]
+    WouldBlock,
+    #[variant(16)]
+    InvalidArgument,
+    // A request with the same session and sequence number was begun, and hasn't finished yet
+    #[variant(17)]
+    RequestInProgress,
+    // A waiting advisory lock request was cancelled, because the process waiting for it was
+    // interrupted
+    #[variant(18)]
+    Interrupted,
 }
 
+// Flags for Request::Rename. These have the same values as the flags to Linux's renameat2()
+// Fail if the destination already exists
+pub const RENAME_NOREPLACE: u32 = 1;
+// Atomically swap the source and destination, which must both exist
+pub const RENAME_EXCHANGE: u32 = 2;
+
+// Inode flags. These have the same values as Linux's FS_IOC_GETFLAGS flags
+// The inode can't be modified, linked or unlinked. No entries can be added to or removed from
+// an immutable directory
+pub const FS_IMMUTABLE_FL: u32 = 0x10;
+// The file can only be appended to, and can't be unlinked. Entries can be added to an
+// append-only directory, but not removed
+pub const FS_APPEND_FL: u32 = 0x20;
+
+// A client session's advisory locks and open handles are released if it doesn't renew its lease
+// for this long, for example because the client crashed or lost its connection to the cluster
+pub const SESSION_LEASE_SECONDS: i64 = 30;
+
 #[derive(Zerializable, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum FileKind {
     #[variant(0)]
@@ -73,6 +104,14 @@ pub enum FileKind {
     Directory,
     #[variant(2)]
     Symlink,
+    #[variant(3)]
+    NamedPipe,
+    #[variant(4)]
+    Socket,
+    #[variant(5)]
+    CharDevice,
+    #[variant(6)]
+    BlockDevice,
 }
 
 impl Value for FileKind {
@@ -91,6 +130,10 @@ impl Value for FileKind {
             1 => FileKind::File,
             2 => FileKind::Directory,
             3 => FileKind::Symlink,
+            4 => FileKind::NamedPipe,
+            5 => FileKind::Socket,
+            6 => FileKind::CharDevice,
+            7 => FileKind::BlockDevice,
             _ => unreachable!(),
         }
     }
@@ -104,6 +147,10 @@ impl Value for FileKind {
             FileKind::File => [1],
             FileKind::Directory => [2],
             FileKind::Symlink => [3],
+            FileKind::NamedPipe => [4],
+            FileKind::Socket => [5],
+            FileKind::CharDevice => [6],
+            FileKind::BlockDevice => [7],
         }
     }
 
@@ -112,6 +159,71 @@ impl Value for FileKind {
     }
 }
 
+// Shared locks may be held by several transactions at once, but never at the same time as an
+// exclusive lock on the same inode or directory entry
+#[derive(Zerializable, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LockMode {
+    #[variant(0)]
+    Shared,
+    #[variant(1)]
+    Exclusive,
+}
+
+#[derive(Zerializable, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RangeLockKind {
+    #[variant(0)]
+    Read,
+    #[variant(1)]
+    Write,
+    #[variant(2)]
+    Unlock,
+}
+
+// A POSIX advisory lock on a range of bytes in a file. Locks are owned by a client session (one per mount)
+// together with the lock owner that the kernel assigned within that session
+#[derive(Zerializable, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RangeLock {
+    #[n(0)]
+    pub session: u64,
+    #[n(1)]
+    pub owner: u64,
+    #[n(2)]
+    pub pid: u32,
+    #[n(3)]
+    pub start: u64,
+    // Inclusive. u64::MAX extends the lock to the end of the file, no matter how large it grows
+    #[n(4)]
+    pub end: u64,
+    #[n(5)]
+    pub kind: RangeLockKind,
+}
+
+impl RangeLock {
+    pub fn same_owner(&self, other: &RangeLock) -> bool {
+        self.session == other.session && self.owner == other.owner
+    }
+
+    pub fn overlaps(&self, other: &RangeLock) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+
+    // Returns true if both locks can't be held at the same time
+    pub fn conflicts_with(&self, other: &RangeLock) -> bool {
+        !self.same_owner(other)
+            && self.overlaps(other)
+            && (self.kind == RangeLockKind::Write || other.kind == RangeLockKind::Write)
+    }
+}
+
+// A handle that a client session has open on an inode
+#[derive(Zerializable, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct OpenHandleId {
+    #[n(0)]
+    pub session: u64,
+    #[n(1)]
+    pub handle: u64,
+}
+
 #[derive(Zerializable, Debug, PartialEq, Eq, Clone, Copy)]
 pub struct CommitId {
     #[n(0)]
@@ -140,17 +252,51 @@ impl InodeUidPair {
     }
 }
 
+// Upper bound on the supplementary groups sent with each request, to keep requests small
+pub const MAX_SUPPLEMENTARY_GROUPS: usize = 64;
+
+// Packs inodes as little endian u64s, for requests and responses that carry a list of them
+pub fn pack_inodes(inodes: &[u64]) -> Vec<u8> {
+    inodes
+        .iter()
+        .flat_map(|inode| inode.to_le_bytes())
+        .collect()
+}
+
+pub fn unpack_inodes(data: &[u8]) -> Vec<u64> {
+    data.chunks_exact(8)
+        .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+        .collect()
+}
+
+// Packs group ids into the format used by UserContext
+pub fn pack_groups(groups: &[u32]) -> Vec<u8> {
+    let groups = &groups[..groups.len().min(MAX_SUPPLEMENTARY_GROUPS)];
+    groups.iter().flat_map(|gid| gid.to_le_bytes()).collect()
+}
+
 #[derive(Zerializable, Debug, PartialEq, Eq, Clone, Copy)]
-pub struct UserContext {
+pub struct UserContext<'a> {
     #[n(0)]
     pub uid: u32,
     #[n(1)]
     pub gid: u32,
+    // Supplementary group ids, packed as little endian u32s by pack_groups()
+    #[n(2)]
+    groups: &'a [u8],
 }
 
-impl UserContext {
+impl<'a> UserContext<'a> {
     pub fn new(uid: u32, gid: u32) -> Self {
-        Self { uid, gid }
+        Self {
+            uid,
+            gid,
+            groups: &[],
+        }
+    }
+
+    pub fn with_groups(uid: u32, gid: u32, groups: &'a [u8]) -> Self {
+        Self { uid, gid, groups }
     }
 
     pub fn uid(&self) -> u32 {
@@ -160,9 +306,22 @@ impl UserContext {
     pub fn gid(&self) -> u32 {
         self.gid
     }
+
+    pub fn packed_groups(&self) -> &'a [u8] {
+        self.groups
+    }
+
+    // True if gid is the user's primary group or one of their supplementary groups
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid
+            || self
+                .groups
+                .chunks_exact(4)
+                .any(|x| u32::from_le_bytes(x.try_into().unwrap()) == gid)
+    }
 }
 
-#[derive(Zerializable, Debug, PartialEq, Eq, Clone, Copy, Value)]
+#[derive(Zerializable, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Value)]
 pub struct Timestamp {
     #[n(0)]
     pub seconds: i64,
@@ -174,6 +333,23 @@ impl Timestamp {
     pub fn new(seconds: i64, nanos: i32) -> Self {
         Self { seconds, nanos }
     }
+
+    // The local wall clock time
+    pub fn now() -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("System time before unix epoch");
+        Timestamp::new(now.as_secs() as i64, now.subsec_nanos() as i32)
+    }
+
+    // The next representable timestamp
+    pub fn next(&self) -> Self {
+        if self.nanos >= 999_999_999 {
+            Timestamp::new(self.seconds + 1, 0)
+        } else {
+            Timestamp::new(self.seconds, self.nanos + 1)
+        }
+    }
 }
 
 impl From<Timestamp> for SystemTime {
@@ -216,6 +392,14 @@ pub struct EntryMetadata {
     // The number of directory entries in the directory. Only available if kind == Directory
     #[n(13)]
     pub directory_entries: Option<u32>,
+    #[n(14)]
+    pub creation_time: Timestamp,
+    // Inode flags, such as FS_IMMUTABLE_FL
+    #[n(15)]
+    pub flags: u32,
+    // Unique for the lifetime of the filesystem, unlike inode numbers which may be reused
+    #[n(16)]
+    pub unique_id: u64,
 }
 
 /// One entry of a directory listing, pointing into the message it was read from
@@ -227,6 +411,9 @@ pub struct DirectoryEntry<'a> {
     pub name: &'a str,
     #[n(2)]
     pub kind: FileKind,
+    // Only included when the listing was requested with attributes
+    #[n(3)]
+    pub attributes: Option<EntryMetadata>,
 }
 
 /// The checksum of one raft group's data, pointing into the message it was read from
@@ -267,6 +454,16 @@ pub enum Request<'a> {
     ListDir {
         #[n(0)]
         inode: u64,
+        // Resume the listing after this name. The "." and ".." entries are only returned when
+        // this is None, and the empty string resumes after them
+        #[n(1)]
+        after: Option<&'a str>,
+        // Maximum number of entries to return
+        #[n(2)]
+        limit: u32,
+        // Include the attributes of each entry, for readdirplus
+        #[n(3)]
+        with_attributes: bool,
     },
     #[variant(7)]
     Create {
@@ -282,6 +479,15 @@ pub enum Request<'a> {
         mode: u16,
         #[n(5)]
         kind: FileKind,
+        // Device number, for character and block devices
+        #[n(6)]
+        rdev: u32,
+        // Applied to mode, unless the parent has a default ACL
+        #[n(7)]
+        umask: u16,
+        // Supplementary groups of the user, packed by pack_groups()
+        #[n(8)]
+        groups: &'a [u8],
     },
     #[variant(8)]
     Mkdir {
@@ -295,6 +501,12 @@ pub enum Request<'a> {
         gid: u32,
         #[n(4)]
         mode: u16,
+        // Applied to mode, unless the parent has a default ACL
+        #[n(5)]
+        umask: u16,
+        // Supplementary groups of the user, packed by pack_groups()
+        #[n(6)]
+        groups: &'a [u8],
     },
     #[variant(9)]
     Unlink {
@@ -303,7 +515,7 @@ pub enum Request<'a> {
         #[n(1)]
         name: &'a str,
         #[n(2)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     #[variant(10)]
     Rmdir {
@@ -312,7 +524,7 @@ pub enum Request<'a> {
         #[n(1)]
         name: &'a str,
         #[n(2)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     #[variant(11)]
     Truncate {
@@ -321,7 +533,11 @@ pub enum Request<'a> {
         #[n(1)]
         new_length: u64,
         #[n(2)]
-        context: UserContext,
+        context: UserContext<'a>,
+        // Set when truncating through a handle that was opened for writing. The truncate is then
+        // allowed even if write permission has since been removed
+        #[n(3)]
+        has_write_handle: bool,
     },
     #[variant(12)]
     Rename {
@@ -334,7 +550,10 @@ pub enum Request<'a> {
         #[n(3)]
         new_name: &'a str,
         #[n(4)]
-        context: UserContext,
+        context: UserContext<'a>,
+        // RENAME_NOREPLACE or RENAME_EXCHANGE
+        #[n(5)]
+        flags: u32,
     },
     #[variant(13)]
     Lookup {
@@ -343,7 +562,7 @@ pub enum Request<'a> {
         #[n(1)]
         name: &'a str,
         #[n(2)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     #[variant(14)]
     Chown {
@@ -354,7 +573,7 @@ pub enum Request<'a> {
         #[n(2)]
         gid: Option<u32>,
         #[n(3)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     #[variant(15)]
     Chmod {
@@ -363,7 +582,7 @@ pub enum Request<'a> {
         #[n(1)]
         mode: u32,
         #[n(2)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     #[variant(16)]
     Utimens {
@@ -374,7 +593,7 @@ pub enum Request<'a> {
         #[n(2)]
         mtime: Option<Timestamp>,
         #[n(3)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     #[variant(17)]
     Hardlink {
@@ -385,7 +604,7 @@ pub enum Request<'a> {
         #[n(2)]
         new_name: &'a str,
         #[n(3)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     #[variant(18)]
     ListXattrs {
@@ -399,7 +618,7 @@ pub enum Request<'a> {
         #[n(1)]
         key: &'a str,
         #[n(2)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     #[variant(20)]
     SetXattr {
@@ -410,7 +629,7 @@ pub enum Request<'a> {
         #[n(2)]
         value: &'a [u8],
         #[n(3)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     #[variant(21)]
     RemoveXattr {
@@ -419,7 +638,7 @@ pub enum Request<'a> {
         #[n(1)]
         key: &'a str,
         #[n(2)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     // Reads only the blocks of data on this node
     #[variant(22)]
@@ -450,6 +669,13 @@ pub enum Request<'a> {
         offset: u64,
         #[n(2)]
         data: &'a [u8],
+        // Writes by users other than root clear the setuid and setgid bits
+        #[n(3)]
+        uid: u32,
+        // Writes at the end of the file instead of at offset. The end is found when the write is
+        // applied, so concurrent appends don't overwrite each other
+        #[n(4)]
+        append: bool,
     },
     #[variant(25)]
     LatestCommit {
@@ -468,11 +694,15 @@ pub enum Request<'a> {
         #[n(1)]
         data: &'a [u8],
     },
-    // Internal request to lock an inode
+    // Internal request to lock an inode, or a single entry of a directory inode if name is provided
     #[variant(28)]
     Lock {
         #[n(0)]
         inode: u64,
+        #[n(1)]
+        name: Option<&'a str>,
+        #[n(2)]
+        mode: LockMode,
     },
     // Internal request to unlock an inode
     #[variant(29)]
@@ -507,7 +737,7 @@ pub enum Request<'a> {
         #[n(4)]
         lock_id: Option<u64>,
         #[n(5)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     // Internal request to atomically replace a directory link, so that it points to a different inode,
     // as part of a transaction. Does not change either inode's link count. It is the callers responsibility to ensure
@@ -525,7 +755,7 @@ pub enum Request<'a> {
         #[n(4)]
         lock_id: Option<u64>,
         #[n(5)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     // Used internally to remove a link entry from a directory. Does *not* decrement the hard link count of the target inode
     #[variant(33)]
@@ -539,7 +769,7 @@ pub enum Request<'a> {
         #[n(3)]
         lock_id: Option<u64>,
         #[n(4)]
-        context: UserContext,
+        context: UserContext<'a>,
     },
     // Internal request to create an inode as part of a create() or mkdir() transaction
     #[variant(34)]
@@ -556,6 +786,18 @@ pub enum Request<'a> {
         mode: u16,
         #[n(5)]
         kind: FileKind,
+        // If set, the inode is created without any links and is kept alive by this handle,
+        // instead of being linked into the parent
+        #[n(6)]
+        open_handle: Option<OpenHandleId>,
+        #[n(7)]
+        rdev: u32,
+        // The parent's default ACL in xattr format, which the inode inherits
+        #[n(8)]
+        default_acl: Option<&'a [u8]>,
+        // If set, the inode is a shard of this directory
+        #[n(9)]
+        shard_of: Option<u64>,
     },
     // Used internally for stage0 of hardlink transactions
     #[variant(35)]
@@ -580,6 +822,10 @@ pub enum Request<'a> {
         inode: u64,
         #[n(1)]
         lock_id: Option<u64>,
+        // Also update the last modified time, as when an entry in one of a directory's shards
+        // has changed
+        #[n(2)]
+        modified: bool,
     },
     // TODO: raft messages have to be idempotent. This one is not.
     // Internal request to decrement inode link count. Will delete the inode if its count reaches zero.
@@ -593,6 +839,210 @@ pub enum Request<'a> {
         #[n(2)]
         lock_id: Option<u64>,
     },
+    // Acquire, change, or release (if the kind is Unlock) an advisory lock. If wait is true and a
+    // conflicting lock is held, the response is delayed until the lock is granted
+    #[variant(39)]
+    SetRangeLock {
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        lock: RangeLock,
+        #[n(2)]
+        wait: bool,
+    },
+    // Returns the first held advisory lock which conflicts with the given one, if any
+    #[variant(40)]
+    GetRangeLock {
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        lock: RangeLock,
+    },
+    // Releases all the advisory locks and open handles of a client session. Sent by the client when
+    // it's done with the session, or by the servers once the session's lease has expired
+    #[variant(41)]
+    ReleaseSession {
+        #[n(0)]
+        raft_group: u16,
+        #[n(1)]
+        session: u64,
+    },
+    // Records that a client has the inode open. Unlinked inodes are only deleted once all their
+    // handles are released
+    #[variant(42)]
+    OpenHandle {
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        session: u64,
+        #[n(2)]
+        handle: u64,
+    },
+    #[variant(43)]
+    ReleaseHandle {
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        session: u64,
+        #[n(2)]
+        handle: u64,
+    },
+    // Creates an unnamed file in the parent directory (O_TMPFILE), which is open on the given
+    // handle. It can later be linked into place with a Hardlink request
+    #[variant(44)]
+    CreateTemporary {
+        #[n(0)]
+        parent: u64,
+        #[n(1)]
+        uid: u32,
+        #[n(2)]
+        gid: u32,
+        #[n(3)]
+        mode: u16,
+        #[n(4)]
+        open_handle: OpenHandleId,
+        // Applied to mode, unless the parent has a default ACL
+        #[n(5)]
+        umask: u16,
+        // Supplementary groups of the user, packed by pack_groups()
+        #[n(6)]
+        groups: &'a [u8],
+    },
+    // Checks whether the user has the requested access (R_OK, W_OK, X_OK) to the inode,
+    // including any access granted by its ACL
+    #[variant(45)]
+    Access {
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        mask: i32,
+        #[n(2)]
+        context: UserContext<'a>,
+    },
+    // Internal request which splits a directory into the given shards, which must be empty
+    // directory inodes. Shard inodes are packed by pack_inodes()
+    #[variant(46)]
+    ShardDirectory {
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        shards: &'a [u8],
+    },
+    // Returns the shards of the directory, which are empty if it isn't sharded
+    #[variant(47)]
+    GetDirectoryShards {
+        #[n(0)]
+        inode: u64,
+    },
+    // Internal request which updates the access times of inodes that were read. Inodes are packed
+    // by pack_inodes(). Unless strict is set, they're only updated if relatime would update them
+    #[variant(48)]
+    UpdateAccessTimes {
+        #[n(0)]
+        raft_group: u16,
+        #[n(1)]
+        inodes: &'a [u8],
+        #[n(2)]
+        strict: bool,
+    },
+    // Sets the inode's flags. Only root may set or clear FS_IMMUTABLE_FL and FS_APPEND_FL
+    #[variant(49)]
+    SetFlags {
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        flags: u32,
+        #[n(2)]
+        context: UserContext<'a>,
+    },
+    // Waits until inodes in the raft group are changed after sequence, and returns them so that
+    // clients can invalidate their caches. epoch and sequence come from the previous response,
+    // or are zero for the first request. Data changes are only returned for files that the
+    // session has open
+    #[variant(50)]
+    WatchInvalidations {
+        #[n(0)]
+        raft_group: u16,
+        #[n(1)]
+        epoch: u64,
+        #[n(2)]
+        sequence: u64,
+        #[n(3)]
+        session: u64,
+    },
+    // Wraps a mutating request, so that it's applied only once even if the client sends it again.
+    // sequence is unique within the client's session, and the results of the session's requests
+    // before acknowledged can be dropped, since the client won't send them again
+    #[variant(51)]
+    Deduplicated {
+        #[n(0)]
+        session: u64,
+        #[n(1)]
+        sequence: u64,
+        #[n(2)]
+        acknowledged: u64,
+        // The encoded request
+        #[n(3)]
+        request: &'a [u8],
+    },
+    // Internal request to begin a deduplicated transaction. Returns the result of the transaction if
+    // it was already applied, and RequestInProgress if another coordinator is applying it and its
+    // lease hasn't expired
+    #[variant(52)]
+    BeginRequest {
+        // Inode whose raft group records the result
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        session: u64,
+        #[n(2)]
+        sequence: u64,
+        #[n(3)]
+        acknowledged: u64,
+        // Identifies the coordinator applying the transaction
+        #[n(4)]
+        owner: u64,
+    },
+    // Internal request to record the encoded result of a deduplicated transaction. Ignored if another
+    // coordinator took over the transaction
+    #[variant(53)]
+    FinishRequest {
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        session: u64,
+        #[n(2)]
+        sequence: u64,
+        #[n(3)]
+        result: &'a [u8],
+        #[n(4)]
+        owner: u64,
+    },
+    // Renews the lease of a client session in the raft group. Clients send this periodically, and a
+    // session which isn't renewed for SESSION_LEASE_SECONDS is released
+    #[variant(54)]
+    RenewSession {
+        #[n(0)]
+        raft_group: u16,
+        #[n(1)]
+        session: u64,
+    },
+    // Internal request which releases the sessions in the raft group whose leases have expired, as
+    // of the time that it's committed at
+    #[variant(55)]
+    ExpireSessions {
+        #[n(0)]
+        raft_group: u16,
+    },
+    // Cancels a waiting SetRangeLock request with the same owner and range, which then fails with
+    // Interrupted. Does nothing if the lock was already granted
+    #[variant(56)]
+    CancelRangeLockWait {
+        #[n(0)]
+        inode: u64,
+        #[n(1)]
+        lock: RangeLock,
+    },
 }
 
 /// A response, over the buffer it was decoded from or over data the sender owns.
@@ -619,6 +1069,11 @@ pub enum WireResponse<
         block_size: u32,
         #[n(1)]
         max_name_length: u32,
+        #[n(2)]
+        raft_groups: u16,
+        // Addresses of the other nodes in the cluster, which clients can fail over to
+        #[n(3)]
+        peers: X,
     },
     #[variant(2)]
     NodeId {
@@ -641,6 +1096,9 @@ pub enum WireResponse<
     Written {
         #[n(0)]
         bytes_written: u32,
+        // Where the data was written, which is the end of the file for appends
+        #[n(1)]
+        offset: u64,
     },
     #[variant(6)]
     Xattrs {
@@ -659,8 +1117,11 @@ pub enum WireResponse<
         #[n(1)]
         index: u64,
     },
+    // The entries, and the name to resume the listing after, or None if it reached the end of the
+    // directory. Entries which are removed while they're listed are left out, so a page may have
+    // fewer entries than requested even if it's not the last
     #[variant(9)]
-    DirectoryListing(#[n(0)] D),
+    DirectoryListing(#[n(0)] D, #[n(1)] Option<&'a str>),
     #[variant(10)]
     EntryMetadata(#[n(0)] EntryMetadata),
     #[variant(11)]
@@ -677,6 +1138,53 @@ pub enum WireResponse<
     // Mapping from raft group ids to their checksum
     #[variant(14)]
     Checksums(#[n(0)] C),
+    #[variant(15)]
+    RangeLock {
+        #[n(0)]
+        conflict: Option<RangeLock>,
+    },
+    // Packed by pack_inodes()
+    #[variant(16)]
+    DirectoryShards {
+        #[n(0)]
+        shards: &'a [u8],
+    },
+    // Inodes are packed by pack_inodes(). If complete is false, some changes were missed and all
+    // the raft group's cached inodes must be invalidated
+    #[variant(17)]
+    Invalidations {
+        #[n(0)]
+        epoch: u64,
+        #[n(1)]
+        sequence: u64,
+        #[n(2)]
+        complete: bool,
+        // Inodes whose attributes changed
+        #[n(3)]
+        inodes: &'a [u8],
+        // Inodes whose data changed
+        #[n(4)]
+        data_inodes: &'a [u8],
+        // Directory entries which changed, as the directories and the names in the same order
+        #[n(5)]
+        entry_parents: &'a [u8],
+        #[n(6)]
+        entry_names: X,
+    },
+    // The encoded result of a request which was already applied
+    #[variant(18)]
+    RequestResult {
+        #[n(0)]
+        result: &'a [u8],
+    },
+    // The entry is stored in one of the directory's shards, rather than the directory itself.
+    // Only returned to transaction coordinators, which retry the request on the shard
+    #[variant(19)]
+    DirectorySharded,
+    // The directory has too many entries, and must be split into shards before more are added.
+    // Only returned to transaction coordinators
+    #[variant(20)]
+    DirectoryNeedsSharding,
 }
 
 /// The schema a response is encoded as, and the view decoding one gives back
@@ -717,6 +1225,7 @@ impl<'a> List for DirectoryEntryList<'a> {
             inode: entry.inode,
             name: &entry.name,
             kind: entry.kind,
+            attributes: entry.attributes,
         })
     }
 }
@@ -743,13 +1252,31 @@ impl<'a> List for ChecksumList<'a> {
 pub type OwnedResponseView<'a> =
     WireResponse<'a, StrList<'a>, DirectoryEntryList<'a>, ChecksumList<'a>>;
 
+// Changes to a raft group, which cached copies have to be invalidated for
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Invalidations {
+    pub epoch: u64,
+    pub sequence: u64,
+    // If false, some changes were missed and everything cached from the raft group is invalid
+    pub complete: bool,
+    pub inodes: Vec<u64>,
+    pub data_inodes: Vec<u64>,
+    // (directory, name) pairs
+    pub entries: Vec<(u64, String)>,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct OwnedDirectoryEntry {
     pub inode: u64,
     pub name: String,
     pub kind: FileKind,
+    pub attributes: Option<EntryMetadata>,
 }
 
+// A page of a directory listing, and the name to resume the listing after, or None if it reached
+// the end of the directory
+pub type DirectoryPage<T> = (Vec<T>, Option<String>);
+
 /// A response a handler owns, which is what it is encoded from.
 ///
 /// The wire format is [`WireResponse`], whose borrowed fields point into the
@@ -763,6 +1290,8 @@ pub enum Response {
     FilesystemInformation {
         block_size: u32,
         max_name_length: u32,
+        raft_groups: u16,
+        peers: Vec<String>,
     },
     NodeId {
         id: u64,
@@ -776,6 +1305,7 @@ pub enum Response {
     },
     Written {
         bytes_written: u32,
+        offset: u64,
     },
     Xattrs {
         attrs: Vec<String>,
@@ -787,7 +1317,7 @@ pub enum Response {
         term: u64,
         index: u64,
     },
-    DirectoryListing(Vec<OwnedDirectoryEntry>),
+    DirectoryListing(Vec<OwnedDirectoryEntry>, Option<String>),
     EntryMetadata(EntryMetadata),
     HardlinkTransaction {
         rollback_last_modified: Timestamp,
@@ -797,6 +1327,26 @@ pub enum Response {
     ErrorOccurred(ErrorCode),
     // Mapping from raft group ids to their checksum
     Checksums(HashMap<u16, Vec<u8>>),
+    RangeLock {
+        conflict: Option<RangeLock>,
+    },
+    DirectoryShards {
+        shards: Vec<u8>,
+    },
+    Invalidations {
+        epoch: u64,
+        sequence: u64,
+        complete: bool,
+        inodes: Vec<u8>,
+        data_inodes: Vec<u8>,
+        entry_parents: Vec<u8>,
+        entry_names: Vec<String>,
+    },
+    RequestResult {
+        result: Vec<u8>,
+    },
+    DirectorySharded,
+    DirectoryNeedsSharding,
 }
 
 impl Response {
@@ -806,9 +1356,13 @@ impl Response {
             Response::FilesystemInformation {
                 block_size,
                 max_name_length,
+                raft_groups,
+                peers,
             } => WireResponse::FilesystemInformation {
                 block_size: *block_size,
                 max_name_length: *max_name_length,
+                raft_groups: *raft_groups,
+                peers: StrList(peers),
             },
             Response::NodeId { id } => WireResponse::NodeId { id: *id },
             Response::Inode { id } => WireResponse::Inode { id: *id },
@@ -816,8 +1370,12 @@ impl Response {
                 id: *id,
                 complete: *complete,
             },
-            Response::Written { bytes_written } => WireResponse::Written {
+            Response::Written {
+                bytes_written,
+                offset,
+            } => WireResponse::Written {
                 bytes_written: *bytes_written,
+                offset: *offset,
             },
             Response::Xattrs { attrs } => WireResponse::Xattrs {
                 attrs: StrList(attrs),
@@ -827,8 +1385,8 @@ impl Response {
                 term: *term,
                 index: *index,
             },
-            Response::DirectoryListing(entries) => {
-                WireResponse::DirectoryListing(DirectoryEntryList(entries))
+            Response::DirectoryListing(entries, resume_after) => {
+                WireResponse::DirectoryListing(DirectoryEntryList(entries), resume_after.as_deref())
             }
             Response::EntryMetadata(metadata) => WireResponse::EntryMetadata(*metadata),
             Response::HardlinkTransaction {
@@ -846,6 +1404,30 @@ impl Response {
                     .map(|(raft_group, checksum)| (*raft_group, checksum.as_slice()))
                     .collect(),
             )),
+            Response::DirectoryShards { shards } => WireResponse::DirectoryShards { shards },
+            Response::Invalidations {
+                epoch,
+                sequence,
+                complete,
+                inodes,
+                data_inodes,
+                entry_parents,
+                entry_names,
+            } => WireResponse::Invalidations {
+                epoch: *epoch,
+                sequence: *sequence,
+                complete: *complete,
+                inodes,
+                data_inodes,
+                entry_parents,
+                entry_names: StrList(entry_names),
+            },
+            Response::RequestResult { result } => WireResponse::RequestResult { result },
+            Response::DirectorySharded => WireResponse::DirectorySharded,
+            Response::DirectoryNeedsSharding => WireResponse::DirectoryNeedsSharding,
+            Response::RangeLock { conflict } => WireResponse::RangeLock {
+                conflict: *conflict,
+            },
         }
     }
 }
@@ -860,6 +1442,32 @@ pub fn decode_response(buffer: &[u8]) -> Result<ResponseView<'_>, zerialize::Err
     zerialize::decode::<ResponseView<'_>>(buffer)
 }
 
+/// Encodes the result of a request, as recorded so that it can be returned again
+pub fn encode_request_result(result: &Result<Response, ErrorCode>) -> Vec<u8> {
+    match result {
+        Ok(response) => encode_response(response),
+        Err(error_code) => encode_response(&Response::ErrorOccurred(*error_code)),
+    }
+}
+
+/// Decodes a result encoded by [`encode_request_result`]. Only the responses
+/// that deduplicated requests return are supported
+pub fn decode_request_result(buffer: &[u8]) -> Result<Response, ErrorCode> {
+    match decode_response(buffer).map_err(|_| ErrorCode::Corrupted)? {
+        WireResponse::Empty => Ok(Response::Empty),
+        WireResponse::Written {
+            bytes_written,
+            offset,
+        } => Ok(Response::Written {
+            bytes_written,
+            offset,
+        }),
+        WireResponse::EntryMetadata(metadata) => Ok(Response::EntryMetadata(metadata)),
+        WireResponse::ErrorOccurred(error_code) => Err(error_code),
+        _ => Err(ErrorCode::Corrupted),
+    }
+}
+
 /// Encodes `request` as the wire format
 pub fn encode_request(request: &Request<'_>) -> Vec<u8> {
     zerialize::encode::<Request<'_>>(request)
@@ -880,6 +1488,18 @@ impl Debug for Request<'_> {
             Request::FilesystemChecksum => write!(f, "FilesystemChecksum"),
             Request::FilesystemCheck => write!(f, "FilesystemCheck"),
             Request::Create { .. } => write!(f, "Create"),
+            Request::CreateTemporary { .. } => write!(f, "CreateTemporary"),
+            Request::ShardDirectory { inode, .. } => write!(f, "ShardDirectory: {inode}"),
+            Request::GetDirectoryShards { inode } => write!(f, "GetDirectoryShards: {inode}"),
+            Request::UpdateAccessTimes { raft_group, .. } => {
+                write!(f, "UpdateAccessTimes: {raft_group}")
+            }
+            Request::SetFlags { inode, flags, .. } => write!(f, "SetFlags: {inode}, {flags:#x}"),
+            Request::WatchInvalidations {
+                raft_group,
+                sequence,
+                ..
+            } => write!(f, "WatchInvalidations: {raft_group}, {sequence}"),
             Request::Mkdir { .. } => write!(f, "Mkdir"),
             Request::Unlink { .. } => write!(f, "Unlink"),
             Request::Truncate { .. } => write!(f, "Truncate"),
@@ -896,9 +1516,10 @@ impl Debug for Request<'_> {
             Request::CreateInode { .. } => write!(f, "CreateInode"),
             Request::Fsync { inode } => write!(f, "Fsync: {inode}"),
             Request::GetAttr { inode } => write!(f, "GetAttr: {inode}"),
-            Request::ListDir { inode } => write!(f, "ListDir: {inode}"),
+            Request::ListDir { inode, .. } => write!(f, "ListDir: {inode}"),
             Request::ListXattrs { inode } => write!(f, "ListXattrs: {inode}"),
             Request::GetXattr { .. } => write!(f, "GetXattr"),
+            Request::Access { inode, mask, .. } => write!(f, "Access: {inode}, {mask}"),
             Request::SetXattr { .. } => write!(f, "SetXattr"),
             Request::RemoveXattr { .. } => write!(f, "RemoveXattr"),
             Request::Write { inode, .. } => write!(f, "Write: {inode}"),
@@ -913,7 +1534,7 @@ impl Debug for Request<'_> {
             Request::ConsensusMessage { raft_group, .. } => {
                 write!(f, "ConsensusMessage: {raft_group}")
             }
-            Request::Lock { inode } => write!(f, "Lock: {inode}"),
+            Request::Lock { inode, mode, .. } => write!(f, "Lock: {inode}, {mode:?}"),
             Request::Unlock { inode, lock_id } => {
                 write!(f, "Unlock: {inode}, {lock_id}")
             }
@@ -932,6 +1553,41 @@ impl Debug for Request<'_> {
             Request::UpdateMetadataChangedTime { inode, .. } => {
                 write!(f, "UpdateMetadataChangedTime: {inode}")
             }
+            Request::SetRangeLock { inode, lock, .. } => {
+                write!(f, "SetRangeLock: {inode}, {:?}", lock.kind)
+            }
+            Request::GetRangeLock { inode, .. } => write!(f, "GetRangeLock: {inode}"),
+            Request::OpenHandle { inode, handle, .. } => {
+                write!(f, "OpenHandle: {inode}, {handle}")
+            }
+            Request::ReleaseHandle { inode, handle, .. } => {
+                write!(f, "ReleaseHandle: {inode}, {handle}")
+            }
+            Request::ReleaseSession {
+                raft_group,
+                session,
+            } => write!(f, "ReleaseSession: {raft_group}, {session}"),
+            Request::RenewSession {
+                raft_group,
+                session,
+            } => write!(f, "RenewSession: {raft_group}, {session}"),
+            Request::ExpireSessions { raft_group } => write!(f, "ExpireSessions: {raft_group}"),
+            Request::CancelRangeLockWait { inode, .. } => write!(f, "CancelRangeLockWait: {inode}"),
+            Request::Deduplicated {
+                session, sequence, ..
+            } => write!(f, "Deduplicated: {session}, {sequence}"),
+            Request::BeginRequest {
+                inode,
+                session,
+                sequence,
+                ..
+            } => write!(f, "BeginRequest: {inode}, {session}, {sequence}"),
+            Request::FinishRequest {
+                inode,
+                session,
+                sequence,
+                ..
+            } => write!(f, "FinishRequest: {inode}, {session}, {sequence}"),
         }
     }
 }
@@ -945,13 +1601,22 @@ impl Request<'_> {
             | Request::FilesystemChecksum => RequestMetaInfo {
                 raft_group: None,
                 inode: None,
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::NoAccess,
                 distribution_requirement: DistributionRequirement::Any,
             },
-            Request::Fsync { inode } => RequestMetaInfo {
+            Request::Fsync { inode }
+            | Request::SetRangeLock { inode, .. }
+            | Request::GetRangeLock { inode, .. }
+            | Request::CancelRangeLockWait { inode, .. }
+            | Request::OpenHandle { inode, .. }
+            | Request::ReleaseHandle { inode, .. }
+            | Request::BeginRequest { inode, .. }
+            | Request::FinishRequest { inode, .. } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::NoAccess,
                 distribution_requirement: DistributionRequirement::RaftGroup,
@@ -959,6 +1624,7 @@ impl Request<'_> {
             Request::ReadRaw { inode, .. } | Request::Read { inode, .. } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::ReadData,
                 distribution_requirement: DistributionRequirement::RaftGroup,
@@ -969,6 +1635,7 @@ impl Request<'_> {
             | Request::Rmdir { parent: inode, .. } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::WriteMetadata,
                 distribution_requirement: DistributionRequirement::RaftGroup,
@@ -976,6 +1643,7 @@ impl Request<'_> {
             Request::Hardlink { .. } | Request::Rename { .. } => RequestMetaInfo {
                 raft_group: None,
                 inode: None,
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::WriteMetadata,
                 distribution_requirement: DistributionRequirement::TransactionCoordinator,
@@ -983,40 +1651,52 @@ impl Request<'_> {
             Request::Write { inode, .. } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::WriteDataAndMetadata,
                 distribution_requirement: DistributionRequirement::RaftGroup,
             },
             Request::ListXattrs { inode }
             | Request::GetXattr { inode, .. }
+            | Request::Access { inode, .. }
             | Request::Lookup { parent: inode, .. }
-            | Request::ListDir { inode }
+            | Request::ListDir { inode, .. }
+            | Request::GetDirectoryShards { inode }
             | Request::GetAttr { inode } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::ReadMetadata,
                 distribution_requirement: DistributionRequirement::RaftGroup,
             },
             Request::LatestCommit { raft_group }
             | Request::RaftGroupLeader { raft_group }
-            | Request::ConsensusMessage { raft_group, .. } => RequestMetaInfo {
+            | Request::ConsensusMessage { raft_group, .. }
+            | Request::ReleaseSession { raft_group, .. }
+            | Request::RenewSession { raft_group, .. }
+            | Request::ExpireSessions { raft_group }
+            | Request::UpdateAccessTimes { raft_group, .. }
+            | Request::WatchInvalidations { raft_group, .. } => RequestMetaInfo {
                 raft_group: Some(*raft_group),
                 inode: None,
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::NoAccess,
                 distribution_requirement: DistributionRequirement::RaftGroup,
             },
-            Request::Lock { inode } => RequestMetaInfo {
+            Request::Lock { inode, name, mode } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: name.map(str::to_string),
                 lock_id: None,
-                access_type: AccessType::LockMetadata,
+                access_type: AccessType::LockMetadata(*mode),
                 distribution_requirement: DistributionRequirement::RaftGroup,
             },
             Request::Unlock { inode, lock_id } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: None,
                 lock_id: Some(*lock_id),
                 access_type: AccessType::NoAccess,
                 distribution_requirement: DistributionRequirement::RaftGroup,
@@ -1024,6 +1704,7 @@ impl Request<'_> {
             Request::CreateInode { raft_group, .. } => RequestMetaInfo {
                 raft_group: Some(*raft_group),
                 inode: None,
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::WriteMetadata,
                 distribution_requirement: DistributionRequirement::RaftGroup,
@@ -1031,37 +1712,62 @@ impl Request<'_> {
             Request::Mkdir { parent, .. } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*parent),
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::WriteMetadata,
                 distribution_requirement: DistributionRequirement::TransactionCoordinator,
             },
-            Request::Create { parent, .. } => RequestMetaInfo {
-                raft_group: None,
-                inode: Some(*parent),
-                lock_id: None,
-                access_type: AccessType::WriteMetadata,
-                distribution_requirement: DistributionRequirement::TransactionCoordinator,
-            },
+            Request::Create { parent, .. } | Request::CreateTemporary { parent, .. } => {
+                RequestMetaInfo {
+                    raft_group: None,
+                    inode: Some(*parent),
+                    entry: None,
+                    lock_id: None,
+                    access_type: AccessType::WriteMetadata,
+                    distribution_requirement: DistributionRequirement::TransactionCoordinator,
+                }
+            }
             Request::RemoveLink {
-                parent: inode,
+                parent,
+                name,
                 lock_id,
                 ..
             }
             | Request::CreateLink {
-                parent: inode,
+                parent,
+                name,
                 lock_id,
                 ..
             }
-            | Request::DecrementInode { inode, lock_id, .. }
-            | Request::UpdateParent { inode, lock_id, .. }
-            | Request::UpdateMetadataChangedTime { inode, lock_id, .. }
             | Request::ReplaceLink {
-                parent: inode,
+                parent,
+                name,
                 lock_id,
                 ..
             } => RequestMetaInfo {
+                raft_group: None,
+                inode: Some(*parent),
+                entry: Some(name.to_string()),
+                lock_id: *lock_id,
+                access_type: AccessType::WriteMetadata,
+                distribution_requirement: DistributionRequirement::RaftGroup,
+            },
+            // Splitting a directory doesn't change its visible entries, so it doesn't wait for
+            // locks. A rename holding a lock on the directory may need to split it
+            Request::ShardDirectory { inode, .. } => RequestMetaInfo {
+                raft_group: None,
+                inode: Some(*inode),
+                entry: None,
+                lock_id: None,
+                access_type: AccessType::NoAccess,
+                distribution_requirement: DistributionRequirement::RaftGroup,
+            },
+            Request::DecrementInode { inode, lock_id, .. }
+            | Request::UpdateParent { inode, lock_id, .. }
+            | Request::UpdateMetadataChangedTime { inode, lock_id, .. } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: None,
                 lock_id: *lock_id,
                 access_type: AccessType::WriteMetadata,
                 distribution_requirement: DistributionRequirement::RaftGroup,
@@ -1069,6 +1775,7 @@ impl Request<'_> {
             Request::Truncate { inode, .. } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::WriteDataAndMetadata,
                 distribution_requirement: DistributionRequirement::RaftGroup,
@@ -1076,16 +1783,122 @@ impl Request<'_> {
             Request::HardlinkRollback { inode, .. }
             | Request::Chown { inode, .. }
             | Request::Chmod { inode, .. }
+            | Request::SetFlags { inode, .. }
             | Request::HardlinkIncrement { inode, .. }
             | Request::Utimens { inode, .. } => RequestMetaInfo {
                 raft_group: None,
                 inode: Some(*inode),
+                entry: None,
                 lock_id: None,
                 access_type: AccessType::WriteMetadata,
                 distribution_requirement: DistributionRequirement::RaftGroup,
             },
+            // Handled wherever the wrapped request would be
+            Request::Deduplicated { request, .. } => match decode_request(request) {
+                Ok(request) => request.meta_info(),
+                Err(_) => RequestMetaInfo {
+                    raft_group: None,
+                    inode: None,
+                    entry: None,
+                    lock_id: None,
+                    access_type: AccessType::NoAccess,
+                    distribution_requirement: DistributionRequirement::Any,
+                },
+            },
         }
     }
+
+    // Whether the request only reads, so that it can be sent again to another node when it's unknown
+    // whether the first one was received. Mutations are wrapped in a Deduplicated request instead
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Request::FilesystemReady
+            | Request::FilesystemInformation
+            | Request::FilesystemChecksum
+            | Request::FilesystemCheck
+            | Request::Fsync { .. }
+            | Request::GetAttr { .. }
+            | Request::ListDir { .. }
+            | Request::Lookup { .. }
+            | Request::ListXattrs { .. }
+            | Request::GetXattr { .. }
+            | Request::ReadRaw { .. }
+            | Request::Read { .. }
+            | Request::LatestCommit { .. }
+            | Request::RaftGroupLeader { .. }
+            | Request::GetRangeLock { .. }
+            | Request::Access { .. }
+            | Request::GetDirectoryShards { .. }
+            | Request::WatchInvalidations { .. } => true,
+            // The servers return the original result, instead of applying it again
+            Request::Deduplicated { .. } => true,
+            Request::Truncate { .. }
+            | Request::Chown { .. }
+            | Request::Chmod { .. }
+            | Request::Utimens { .. }
+            | Request::SetXattr { .. }
+            | Request::Write { .. }
+            | Request::SetFlags { .. }
+            | Request::SetRangeLock { .. }
+            | Request::CancelRangeLockWait { .. }
+            | Request::RenewSession { .. }
+            | Request::OpenHandle { .. }
+            | Request::Create { .. }
+            | Request::Mkdir { .. }
+            | Request::Unlink { .. }
+            | Request::Rmdir { .. }
+            | Request::Rename { .. }
+            | Request::Hardlink { .. }
+            | Request::RemoveXattr { .. }
+            | Request::ConsensusMessage { .. }
+            | Request::Lock { .. }
+            | Request::Unlock { .. }
+            | Request::HardlinkRollback { .. }
+            | Request::CreateLink { .. }
+            | Request::ReplaceLink { .. }
+            | Request::RemoveLink { .. }
+            | Request::CreateInode { .. }
+            | Request::HardlinkIncrement { .. }
+            | Request::UpdateParent { .. }
+            | Request::UpdateMetadataChangedTime { .. }
+            | Request::DecrementInode { .. }
+            | Request::ReleaseSession { .. }
+            | Request::ExpireSessions { .. }
+            | Request::ReleaseHandle { .. }
+            | Request::CreateTemporary { .. }
+            | Request::ShardDirectory { .. }
+            | Request::UpdateAccessTimes { .. }
+            | Request::BeginRequest { .. }
+            | Request::FinishRequest { .. } => false,
+        }
+    }
+
+    // Whether the request changes the filesystem or its advisory locks, and can be wrapped in a
+    // Deduplicated request. Waiting for a lock isn't, since it doesn't complete when it's applied
+    pub fn can_deduplicate(&self) -> bool {
+        matches!(
+            self,
+            Request::Write { .. }
+                | Request::Truncate { .. }
+                | Request::Chown { .. }
+                | Request::Chmod { .. }
+                | Request::Utimens { .. }
+                | Request::SetXattr { .. }
+                | Request::RemoveXattr { .. }
+                | Request::SetFlags { .. }
+                | Request::SetRangeLock { wait: false, .. }
+                | Request::CancelRangeLockWait { .. }
+                | Request::OpenHandle { .. }
+                | Request::ReleaseHandle { .. }
+                | Request::Create { .. }
+                | Request::Mkdir { .. }
+                | Request::CreateTemporary { .. }
+                | Request::Unlink { .. }
+                | Request::Rmdir { .. }
+                | Request::Rename { .. }
+                | Request::Hardlink { .. }
+        )
+    }
 }
 
 // Helper methods for reading a decoded response
@@ -1112,9 +1925,10 @@ impl<'a, X: List<Item = &'a str>, D: List<Item = DirectoryEntry<'a>>, C: List<It
         }
     }
 
-    pub fn as_directory_listing_response(&self) -> Option<&D> {
-        if let WireResponse::DirectoryListing(entries) = self {
-            Some(entries)
+    // Returns the entries, and the name to resume the listing after
+    pub fn as_directory_listing_response(&self) -> Option<(&D, Option<&'a str>)> {
+        if let WireResponse::DirectoryListing(entries, resume_after) = self {
+            Some((entries, *resume_after))
         } else {
             None
         }
@@ -1153,13 +1967,26 @@ impl<'a, X: List<Item = &'a str>, D: List<Item = DirectoryEntry<'a>>, C: List<It
     }
 
     pub fn as_bytes_written_response(&self) -> Option<u32> {
-        if let WireResponse::Written { bytes_written } = self {
+        if let WireResponse::Written { bytes_written, .. } = self {
             Some(*bytes_written)
         } else {
             None
         }
     }
 
+    // Returns the offset the data was written at, and the number of bytes written
+    pub fn as_written_response(&self) -> Option<(u64, u32)> {
+        if let WireResponse::Written {
+            bytes_written,
+            offset,
+        } = self
+        {
+            Some((*offset, *bytes_written))
+        } else {
+            None
+        }
+    }
+
     pub fn as_inode_response(&self) -> Option<u64> {
         if let WireResponse::Inode { id } = self {
             Some(*id)
@@ -1175,4 +2002,47 @@ impl<'a, X: List<Item = &'a str>, D: List<Item = DirectoryEntry<'a>>, C: List<It
             None
         }
     }
+
+    pub fn as_directory_shards_response(&self) -> Option<Vec<u64>> {
+        if let WireResponse::DirectoryShards { shards } = self {
+            Some(unpack_inodes(shards))
+        } else {
+            None
+        }
+    }
+
+    pub fn as_invalidations_response(&self) -> Option<Invalidations> {
+        if let WireResponse::Invalidations {
+            epoch,
+            sequence,
+            complete,
+            inodes,
+            data_inodes,
+            entry_parents,
+            entry_names,
+        } = self
+        {
+            Some(Invalidations {
+                epoch: *epoch,
+                sequence: *sequence,
+                complete: *complete,
+                inodes: unpack_inodes(inodes),
+                data_inodes: unpack_inodes(data_inodes),
+                entries: unpack_inodes(entry_parents)
+                    .into_iter()
+                    .zip(entry_names.iter().map(ToString::to_string))
+                    .collect(),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn as_range_lock_response(&self) -> Option<Option<RangeLock>> {
+        if let WireResponse::RangeLock { conflict } = self {
+            Some(*conflict)
+        } else {
+            None
+        }
+    }
 }
@@ -1,7 +1,9 @@
+mod acl;
 mod local_context;
 mod message_types;
 mod utils;
 
-pub use local_context::LocalContext;
+pub use acl::{ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR, PosixAcl};
+pub use local_context::{AtimeMode, LocalContext};
 pub use message_types::*;
 pub use utils::{check_access, node_contains_raft_group, node_id_from_address, response_or_error};
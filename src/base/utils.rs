@@ -1,5 +1,5 @@
-use crate::base::ErrorCode;
 use crate::base::message_types::{ResponseView, decode_response};
+use crate::base::{ErrorCode, PosixAcl, UserContext};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 use std::net::{IpAddr, SocketAddr};
@@ -38,8 +38,9 @@ pub fn check_access(
     file_uid: u32,
     file_gid: u32,
     file_mode: u16,
-    uid: u32,
-    gid: u32,
+    // The file's access ACL, if it has one beyond its mode bits
+    acl: Option<&PosixAcl>,
+    context: &UserContext,
     mut access_mask: i32,
 ) -> bool {
     // F_OK tests for existence of file
@@ -47,6 +48,7 @@ pub fn check_access(
         return true;
     }
     let file_mode = i32::from(file_mode);
+    let uid = context.uid();
 
     // root is allowed to read & write anything
     if uid == 0 {
@@ -58,9 +60,13 @@ pub fn check_access(
         return access_mask == 0;
     }
 
+    if let Some(acl) = acl {
+        return acl.check_access(file_uid, file_gid, context, access_mask);
+    }
+
     if uid == file_uid {
         access_mask -= access_mask & (file_mode >> 6);
-    } else if gid == file_gid {
+    } else if context.in_group(file_gid) {
         access_mask -= access_mask & (file_mode >> 3);
     } else {
         access_mask -= access_mask & file_mode;
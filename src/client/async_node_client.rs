@@ -0,0 +1,1090 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+use crate::base::response_or_error;
+use crate::base::{
+    DirectoryPage, EntryMetadata, ErrorCode, FileKind, OpenHandleId, RangeLock, RangeLockKind,
+    Request, SESSION_LEASE_SECONDS, Timestamp, UserContext, WireResponse, encode_request,
+};
+use crate::client::node_client::StatFS;
+use crate::client::tcp_client::FailoverPolicy;
+use crate::client::{PeerClient, TcpPeerClient};
+use crate::storage::ROOT_INODE;
+use log::warn;
+use rand::Rng;
+use zerialize::List;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// How long to keep waiting for a request that was sent again, while the first attempt is still
+// being applied
+const REQUEST_IN_PROGRESS_TIMEOUT: Duration = Duration::from_secs(60);
+const REQUEST_IN_PROGRESS_RETRY_DELAY: Duration = Duration::from_millis(100);
+// How often the session's lease is renewed, so that a few renewals can fail before it expires
+const SESSION_RENEW_INTERVAL: Duration = Duration::from_secs(SESSION_LEASE_SECONDS as u64 / 6);
+
+// Removes the sequence number from the in flight requests, even if the request's future is dropped
+struct InFlight<'a> {
+    in_flight: &'a Mutex<BTreeSet<u64>>,
+    sequence: u64,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.in_flight
+            .lock()
+            .expect("lock acquisition failed")
+            .remove(&self.sequence);
+    }
+}
+
+// Waits for a lock until it's granted. If dropped before then, the wait is cancelled, so that the
+// lock isn't granted to a caller which stopped waiting for it
+struct RangeLockWait {
+    client: AsyncNodeClient,
+    inode: u64,
+    lock: RangeLock,
+    done: bool,
+}
+
+impl Drop for RangeLockWait {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let request = Request::CancelRangeLockWait {
+            inode: self.inode,
+            lock: self.lock,
+        };
+        let cancel = self.client.send_request(&request);
+        // The runtime may be shutting down, in which case the session's locks are released anyway
+        // once its lease expires
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move {
+                if let Err(error_code) = cancel.await {
+                    warn!("Failed to cancel lock wait: {error_code:?}");
+                }
+            });
+        }
+    }
+}
+
+// Renews the session's lease in every raft group, until all clones of the client are dropped
+async fn renew_session(
+    servers: Arc<RwLock<Vec<TcpPeerClient>>>,
+    failover: Arc<FailoverPolicy>,
+    session: u64,
+    next_sequence: Arc<AtomicU64>,
+    in_flight: Weak<Mutex<BTreeSet<u64>>>,
+) {
+    let mut raft_groups = None;
+    loop {
+        tokio::time::sleep(SESSION_RENEW_INTERVAL).await;
+        let Some(in_flight) = in_flight.upgrade() else {
+            return;
+        };
+        // Only holds the client's state until this renewal is done
+        let client = AsyncNodeClient {
+            servers: servers.clone(),
+            failover: failover.clone(),
+            session,
+            next_sequence: next_sequence.clone(),
+            in_flight,
+        };
+        client.renew_session(&mut raft_groups).await;
+    }
+}
+
+// Async version of NodeClient, for use from a tokio runtime. Requests share the connections of a
+// TcpPeerClient, which can each have many requests in flight, so no thread is needed per request.
+// Nothing is cached, and clones share the same connections and session
+#[derive(Clone)]
+pub struct AsyncNodeClient {
+    // Only ever appended to, so that indices into it stay valid
+    servers: Arc<RwLock<Vec<TcpPeerClient>>>,
+    failover: Arc<FailoverPolicy>,
+    // Identifies this client's advisory locks, open handles and mutating requests. See NodeClient
+    session: u64,
+    next_sequence: Arc<AtomicU64>,
+    // Sequence numbers of the mutating requests which haven't completed
+    in_flight: Arc<Mutex<BTreeSet<u64>>>,
+}
+
+impl AsyncNodeClient {
+    // Requests are sent to the first of the servers, and fail over to the others. Must be called
+    // from within a tokio runtime, which the session's lease is renewed on
+    pub fn new(servers: Vec<SocketAddr>) -> AsyncNodeClient {
+        assert!(!servers.is_empty());
+        let client = AsyncNodeClient {
+            servers: Arc::new(RwLock::new(
+                servers.into_iter().map(TcpPeerClient::new).collect(),
+            )),
+            failover: Arc::new(FailoverPolicy::new()),
+            session: rand::rng().random(),
+            next_sequence: Arc::new(AtomicU64::new(0)),
+            in_flight: Arc::new(Mutex::new(BTreeSet::new())),
+        };
+        tokio::spawn(renew_session(
+            client.servers.clone(),
+            client.failover.clone(),
+            client.session,
+            client.next_sequence.clone(),
+            Arc::downgrade(&client.in_flight),
+        ));
+
+        client
+    }
+
+    // Renews the session's lease in every raft group. The number of raft groups is looked up by the
+    // first renewal
+    async fn renew_session(&self, raft_groups: &mut Option<u16>) {
+        let groups = match *raft_groups {
+            Some(groups) => groups,
+            None => match self.statfs().await {
+                Ok(statfs) => *raft_groups.insert(statfs.raft_groups),
+                Err(error_code) => {
+                    warn!("Failed to renew session: {error_code:?}");
+                    return;
+                }
+            },
+        };
+        for raft_group in 0..groups {
+            let request = Request::RenewSession {
+                raft_group,
+                session: self.session,
+            };
+            if let Err(error_code) = self.send_request(&request).await {
+                warn!("Failed to renew session in rgroup {raft_group}: {error_code:?}");
+            }
+        }
+    }
+
+    // Adds the other nodes in the cluster to the servers which requests can fail over to
+    pub async fn discover_nodes(&self) -> Result<(), ErrorCode> {
+        let peers = self.statfs().await?.peers;
+        let mut servers = self.servers.write().expect("lock acquisition failed");
+        for peer in peers {
+            if !servers.iter().any(|server| server.server_ip_port() == peer) {
+                servers.push(TcpPeerClient::new(peer));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn server(&self) -> (usize, TcpPeerClient) {
+        let servers = self.servers.read().expect("lock acquisition failed");
+        let index = self.failover.current_server(servers.len());
+        (index, servers[index].clone())
+    }
+
+    fn request_failed(&self, failed: usize, error: &std::io::Error) {
+        let servers: Vec<SocketAddr> = self
+            .servers
+            .read()
+            .expect("lock acquisition failed")
+            .iter()
+            .map(|server| server.server_ip_port())
+            .collect();
+        self.failover.request_failed(failed, error, &servers);
+    }
+
+    // idempotent must only be set if the request can safely be applied more than once
+    async fn send_raw(
+        &self,
+        data: Arc<[u8]>,
+        idempotent: bool,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        let attempts = self.servers.read().expect("lock acquisition failed").len();
+        for _ in 0..attempts {
+            let (index, server) = self.server();
+            let response = server.send_raw(data.clone());
+            let result = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, response)
+                    .await
+                    .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())),
+                None => response.await,
+            };
+            match result {
+                Ok(buffer) => {
+                    self.failover.request_succeeded();
+                    return Ok(buffer);
+                }
+                Err(error) => {
+                    self.request_failed(index, &error);
+                    // The request may have been applied, so it's only sent again if that's safe
+                    if !idempotent {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Err(ErrorCode::Uncategorized)
+    }
+
+    // Sends a mutating request with a sequence number, so that it can safely be sent again after a
+    // failure. The servers return the original result, if it was already applied
+    async fn send_deduplicated(&self, request: &[u8]) -> Result<Vec<u8>, ErrorCode> {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        let acknowledged = {
+            let mut in_flight = self.in_flight.lock().expect("lock acquisition failed");
+            in_flight.insert(sequence);
+            *in_flight.first().unwrap()
+        };
+        let _in_flight = InFlight {
+            in_flight: &self.in_flight,
+            sequence,
+        };
+        let request: Arc<[u8]> = encode_request(&Request::Deduplicated {
+            session: self.session,
+            sequence,
+            acknowledged,
+            request,
+        })
+        .into();
+        let start = Instant::now();
+        loop {
+            let buffer = self.send_raw(request.clone(), true, Some(TIMEOUT)).await?;
+            // The request may have reached another node, which is still applying it
+            if response_or_error(&buffer).err() == Some(ErrorCode::RequestInProgress)
+                && start.elapsed() < REQUEST_IN_PROGRESS_TIMEOUT
+            {
+                tokio::time::sleep(REQUEST_IN_PROGRESS_RETRY_DELAY).await;
+                continue;
+            }
+            return Ok(buffer);
+        }
+    }
+
+    // Sends any request, and returns the encoded response, which can be decoded with
+    // decode_response(). The future doesn't borrow the client or the request, so it can be spawned
+    pub fn send_request(
+        &self,
+        request: &Request<'_>,
+    ) -> impl Future<Output = Result<Vec<u8>, ErrorCode>> + Send + 'static {
+        let client = self.clone();
+        let data = encode_request(request);
+        let deduplicate = request.can_deduplicate();
+        let idempotent = request.is_idempotent();
+        // Waiting for a lock may take arbitrarily long, so the request doesn't time out
+        let timeout = match request {
+            Request::SetRangeLock { wait: true, .. } => None,
+            _ => Some(TIMEOUT),
+        };
+        async move {
+            let buffer = if deduplicate {
+                client.send_deduplicated(&data).await?
+            } else {
+                client.send_raw(data.into(), idempotent, timeout).await?
+            };
+            response_or_error(&buffer)?;
+
+            Ok(buffer)
+        }
+    }
+
+    pub async fn filesystem_ready(&self) -> Result<(), ErrorCode> {
+        let buffer = self.send_request(&Request::FilesystemReady).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn fsck(&self) -> Result<(), ErrorCode> {
+        let buffer = self.send_request(&Request::FilesystemCheck).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn statfs(&self) -> Result<StatFS, ErrorCode> {
+        let buffer = self.send_request(&Request::FilesystemInformation).await?;
+        if let WireResponse::FilesystemInformation {
+            block_size,
+            max_name_length,
+            raft_groups,
+            peers,
+        } = response_or_error(&buffer)?
+        {
+            Ok(StatFS {
+                block_size,
+                max_name_length,
+                raft_groups,
+                peers: peers.iter().filter_map(|peer| peer.parse().ok()).collect(),
+            })
+        } else {
+            Err(ErrorCode::BadResponse)
+        }
+    }
+
+    pub async fn mkdir(
+        &self,
+        parent: u64,
+        name: &str,
+        // The new inode is owned by this user
+        context: UserContext<'_>,
+        mode: u16,
+        umask: u16,
+    ) -> Result<EntryMetadata, ErrorCode> {
+        let request = Request::Mkdir {
+            parent,
+            name,
+            uid: context.uid(),
+            gid: context.gid(),
+            mode,
+            umask,
+            groups: context.packed_groups(),
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_attr_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn lookup(
+        &self,
+        parent: u64,
+        name: &str,
+        context: UserContext<'_>,
+    ) -> Result<u64, ErrorCode> {
+        let request = Request::Lookup {
+            parent,
+            name,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_inode_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(
+        &self,
+        parent: u64,
+        name: &str,
+        // The new inode is owned by this user
+        context: UserContext<'_>,
+        mode: u16,
+        umask: u16,
+        kind: FileKind,
+        rdev: u32,
+    ) -> Result<EntryMetadata, ErrorCode> {
+        let request = Request::Create {
+            parent,
+            name,
+            uid: context.uid(),
+            gid: context.gid(),
+            mode,
+            kind,
+            rdev,
+            umask,
+            groups: context.packed_groups(),
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_attr_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    // Creates an unnamed file in parent, which stays open on handle until it's released
+    pub async fn create_temporary(
+        &self,
+        parent: u64,
+        // The new inode is owned by this user
+        context: UserContext<'_>,
+        mode: u16,
+        umask: u16,
+        handle: u64,
+    ) -> Result<EntryMetadata, ErrorCode> {
+        let request = Request::CreateTemporary {
+            parent,
+            uid: context.uid(),
+            gid: context.gid(),
+            mode,
+            open_handle: OpenHandleId {
+                session: self.session,
+                handle,
+            },
+            umask,
+            groups: context.packed_groups(),
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_attr_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn getattr(&self, inode: u64) -> Result<EntryMetadata, ErrorCode> {
+        let buffer = self.send_request(&Request::GetAttr { inode }).await?;
+        response_or_error(&buffer)?
+            .as_attr_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    // Checks access to the inode, including any access granted by its ACL
+    pub async fn access(
+        &self,
+        inode: u64,
+        mask: i32,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        let request = Request::Access {
+            inode,
+            mask,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn getxattr(
+        &self,
+        inode: u64,
+        key: &str,
+        context: UserContext<'_>,
+    ) -> Result<Vec<u8>, ErrorCode> {
+        let request = Request::GetXattr {
+            inode,
+            key,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        let data = response_or_error(&buffer)?
+            .as_read_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        Ok(data.to_vec())
+    }
+
+    pub async fn listxattr(&self, inode: u64) -> Result<Vec<String>, ErrorCode> {
+        let buffer = self.send_request(&Request::ListXattrs { inode }).await?;
+        let response = response_or_error(&buffer)?;
+        let xattrs = response
+            .as_xattrs_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        Ok(xattrs.iter().map(|x| x.to_string()).collect())
+    }
+
+    pub async fn setxattr(
+        &self,
+        inode: u64,
+        key: &str,
+        value: &[u8],
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        let request = Request::SetXattr {
+            inode,
+            key,
+            value,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn removexattr(
+        &self,
+        inode: u64,
+        key: &str,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        let request = Request::RemoveXattr {
+            inode,
+            key,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn utimens(
+        &self,
+        inode: u64,
+        atime: Option<Timestamp>,
+        mtime: Option<Timestamp>,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+        let request = Request::Utimens {
+            inode,
+            atime,
+            mtime,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn chmod(
+        &self,
+        inode: u64,
+        mode: u32,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        if inode == ROOT_INODE {
+            return Err(ErrorCode::OperationNotPermitted);
+        }
+        let request = Request::Chmod {
+            inode,
+            mode,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn set_flags(
+        &self,
+        inode: u64,
+        flags: u32,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        let request = Request::SetFlags {
+            inode,
+            flags,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn chown(
+        &self,
+        inode: u64,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+        let request = Request::Chown {
+            inode,
+            uid,
+            gid,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn hardlink(
+        &self,
+        inode: u64,
+        new_parent: u64,
+        new_name: &str,
+        context: UserContext<'_>,
+    ) -> Result<EntryMetadata, ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+        let request = Request::Hardlink {
+            inode,
+            new_parent,
+            new_name,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_attr_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+        flags: u32,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        let request = Request::Rename {
+            parent,
+            name,
+            new_parent,
+            new_name,
+            context,
+            flags,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn readlink(&self, inode: u64) -> Result<Vec<u8>, ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+        // TODO: this just tries to read a value longer than the longest link, like NodeClient
+        self.read(inode, 0, 999_999).await
+    }
+
+    pub async fn read(&self, inode: u64, offset: u64, size: u32) -> Result<Vec<u8>, ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+        let request = Request::Read {
+            inode,
+            offset,
+            read_size: size,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        let data = response_or_error(&buffer)?
+            .as_read_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        Ok(data.to_vec())
+    }
+
+    // Lists up to limit entries, starting after the given name. Also returns the name to resume
+    // the listing after, or None if it reached the end of the directory. See Request::ListDir
+    pub async fn readdir(
+        &self,
+        inode: u64,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<DirectoryPage<(u64, String, FileKind)>, ErrorCode> {
+        let request = Request::ListDir {
+            inode,
+            after,
+            limit,
+            with_attributes: false,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        let response = response_or_error(&buffer)?;
+        let (entries, resume_after) = response
+            .as_directory_listing_response()
+            .ok_or(ErrorCode::BadResponse)?;
+
+        let result = entries
+            .iter()
+            .map(|entry| (entry.inode, entry.name.to_string(), entry.kind))
+            .collect();
+
+        Ok((result, resume_after.map(str::to_string)))
+    }
+
+    // Same as readdir(), but also returns the attributes of each entry
+    pub async fn readdirplus(
+        &self,
+        inode: u64,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<DirectoryPage<(String, EntryMetadata)>, ErrorCode> {
+        let request = Request::ListDir {
+            inode,
+            after,
+            limit,
+            with_attributes: true,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        let response = response_or_error(&buffer)?;
+        let (entries, resume_after) = response
+            .as_directory_listing_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        let mut result = vec![];
+        for entry in entries.iter() {
+            let attr = entry.attributes.ok_or(ErrorCode::BadResponse)?;
+            result.push((entry.name.to_string(), attr));
+        }
+
+        Ok((result, resume_after.map(str::to_string)))
+    }
+
+    pub async fn truncate(
+        &self,
+        inode: u64,
+        length: u64,
+        has_write_handle: bool,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        assert_ne!(inode, ROOT_INODE);
+        let request = Request::Truncate {
+            inode,
+            new_length: length,
+            context,
+            has_write_handle,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn write(
+        &self,
+        inode: u64,
+        data: &[u8],
+        offset: u64,
+        uid: u32,
+    ) -> Result<u32, ErrorCode> {
+        let request = Request::Write {
+            inode,
+            offset,
+            data,
+            uid,
+            append: false,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_bytes_written_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    // Returns the held lock which conflicts with the requested one, if any
+    pub async fn get_range_lock(
+        &self,
+        inode: u64,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        kind: RangeLockKind,
+    ) -> Result<Option<RangeLock>, ErrorCode> {
+        let lock = RangeLock {
+            session: self.session,
+            owner,
+            pid,
+            start,
+            end,
+            kind,
+        };
+
+        let buffer = self
+            .send_request(&Request::GetRangeLock { inode, lock })
+            .await?;
+        response_or_error(&buffer)?
+            .as_range_lock_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    // If wait is true, resolves once any conflicting locks are released. The wait doesn't time out,
+    // but is cancelled if the future is dropped, such as by tokio::time::timeout()
+    #[allow(clippy::too_many_arguments)]
+    pub async fn set_range_lock(
+        &self,
+        inode: u64,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        kind: RangeLockKind,
+        wait: bool,
+    ) -> Result<(), ErrorCode> {
+        let lock = RangeLock {
+            session: self.session,
+            owner,
+            pid,
+            start,
+            end,
+            kind,
+        };
+
+        let mut lock_wait = RangeLockWait {
+            client: self.clone(),
+            inode,
+            lock,
+            done: !wait,
+        };
+        let response = self
+            .send_request(&Request::SetRangeLock { inode, lock, wait })
+            .await;
+        lock_wait.done = true;
+        let buffer = response?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn open_handle(&self, inode: u64, handle: u64) -> Result<(), ErrorCode> {
+        let request = Request::OpenHandle {
+            inode,
+            session: self.session,
+            handle,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn release_handle(&self, inode: u64, handle: u64) -> Result<(), ErrorCode> {
+        let request = Request::ReleaseHandle {
+            inode,
+            session: self.session,
+            handle,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn fsync(&self, inode: u64) -> Result<(), ErrorCode> {
+        let buffer = self.send_request(&Request::Fsync { inode }).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn unlink(
+        &self,
+        parent: u64,
+        name: &str,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        let request = Request::Unlink {
+            parent,
+            name,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+
+    pub async fn rmdir(
+        &self,
+        parent: u64,
+        name: &str,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        let request = Request::Rmdir {
+            parent,
+            name,
+            context,
+        };
+
+        let buffer = self.send_request(&request).await?;
+        response_or_error(&buffer)?
+            .as_empty_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{
+        RangeLockKind, Request, Response, decode_request, encode_request, encode_response,
+    };
+    use crate::client::AsyncNodeClient;
+    use crate::client::REQUEST_ID_SIZE;
+    use crate::client::tcp_client::TIMEOUTS_BEFORE_FAIL_OVER;
+    use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Clone, Copy)]
+    enum Behaviour {
+        Respond,
+        NeverRespond,
+        // Closes the connection once a request has been received
+        Close,
+    }
+
+    // Starts a server which responds to requests with Empty, except that lock waits are never
+    // granted. Returns the requests that it received
+    fn server(behaviour: Behaviour) -> (SocketAddr, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(vec![]));
+        let server_received = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let received = server_received.clone();
+                thread::spawn(move || {
+                    while let Ok(size) = stream.read_u32::<LittleEndian>() {
+                        let mut frame = vec![0; size as usize];
+                        stream.read_exact(&mut frame).unwrap();
+                        let (request_id, request) = frame.split_at(REQUEST_ID_SIZE);
+                        received.lock().unwrap().push(request.to_vec());
+                        let response = match (behaviour, decode_request(request).unwrap()) {
+                            (Behaviour::Close, _) => break,
+                            (Behaviour::NeverRespond, _) => continue,
+                            (_, Request::SetRangeLock { wait: true, .. }) => continue,
+                            (_, Request::FilesystemInformation) => {
+                                Response::FilesystemInformation {
+                                    block_size: 512,
+                                    max_name_length: 255,
+                                    raft_groups: 2,
+                                    peers: vec![],
+                                }
+                            }
+                            _ => Response::Empty,
+                        };
+                        let response = encode_response(&response);
+                        stream
+                            .write_u32::<LittleEndian>((REQUEST_ID_SIZE + response.len()) as u32)
+                            .unwrap();
+                        stream.write_all(request_id).unwrap();
+                        stream.write_all(&response).unwrap();
+                    }
+                });
+            }
+        });
+
+        (address, received)
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_io()
+            .enable_time()
+            .worker_threads(1)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn fail_over_after_repeated_timeouts() {
+        runtime().block_on(async {
+            let (unresponsive, _) = server(Behaviour::NeverRespond);
+            let (responsive, _) = server(Behaviour::Respond);
+            let client = AsyncNodeClient::new(vec![unresponsive, responsive]);
+            let request: Arc<[u8]> = encode_request(&Request::FilesystemReady).into();
+            let timeout = Some(Duration::from_millis(50));
+            // A node that's slow to respond may just be busy, so it isn't failed over from at first
+            for _ in 1..TIMEOUTS_BEFORE_FAIL_OVER {
+                let result = client.send_raw(request.clone(), false, timeout).await;
+                assert!(result.is_err());
+                assert_eq!(client.server().0, 0);
+            }
+
+            // Once enough requests have timed out, an idempotent one is sent to the next node
+            client.send_raw(request, true, timeout).await.unwrap();
+            assert_eq!(client.server().0, 1);
+        });
+    }
+
+    #[test]
+    fn non_idempotent_requests_not_sent_again() {
+        runtime().block_on(async {
+            let (closing, closing_received) = server(Behaviour::Close);
+            let (responsive, received) = server(Behaviour::Respond);
+            let client = AsyncNodeClient::new(vec![closing, responsive]);
+
+            // The request reached the first node, so it may have been applied
+            let result = client
+                .set_range_lock(2, 1, 1, 0, u64::MAX, RangeLockKind::Write, true)
+                .await;
+            assert!(result.is_err());
+            assert_eq!(closing_received.lock().unwrap().len(), 1);
+            assert!(received.lock().unwrap().is_empty());
+            // A lost connection fails over straight away
+            assert_eq!(client.server().0, 1);
+            client.filesystem_ready().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn dropped_lock_wait_cancelled() {
+        runtime().block_on(async {
+            let (address, received) = server(Behaviour::Respond);
+            let client = AsyncNodeClient::new(vec![address]);
+            let wait = client.set_range_lock(2, 1, 1, 0, u64::MAX, RangeLockKind::Write, true);
+            assert!(
+                tokio::time::timeout(Duration::from_millis(50), wait)
+                    .await
+                    .is_err()
+            );
+
+            let cancelled = || {
+                received.lock().unwrap().iter().any(|request| {
+                    let request = match decode_request(request).unwrap() {
+                        Request::Deduplicated { request, .. } => decode_request(request).unwrap(),
+                        request => request,
+                    };
+                    matches!(request, Request::CancelRangeLockWait { inode: 2, .. })
+                })
+            };
+            for _ in 0..100 {
+                if cancelled() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert!(cancelled());
+        });
+    }
+
+    #[test]
+    fn session_renewed_in_every_raft_group() {
+        runtime().block_on(async {
+            let (address, received) = server(Behaviour::Respond);
+            let client = AsyncNodeClient::new(vec![address]);
+            let mut raft_groups = None;
+            client.renew_session(&mut raft_groups).await;
+            client.renew_session(&mut raft_groups).await;
+            assert_eq!(raft_groups, Some(2));
+
+            let received = received.lock().unwrap();
+            let requests: Vec<Request> = received
+                .iter()
+                .map(|request| decode_request(request).unwrap())
+                .collect();
+            // The raft groups are only looked up once
+            let lookups = requests
+                .iter()
+                .filter(|request| matches!(request, Request::FilesystemInformation))
+                .count();
+            assert_eq!(lookups, 1);
+            let renewed: Vec<(u16, u64)> = requests
+                .iter()
+                .filter_map(|request| match request {
+                    Request::RenewSession {
+                        raft_group,
+                        session,
+                    } => Some((*raft_group, *session)),
+                    _ => None,
+                })
+                .collect();
+            let session = client.session;
+            assert_eq!(
+                renewed,
+                [(0, session), (1, session), (0, session), (1, session)]
+            );
+        });
+    }
+}
@@ -110,7 +110,10 @@ impl RemoteRaftGroups {
         request: Vec<u8>,
         meta: RequestMetaInfo,
     ) -> impl Future<Output = Result<Vec<u8>, std::io::Error>> + use<> {
-        let raft_group_id = meta.inode.unwrap() % self.total_raft_groups as u64;
+        let raft_group_id = match meta.raft_group {
+            Some(raft_group) => raft_group as u64,
+            None => meta.inode.unwrap() % self.total_raft_groups as u64,
+        };
         self.groups
             .get(&(raft_group_id as u16))
             .unwrap()
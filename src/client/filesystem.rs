@@ -0,0 +1,664 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::warn;
+
+use crate::base::{DirectoryPage, EntryMetadata, ErrorCode, FileKind, UserContext, pack_groups};
+use crate::client::NodeClient;
+use crate::storage::ROOT_INODE;
+
+// Number of directory entries requested from the server at a time
+const READ_DIR_PAGE_SIZE: u32 = 512;
+// Largest read or write sent in a single request. Larger ones are done partially, as the io traits
+// allow
+const MAX_TRANSFER_SIZE: usize = 1024 * 1024;
+
+impl From<ErrorCode> for io::Error {
+    fn from(error: ErrorCode) -> io::Error {
+        let errno = match error {
+            ErrorCode::DoesNotExist => libc::ENOENT,
+            ErrorCode::InodeDoesNotExist => libc::EBADF,
+            ErrorCode::FileTooLarge => libc::EFBIG,
+            ErrorCode::AccessDenied => libc::EACCES,
+            ErrorCode::OperationNotPermitted => libc::EPERM,
+            ErrorCode::AlreadyExists => libc::EEXIST,
+            ErrorCode::NameTooLong => libc::ENAMETOOLONG,
+            ErrorCode::NotEmpty => libc::ENOTEMPTY,
+            ErrorCode::MissingXattrKey => libc::ENODATA,
+            ErrorCode::InvalidXattrNamespace => libc::ENOTSUP,
+            ErrorCode::WouldBlock => libc::EAGAIN,
+            ErrorCode::InvalidArgument => libc::EINVAL,
+            ErrorCode::Interrupted => libc::EINTR,
+            ErrorCode::BadResponse
+            | ErrorCode::BadRequest
+            | ErrorCode::Corrupted
+            | ErrorCode::RaftFailure
+            | ErrorCode::Uncategorized
+            | ErrorCode::RequestInProgress => libc::EIO,
+        };
+        io::Error::from_raw_os_error(errno)
+    }
+}
+
+// The user that requests are made as, whose permissions are checked by the servers
+struct User {
+    uid: u32,
+    gid: u32,
+    // Packed by pack_groups()
+    groups: Vec<u8>,
+}
+
+impl User {
+    fn context(&self) -> UserContext<'_> {
+        UserContext::with_groups(self.uid, self.gid, &self.groups)
+    }
+}
+
+// Options for opening a file, with the same meanings as std::fs::OpenOptions
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+    mode: u16,
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            mode: 0o666,
+        }
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.write = write;
+        self
+    }
+
+    // Writes go to the end of the file. Implies write
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.create = create;
+        self
+    }
+
+    // Fail if the file already exists. Implies create
+    pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        self.create_new = create_new;
+        self
+    }
+
+    // Permissions of the file, if it's created
+    pub fn mode(&mut self, mode: u16) -> &mut OpenOptions {
+        self.mode = mode;
+        self
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions::new()
+    }
+}
+
+// Path-based access to a FleetFS cluster, for programs which use it without mounting it.
+// Paths are resolved from the root of the filesystem, and symbolic links in them aren't followed
+pub struct Filesystem {
+    client: Arc<NodeClient>,
+    user: Arc<User>,
+    next_file_handle: AtomicU64,
+}
+
+impl Filesystem {
+    // Connects to the cluster through the given nodes, and makes requests as the given user
+    pub fn connect(
+        servers: Vec<SocketAddr>,
+        uid: u32,
+        gid: u32,
+        groups: &[u32],
+    ) -> Result<Filesystem, ErrorCode> {
+        let client = NodeClient::new(servers);
+        client.discover_nodes()?;
+        // Caches metadata, now that it will be invalidated when other clients change it
+        client.start_invalidation_watchers(Arc::new(|_| {}))?;
+
+        Ok(Filesystem {
+            client: Arc::new(client),
+            user: Arc::new(User {
+                uid,
+                gid,
+                groups: pack_groups(groups),
+            }),
+            next_file_handle: AtomicU64::new(0),
+        })
+    }
+
+    fn resolve(&self, path: &Path) -> Result<u64, ErrorCode> {
+        // The directories leading to the current one, so that ".." can be resolved
+        let mut inodes = vec![ROOT_INODE];
+        for component in path.components() {
+            match component {
+                Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+                Component::ParentDir => {
+                    if inodes.len() > 1 {
+                        inodes.pop();
+                    }
+                }
+                Component::Normal(name) => {
+                    let name = name.to_str().ok_or(ErrorCode::InvalidArgument)?;
+                    let parent = *inodes.last().unwrap();
+                    inodes.push(self.client.lookup(parent, name, self.user.context())?);
+                }
+            }
+        }
+
+        Ok(*inodes.last().unwrap())
+    }
+
+    // Resolves the directory containing the path, and returns it with the path's last component
+    fn resolve_parent<'a>(&self, path: &'a Path) -> Result<(u64, &'a str), ErrorCode> {
+        let name = path.file_name().ok_or(ErrorCode::InvalidArgument)?;
+        let name = name.to_str().ok_or(ErrorCode::InvalidArgument)?;
+        let parent = self.resolve(path.parent().unwrap_or(Path::new("/")))?;
+
+        Ok((parent, name))
+    }
+
+    pub fn metadata(&self, path: impl AsRef<Path>) -> Result<EntryMetadata, ErrorCode> {
+        let inode = self.resolve(path.as_ref())?;
+        self.client.getattr(inode)
+    }
+
+    pub fn set_permissions(&self, path: impl AsRef<Path>, mode: u32) -> Result<(), ErrorCode> {
+        let inode = self.resolve(path.as_ref())?;
+        self.client.chmod(inode, mode, self.user.context())
+    }
+
+    pub fn open(&self, path: impl AsRef<Path>, options: &OpenOptions) -> Result<File, ErrorCode> {
+        let path = path.as_ref();
+        let write = options.write || options.append;
+        let (parent, name) = self.resolve_parent(path)?;
+        let mut created = false;
+        let inode = match self.client.lookup(parent, name, self.user.context()) {
+            Ok(_) if options.create_new => return Err(ErrorCode::AlreadyExists),
+            Ok(inode) => inode,
+            Err(ErrorCode::DoesNotExist) if options.create || options.create_new => {
+                match self.client.create(
+                    parent,
+                    name,
+                    self.user.context(),
+                    options.mode,
+                    0,
+                    FileKind::File,
+                    0,
+                ) {
+                    Ok(attr) => {
+                        created = true;
+                        attr.inode
+                    }
+                    // Another client created it first
+                    Err(ErrorCode::AlreadyExists) if !options.create_new => {
+                        self.client.lookup(parent, name, self.user.context())?
+                    }
+                    Err(error_code) => return Err(error_code),
+                }
+            }
+            Err(error_code) => return Err(error_code),
+        };
+
+        if self.client.getattr(inode)?.kind == FileKind::Directory {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        // The creator can use the file regardless of the permissions it was created with
+        if !created {
+            let mut mask = 0;
+            if options.read {
+                mask |= libc::R_OK;
+            }
+            if write {
+                mask |= libc::W_OK;
+            }
+            self.client.access(inode, mask, self.user.context())?;
+        }
+        if options.truncate && write && !created {
+            self.client.truncate(inode, 0, true, self.user.context())?;
+        }
+        let handle = self.next_file_handle.fetch_add(1, Ordering::SeqCst);
+        self.client.open_handle(inode, handle)?;
+
+        Ok(File {
+            client: self.client.clone(),
+            user: self.user.clone(),
+            inode,
+            handle,
+            position: 0,
+            read: options.read,
+            write,
+            append: options.append,
+        })
+    }
+
+    pub fn create_dir(
+        &self,
+        path: impl AsRef<Path>,
+        mode: u16,
+    ) -> Result<EntryMetadata, ErrorCode> {
+        let (parent, name) = self.resolve_parent(path.as_ref())?;
+        self.client
+            .mkdir(parent, name, self.user.context(), mode, 0)
+    }
+
+    pub fn remove_file(&self, path: impl AsRef<Path>) -> Result<(), ErrorCode> {
+        let (parent, name) = self.resolve_parent(path.as_ref())?;
+        // The servers allow directories to be unlinked, since the kernel checks for them when mounted
+        let inode = self.client.lookup(parent, name, self.user.context())?;
+        if self.client.getattr(inode)?.kind == FileKind::Directory {
+            return Err(ErrorCode::OperationNotPermitted);
+        }
+        self.client.unlink(parent, name, self.user.context())
+    }
+
+    pub fn remove_dir(&self, path: impl AsRef<Path>) -> Result<(), ErrorCode> {
+        let (parent, name) = self.resolve_parent(path.as_ref())?;
+        self.client.rmdir(parent, name, self.user.context())
+    }
+
+    // Replaces the destination, if it exists
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), ErrorCode> {
+        let (parent, name) = self.resolve_parent(from.as_ref())?;
+        let (new_parent, new_name) = self.resolve_parent(to.as_ref())?;
+        self.client
+            .rename(parent, name, new_parent, new_name, 0, self.user.context())
+    }
+
+    pub fn read_link(&self, path: impl AsRef<Path>) -> Result<PathBuf, ErrorCode> {
+        let inode = self.resolve(path.as_ref())?;
+        let target = self.client.readlink(inode)?;
+
+        Ok(PathBuf::from(OsString::from_vec(target)))
+    }
+
+    // Lists the entries of the directory, other than "." and ".."
+    pub fn read_dir(&self, path: impl AsRef<Path>) -> Result<ReadDir<'_>, ErrorCode> {
+        self.list_directory(path.as_ref(), false)
+    }
+
+    // Same as read_dir(), but each entry's metadata is fetched along with it
+    pub fn read_dir_with_metadata(&self, path: impl AsRef<Path>) -> Result<ReadDir<'_>, ErrorCode> {
+        self.list_directory(path.as_ref(), true)
+    }
+
+    fn list_directory(&self, path: &Path, with_metadata: bool) -> Result<ReadDir<'_>, ErrorCode> {
+        let inode = self.resolve(path)?;
+        self.client.access(inode, libc::R_OK, self.user.context())?;
+
+        Ok(ReadDir {
+            client: &self.client,
+            inode,
+            with_metadata,
+            entries: VecDeque::new(),
+            after: None,
+            done: false,
+        })
+    }
+
+    pub fn get_xattr(&self, path: impl AsRef<Path>, key: &str) -> Result<Vec<u8>, ErrorCode> {
+        let inode = self.resolve(path.as_ref())?;
+        self.client.getxattr(inode, key, self.user.context())
+    }
+
+    pub fn set_xattr(
+        &self,
+        path: impl AsRef<Path>,
+        key: &str,
+        value: &[u8],
+    ) -> Result<(), ErrorCode> {
+        let inode = self.resolve(path.as_ref())?;
+        self.client.setxattr(inode, key, value, self.user.context())
+    }
+
+    pub fn list_xattrs(&self, path: impl AsRef<Path>) -> Result<Vec<String>, ErrorCode> {
+        let inode = self.resolve(path.as_ref())?;
+        self.client.listxattr(inode)
+    }
+
+    pub fn remove_xattr(&self, path: impl AsRef<Path>, key: &str) -> Result<(), ErrorCode> {
+        let inode = self.resolve(path.as_ref())?;
+        self.client.removexattr(inode, key, self.user.context())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub inode: u64,
+    pub name: String,
+    pub kind: FileKind,
+    // Only listed by Filesystem::read_dir_with_metadata()
+    pub metadata: Option<EntryMetadata>,
+}
+
+// Iterates over a directory, fetching its entries from the server a page at a time
+pub struct ReadDir<'a> {
+    client: &'a NodeClient,
+    inode: u64,
+    with_metadata: bool,
+    entries: VecDeque<DirEntry>,
+    // Name to resume the listing after
+    after: Option<String>,
+    done: bool,
+}
+
+impl ReadDir<'_> {
+    fn next_page(&self) -> Result<DirectoryPage<DirEntry>, ErrorCode> {
+        let after = self.after.as_deref();
+        if self.with_metadata {
+            let (page, resume_after) =
+                self.client
+                    .readdirplus(self.inode, after, READ_DIR_PAGE_SIZE)?;
+            let entries = page
+                .into_iter()
+                .map(|(name, metadata)| DirEntry {
+                    inode: metadata.inode,
+                    name,
+                    kind: metadata.kind,
+                    metadata: Some(metadata),
+                })
+                .collect();
+            Ok((entries, resume_after))
+        } else {
+            let (page, resume_after) =
+                self.client.readdir(self.inode, after, READ_DIR_PAGE_SIZE)?;
+            let entries = page
+                .into_iter()
+                .map(|(inode, name, kind)| DirEntry {
+                    inode,
+                    name,
+                    kind,
+                    metadata: None,
+                })
+                .collect();
+            Ok((entries, resume_after))
+        }
+    }
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = Result<DirEntry, ErrorCode>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.entries.is_empty() && !self.done {
+            let (page, resume_after) = match self.next_page() {
+                Ok(page) => page,
+                Err(error_code) => {
+                    self.done = true;
+                    return Some(Err(error_code));
+                }
+            };
+            for entry in page {
+                // "." and ".." are only listed in the first page
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                self.entries.push_back(entry);
+            }
+            self.done = resume_after.is_none();
+            self.after = resume_after;
+        }
+
+        self.entries.pop_front().map(Ok)
+    }
+}
+
+// An open file, which is read and written at its current position. The handle is released when
+// it's dropped
+pub struct File {
+    client: Arc<NodeClient>,
+    user: Arc<User>,
+    inode: u64,
+    handle: u64,
+    position: u64,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+impl File {
+    pub fn inode(&self) -> u64 {
+        self.inode
+    }
+
+    pub fn metadata(&self) -> Result<EntryMetadata, ErrorCode> {
+        self.client.getattr(self.inode)
+    }
+
+    pub fn set_len(&self, size: u64) -> Result<(), ErrorCode> {
+        if !self.write {
+            return Err(ErrorCode::AccessDenied);
+        }
+        self.client
+            .truncate(self.inode, size, true, self.user.context())
+    }
+
+    // Waits until the file's data is durable on the servers
+    pub fn sync_all(&self) -> Result<(), ErrorCode> {
+        self.client.fsync(self.inode)
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.read {
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        let size = buf.len().min(MAX_TRANSFER_SIZE);
+        let mut result = Ok(0);
+        self.client.read(
+            self.inode,
+            self.handle,
+            self.position,
+            size as u32,
+            |data| {
+                result = data.map(|data| {
+                    buf[..data.len()].copy_from_slice(data);
+                    data.len()
+                })
+            },
+        );
+        let read = result?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.write {
+            return Err(ErrorCode::AccessDenied.into());
+        }
+        let data = &buf[..buf.len().min(MAX_TRANSFER_SIZE)];
+        let written = if self.append {
+            // The servers find the end of the file, so that appends by other clients aren't
+            // overwritten
+            let (offset, written) = self.client.append(self.inode, data, self.user.uid)?;
+            self.position = offset;
+            written
+        } else {
+            self.client
+                .write(self.inode, data, self.position, self.user.uid)?
+        };
+        self.position += written as u64;
+
+        Ok(written as usize)
+    }
+
+    // Writes aren't buffered, so there's nothing to flush
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for File {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self
+                .client
+                .getattr(self.inode)?
+                .size_bytes
+                .checked_add_signed(delta),
+        };
+        self.position = position.ok_or(io::ErrorKind::InvalidInput)?;
+
+        Ok(self.position)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Err(error_code) = self.client.release_handle(self.inode, self.handle) {
+            warn!(
+                "Failed to release handle {} of inode {}: {:?}",
+                self.handle, self.inode, error_code
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{AtimeMode, ErrorCode};
+    use crate::client::{Filesystem, NodeClient, OpenOptions};
+    use crate::storage::Node;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::{TempDir, tempdir};
+
+    // Starts a single node cluster, and connects to it as root
+    fn connect() -> (Filesystem, TempDir) {
+        let dir = tempdir().unwrap();
+        let address: SocketAddr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let node = Node::new(
+            dir.path().to_str().unwrap(),
+            address,
+            vec![],
+            1,
+            AtimeMode::NoAtime,
+        );
+        thread::spawn(move || node.run());
+        let client = NodeClient::new(vec![address]);
+        while client.filesystem_ready().is_err() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        (Filesystem::connect(vec![address], 0, 0, &[]).unwrap(), dir)
+    }
+
+    #[test]
+    fn files_and_directories() {
+        let (fs, _dir) = connect();
+        fs.create_dir("/dir", 0o755).unwrap();
+        let mut file = fs
+            .open("/dir/file", OpenOptions::new().write(true).create(true))
+            .unwrap();
+        file.write_all(b"hello world").unwrap();
+        file.seek(SeekFrom::Start(6)).unwrap();
+        file.write_all(b"there").unwrap();
+        drop(file);
+
+        let mut contents = String::new();
+        fs.open("/dir/file", OpenOptions::new().read(true))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "hello there");
+        assert_eq!(fs.metadata("/dir/file").unwrap().size_bytes, 11);
+        let names: Vec<String> = fs
+            .read_dir("/dir")
+            .unwrap()
+            .map(|entry| entry.unwrap().name)
+            .collect();
+        assert_eq!(names, ["file"]);
+
+        fs.rename("/dir/file", "/dir/renamed").unwrap();
+        assert_eq!(
+            fs.open("/dir/file", OpenOptions::new().read(true)).err(),
+            Some(ErrorCode::DoesNotExist)
+        );
+        assert_eq!(fs.remove_dir("/dir"), Err(ErrorCode::NotEmpty));
+        fs.remove_file("/dir/renamed").unwrap();
+        fs.remove_dir("/dir").unwrap();
+    }
+
+    #[test]
+    fn appends_not_overwritten() {
+        let (fs, _dir) = connect();
+        let options = OpenOptions::new().append(true).create(true).clone();
+        let mut file = fs.open("/log", &options).unwrap();
+        file.write_all(b"start").unwrap();
+        assert_eq!(file.stream_position().unwrap(), 5);
+
+        // Appends from several clients at once are all placed at the end by the servers
+        thread::scope(|scope| {
+            for writer in 0..4 {
+                let fs = &fs;
+                let options = &options;
+                scope.spawn(move || {
+                    let mut file = fs.open("/log", options).unwrap();
+                    for _ in 0..25 {
+                        file.write_all(format!("{writer}").as_bytes()).unwrap();
+                    }
+                });
+            }
+        });
+        file.write_all(b"end").unwrap();
+        assert_eq!(file.stream_position().unwrap(), 108);
+
+        let mut contents = String::new();
+        fs.open("/log", OpenOptions::new().read(true))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert!(contents.starts_with("start") && contents.ends_with("end"));
+        for writer in 0..4 {
+            let digit = char::from_digit(writer, 10).unwrap();
+            assert_eq!(contents.chars().filter(|c| *c == digit).count(), 25);
+        }
+    }
+}
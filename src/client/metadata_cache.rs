@@ -0,0 +1,336 @@
+use crate::base::{EntryMetadata, UserContext};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Upper bound on how long anything is cached for. Entries are normally invalidated by the servers
+// when they change, so this only matters if an invalidation is lost
+const CACHE_TTL: Duration = Duration::from_secs(10);
+// Upper bounds on the attributes and directory entries cached. Once either is reached, expired
+// items and then the oldest ones are evicted
+const MAX_CACHED_INODES: usize = 100_000;
+const MAX_CACHED_ENTRIES: usize = 100_000;
+
+struct CachedEntry {
+    inode: u64,
+    expires: Instant,
+    // Users which are allowed to look up the entry, identified by context_key(). Looking up an
+    // entry requires search permission on the directory, so a lookup by one user can't be reused
+    // by another
+    contexts: HashSet<u64>,
+}
+
+#[derive(Default)]
+struct CachedEntries {
+    // Mapping from directory inodes to their entries
+    directories: HashMap<u64, HashMap<String, CachedEntry>>,
+    // Total number of entries in all directories
+    len: usize,
+}
+
+impl CachedEntries {
+    fn evict(&mut self, now: Instant) {
+        let expiries = self
+            .directories
+            .values()
+            .flat_map(|directory| directory.values().map(|entry| entry.expires))
+            .collect();
+        let cutoff = eviction_cutoff(expiries, now);
+        let mut len = 0;
+        self.directories.retain(|_, directory| {
+            directory.retain(|_, entry| entry.expires > cutoff);
+            len += directory.len();
+            !directory.is_empty()
+        });
+        self.len = len;
+    }
+}
+
+// Returns the time at or before which items should be evicted, to free at least a quarter of the
+// cache. Everything is cached for the same time, so items expiring first are the oldest
+fn eviction_cutoff(mut expiries: Vec<Instant>, now: Instant) -> Instant {
+    if expiries.is_empty() {
+        return now;
+    }
+    let index = expiries.len() / 4;
+    let (_, oldest, _) = expiries.select_nth_unstable(index);
+    (*oldest).max(now)
+}
+
+fn context_key(context: &UserContext<'_>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    context.uid().hash(&mut hasher);
+    context.gid().hash(&mut hasher);
+    context.packed_groups().hash(&mut hasher);
+    hasher.finish()
+}
+
+// Caches inode attributes and directory entries on the client.
+// Callers read generation() before sending a request and pass it to the insert methods, so that a
+// response which raced with an invalidation isn't cached
+pub struct MetadataCache {
+    // Nothing is cached until the servers are being watched for invalidations
+    enabled: AtomicBool,
+    generation: AtomicU64,
+    attributes: Mutex<HashMap<u64, (EntryMetadata, Instant)>>,
+    entries: Mutex<CachedEntries>,
+}
+
+impl MetadataCache {
+    pub fn new() -> MetadataCache {
+        MetadataCache {
+            enabled: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            attributes: Mutex::new(HashMap::new()),
+            entries: Mutex::new(CachedEntries::default()),
+        }
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn get_attributes(&self, inode: u64) -> Option<EntryMetadata> {
+        let attributes = self.attributes.lock().unwrap();
+        let (attr, expires) = attributes.get(&inode)?;
+        if *expires > Instant::now() {
+            Some(*attr)
+        } else {
+            None
+        }
+    }
+
+    pub fn insert_attributes(&self, attr: &EntryMetadata, generation: u64) {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut attributes = self.attributes.lock().unwrap();
+        // Checked while holding the lock, since invalidate() holds it while changing the generation
+        if self.generation() == generation {
+            let now = Instant::now();
+            if attributes.len() >= MAX_CACHED_INODES && !attributes.contains_key(&attr.inode) {
+                let expiries = attributes.values().map(|(_, expires)| *expires).collect();
+                let cutoff = eviction_cutoff(expiries, now);
+                attributes.retain(|_, (_, expires)| *expires > cutoff);
+            }
+            attributes.insert(attr.inode, (*attr, now + CACHE_TTL));
+        }
+    }
+
+    pub fn get_entry(&self, parent: u64, name: &str, context: &UserContext<'_>) -> Option<u64> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.directories.get(&parent)?.get(name)?;
+        if entry.expires > Instant::now() && entry.contexts.contains(&context_key(context)) {
+            Some(entry.inode)
+        } else {
+            None
+        }
+    }
+
+    pub fn insert_entry(
+        &self,
+        parent: u64,
+        name: &str,
+        inode: u64,
+        context: &UserContext<'_>,
+        generation: u64,
+    ) {
+        if !self.enabled.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if self.generation() != generation {
+            return;
+        }
+        let now = Instant::now();
+        let cached = entries
+            .directories
+            .get(&parent)
+            .is_some_and(|directory| directory.contains_key(name));
+        if entries.len >= MAX_CACHED_ENTRIES && !cached {
+            entries.evict(now);
+        }
+        let directory = entries.directories.entry(parent).or_default();
+        match directory.get_mut(name) {
+            Some(entry) if entry.inode == inode && entry.expires > now => {
+                entry.contexts.insert(context_key(context));
+            }
+            _ => {
+                let replaced = directory.insert(
+                    name.to_string(),
+                    CachedEntry {
+                        inode,
+                        expires: now + CACHE_TTL,
+                        contexts: HashSet::from([context_key(context)]),
+                    },
+                );
+                if replaced.is_none() {
+                    entries.len += 1;
+                }
+            }
+        }
+    }
+
+    // Returns the inode that the entry linked to, if it was cached
+    pub fn remove_entry(&self, parent: u64, name: &str) -> Option<u64> {
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let entry = entries.directories.get_mut(&parent)?.remove(name)?;
+        entries.len -= 1;
+        Some(entry.inode)
+    }
+
+    // Drops the inode's attributes, and its entries if it's a directory
+    pub fn invalidate(&self, inode: u64) {
+        let mut attributes = self.attributes.lock().unwrap();
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        attributes.remove(&inode);
+        if let Some(directory) = entries.directories.remove(&inode) {
+            entries.len -= directory.len();
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut attributes = self.attributes.lock().unwrap();
+        let mut entries = self.entries.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        attributes.clear();
+        *entries = CachedEntries::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{EntryMetadata, FileKind, Timestamp, UserContext};
+    use crate::client::metadata_cache::{MAX_CACHED_ENTRIES, MAX_CACHED_INODES, MetadataCache};
+    use std::time::Instant;
+
+    fn attributes(inode: u64) -> EntryMetadata {
+        EntryMetadata {
+            inode,
+            size_bytes: 0,
+            size_blocks: 0,
+            last_access_time: Timestamp::new(0, 0),
+            last_modified_time: Timestamp::new(0, 0),
+            last_metadata_modified_time: Timestamp::new(0, 0),
+            kind: FileKind::File,
+            mode: 0o644,
+            hard_links: 1,
+            user_id: 0,
+            group_id: 0,
+            device_id: 0,
+            block_size: 4096,
+            directory_entries: None,
+            creation_time: Timestamp::new(0, 0),
+            flags: 0,
+            unique_id: inode,
+        }
+    }
+
+    fn enabled_cache() -> MetadataCache {
+        let cache = MetadataCache::new();
+        cache.enable();
+        cache
+    }
+
+    #[test]
+    fn responses_racing_invalidation_not_cached() {
+        let cache = enabled_cache();
+        let user = UserContext::new(1, 1);
+        let generation = cache.generation();
+        cache.invalidate(2);
+        cache.insert_attributes(&attributes(3), generation);
+        cache.insert_entry(2, "file", 3, &user, generation);
+        assert_eq!(cache.get_attributes(3), None);
+        assert_eq!(cache.get_entry(2, "file", &user), None);
+
+        let generation = cache.generation();
+        cache.insert_attributes(&attributes(3), generation);
+        cache.insert_entry(2, "file", 3, &user, generation);
+        assert_eq!(cache.get_attributes(3), Some(attributes(3)));
+        assert_eq!(cache.get_entry(2, "file", &user), Some(3));
+
+        assert_eq!(cache.remove_entry(2, "file"), Some(3));
+        assert_eq!(cache.get_entry(2, "file", &user), None);
+        cache.insert_entry(2, "file", 3, &user, generation);
+        assert_eq!(cache.get_entry(2, "file", &user), None);
+    }
+
+    #[test]
+    fn entries_cached_per_user() {
+        let cache = enabled_cache();
+        let user = UserContext::new(1, 1);
+        let other = UserContext::new(2, 2);
+        cache.insert_entry(2, "file", 3, &user, cache.generation());
+        assert_eq!(cache.get_entry(2, "file", &user), Some(3));
+        assert_eq!(cache.get_entry(2, "file", &other), None);
+
+        cache.insert_entry(2, "file", 3, &other, cache.generation());
+        assert_eq!(cache.get_entry(2, "file", &user), Some(3));
+        assert_eq!(cache.get_entry(2, "file", &other), Some(3));
+        // The entry now links to a different inode, so the lookup by the first user is dropped
+        cache.insert_entry(2, "file", 4, &other, cache.generation());
+        assert_eq!(cache.get_entry(2, "file", &user), None);
+        assert_eq!(cache.get_entry(2, "file", &other), Some(4));
+    }
+
+    #[test]
+    fn expired_items_not_returned() {
+        let cache = enabled_cache();
+        let user = UserContext::new(1, 1);
+        cache.insert_attributes(&attributes(3), cache.generation());
+        cache.insert_entry(2, "file", 3, &user, cache.generation());
+
+        let now = Instant::now();
+        cache.attributes.lock().unwrap().get_mut(&3).unwrap().1 = now;
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .directories
+            .get_mut(&2)
+            .unwrap()
+            .get_mut("file")
+            .unwrap()
+            .expires = now;
+        assert_eq!(cache.get_attributes(3), None);
+        assert_eq!(cache.get_entry(2, "file", &user), None);
+    }
+
+    #[test]
+    fn size_bounded() {
+        let cache = enabled_cache();
+        let user = UserContext::new(1, 1);
+        for i in 0..=MAX_CACHED_ENTRIES as u64 {
+            cache.insert_entry(i % 100, &i.to_string(), i, &user, cache.generation());
+        }
+        for i in 0..=MAX_CACHED_INODES as u64 {
+            cache.insert_attributes(&attributes(i), cache.generation());
+        }
+
+        let entries = cache.entries.lock().unwrap();
+        assert!(entries.len <= MAX_CACHED_ENTRIES);
+        let total: usize = entries.directories.values().map(|x| x.len()).sum();
+        assert_eq!(entries.len, total);
+        drop(entries);
+        assert!(cache.attributes.lock().unwrap().len() <= MAX_CACHED_INODES);
+        // The oldest items are evicted first
+        assert_eq!(cache.get_entry(0, "0", &user), None);
+        let last = MAX_CACHED_ENTRIES as u64;
+        assert_eq!(
+            cache.get_entry(last % 100, &last.to_string(), &user),
+            Some(last)
+        );
+        assert_eq!(cache.get_attributes(0), None);
+        let last = MAX_CACHED_INODES as u64;
+        assert_eq!(cache.get_attributes(last), Some(attributes(last)));
+    }
+}
@@ -1,9 +1,16 @@
+mod async_node_client;
 mod cluster_client;
+mod filesystem;
+mod metadata_cache;
 mod node_client;
 mod peer_client;
+mod readahead;
 mod tcp_client;
 
+pub use async_node_client::AsyncNodeClient;
 pub use cluster_client::RemoteRaftGroups;
-pub use node_client::NodeClient;
+pub use filesystem::{DirEntry, File, Filesystem, OpenOptions, ReadDir};
+pub use node_client::{InvalidationListener, NodeClient, RangeLockWait, StatFS};
 pub use peer_client::PeerClient;
 pub use peer_client::TcpPeerClient;
+pub use tcp_client::REQUEST_ID_SIZE;
@@ -1,75 +1,351 @@
 use std::cell::RefCell;
-use std::ffi::OsString;
+use std::collections::BTreeSet;
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::base::response_or_error;
 use crate::base::{
-    EntryMetadata, ErrorCode, FileKind, Request, ResponseView, Timestamp, UserContext,
+    AccessType, DirectoryPage, EntryMetadata, ErrorCode, FileKind, Invalidations, OpenHandleId,
+    RangeLock, RangeLockKind, Request, ResponseView, SESSION_LEASE_SECONDS, Timestamp, UserContext,
     WireResponse, encode_request,
 };
-use crate::client::tcp_client::TcpClient;
+use crate::client::metadata_cache::MetadataCache;
+use crate::client::readahead::Readahead;
+use crate::client::tcp_client::{PendingRequest, TcpClient};
 use crate::storage::ROOT_INODE;
-use fuser::FileAttr;
-use std::time::SystemTime;
+use log::warn;
+use rand::Rng;
 use zerialize::List;
 
-fn to_fuse_file_type(file_type: FileKind) -> fuser::FileType {
-    match file_type {
-        FileKind::File => fuser::FileType::RegularFile,
-        FileKind::Directory => fuser::FileType::Directory,
-        FileKind::Symlink => fuser::FileType::Symlink,
-    }
-}
+// How long to wait before watching for invalidations again, after a failure
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Number of connections to the server, which requests from different threads are spread over
+const CONNECTION_POOL_SIZE: usize = 4;
+// How long to keep waiting for a request that was sent again, while the first attempt is still
+// being applied
+const REQUEST_IN_PROGRESS_TIMEOUT: Duration = Duration::from_secs(60);
+const REQUEST_IN_PROGRESS_RETRY_DELAY: Duration = Duration::from_millis(100);
+// How often the session's lease is renewed, so that a few renewals can fail before it expires
+const SESSION_RENEW_INTERVAL: Duration = Duration::from_secs(SESSION_LEASE_SECONDS as u64 / 6);
 
 pub struct StatFS {
     pub block_size: u32,
     pub max_name_length: u32,
+    pub raft_groups: u16,
+    pub peers: Vec<SocketAddr>,
+}
+
+thread_local! {
+    static RESPONSE_BUFFERS: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
 }
 
-fn metadata_to_fuse_fileattr(metadata: &EntryMetadata) -> FileAttr {
-    FileAttr {
-        ino: fuser::INodeNo(metadata.inode),
-        size: metadata.size_bytes,
-        blocks: metadata.size_blocks,
-        atime: metadata.last_access_time.into(),
-        mtime: metadata.last_modified_time.into(),
-        ctime: metadata.last_metadata_modified_time.into(),
-        crtime: SystemTime::UNIX_EPOCH,
-        kind: to_fuse_file_type(metadata.kind),
-        perm: metadata.mode,
-        nlink: metadata.hard_links,
-        uid: metadata.user_id,
-        gid: metadata.group_id,
-        rdev: metadata.device_id,
-        flags: 0,
-        blksize: metadata.block_size,
+// Called with the changes to each raft group, after they've been invalidated in the NodeClient's
+// cache
+pub type InvalidationListener = Arc<dyn Fn(&Invalidations) + Send + Sync>;
+
+// Invalidates the cached inodes which are changed in the raft group, until the cache is dropped
+fn watch_invalidations(
+    tcp_client: Arc<TcpClient>,
+    raft_group: u16,
+    session: u64,
+    cache: Weak<MetadataCache>,
+    readahead: Weak<Readahead>,
+    listener: InvalidationListener,
+) {
+    let mut buffer = vec![];
+    let mut epoch = 0;
+    let mut sequence = 0;
+    loop {
+        let request = encode_request(&Request::WatchInvalidations {
+            raft_group,
+            epoch,
+            sequence,
+            session,
+        });
+        let result = tcp_client
+            .send_and_receive(&request, &mut buffer, true)
+            .map_err(|_| ErrorCode::Uncategorized)
+            .and_then(|_| {
+                response_or_error(&buffer)?
+                    .as_invalidations_response()
+                    .ok_or(ErrorCode::BadResponse)
+            });
+        let (Some(cache), Some(readahead)) = (cache.upgrade(), readahead.upgrade()) else {
+            return;
+        };
+        match result {
+            Ok(invalidations) => {
+                if invalidations.complete {
+                    for inode in invalidations.inodes.iter() {
+                        cache.invalidate(*inode);
+                    }
+                    for inode in invalidations.data_inodes.iter() {
+                        readahead.invalidate(*inode);
+                    }
+                } else {
+                    cache.clear();
+                    readahead.clear();
+                }
+                listener(&invalidations);
+                epoch = invalidations.epoch;
+                sequence = invalidations.sequence;
+            }
+            Err(error_code) => {
+                warn!("Failed to watch rgroup {raft_group} for invalidations: {error_code:?}");
+                // Changes may be missed until the watch succeeds again
+                cache.clear();
+                readahead.clear();
+                drop(cache);
+                drop(readahead);
+                thread::sleep(WATCH_RETRY_DELAY);
+            }
+        }
     }
 }
 
-thread_local! {
-    static RESPONSE_BUFFERS: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+fn raft_group_count(tcp_client: &TcpClient, buffer: &mut Vec<u8>) -> Result<u16, ErrorCode> {
+    tcp_client
+        .send_and_receive(
+            &encode_request(&Request::FilesystemInformation),
+            buffer,
+            true,
+        )
+        .map_err(|_| ErrorCode::Uncategorized)?;
+    if let WireResponse::FilesystemInformation { raft_groups, .. } = response_or_error(buffer)? {
+        Ok(raft_groups)
+    } else {
+        Err(ErrorCode::BadResponse)
+    }
+}
+
+// Renews the session's lease in every raft group, until the cache is dropped
+fn renew_session(tcp_client: Arc<TcpClient>, session: u64, cache: Weak<MetadataCache>) {
+    let mut buffer = vec![];
+    let mut raft_groups = None;
+    loop {
+        thread::sleep(SESSION_RENEW_INTERVAL);
+        if cache.strong_count() == 0 {
+            return;
+        }
+        let groups = match raft_groups {
+            Some(groups) => groups,
+            None => match raft_group_count(&tcp_client, &mut buffer) {
+                Ok(groups) => *raft_groups.insert(groups),
+                Err(error_code) => {
+                    warn!("Failed to renew session: {error_code:?}");
+                    continue;
+                }
+            },
+        };
+        for raft_group in 0..groups {
+            let request = encode_request(&Request::RenewSession {
+                raft_group,
+                session,
+            });
+            let result = tcp_client
+                .send_and_receive(&request, &mut buffer, false)
+                .map_err(|_| ErrorCode::Uncategorized)
+                .and_then(|_| response_or_error(&buffer).map(|_| ()));
+            if let Err(error_code) = result {
+                warn!("Failed to renew session in rgroup {raft_group}: {error_code:?}");
+            }
+        }
+    }
+}
+
+// A request for an advisory lock, which is waiting for the conflicting locks to be released
+pub struct RangeLockWait {
+    inode: u64,
+    lock: RangeLock,
+    request: PendingRequest,
 }
 
 pub struct NodeClient {
-    tcp_client: TcpClient,
+    tcp_client: Arc<TcpClient>,
+    // Identifies this client's advisory locks and open handles. The servers release them once the
+    // session's lease expires, after the client stops renewing it. Also identifies the client's
+    // mutating requests, so that the servers apply them only once
+    session: u64,
+    next_sequence: AtomicU64,
+    // Sequence numbers of the mutating requests which haven't completed
+    in_flight: Mutex<BTreeSet<u64>>,
+    cache: Arc<MetadataCache>,
+    readahead: Arc<Readahead>,
+}
+
+impl Drop for NodeClient {
+    // Releases the session's locks and handles right away, rather than once its lease expires
+    fn drop(&mut self) {
+        let mut buffer = vec![];
+        let Ok(raft_groups) = raft_group_count(&self.tcp_client, &mut buffer) else {
+            return;
+        };
+        for raft_group in 0..raft_groups {
+            let request = encode_request(&Request::ReleaseSession {
+                raft_group,
+                session: self.session,
+            });
+            if let Err(error) = self
+                .tcp_client
+                .send_and_receive(&request, &mut buffer, false)
+            {
+                warn!("Failed to release session in rgroup {raft_group}: {error}");
+            }
+        }
+    }
 }
 
 impl NodeClient {
-    pub fn new(server_ip_port: SocketAddr) -> NodeClient {
+    // Requests are sent to the first of the servers, and fail over to the others
+    pub fn new(servers: Vec<SocketAddr>) -> NodeClient {
+        let tcp_client = Arc::new(TcpClient::new(servers, CONNECTION_POOL_SIZE));
+        let session = rand::rng().random();
+        let cache = Arc::new(MetadataCache::new());
+        let renew_client = tcp_client.clone();
+        let renew_cache = Arc::downgrade(&cache);
+        thread::spawn(move || renew_session(renew_client, session, renew_cache));
         NodeClient {
-            tcp_client: TcpClient::new(server_ip_port),
+            tcp_client: tcp_client.clone(),
+            session,
+            next_sequence: AtomicU64::new(0),
+            in_flight: Mutex::new(BTreeSet::new()),
+            cache,
+            readahead: Arc::new(Readahead::new(tcp_client)),
+        }
+    }
+
+    // Adds the other nodes in the cluster to the servers which requests can fail over to
+    pub fn discover_nodes(&self) -> Result<(), ErrorCode> {
+        let peers = self.statfs()?.peers;
+        self.tcp_client.add_servers(&peers);
+
+        Ok(())
+    }
+
+    // Enables caching of attributes and directory entries, which the servers are then watched
+    // for changes to
+    pub fn start_invalidation_watchers(
+        &self,
+        listener: InvalidationListener,
+    ) -> Result<(), ErrorCode> {
+        let raft_groups = self.statfs()?.raft_groups;
+        for raft_group in 0..raft_groups {
+            let tcp_client = self.tcp_client.clone();
+            let session = self.session;
+            let cache = Arc::downgrade(&self.cache);
+            let readahead = Arc::downgrade(&self.readahead);
+            let listener = listener.clone();
+            thread::spawn(move || {
+                watch_invalidations(tcp_client, raft_group, session, cache, readahead, listener)
+            });
+        }
+        self.cache.enable();
+
+        Ok(())
+    }
+
+    // Invalidates the cached inodes which the request may have changed
+    fn invalidate_changed(&self, request: &Request<'_>) {
+        match request {
+            Request::Unlink { parent, name, .. } | Request::Rmdir { parent, name, .. } => {
+                if let Some(inode) = self.cache.remove_entry(*parent, name) {
+                    self.cache.invalidate(inode);
+                }
+                self.cache.invalidate(*parent);
+            }
+            Request::Rename {
+                parent,
+                name,
+                new_parent,
+                new_name,
+                ..
+            } => {
+                for (parent, name) in [(parent, name), (new_parent, new_name)] {
+                    if let Some(inode) = self.cache.remove_entry(*parent, name) {
+                        self.cache.invalidate(inode);
+                    }
+                    self.cache.invalidate(*parent);
+                }
+            }
+            Request::Hardlink {
+                inode, new_parent, ..
+            } => {
+                self.cache.invalidate(*inode);
+                self.cache.invalidate(*new_parent);
+            }
+            _ => {
+                let meta = request.meta_info();
+                if let AccessType::WriteMetadata | AccessType::WriteDataAndMetadata =
+                    meta.access_type
+                    && let Some(inode) = meta.inode
+                {
+                    self.cache.invalidate(inode);
+                    if let AccessType::WriteDataAndMetadata = meta.access_type {
+                        self.readahead.invalidate(inode);
+                    }
+                }
+            }
         }
     }
 
+    // Sends a mutating request with a sequence number, so that it can safely be sent again after a
+    // failure. The servers return the original result, if it was already applied
+    fn send_deduplicated(&self, request: &[u8], buffer: &mut Vec<u8>) -> Result<(), ErrorCode> {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        let acknowledged = {
+            let mut in_flight = self.in_flight.lock().expect("lock acquisition failed");
+            in_flight.insert(sequence);
+            *in_flight.first().unwrap()
+        };
+        let request = encode_request(&Request::Deduplicated {
+            session: self.session,
+            sequence,
+            acknowledged,
+            request,
+        });
+        let start = Instant::now();
+        let result = loop {
+            let result = self
+                .tcp_client
+                .send_and_receive(&request, buffer, true)
+                .map_err(|_| ErrorCode::Uncategorized);
+            // The request may have reached another node, which is still applying it
+            if result.is_ok()
+                && response_or_error(buffer).err() == Some(ErrorCode::RequestInProgress)
+                && start.elapsed() < REQUEST_IN_PROGRESS_TIMEOUT
+            {
+                thread::sleep(REQUEST_IN_PROGRESS_RETRY_DELAY);
+                continue;
+            }
+            break result;
+        };
+        self.in_flight
+            .lock()
+            .expect("lock acquisition failed")
+            .remove(&sequence);
+
+        result
+    }
+
     fn send<'a>(
         &self,
         request: Request<'_>,
         buffer: &'a mut Vec<u8>,
     ) -> Result<ResponseView<'a>, ErrorCode> {
         let request_buffer = encode_request(&request);
-        self.tcp_client
-            .send_and_receive(&request_buffer, buffer)
-            .map_err(|_| ErrorCode::Uncategorized)?;
+        let result = if request.can_deduplicate() {
+            self.send_deduplicated(&request_buffer, buffer)
+        } else {
+            self.tcp_client
+                .send_and_receive(&request_buffer, buffer, request.is_idempotent())
+                .map_err(|_| ErrorCode::Uncategorized)
+        };
+        // Even failed requests may have been applied
+        self.invalidate_changed(&request);
+        result?;
         response_or_error(buffer)
     }
 
@@ -95,28 +371,38 @@ impl NodeClient {
         &self,
         parent: u64,
         name: &str,
-        uid: u32,
-        gid: u32,
+        // The new inode is owned by this user
+        context: UserContext<'_>,
         mode: u16,
-    ) -> Result<FileAttr, ErrorCode> {
+        umask: u16,
+    ) -> Result<EntryMetadata, ErrorCode> {
         let request = Request::Mkdir {
             parent,
             name,
-            uid,
-            gid,
+            uid: context.uid(),
+            gid: context.gid(),
             mode,
+            umask,
+            groups: context.packed_groups(),
         };
 
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             let response = self.send(request, buffer)?;
 
-            Ok(metadata_to_fuse_fileattr(
-                &response.as_attr_response().unwrap(),
-            ))
+            response.as_attr_response().ok_or(ErrorCode::BadResponse)
         })
     }
 
-    pub fn lookup(&self, parent: u64, name: &str, context: UserContext) -> Result<u64, ErrorCode> {
+    pub fn lookup(
+        &self,
+        parent: u64,
+        name: &str,
+        context: UserContext<'_>,
+    ) -> Result<u64, ErrorCode> {
+        if let Some(inode) = self.cache.get_entry(parent, name, &context) {
+            return Ok(inode);
+        }
+        let generation = self.cache.generation();
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             let response = self.send(
                 Request::Lookup {
@@ -127,34 +413,72 @@ impl NodeClient {
                 buffer,
             )?;
 
-            response.as_inode_response().ok_or(ErrorCode::BadResponse)
+            let inode = response.as_inode_response().ok_or(ErrorCode::BadResponse)?;
+            self.cache
+                .insert_entry(parent, name, inode, &context, generation);
+
+            Ok(inode)
         })
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
         &self,
         parent: u64,
         name: &str,
-        uid: u32,
-        gid: u32,
+        // The new inode is owned by this user
+        context: UserContext<'_>,
         mode: u16,
+        umask: u16,
         kind: FileKind,
-    ) -> Result<FileAttr, ErrorCode> {
+        rdev: u32,
+    ) -> Result<EntryMetadata, ErrorCode> {
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             let response = self.send(
                 Request::Create {
                     parent,
                     name,
-                    uid,
-                    gid,
+                    uid: context.uid(),
+                    gid: context.gid(),
                     mode,
                     kind,
+                    rdev,
+                    umask,
+                    groups: context.packed_groups(),
+                },
+                buffer,
+            )?;
+            response.as_attr_response().ok_or(ErrorCode::BadResponse)
+        })
+    }
+
+    // Creates an unnamed file in parent, which stays open on handle until it's released
+    pub fn create_temporary(
+        &self,
+        parent: u64,
+        // The new inode is owned by this user
+        context: UserContext<'_>,
+        mode: u16,
+        umask: u16,
+        handle: u64,
+    ) -> Result<EntryMetadata, ErrorCode> {
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(
+                Request::CreateTemporary {
+                    parent,
+                    uid: context.uid(),
+                    gid: context.gid(),
+                    mode,
+                    open_handle: OpenHandleId {
+                        session: self.session,
+                        handle,
+                    },
+                    umask,
+                    groups: context.packed_groups(),
                 },
                 buffer,
             )?;
-            Ok(metadata_to_fuse_fileattr(
-                &response.as_attr_response().unwrap(),
-            ))
+            response.as_attr_response().ok_or(ErrorCode::BadResponse)
         })
     }
 
@@ -164,11 +488,15 @@ impl NodeClient {
             if let WireResponse::FilesystemInformation {
                 block_size,
                 max_name_length,
+                raft_groups,
+                peers,
             } = response
             {
                 Ok(StatFS {
                     block_size,
                     max_name_length,
+                    raft_groups,
+                    peers: peers.iter().filter_map(|peer| peer.parse().ok()).collect(),
                 })
             } else {
                 Err(ErrorCode::BadResponse)
@@ -176,13 +504,32 @@ impl NodeClient {
         })
     }
 
-    pub fn getattr(&self, inode: u64) -> Result<FileAttr, ErrorCode> {
+    pub fn getattr(&self, inode: u64) -> Result<EntryMetadata, ErrorCode> {
+        if let Some(attr) = self.cache.get_attributes(inode) {
+            return Ok(attr);
+        }
+        let generation = self.cache.generation();
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             let response = self.send(Request::GetAttr { inode }, buffer)?;
 
-            Ok(metadata_to_fuse_fileattr(
-                &response.as_attr_response().unwrap(),
-            ))
+            let attr = response.as_attr_response().ok_or(ErrorCode::BadResponse)?;
+            self.cache.insert_attributes(&attr, generation);
+
+            Ok(attr)
+        })
+    }
+
+    // Checks access to the inode, including any access granted by its ACL
+    pub fn access(&self, inode: u64, mask: i32, context: UserContext<'_>) -> Result<(), ErrorCode> {
+        let request = Request::Access {
+            inode,
+            mask,
+            context,
+        };
+
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(request, buffer)?;
+            response.as_empty_response().ok_or(ErrorCode::BadResponse)
         })
     }
 
@@ -190,7 +537,7 @@ impl NodeClient {
         &self,
         inode: u64,
         key: &str,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Vec<u8>, ErrorCode> {
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             let response = self.send(
@@ -226,7 +573,7 @@ impl NodeClient {
         inode: u64,
         key: &str,
         value: &[u8],
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             let response = self.send(
@@ -248,7 +595,7 @@ impl NodeClient {
         &self,
         inode: u64,
         key: &str,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         let request = Request::RemoveXattr {
             inode,
@@ -269,7 +616,7 @@ impl NodeClient {
         inode: u64,
         atime: Option<Timestamp>,
         mtime: Option<Timestamp>,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         assert_ne!(inode, ROOT_INODE);
         let request = Request::Utimens {
@@ -287,7 +634,7 @@ impl NodeClient {
         })
     }
 
-    pub fn chmod(&self, inode: u64, mode: u32, context: UserContext) -> Result<(), ErrorCode> {
+    pub fn chmod(&self, inode: u64, mode: u32, context: UserContext<'_>) -> Result<(), ErrorCode> {
         if inode == ROOT_INODE {
             return Err(ErrorCode::OperationNotPermitted);
         }
@@ -305,12 +652,41 @@ impl NodeClient {
         })
     }
 
+    pub fn get_flags(&self, inode: u64) -> Result<u32, ErrorCode> {
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(Request::GetAttr { inode }, buffer)?;
+            let metadata = response.as_attr_response().ok_or(ErrorCode::BadResponse)?;
+
+            Ok(metadata.flags)
+        })
+    }
+
+    pub fn set_flags(
+        &self,
+        inode: u64,
+        flags: u32,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        let request = Request::SetFlags {
+            inode,
+            flags,
+            context,
+        };
+
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(request, buffer)?;
+            response.as_empty_response().ok_or(ErrorCode::BadResponse)?;
+
+            Ok(())
+        })
+    }
+
     pub fn chown(
         &self,
         inode: u64,
         uid: Option<u32>,
         gid: Option<u32>,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         assert_ne!(inode, ROOT_INODE);
         let request = Request::Chown {
@@ -333,8 +709,8 @@ impl NodeClient {
         inode: u64,
         new_parent: u64,
         new_name: &str,
-        context: UserContext,
-    ) -> Result<FileAttr, ErrorCode> {
+        context: UserContext<'_>,
+    ) -> Result<EntryMetadata, ErrorCode> {
         assert_ne!(inode, ROOT_INODE);
         let request = Request::Hardlink {
             inode,
@@ -346,9 +722,7 @@ impl NodeClient {
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             let response = self.send(request, buffer)?;
 
-            Ok(metadata_to_fuse_fileattr(
-                &response.as_attr_response().unwrap(),
-            ))
+            response.as_attr_response().ok_or(ErrorCode::BadResponse)
         })
     }
 
@@ -358,7 +732,8 @@ impl NodeClient {
         name: &str,
         new_parent: u64,
         new_name: &str,
-        context: UserContext,
+        flags: u32,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         let request = Request::Rename {
             parent,
@@ -366,6 +741,7 @@ impl NodeClient {
             new_parent,
             new_name,
             context,
+            flags,
         };
 
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
@@ -397,15 +773,22 @@ impl NodeClient {
         })
     }
 
+    // Reads through the file handle, which sequential reads are detected on
     pub fn read<F: FnOnce(Result<&[u8], ErrorCode>)>(
         &self,
         inode: u64,
+        handle: u64,
         offset: u64,
         size: u32,
         callback: F,
     ) {
         assert_ne!(inode, ROOT_INODE);
 
+        if let Some(data) = self.readahead.take(inode, handle, offset, size) {
+            self.readahead.prefetch(handle, offset, size, data.len());
+            callback(Ok(&data));
+            return;
+        }
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             match self.send(
                 Request::Read {
@@ -417,6 +800,7 @@ impl NodeClient {
             ) {
                 Ok(response) => {
                     let data = response.as_read_response().unwrap();
+                    self.readahead.prefetch(handle, offset, size, data.len());
                     callback(Ok(data));
                 }
                 Err(e) => {
@@ -426,32 +810,81 @@ impl NodeClient {
         })
     }
 
-    pub fn readdir(&self, inode: u64) -> Result<Vec<(u64, OsString, fuser::FileType)>, ErrorCode> {
+    // Lists up to limit entries, starting after the given name. Also returns the name to resume
+    // the listing after, or None if it reached the end of the directory. See Request::ListDir
+    pub fn readdir(
+        &self,
+        inode: u64,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<DirectoryPage<(u64, String, FileKind)>, ErrorCode> {
+        let request = Request::ListDir {
+            inode,
+            after,
+            limit,
+            with_attributes: false,
+        };
+
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
-            let response = self.send(Request::ListDir { inode }, buffer)?;
+            let response = self.send(request, buffer)?;
 
             let mut result = vec![];
-            let entries = response
+            let (entries, resume_after) = response
                 .as_directory_listing_response()
                 .ok_or(ErrorCode::BadResponse)?;
             for entry in entries.iter() {
-                result.push((
-                    entry.inode,
-                    OsString::from(entry.name),
-                    to_fuse_file_type(entry.kind),
-                ));
+                result.push((entry.inode, entry.name.to_string(), entry.kind));
             }
 
-            Ok(result)
+            Ok((result, resume_after.map(str::to_string)))
         })
     }
 
-    pub fn truncate(&self, inode: u64, length: u64, context: UserContext) -> Result<(), ErrorCode> {
+    // Same as readdir(), but also returns the attributes of each entry
+    pub fn readdirplus(
+        &self,
+        inode: u64,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<DirectoryPage<(String, EntryMetadata)>, ErrorCode> {
+        let request = Request::ListDir {
+            inode,
+            after,
+            limit,
+            with_attributes: true,
+        };
+
+        let generation = self.cache.generation();
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(request, buffer)?;
+
+            let mut result = vec![];
+            let (entries, resume_after) = response
+                .as_directory_listing_response()
+                .ok_or(ErrorCode::BadResponse)?;
+            for entry in entries.iter() {
+                let attr = entry.attributes.ok_or(ErrorCode::BadResponse)?;
+                self.cache.insert_attributes(&attr, generation);
+                result.push((entry.name.to_string(), attr));
+            }
+
+            Ok((result, resume_after.map(str::to_string)))
+        })
+    }
+
+    pub fn truncate(
+        &self,
+        inode: u64,
+        length: u64,
+        has_write_handle: bool,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
         assert_ne!(inode, ROOT_INODE);
         let request = Request::Truncate {
             inode,
             new_length: length,
             context,
+            has_write_handle,
         };
 
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
@@ -462,13 +895,15 @@ impl NodeClient {
         })
     }
 
-    pub fn write(&self, inode: u64, data: &[u8], offset: u64) -> Result<u32, ErrorCode> {
+    pub fn write(&self, inode: u64, data: &[u8], offset: u64, uid: u32) -> Result<u32, ErrorCode> {
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             let response = self.send(
                 Request::Write {
                     inode,
                     offset,
                     data,
+                    uid,
+                    append: false,
                 },
                 buffer,
             )?;
@@ -479,6 +914,186 @@ impl NodeClient {
         })
     }
 
+    // Writes at the end of the file, as found by the servers. Returns the offset the data was
+    // written at, and the number of bytes written
+    pub fn append(&self, inode: u64, data: &[u8], uid: u32) -> Result<(u64, u32), ErrorCode> {
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(
+                Request::Write {
+                    inode,
+                    offset: 0,
+                    data,
+                    uid,
+                    append: true,
+                },
+                buffer,
+            )?;
+
+            response.as_written_response().ok_or(ErrorCode::BadResponse)
+        })
+    }
+
+    // Returns the held lock which conflicts with the requested one, if any
+    pub fn get_range_lock(
+        &self,
+        inode: u64,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        kind: RangeLockKind,
+    ) -> Result<Option<RangeLock>, ErrorCode> {
+        let lock = RangeLock {
+            session: self.session,
+            owner,
+            pid,
+            start,
+            end,
+            kind,
+        };
+
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(Request::GetRangeLock { inode, lock }, buffer)?;
+
+            response
+                .as_range_lock_response()
+                .ok_or(ErrorCode::BadResponse)
+        })
+    }
+
+    // Fails with WouldBlock, if a conflicting lock is held
+    pub fn set_range_lock(
+        &self,
+        inode: u64,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        kind: RangeLockKind,
+    ) -> Result<(), ErrorCode> {
+        let lock = RangeLock {
+            session: self.session,
+            owner,
+            pid,
+            start,
+            end,
+            kind,
+        };
+
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let request = Request::SetRangeLock {
+                inode,
+                lock,
+                wait: false,
+            };
+            let response = self.send(request, buffer)?;
+            response.as_empty_response().ok_or(ErrorCode::BadResponse)
+        })
+    }
+
+    // Requests the lock, which is granted once the conflicting locks are released. Waiting may take
+    // arbitrarily long, so the result is polled for with poll_range_lock_wait()
+    pub fn wait_for_range_lock(
+        &self,
+        inode: u64,
+        owner: u64,
+        pid: u32,
+        start: u64,
+        end: u64,
+        kind: RangeLockKind,
+    ) -> Result<RangeLockWait, ErrorCode> {
+        let lock = RangeLock {
+            session: self.session,
+            owner,
+            pid,
+            start,
+            end,
+            kind,
+        };
+        let request = self.send_range_lock_wait(inode, lock)?;
+
+        Ok(RangeLockWait {
+            inode,
+            lock,
+            request,
+        })
+    }
+
+    fn send_range_lock_wait(
+        &self,
+        inode: u64,
+        lock: RangeLock,
+    ) -> Result<PendingRequest, ErrorCode> {
+        let request = encode_request(&Request::SetRangeLock {
+            inode,
+            lock,
+            wait: true,
+        });
+        self.tcp_client
+            .send_without_waiting(&request)
+            .map_err(|_| ErrorCode::Uncategorized)
+    }
+
+    // Returns the result once the lock was granted, or the wait failed. If the connection is lost
+    // the request is sent again, since setting the same lock twice has no effect
+    pub fn poll_range_lock_wait(&self, wait: &mut RangeLockWait) -> Option<Result<(), ErrorCode>> {
+        let mut buffer = vec![];
+        match wait.request.try_receive(&mut buffer)? {
+            Ok(()) => {
+                Some(response_or_error(&buffer).and_then(|response| {
+                    response.as_empty_response().ok_or(ErrorCode::BadResponse)
+                }))
+            }
+            Err(_) => match self.send_range_lock_wait(wait.inode, wait.lock) {
+                Ok(request) => {
+                    wait.request = request;
+                    None
+                }
+                Err(error_code) => Some(Err(error_code)),
+            },
+        }
+    }
+
+    // The wait then fails with Interrupted, unless the lock was already granted
+    pub fn cancel_range_lock_wait(&self, wait: &RangeLockWait) -> Result<(), ErrorCode> {
+        let request = Request::CancelRangeLockWait {
+            inode: wait.inode,
+            lock: wait.lock,
+        };
+
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(request, buffer)?;
+            response.as_empty_response().ok_or(ErrorCode::BadResponse)
+        })
+    }
+
+    pub fn open_handle(&self, inode: u64, handle: u64) -> Result<(), ErrorCode> {
+        let request = Request::OpenHandle {
+            inode,
+            session: self.session,
+            handle,
+        };
+
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(request, buffer)?;
+            response.as_empty_response().ok_or(ErrorCode::BadResponse)
+        })
+    }
+
+    pub fn release_handle(&self, inode: u64, handle: u64) -> Result<(), ErrorCode> {
+        self.readahead.release(handle);
+        let request = Request::ReleaseHandle {
+            inode,
+            session: self.session,
+            handle,
+        };
+
+        RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
+            let response = self.send(request, buffer)?;
+            response.as_empty_response().ok_or(ErrorCode::BadResponse)
+        })
+    }
+
     pub fn fsync(&self, inode: u64) -> Result<(), ErrorCode> {
         RESPONSE_BUFFERS.with_borrow_mut(|buffer| {
             let response = self.send(Request::Fsync { inode }, buffer)?;
@@ -488,7 +1103,12 @@ impl NodeClient {
         })
     }
 
-    pub fn unlink(&self, parent: u64, name: &str, context: UserContext) -> Result<(), ErrorCode> {
+    pub fn unlink(
+        &self,
+        parent: u64,
+        name: &str,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
         let request = Request::Unlink {
             parent,
             name,
@@ -503,7 +1123,12 @@ impl NodeClient {
         })
     }
 
-    pub fn rmdir(&self, parent: u64, name: &str, context: UserContext) -> Result<(), ErrorCode> {
+    pub fn rmdir(
+        &self,
+        parent: u64,
+        name: &str,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
         let request = Request::Rmdir {
             parent,
             name,
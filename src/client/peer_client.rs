@@ -3,13 +3,20 @@ use std::net::SocketAddr;
 
 use crate::base::response_or_error;
 use crate::base::{CommitId, ErrorCode, Request, encode_request};
+use crate::client::REQUEST_ID_SIZE;
 use byteorder::{ByteOrder, LittleEndian};
-use futures::FutureExt;
-use futures::future::{BoxFuture, Either, ok, ready};
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::lock::Mutex as AsyncMutex;
+use futures::{FutureExt, StreamExt};
 use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 
 // TODO: should have a larger pool for connections to the leader, and smaller for other peers
 const POOL_SIZE: usize = 8;
@@ -36,77 +43,188 @@ pub trait PeerClient {
     ) -> BoxFuture<'static, Result<Vec<u8>, std::io::Error>>;
 }
 
+// Requests waiting for a response, by request ID
+type PendingRequests = Arc<Mutex<HashMap<u64, oneshot::Sender<Vec<u8>>>>>;
+
+// A connection that many requests can be in flight on at once. The server may respond to them in
+// any order, so responses are matched to requests by ID
 #[derive(Debug)]
+struct Connection {
+    // Frames to be written by the connection's writer task
+    frames: UnboundedSender<Vec<u8>>,
+    next_request_id: AtomicU64,
+    pending: PendingRequests,
+    // Set, while holding the pending lock, once the connection has failed
+    closed: Arc<AtomicBool>,
+}
+
+fn close_connection(pending: &PendingRequests, closed: &AtomicBool) {
+    let mut pending = pending.lock().unwrap();
+    closed.store(true, Ordering::SeqCst);
+    // Dropping the senders fails the requests which are still waiting
+    pending.clear();
+}
+
+// Delivers each response to the request that's waiting for it, until the connection is closed
+async fn receive_responses(
+    mut stream: OwnedReadHalf,
+    pending: PendingRequests,
+    closed: Arc<AtomicBool>,
+) {
+    while let Ok(size) = stream.read_u32_le().await {
+        let size = size as usize;
+        if size < REQUEST_ID_SIZE {
+            break;
+        }
+        let Ok(request_id) = stream.read_u64_le().await else {
+            break;
+        };
+        let mut buffer = vec![0u8; size - REQUEST_ID_SIZE];
+        if stream.read_exact(&mut buffer).await.is_err() {
+            break;
+        }
+        if let Some(sender) = pending.lock().unwrap().remove(&request_id) {
+            // Ignore errors, since the request may have been dropped
+            sender.send(buffer).ok();
+        }
+    }
+
+    close_connection(&pending, &closed);
+}
+
+// Writes frames until the connection is dropped, or fails
+async fn send_requests(
+    mut stream: OwnedWriteHalf,
+    mut frames: UnboundedReceiver<Vec<u8>>,
+    pending: PendingRequests,
+    closed: Arc<AtomicBool>,
+) {
+    while let Some(frame) = frames.next().await {
+        if stream.write_all(&frame).await.is_err() {
+            // A partially written frame can't be recovered from
+            close_connection(&pending, &closed);
+            break;
+        }
+    }
+    // Dropping the stream shuts down the write side, and the server then closes the connection
+}
+
+impl Connection {
+    async fn open(server: SocketAddr) -> Result<Connection, std::io::Error> {
+        let stream = TcpStream::connect(server).await?;
+        // Frames from many requests are written back to back, and each is written whole, so they
+        // shouldn't be held back waiting for the ACKs of earlier ones
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        let (frames, receiver) = unbounded();
+        let pending: PendingRequests = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        tokio::spawn(receive_responses(reader, pending.clone(), closed.clone()));
+        tokio::spawn(send_requests(
+            writer,
+            receiver,
+            pending.clone(),
+            closed.clone(),
+        ));
+
+        Ok(Connection {
+            frames,
+            next_request_id: AtomicU64::new(0),
+            pending,
+            closed,
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn send(&self, data: &[u8]) -> Result<oneshot::Receiver<Vec<u8>>, std::io::Error> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if self.is_closed() {
+                return Err(std::io::ErrorKind::ConnectionAborted.into());
+            }
+            pending.insert(request_id, sender);
+        }
+
+        let header_size = 4 + REQUEST_ID_SIZE;
+        let mut frame = vec![0; header_size + data.len()];
+        LittleEndian::write_u32(&mut frame[..4], (REQUEST_ID_SIZE + data.len()) as u32);
+        LittleEndian::write_u64(&mut frame[4..header_size], request_id);
+        // TODO: remove this copy and use vectored write of the header and data separately,
+        // once that's supported in tokio: https://github.com/tokio-rs/tokio/issues/1271
+        // We merge them into a single buffer to be sure it's sent a single packet.
+        // Otherwise delayed TCP ACKs can add ~40ms of latency: https://eklitzke.org/the-caveats-of-tcp-nodelay
+        frame[header_size..].copy_from_slice(data);
+        if self.frames.unbounded_send(frame).is_err() {
+            self.pending.lock().unwrap().remove(&request_id);
+            return Err(std::io::ErrorKind::ConnectionAborted.into());
+        }
+
+        Ok(receiver)
+    }
+}
+
+// Client for a single node. Requests are spread over a pool of connections, each of which can have
+// many requests in flight, so any number of concurrent requests can share them
+#[derive(Clone, Debug)]
 pub struct TcpPeerClient {
     server_ip_port: SocketAddr,
-    pool: Arc<Mutex<Vec<TcpStream>>>,
+    pool: Arc<Vec<AsyncMutex<Option<Arc<Connection>>>>>,
+    next_connection: Arc<AtomicUsize>,
 }
 
 async fn async_send_and_receive<T: AsRef<[u8]> + Send>(
-    mut stream: TcpStream,
+    connection: impl Future<Output = Result<Arc<Connection>, std::io::Error>>,
     data: T,
-    pool: Arc<Mutex<Vec<TcpStream>>>,
 ) -> Result<Vec<u8>, std::io::Error> {
-    let mut request = vec![0; 4 + data.as_ref().len()];
-    LittleEndian::write_u32(&mut request[..4], data.as_ref().len() as u32);
-    // TODO: remove this copy and use vectored write of the header and data separately,
-    // once that's supported in tokio: https://github.com/tokio-rs/tokio/issues/1271
-    // We merge them into a single buffer to be sure it's sent a single packet.
-    // Otherwise delayed TCP ACKs can add ~40ms of latency: https://eklitzke.org/the-caveats-of-tcp-nodelay
-    request[4..(data.as_ref().len() + 4)].clone_from_slice(data.as_ref());
-    stream.write_all(&request).await?;
-
-    let mut response_size = vec![0; 4];
-    stream.read_exact(&mut response_size).await?;
-
-    let size = LittleEndian::read_u32(&response_size);
-    let mut buffer = vec![0u8; size as usize];
-    stream.read_exact(&mut buffer).await?;
-
-    TcpPeerClient::return_connection(pool, stream);
-
-    Ok(buffer)
+    let response = connection.await?.send(data.as_ref())?;
+    response
+        .await
+        .map_err(|_| std::io::ErrorKind::ConnectionAborted.into())
 }
 
 impl TcpPeerClient {
     pub fn new(server_ip_port: SocketAddr) -> TcpPeerClient {
         TcpPeerClient {
             server_ip_port,
-            pool: Arc::new(Mutex::new(vec![])),
+            pool: Arc::new((0..POOL_SIZE).map(|_| AsyncMutex::new(None)).collect()),
+            next_connection: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    fn connect(&self) -> BoxFuture<'static, Result<TcpStream, std::io::Error>> {
-        let mut locked = self.pool.lock().unwrap();
-        match locked.pop() {
-            Some(stream) => ok(stream).boxed(),
-            _ => {
-                // TODO: should have an upper limit on the number of outstanding connections
-                TcpStream::connect(self.server_ip_port).boxed()
+    fn connect(&self) -> impl Future<Output = Result<Arc<Connection>, std::io::Error>> + use<> {
+        let index = self.next_connection.fetch_add(1, Ordering::Relaxed) % POOL_SIZE;
+        let pool = self.pool.clone();
+        let server_ip_port = self.server_ip_port;
+        async move {
+            // Held while connecting, so that concurrent requests wait for the connection instead of
+            // each opening their own
+            let mut locked = pool[index].lock().await;
+            if let Some(connection) = locked.as_ref()
+                && !connection.is_closed()
+            {
+                return Ok(connection.clone());
             }
+            let connection = Arc::new(Connection::open(server_ip_port).await?);
+            locked.replace(connection.clone());
+
+            Ok(connection)
         }
     }
 
+    pub fn server_ip_port(&self) -> SocketAddr {
+        self.server_ip_port
+    }
+
     pub fn send(
         &self,
         request: &Request<'_>,
     ) -> BoxFuture<'static, Result<Vec<u8>, std::io::Error>> {
-        let pool = self.pool.clone();
-        let request_bytes = encode_request(request);
-
-        self.connect()
-            .then(move |tcp_stream| match tcp_stream {
-                Ok(stream) => Either::Left(async_send_and_receive(stream, request_bytes, pool)),
-                Err(e) => Either::Right(ready(Err(e))),
-            })
-            .boxed()
-    }
-
-    fn return_connection(pool: Arc<Mutex<Vec<TcpStream>>>, connection: TcpStream) {
-        let mut locked = pool.lock().unwrap();
-        if locked.len() < POOL_SIZE {
-            locked.push(connection);
-        }
+        self.send_raw(encode_request(request))
     }
 }
 
@@ -115,14 +233,7 @@ impl PeerClient for TcpPeerClient {
         &self,
         data: T,
     ) -> BoxFuture<'static, Result<Vec<u8>, std::io::Error>> {
-        let pool = self.pool.clone();
-
-        self.connect()
-            .then(move |tcp_stream| match tcp_stream {
-                Ok(stream) => Either::Left(async_send_and_receive(stream, data, pool)),
-                Err(e) => Either::Right(ready(Err(e))),
-            })
-            .boxed()
+        async_send_and_receive(self.connect(), data).boxed()
     }
 
     fn send_consensus_message(&self, raft_group: u16, data: Vec<u8>) -> BoxFuture<'static, ()> {
@@ -0,0 +1,223 @@
+use crate::base::{ErrorCode, Request, encode_request, response_or_error};
+use crate::client::tcp_client::{PendingRequest, TcpClient};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+// Largest amount of data that's prefetched ahead of a sequential reader
+const MAX_READAHEAD_BYTES: u64 = 8 * 1024 * 1024;
+// Most reads that are prefetched at once for a handle, so that small reads don't flood the server
+const MAX_PREFETCHES: usize = 32;
+
+// A read that's been sent to the server, and whose response hasn't been used yet
+struct Prefetch {
+    offset: u64,
+    size: u32,
+    request: PendingRequest,
+}
+
+struct HandleState {
+    inode: u64,
+    // Where the next read starts, if the handle is being read sequentially
+    next_offset: u64,
+    // How far ahead of the reader to prefetch. Zero after a random read
+    window: u64,
+    // Consecutive ranges being prefetched, in order
+    prefetches: VecDeque<Prefetch>,
+}
+
+impl HandleState {
+    fn new(inode: u64) -> HandleState {
+        HandleState {
+            inode,
+            next_offset: 0,
+            window: 0,
+            prefetches: VecDeque::new(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.window = 0;
+        self.prefetches.clear();
+    }
+
+    // Records a read of the handle. The window grows while it's read sequentially
+    fn record_read(&mut self, offset: u64, size: u32) {
+        if offset == self.next_offset {
+            self.window = (self.window * 2).max(size as u64).min(MAX_READAHEAD_BYTES);
+        } else {
+            self.reset();
+        }
+        self.next_offset = offset + size as u64;
+    }
+
+    // Offsets of the reads to prefetch after a sequential read that ended at end
+    fn ranges_to_prefetch(&self, end: u64, size: u32) -> Vec<u64> {
+        let mut next = self
+            .prefetches
+            .back()
+            .map(|prefetch| prefetch.offset + prefetch.size as u64)
+            .unwrap_or(end);
+        let mut offsets = vec![];
+        while next < end + self.window && self.prefetches.len() + offsets.len() < MAX_PREFETCHES {
+            offsets.push(next);
+            next += size as u64;
+        }
+
+        offsets
+    }
+}
+
+fn read_response(prefetch: Prefetch) -> Result<Vec<u8>, ErrorCode> {
+    let mut buffer = vec![];
+    prefetch
+        .request
+        .receive(&mut buffer)
+        .map_err(|_| ErrorCode::Uncategorized)?;
+    let data = response_or_error(&buffer)?
+        .as_read_response()
+        .ok_or(ErrorCode::BadResponse)?;
+
+    Ok(data.to_vec())
+}
+
+// Detects sequential reads of each file handle, and prefetches the ranges after them. The prefetches
+// are sent without waiting for their responses, so they're in flight on the client's connections
+// at once.
+// The window doubles with each sequential read, and is dropped on a random read or when the file
+// is written or truncated
+pub struct Readahead {
+    tcp_client: Arc<TcpClient>,
+    handles: Mutex<HashMap<u64, HandleState>>,
+}
+
+impl Readahead {
+    pub fn new(tcp_client: Arc<TcpClient>) -> Readahead {
+        Readahead {
+            tcp_client,
+            handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns the data for the read, if it was prefetched. Must be followed by a call to prefetch()
+    pub fn take(&self, inode: u64, handle: u64, offset: u64, size: u32) -> Option<Vec<u8>> {
+        let prefetch = {
+            let mut handles = self.handles.lock().expect("handles lock is poisoned");
+            let state = handles
+                .entry(handle)
+                .or_insert_with(|| HandleState::new(inode));
+            state.record_read(offset, size);
+            match state.prefetches.pop_front() {
+                Some(prefetch) if prefetch.offset == offset && prefetch.size == size => prefetch,
+                _ => {
+                    // The reads changed size, so none of the prefetched ranges line up with them
+                    state.prefetches.clear();
+                    return None;
+                }
+            }
+        };
+
+        read_response(prefetch).ok()
+    }
+
+    // Starts prefetching the ranges after a sequential read. read is the amount of data returned
+    pub fn prefetch(&self, handle: u64, offset: u64, size: u32, read: usize) {
+        let mut handles = self.handles.lock().expect("handles lock is poisoned");
+        let Some(state) = handles.get_mut(&handle) else {
+            return;
+        };
+        let end = offset + size as u64;
+        // Nothing to prefetch past the end of the file, and another read may have already moved on
+        if state.window == 0 || read < size as usize || state.next_offset != end {
+            return;
+        }
+        for next in state.ranges_to_prefetch(end, size) {
+            let request = encode_request(&Request::Read {
+                inode: state.inode,
+                offset: next,
+                read_size: size,
+            });
+            // Reads are idempotent, so it's safe to drop the prefetch without waiting for it
+            let Ok(request) = self.tcp_client.send_without_waiting(&request) else {
+                // The read will be sent again when it's reached, and fail over if needed
+                return;
+            };
+            state.prefetches.push_back(Prefetch {
+                offset: next,
+                size,
+                request,
+            });
+        }
+    }
+
+    // Drops the data prefetched from the inode, because it changed
+    pub fn invalidate(&self, inode: u64) {
+        let mut handles = self.handles.lock().expect("handles lock is poisoned");
+        for state in handles.values_mut() {
+            if state.inode == inode {
+                state.reset();
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut handles = self.handles.lock().expect("handles lock is poisoned");
+        for state in handles.values_mut() {
+            state.reset();
+        }
+    }
+
+    pub fn release(&self, handle: u64) {
+        self.handles
+            .lock()
+            .expect("handles lock is poisoned")
+            .remove(&handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::readahead::{HandleState, MAX_PREFETCHES, MAX_READAHEAD_BYTES};
+
+    #[test]
+    fn window_grows_while_sequential() {
+        let mut state = HandleState::new(1);
+        state.record_read(0, 4096);
+        assert_eq!(state.window, 4096);
+        assert_eq!(state.ranges_to_prefetch(4096, 4096), [4096]);
+        state.record_read(4096, 4096);
+        assert_eq!(state.window, 8192);
+        assert_eq!(state.ranges_to_prefetch(8192, 4096), [8192, 12288]);
+
+        // The window is capped, and so is the number of reads in flight
+        for i in 2..20 {
+            state.record_read(i * 4096, 4096);
+        }
+        assert_eq!(state.window, MAX_READAHEAD_BYTES);
+        assert_eq!(
+            state.ranges_to_prefetch(20 * 4096, 4096).len(),
+            MAX_PREFETCHES
+        );
+        let large = 1024 * 1024;
+        for i in 0..8 {
+            state.record_read(i * large, large as u32);
+        }
+        assert_eq!(
+            state.ranges_to_prefetch(8 * large, large as u32).len() as u64,
+            MAX_READAHEAD_BYTES / large
+        );
+    }
+
+    #[test]
+    fn window_reset_by_random_read() {
+        let mut state = HandleState::new(1);
+        state.record_read(0, 4096);
+        state.record_read(4096, 4096);
+        state.record_read(100_000, 4096);
+        assert_eq!(state.window, 0);
+        assert!(state.ranges_to_prefetch(104_096, 4096).is_empty());
+
+        // Reading sequentially from the new offset grows the window again
+        state.record_read(104_096, 4096);
+        assert_eq!(state.window, 4096);
+    }
+}
@@ -1,74 +1,469 @@
+use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::sync::Mutex;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError, channel};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
 
 use byteorder::ReadBytesExt;
 use byteorder::{ByteOrder, LittleEndian};
 use core::time::Duration;
+use log::warn;
 
 const TIMEOUT: u64 = 10;
+// A node that's slow to respond may just be busy, so it's only failed over from after this many
+// requests to it in a row time out
+pub const TIMEOUTS_BEFORE_FAIL_OVER: usize = 3;
+// Each request and response frame starts with the ID of the request
+pub const REQUEST_ID_SIZE: usize = 8;
 
-pub struct TcpClient {
+// Requests waiting for a response, by request ID
+type PendingRequests = Arc<Mutex<HashMap<u64, Sender<Vec<u8>>>>>;
+
+// A connection that many requests can be in flight on at once. The server may respond to them in
+// any order, so responses are matched to requests by ID
+struct Connection {
     server: SocketAddr,
-    // TODO: should probably have a connection pool here
-    connection: Mutex<Option<TcpStream>>,
+    stream: Mutex<TcpStream>,
+    next_request_id: AtomicU64,
+    pending: PendingRequests,
+    // Set, while holding the pending lock, once the connection has failed
+    closed: Arc<AtomicBool>,
+}
+
+// Delivers each response to the request that's waiting for it, until the connection is closed
+fn receive_responses(mut stream: TcpStream, pending: PendingRequests, closed: Arc<AtomicBool>) {
+    loop {
+        let frame = stream
+            .read_u32::<LittleEndian>()
+            .and_then(|size| {
+                let mut frame = vec![0; size as usize];
+                stream.read_exact(&mut frame)?;
+                Ok(frame)
+            })
+            .ok()
+            .filter(|frame| frame.len() >= REQUEST_ID_SIZE);
+        let Some(frame) = frame else {
+            break;
+        };
+        let request_id = LittleEndian::read_u64(&frame);
+        if let Some(sender) = pending.lock().unwrap().remove(&request_id) {
+            // Ignore errors, since the request may have timed out
+            sender.send(frame).ok();
+        }
+    }
+
+    let mut pending = pending.lock().unwrap();
+    closed.store(true, Ordering::SeqCst);
+    // Dropping the senders fails the requests which are still waiting
+    pending.clear();
+}
+
+impl Connection {
+    fn open(server: SocketAddr) -> Result<Connection, std::io::Error> {
+        let stream = TcpStream::connect_timeout(&server, Duration::from_secs(TIMEOUT))?;
+        // Reads aren't given a timeout, since the connection may be idle. Each request times out
+        // separately instead
+        stream
+            .set_write_timeout(Some(Duration::from_secs(TIMEOUT)))
+            .expect("Timeout cannot be zero");
+        let pending: PendingRequests = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let reader = stream.try_clone()?;
+        let reader_pending = pending.clone();
+        let reader_closed = closed.clone();
+        thread::spawn(move || receive_responses(reader, reader_pending, reader_closed));
+
+        Ok(Connection {
+            server,
+            stream: Mutex::new(stream),
+            next_request_id: AtomicU64::new(0),
+            pending,
+            closed,
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // Wakes up the reader, which fails the pending requests
+        self.stream.lock().unwrap().shutdown(Shutdown::Both).ok();
+    }
+
+    // Sends the request. If this fails, the server didn't receive it
+    fn send(self: &Arc<Self>, data: &[u8]) -> Result<InFlightRequest, std::io::Error> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if self.is_closed() {
+                return Err(std::io::ErrorKind::ConnectionAborted.into());
+            }
+            pending.insert(request_id, sender);
+        }
+
+        let mut send_buffer = vec![0; data.len() + REQUEST_ID_SIZE + 4];
+        LittleEndian::write_u32(&mut send_buffer, (data.len() + REQUEST_ID_SIZE) as u32);
+        LittleEndian::write_u64(&mut send_buffer[4..], request_id);
+        // TODO: optimize out this copy
+        send_buffer[(REQUEST_ID_SIZE + 4)..].copy_from_slice(data);
+        if let Err(error) = self.stream.lock().unwrap().write_all(&send_buffer) {
+            self.pending.lock().unwrap().remove(&request_id);
+            // A partially written frame can't be recovered from
+            self.close();
+            return Err(error);
+        }
+
+        Ok(InFlightRequest {
+            connection: self.clone(),
+            request_id,
+            receiver,
+        })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+struct InFlightRequest {
+    connection: Arc<Connection>,
+    request_id: u64,
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl InFlightRequest {
+    // Returns the response frame, including the request ID
+    fn wait(self, timeout: Duration) -> Result<Vec<u8>, std::io::Error> {
+        self.receiver
+            .recv_timeout(timeout)
+            .map_err(|error| match error {
+                RecvTimeoutError::Timeout => std::io::ErrorKind::TimedOut.into(),
+                RecvTimeoutError::Disconnected => std::io::ErrorKind::ConnectionAborted.into(),
+            })
+    }
+
+    // Returns the response frame, including the request ID, if it has arrived
+    fn try_wait(&self) -> Option<Result<Vec<u8>, std::io::Error>> {
+        match self.receiver.try_recv() {
+            Ok(frame) => Some(Ok(frame)),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                Some(Err(std::io::ErrorKind::ConnectionAborted.into()))
+            }
+        }
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        // The response is no longer waited for, if it hasn't arrived
+        self.connection
+            .pending
+            .lock()
+            .unwrap()
+            .remove(&self.request_id);
+    }
+}
+
+// A request which has been sent, and whose response can be polled for without blocking
+pub struct PendingRequest {
+    request: InFlightRequest,
+}
+
+impl PendingRequest {
+    // Returns once the response has arrived, or the connection it was sent on failed
+    pub fn try_receive(&self, response: &mut Vec<u8>) -> Option<Result<(), std::io::Error>> {
+        let frame = match self.request.try_wait()? {
+            Ok(frame) => frame,
+            Err(error) => return Some(Err(error)),
+        };
+        response.clear();
+        response.extend_from_slice(&frame[REQUEST_ID_SIZE..]);
+
+        Some(Ok(()))
+    }
+
+    // Waits for the response, until the request times out
+    pub fn receive(self, response: &mut Vec<u8>) -> Result<(), std::io::Error> {
+        let frame = self.request.wait(Duration::from_secs(TIMEOUT))?;
+        response.clear();
+        response.extend_from_slice(&frame[REQUEST_ID_SIZE..]);
+
+        Ok(())
+    }
+}
+
+// Chooses the node that requests are sent to, and when to fail over from it to the next one.
+// Shared by the sync and async clients, so that they react to failures the same way
+pub struct FailoverPolicy {
+    // Index of the node that requests are sent to
+    current_server: AtomicUsize,
+    // Requests to the current node which have timed out since one last succeeded
+    timeouts: AtomicUsize,
+}
+
+impl FailoverPolicy {
+    pub fn new() -> FailoverPolicy {
+        FailoverPolicy {
+            current_server: AtomicUsize::new(0),
+            timeouts: AtomicUsize::new(0),
+        }
+    }
+
+    // Index of the node that requests are sent to, out of server_count nodes
+    pub fn current_server(&self, server_count: usize) -> usize {
+        self.current_server.load(Ordering::SeqCst) % server_count
+    }
+
+    pub fn request_succeeded(&self) {
+        self.timeouts.store(0, Ordering::SeqCst);
+    }
+
+    // Fails over from the node after a request to it failed with error. Timeouts only fail over
+    // once there have been several in a row
+    pub fn request_failed(&self, failed: usize, error: &std::io::Error, servers: &[SocketAddr]) {
+        if error.kind() != std::io::ErrorKind::TimedOut
+            || self.timeouts.fetch_add(1, Ordering::SeqCst) + 1 >= TIMEOUTS_BEFORE_FAIL_OVER
+        {
+            self.fail_over(failed, servers);
+        }
+    }
+
+    // Moves to the next node, unless another request already moved away from the failed one
+    pub fn fail_over(&self, failed: usize, servers: &[SocketAddr]) {
+        let next = (failed + 1) % servers.len();
+        if self
+            .current_server
+            .compare_exchange(failed, next, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+            && next != failed
+        {
+            self.timeouts.store(0, Ordering::SeqCst);
+            warn!("Failing over from {} to {}", servers[failed], servers[next]);
+        }
+    }
+}
+
+// Client for the storage nodes' request protocol. Requests are spread over a pool of connections,
+// each of which can have many requests in flight. All the connections go to one node, until it
+// fails and the client fails over to the next one
+pub struct TcpClient {
+    // Only ever appended to, so that indices into it stay valid
+    servers: RwLock<Vec<SocketAddr>>,
+    failover: FailoverPolicy,
+    connections: Vec<Mutex<Option<Arc<Connection>>>>,
+    next_connection: AtomicUsize,
 }
 
 impl TcpClient {
-    pub fn new(server: SocketAddr) -> TcpClient {
+    pub fn new(servers: Vec<SocketAddr>, connections: usize) -> TcpClient {
+        assert!(!servers.is_empty());
+        assert!(connections > 0);
         TcpClient {
-            server,
-            connection: Mutex::new(None),
+            servers: RwLock::new(servers),
+            failover: FailoverPolicy::new(),
+            connections: (0..connections).map(|_| Mutex::new(None)).collect(),
+            next_connection: AtomicUsize::new(0),
         }
     }
 
-    pub fn send_and_receive(
+    // Adds nodes which can be failed over to
+    pub fn add_servers(&self, new_servers: &[SocketAddr]) {
+        let mut servers = self.servers.write().expect("lock acquisition failed");
+        for server in new_servers {
+            if !servers.contains(server) {
+                servers.push(*server);
+            }
+        }
+    }
+
+    fn server(&self) -> (usize, SocketAddr) {
+        let servers = self.servers.read().expect("lock acquisition failed");
+        let index = self.failover.current_server(servers.len());
+        (index, servers[index])
+    }
+
+    fn fail_over(&self, failed: usize) {
+        let servers = self.servers.read().expect("lock acquisition failed");
+        self.failover.fail_over(failed, &servers);
+    }
+
+    fn request_failed(&self, failed: usize, error: &std::io::Error) {
+        let servers = self.servers.read().expect("lock acquisition failed");
+        self.failover.request_failed(failed, error, &servers);
+    }
+
+    fn connection(
+        &self,
+        index: usize,
+        server: SocketAddr,
+        reconnect: bool,
+    ) -> Result<Arc<Connection>, std::io::Error> {
+        let mut locked = self.connections[index]
+            .lock()
+            .expect("lock acquisition failed");
+        if let Some(connection) = locked.as_ref()
+            && !reconnect
+            && !connection.is_closed()
+            && connection.server == server
+        {
+            return Ok(connection.clone());
+        }
+        let connection = Arc::new(Connection::open(server)?);
+        locked.replace(connection.clone());
+
+        Ok(connection)
+    }
+
+    // Sends the request to the server. If this fails, the server didn't receive it
+    fn send(
+        &self,
+        index: usize,
+        server: SocketAddr,
+        data: &[u8],
+    ) -> Result<InFlightRequest, std::io::Error> {
+        match self.connection(index, server, false)?.send(data) {
+            Ok(request) => Ok(request),
+            // Retry once, since the server may have closed the connection while it was idle
+            Err(_) => self.connection(index, server, true)?.send(data),
+        }
+    }
+
+    fn send_and_receive_inner(
         &self,
         data: &[u8],
         response: &mut Vec<u8>,
+        idempotent: bool,
+        timeout: Duration,
     ) -> Result<(), std::io::Error> {
-        let mut locked = self.connection.lock().expect("lock acquisition failed");
-        if locked.is_none() {
-            let stream = TcpStream::connect_timeout(&self.server, Duration::from_secs(TIMEOUT))?;
-            stream
-                .set_read_timeout(Some(Duration::from_secs(TIMEOUT)))
-                .expect("Timeout cannot be zero");
-            stream
-                .set_write_timeout(Some(Duration::from_secs(TIMEOUT)))
-                .expect("Timeout cannot be zero");
-            locked.replace(stream);
+        let index = self.next_connection.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        let attempts = self.servers.read().expect("lock acquisition failed").len();
+        let mut last_error = std::io::ErrorKind::NotConnected.into();
+        for _ in 0..attempts {
+            let (server_index, server) = self.server();
+            match self.send(index, server, data) {
+                Ok(request) => {
+                    match request.wait(timeout) {
+                        Ok(frame) => {
+                            self.failover.request_succeeded();
+                            response.clear();
+                            response.extend_from_slice(&frame[REQUEST_ID_SIZE..]);
+                            return Ok(());
+                        }
+                        Err(error) => {
+                            self.request_failed(server_index, &error);
+                            // The request may have been applied, so it's only sent again if that's
+                            // safe
+                            if !idempotent {
+                                return Err(error);
+                            }
+                            last_error = error;
+                        }
+                    }
+                }
+                Err(error) => {
+                    self.fail_over(server_index);
+                    last_error = error;
+                }
+            }
         }
 
-        let mut stream = locked.take().expect("connected stream is None");
+        Err(last_error)
+    }
 
-        let mut send_buffer = vec![0; data.len() + 4];
-        LittleEndian::write_u32(&mut send_buffer, data.len() as u32);
-        // TODO: optimize out this copy
-        send_buffer[4..].copy_from_slice(data);
-
-        match stream.write_all(&send_buffer) {
-            Ok(_) => {}
-            Err(_) => {
-                // Retry once
-                stream = TcpStream::connect_timeout(&self.server, Duration::from_secs(TIMEOUT))?;
-                stream
-                    .set_read_timeout(Some(Duration::from_secs(TIMEOUT)))
-                    .expect("Timeout cannot be zero");
-                stream
-                    .set_write_timeout(Some(Duration::from_secs(TIMEOUT)))
-                    .expect("Timeout cannot be zero");
-                stream.write_all(&send_buffer)?;
+    // Sends the request without waiting for its response. The request must be idempotent, since
+    // the caller can't tell whether it was applied if the connection fails
+    pub fn send_without_waiting(&self, data: &[u8]) -> Result<PendingRequest, std::io::Error> {
+        let index = self.next_connection.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        let attempts = self.servers.read().expect("lock acquisition failed").len();
+        let mut last_error = std::io::ErrorKind::NotConnected.into();
+        for _ in 0..attempts {
+            let (server_index, server) = self.server();
+            match self.send(index, server, data) {
+                Ok(request) => return Ok(PendingRequest { request }),
+                Err(error) => {
+                    self.fail_over(server_index);
+                    last_error = error;
+                }
             }
         }
 
-        let data_size = stream.read_u32::<LittleEndian>()? as usize;
-        response.clear();
-        response.resize(data_size, 0);
-        stream.read_exact(response)?;
+        Err(last_error)
+    }
+
+    // idempotent must only be set if the request can safely be applied more than once
+    pub fn send_and_receive(
+        &self,
+        data: &[u8],
+        response: &mut Vec<u8>,
+        idempotent: bool,
+    ) -> Result<(), std::io::Error> {
+        self.send_and_receive_inner(data, response, idempotent, Duration::from_secs(TIMEOUT))
+    }
+}
 
-        // If the connection is still working, store it back
-        locked.replace(stream);
+#[cfg(test)]
+mod tests {
+    use crate::client::tcp_client::{TIMEOUTS_BEFORE_FAIL_OVER, TcpClient};
+    use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
 
-        Ok(())
+    // Starts a server which echoes each request, or never responds if it's unresponsive
+    fn server(responsive: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    while let Ok(size) = stream.read_u32::<LittleEndian>() {
+                        let mut frame = vec![0; size as usize];
+                        stream.read_exact(&mut frame).unwrap();
+                        if responsive {
+                            stream.write_u32::<LittleEndian>(size).unwrap();
+                            stream.write_all(&frame).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    #[test]
+    fn fail_over_after_repeated_timeouts() {
+        let client = TcpClient::new(vec![server(false), server(true)], 1);
+        let timeout = Duration::from_millis(50);
+        let mut response = vec![];
+        // A request that times out isn't sent again, unless that's safe
+        let result = client.send_and_receive_inner(b"request", &mut response, false, timeout);
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(client.server().0, 0);
+        for _ in 1..(TIMEOUTS_BEFORE_FAIL_OVER - 1) {
+            assert!(
+                client
+                    .send_and_receive_inner(b"request", &mut response, false, timeout)
+                    .is_err()
+            );
+            assert_eq!(client.server().0, 0);
+        }
+
+        // Once enough requests have timed out, an idempotent one is sent to the next node
+        client
+            .send_and_receive_inner(b"request", &mut response, true, timeout)
+            .unwrap();
+        assert_eq!(client.server().0, 1);
+        assert_eq!(response, b"request");
     }
 }
@@ -0,0 +1,509 @@
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use fleetfs::base::{EntryMetadata, ErrorCode, FileKind};
+use fleetfs::client::{Filesystem, OpenOptions};
+use std::fs;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+// Buffer size for cat, put and get. Each read or write of the buffer is a single request
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+// Commands for working with the files in a cluster, without mounting it
+pub fn subcommands() -> Vec<Command> {
+    let path = Arg::new("path").value_name("PATH").required(true);
+    let paths = Arg::new("paths")
+        .value_name("PATH")
+        .required(true)
+        .num_args(1..);
+
+    vec![
+        Command::new("ls")
+            .about("List a directory")
+            .arg(
+                Arg::new("long")
+                    .short('l')
+                    .action(ArgAction::SetTrue)
+                    .help("Show the mode, links, owner and size of each entry"),
+            )
+            .arg(Arg::new("path").value_name("PATH").default_value("/")),
+        Command::new("stat")
+            .about("Print the attributes of a file")
+            .arg(path.clone()),
+        Command::new("cat")
+            .about("Print the contents of files")
+            .arg(paths.clone()),
+        Command::new("put")
+            .about("Copy a local file into the cluster, replacing the destination")
+            .arg(
+                Arg::new("local")
+                    .value_name("LOCAL")
+                    .required(true)
+                    .help("Local file, or - for stdin"),
+            )
+            .arg(Arg::new("remote").value_name("REMOTE").required(true)),
+        Command::new("get")
+            .about("Copy a file out of the cluster")
+            .arg(Arg::new("remote").value_name("REMOTE").required(true))
+            .arg(
+                Arg::new("local")
+                    .value_name("LOCAL")
+                    .required(true)
+                    .help("Local file, or - for stdout"),
+            ),
+        Command::new("rm")
+            .about("Remove files")
+            .arg(
+                Arg::new("recursive")
+                    .short('r')
+                    .action(ArgAction::SetTrue)
+                    .help("Remove directories and their contents"),
+            )
+            .arg(paths.clone()),
+        Command::new("mkdir")
+            .about("Create directories")
+            .arg(
+                Arg::new("parents")
+                    .short('p')
+                    .action(ArgAction::SetTrue)
+                    .help("Create missing parent directories, and ignore existing ones"),
+            )
+            .arg(paths),
+        Command::new("mv")
+            .about("Move or rename a file, replacing the destination")
+            .arg(Arg::new("from").value_name("FROM").required(true))
+            .arg(Arg::new("to").value_name("TO").required(true)),
+        Command::new("getfattr")
+            .about("Print the extended attributes of a file")
+            .arg(
+                Arg::new("name")
+                    .short('n')
+                    .value_name("NAME")
+                    .help("Only print the named attribute"),
+            )
+            .arg(path.clone()),
+        Command::new("setfattr")
+            .about("Set or remove an extended attribute of a file")
+            .arg(
+                Arg::new("name")
+                    .short('n')
+                    .value_name("NAME")
+                    .help("Set the named attribute"),
+            )
+            .arg(
+                Arg::new("value")
+                    .short('v')
+                    .value_name("VALUE")
+                    .default_value("")
+                    .requires("name")
+                    .help("Value to set the attribute to"),
+            )
+            .arg(
+                Arg::new("remove")
+                    .short('x')
+                    .value_name("NAME")
+                    .help("Remove the named attribute"),
+            )
+            .group(
+                ArgGroup::new("attribute")
+                    .args(["name", "remove"])
+                    .required(true),
+            )
+            .arg(path),
+    ]
+}
+
+// Adds the path that an operation failed on to its error
+fn path_error(path: &Path) -> impl Fn(ErrorCode) -> io::Error + '_ {
+    move |error_code| with_path(path, io::Error::from(error_code))
+}
+
+fn with_path(path: &Path, error: io::Error) -> io::Error {
+    io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
+
+fn current_groups() -> Vec<u32> {
+    // Safe, since a zero size only returns the number of groups
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if count <= 0 {
+        return vec![];
+    }
+    let mut groups = vec![0; count as usize];
+    // Safe, since groups has space for count entries
+    let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+
+    groups
+}
+
+fn kind_name(kind: FileKind) -> &'static str {
+    match kind {
+        FileKind::File => "regular file",
+        FileKind::Directory => "directory",
+        FileKind::Symlink => "symbolic link",
+        FileKind::NamedPipe => "fifo",
+        FileKind::Socket => "socket",
+        FileKind::CharDevice => "character special file",
+        FileKind::BlockDevice => "block special file",
+    }
+}
+
+// Formats the kind and mode like ls does, e.g. "drwxr-xr-x"
+fn mode_string(attr: &EntryMetadata) -> String {
+    let kind = match attr.kind {
+        FileKind::File => '-',
+        FileKind::Directory => 'd',
+        FileKind::Symlink => 'l',
+        FileKind::NamedPipe => 'p',
+        FileKind::Socket => 's',
+        FileKind::CharDevice => 'c',
+        FileKind::BlockDevice => 'b',
+    };
+    let mut result = String::from(kind);
+    for (i, permission) in "rwxrwxrwx".chars().enumerate() {
+        if attr.mode & (1 << (8 - i)) != 0 {
+            result.push(permission);
+        } else {
+            result.push('-');
+        }
+    }
+
+    result
+}
+
+fn print_long(output: &mut dyn Write, attr: &EntryMetadata, name: &str) -> io::Result<()> {
+    writeln!(
+        output,
+        "{} {:>3} {:>5} {:>5} {:>12} {}",
+        mode_string(attr),
+        attr.hard_links,
+        attr.user_id,
+        attr.group_id,
+        attr.size_bytes,
+        name
+    )
+}
+
+fn ls(filesystem: &Filesystem, path: &Path, long: bool, output: &mut dyn Write) -> io::Result<()> {
+    let attr = filesystem.metadata(path).map_err(path_error(path))?;
+    if attr.kind != FileKind::Directory {
+        if long {
+            return print_long(output, &attr, &path.display().to_string());
+        }
+        return writeln!(output, "{}", path.display());
+    }
+
+    // The entries' attributes are listed along with them, instead of being looked up one by one
+    let entries = if long {
+        filesystem.read_dir_with_metadata(path)
+    } else {
+        filesystem.read_dir(path)
+    }
+    .map_err(path_error(path))?;
+    for entry in entries {
+        let entry = entry.map_err(path_error(path))?;
+        match entry.metadata {
+            Some(attr) => print_long(output, &attr, &entry.name)?,
+            None => writeln!(output, "{}", entry.name)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn stat(filesystem: &Filesystem, path: &Path) -> io::Result<()> {
+    let attr = filesystem.metadata(path).map_err(path_error(path))?;
+    println!("  File: {}", path.display());
+    println!(
+        "  Size: {}\tBlocks: {}\t{}",
+        attr.size_bytes,
+        attr.size_blocks,
+        kind_name(attr.kind)
+    );
+    println!("Inode: {}\tLinks: {}", attr.inode, attr.hard_links);
+    println!(
+        "Access: ({:04o}/{})\tUid: {}\tGid: {}",
+        attr.mode,
+        mode_string(&attr),
+        attr.user_id,
+        attr.group_id
+    );
+    for (name, time) in [
+        ("Access", attr.last_access_time),
+        ("Modify", attr.last_modified_time),
+        ("Change", attr.last_metadata_modified_time),
+    ] {
+        println!("{name}: {}.{:09}", time.seconds, time.nanos);
+    }
+
+    Ok(())
+}
+
+fn get(filesystem: &Filesystem, remote: &Path, output: &mut dyn Write) -> io::Result<()> {
+    let file = filesystem
+        .open(remote, OpenOptions::new().read(true))
+        .map_err(path_error(remote))?;
+    let mut reader = BufReader::with_capacity(COPY_BUFFER_SIZE, file);
+    io::copy(&mut reader, output)?;
+
+    Ok(())
+}
+
+fn put(filesystem: &Filesystem, local: &str, remote: &Path) -> io::Result<()> {
+    let (mut input, mode): (Box<dyn io::Read>, u32) = if local == "-" {
+        (Box::new(io::stdin().lock()), 0o644)
+    } else {
+        let file = fs::File::open(local)?;
+        let mode = file.metadata()?.permissions().mode();
+        (Box::new(file), mode)
+    };
+    let file = filesystem
+        .open(
+            remote,
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode((mode & 0o7777) as u16),
+        )
+        .map_err(path_error(remote))?;
+    let mut writer = BufWriter::with_capacity(COPY_BUFFER_SIZE, file);
+    io::copy(&mut input, &mut writer)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn remove(filesystem: &Filesystem, path: &Path, recursive: bool) -> io::Result<()> {
+    if filesystem.metadata(path).map_err(path_error(path))?.kind == FileKind::Directory {
+        if !recursive {
+            return Err(with_path(path, io::Error::from_raw_os_error(libc::EISDIR)));
+        }
+        // Listed up front, since removing entries while listing could skip some
+        let entries: Vec<_> = filesystem
+            .read_dir(path)
+            .map_err(path_error(path))?
+            .collect::<Result<_, _>>()
+            .map_err(path_error(path))?;
+        for entry in entries {
+            remove(filesystem, &path.join(entry.name), true)?;
+        }
+        return filesystem.remove_dir(path).map_err(path_error(path));
+    }
+
+    filesystem.remove_file(path).map_err(path_error(path))
+}
+
+fn mkdir(filesystem: &Filesystem, path: &Path, parents: bool) -> io::Result<()> {
+    if !parents {
+        filesystem
+            .create_dir(path, 0o755)
+            .map_err(path_error(path))?;
+        return Ok(());
+    }
+
+    let mut partial = PathBuf::new();
+    for component in path.components() {
+        partial.push(component);
+        if partial.file_name().is_none() {
+            continue;
+        }
+        match filesystem.create_dir(&partial, 0o755) {
+            Ok(_) => {}
+            Err(ErrorCode::AlreadyExists) => {
+                let attr = filesystem
+                    .metadata(&partial)
+                    .map_err(path_error(&partial))?;
+                if attr.kind != FileKind::Directory {
+                    return Err(path_error(&partial)(ErrorCode::AlreadyExists));
+                }
+            }
+            Err(error_code) => return Err(path_error(&partial)(error_code)),
+        }
+    }
+
+    Ok(())
+}
+
+// Prints the value quoted, if it's text, and in hex otherwise
+fn format_xattr_value(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) if !text.chars().any(char::is_control) => format!("{text:?}"),
+        _ => {
+            let hex: String = value.iter().map(|byte| format!("{byte:02x}")).collect();
+            format!("0x{hex}")
+        }
+    }
+}
+
+fn getfattr(filesystem: &Filesystem, path: &Path, name: Option<&String>) -> io::Result<()> {
+    let names = match name {
+        Some(name) => vec![name.clone()],
+        None => filesystem.list_xattrs(path).map_err(path_error(path))?,
+    };
+    for name in names {
+        let value = filesystem
+            .get_xattr(path, &name)
+            .map_err(path_error(path))?;
+        println!("{name}={}", format_xattr_value(&value));
+    }
+
+    Ok(())
+}
+
+// Runs one of the subcommands(), as the current user
+pub fn run(servers: Vec<SocketAddr>, command: &str, matches: &ArgMatches) -> io::Result<()> {
+    // Safe, since these can't fail
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let filesystem = Filesystem::connect(servers.clone(), uid, gid, &current_groups()).map_err(
+        |error_code| {
+            let error = io::Error::from(error_code);
+            io::Error::new(
+                error.kind(),
+                format!("Failed to connect to {servers:?}: {error}"),
+            )
+        },
+    )?;
+    let path = |name: &str| Path::new(matches.get_one::<String>(name).unwrap());
+    let paths = || matches.get_many::<String>("paths").unwrap().map(Path::new);
+
+    match command {
+        "ls" => ls(
+            &filesystem,
+            path("path"),
+            matches.get_flag("long"),
+            &mut io::stdout().lock(),
+        ),
+        "stat" => stat(&filesystem, path("path")),
+        "cat" => {
+            let mut stdout = io::stdout().lock();
+            for path in paths() {
+                get(&filesystem, path, &mut stdout)?;
+            }
+            Ok(())
+        }
+        "put" => put(
+            &filesystem,
+            matches.get_one::<String>("local").unwrap(),
+            path("remote"),
+        ),
+        "get" => {
+            let local = matches.get_one::<String>("local").unwrap();
+            if local == "-" {
+                get(&filesystem, path("remote"), &mut io::stdout().lock())
+            } else {
+                let mut file = fs::File::create(local)?;
+                get(&filesystem, path("remote"), &mut file)
+            }
+        }
+        "rm" => {
+            for path in paths() {
+                remove(&filesystem, path, matches.get_flag("recursive"))?;
+            }
+            Ok(())
+        }
+        "mkdir" => {
+            for path in paths() {
+                mkdir(&filesystem, path, matches.get_flag("parents"))?;
+            }
+            Ok(())
+        }
+        "mv" => {
+            let (from, to) = (path("from"), path("to"));
+            filesystem.rename(from, to).map_err(path_error(from))
+        }
+        "getfattr" => getfattr(&filesystem, path("path"), matches.get_one("name")),
+        "setfattr" => {
+            let path = path("path");
+            if let Some(name) = matches.get_one::<String>("remove") {
+                filesystem.remove_xattr(path, name)
+            } else {
+                let name = matches.get_one::<String>("name").unwrap();
+                let value = matches.get_one::<String>("value").unwrap();
+                filesystem.set_xattr(path, name, value.as_bytes())
+            }
+            .map_err(path_error(path))
+        }
+        _ => unreachable!("Unknown command {command}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_tool::{ls, remove};
+    use fleetfs::base::{AtimeMode, ErrorCode};
+    use fleetfs::client::{Filesystem, NodeClient, OpenOptions};
+    use fleetfs::storage::Node;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::{TempDir, tempdir};
+
+    // Starts a single node cluster, and connects to it as root
+    fn connect() -> (Filesystem, TempDir) {
+        let dir = tempdir().unwrap();
+        let address: SocketAddr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let node = Node::new(
+            dir.path().to_str().unwrap(),
+            address,
+            vec![],
+            1,
+            AtimeMode::NoAtime,
+        );
+        thread::spawn(move || node.run());
+        let client = NodeClient::new(vec![address]);
+        while client.filesystem_ready().is_err() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        (Filesystem::connect(vec![address], 0, 0, &[]).unwrap(), dir)
+    }
+
+    #[test]
+    fn long_listing() {
+        let (fs, _dir) = connect();
+        fs.create_dir("/dir", 0o750).unwrap();
+        fs.open(
+            "/file",
+            OpenOptions::new().write(true).create(true).mode(0o640),
+        )
+        .unwrap()
+        .write_all(b"hello")
+        .unwrap();
+
+        let mut output = vec![];
+        ls(&fs, Path::new("/"), true, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "drwxr-x---   2     0     0          512 dir\n\
+             -rw-r-----   1     0     0            5 file\n"
+        );
+        let mut output = vec![];
+        ls(&fs, Path::new("/file"), false, &mut output).unwrap();
+        assert_eq!(output, b"/file\n");
+    }
+
+    #[test]
+    fn remove_directory() {
+        let (fs, _dir) = connect();
+        fs.create_dir("/dir", 0o755).unwrap();
+        fs.create_dir("/dir/nested", 0o755).unwrap();
+        fs.open(
+            "/dir/nested/file",
+            OpenOptions::new().write(true).create(true),
+        )
+        .unwrap();
+
+        let error = remove(&fs, Path::new("/dir"), false).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::IsADirectory);
+        remove(&fs, Path::new("/dir"), true).unwrap();
+        assert_eq!(fs.metadata("/dir").unwrap_err(), ErrorCode::DoesNotExist);
+    }
+}
@@ -1,27 +1,27 @@
 use clap::Arg;
 use clap::Command;
-use clap::{ArgAction, crate_version};
+use clap::{ArgAction, ArgMatches, crate_version};
 
-use crate::client::NodeClient;
 use crate::fuse_adapter::FleetFUSE;
-use crate::storage::Node;
+use fleetfs::client::NodeClient;
+use fleetfs::storage::Node;
 use log::LevelFilter;
 use log::debug;
 use log::warn;
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 
-use crate::base::ErrorCode;
+use fleetfs::base::{AtimeMode, ErrorCode};
 use fuser::{Config, MountOption, SessionACL};
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, BufReader};
+use std::process::exit;
 use std::thread::sleep;
 use std::time::Duration;
 
-pub mod base;
-pub mod client;
-pub mod fuse_adapter;
-pub mod storage;
+mod file_tool;
+mod fuse_adapter;
+mod write_back;
 
 pub fn fuse_allow_other_enabled() -> io::Result<bool> {
     let file = File::open("/etc/fuse.conf")?;
@@ -33,10 +33,66 @@ pub fn fuse_allow_other_enabled() -> io::Result<bool> {
     Ok(false)
 }
 
-fn main() -> Result<(), ErrorCode> {
-    let matches = Command::new("FleetFS")
-        .version(crate_version!())
-        .author("Christopher Berner")
+fn is_verbosity_flag(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|c| c == 'v')
+}
+
+// Converts the flags which selected the mode, before there were subcommands, to the equivalent
+// subcommand. External test harnesses still run the binary that way
+fn upgrade_legacy_arguments(command: &Command, args: Vec<String>) -> Vec<String> {
+    // Only the global options may come before the subcommand, so the first other argument is
+    // either the subcommand or a legacy flag
+    let mut options = args.iter().skip(1);
+    let mut first_positional = None;
+    while let Some(arg) = options.next() {
+        if arg == "--server-ip-port" {
+            options.next();
+        } else if !is_verbosity_flag(arg) && !arg.starts_with("--server-ip-port=") {
+            first_positional = Some(arg.as_str());
+            break;
+        }
+    }
+    let current = match first_positional {
+        // Running a server used to need no arguments other than the verbosity
+        None => args.len() == 1,
+        Some(arg) => {
+            command
+                .get_subcommands()
+                .any(|subcommand| subcommand.get_name() == arg)
+                || ["help", "-h", "--help", "-V", "--version"].contains(&arg)
+        }
+    };
+    if current {
+        return args;
+    }
+
+    let (verbosity, mut rest): (Vec<String>, Vec<String>) = args[1..]
+        .iter()
+        .cloned()
+        .partition(|arg| is_verbosity_flag(arg));
+    let subcommand = if let Some(index) = rest.iter().position(|arg| arg == "--mount-point") {
+        rest.remove(index);
+        "mount"
+    } else if let Some(index) = rest.iter().position(|arg| arg == "--fsck") {
+        rest.remove(index);
+        "fsck"
+    } else if let Some(index) = rest.iter().position(|arg| arg == "--get-leader") {
+        rest.remove(index);
+        "get-leader"
+    } else {
+        "server"
+    };
+
+    let mut upgraded = vec![args[0].clone()];
+    upgraded.extend(verbosity);
+    upgraded.push(subcommand.to_string());
+    upgraded.extend(rest);
+    upgraded
+}
+
+fn server_command() -> Command {
+    Command::new("server")
+        .about("Run a storage node")
         .arg(
             Arg::new("port")
                 .long("port")
@@ -81,60 +137,39 @@ fn main() -> Result<(), ErrorCode> {
                 .help("Number of failures that can be tolerated, in a replication group, before data is lost"),
         )
         .arg(
-            Arg::new("server-ip-port")
-                .long("server-ip-port")
-                .value_name("IP_PORT")
-                .default_value("127.0.0.1:3000")
-                .help("Act as a client, and connect to given server"),
+            Arg::new("atime")
+                .long("atime")
+                .value_name("MODE")
+                .value_parser(["noatime", "relatime", "strictatime"])
+                .default_value("relatime")
+                .help("When reads update access times"),
         )
+}
+
+fn mount_command() -> Command {
+    Command::new("mount")
+        .about("Mount the filesystem with FUSE")
         .arg(
             Arg::new("mount-point")
-                .long("mount-point")
                 .value_name("MOUNT_POINT")
-                .default_value("")
-                .help("Act as a client, and mount FUSE at given path"),
+                .required(true)
+                .help("Path to mount FUSE at"),
         )
         .arg(
             Arg::new("direct-io")
                 .long("direct-io")
                 .action(ArgAction::SetTrue)
-                .requires("mount-point")
                 .help("Mount FUSE with direct IO"),
         )
         .arg(
-            Arg::new("fsck")
-                .long("fsck")
+            Arg::new("write-back")
+                .long("write-back")
                 .action(ArgAction::SetTrue)
-                .help("Run a filesystem check on the cluster"),
+                .help("Buffer writes in the client until files are flushed or closed"),
         )
-        .arg(
-            Arg::new("get-leader")
-                .long("get-leader")
-                .action(ArgAction::SetTrue)
-                .help("Print the ID of the leader node"),
-        )
-        .arg(
-            Arg::new("v")
-                .short('v')
-                .action(ArgAction::Count)
-                .help("Sets the level of verbosity"),
-        )
-        .get_matches();
-
-    let verbosity = matches.get_count("v");
-    let log_level = match verbosity {
-        0 => LevelFilter::Error,
-        1 => LevelFilter::Warn,
-        2 => LevelFilter::Info,
-        3 => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
-    };
-
-    env_logger::builder()
-        .format_timestamp_nanos()
-        .filter_level(log_level)
-        .init();
+}
 
+fn run_server(matches: &ArgMatches) {
     let port: u16 = matches.get_one::<String>("port").unwrap().parse().unwrap();
     let data_dir: String = matches.get_one::<String>("data-dir").unwrap().to_string();
     let bind_ip: IpAddr = matches
@@ -143,18 +178,11 @@ fn main() -> Result<(), ErrorCode> {
         .parse()
         .unwrap();
     let bind_address: SocketAddr = (bind_ip, port).into();
-    let server_ip_port: SocketAddr = matches
-        .get_one::<String>("server-ip-port")
-        .unwrap()
-        .parse()
-        .unwrap();
-    let mount_point: String = matches
-        .get_one::<String>("mount-point")
-        .unwrap()
-        .to_string();
-    let direct_io: bool = matches.get_flag("direct-io");
-    let fsck: bool = matches.get_flag("fsck");
-    let get_leader: bool = matches.get_flag("get-leader");
+    let atime_mode = match matches.get_one::<String>("atime").unwrap().as_str() {
+        "noatime" => AtimeMode::NoAtime,
+        "strictatime" => AtimeMode::StrictAtime,
+        _ => AtimeMode::Relatime,
+    };
     let num_peers: usize = matches
         .get_one::<String>("num-peers")
         .unwrap()
@@ -205,52 +233,193 @@ fn main() -> Result<(), ErrorCode> {
             peers.len() + 1
         };
 
-    if fsck {
-        let client = NodeClient::new(server_ip_port);
-        match client.fsck() {
-            Ok(_) => println!("Filesystem is ok"),
-            Err(e) => {
-                match e {
-                    ErrorCode::Corrupted => println!("Filesystem corrupted!"),
-                    _ => println!("Filesystem check failed. Try again."),
+    println!("Starting with peers: {peers:?}");
+    Node::new(
+        &data_dir,
+        bind_address,
+        peers,
+        replicas_per_raft_group,
+        atime_mode,
+    )
+    .run();
+}
+
+fn mount(servers: Vec<SocketAddr>, matches: &ArgMatches) {
+    let mount_point = matches.get_one::<String>("mount-point").unwrap();
+    let direct_io: bool = matches.get_flag("direct-io");
+    let write_back: bool = matches.get_flag("write-back");
+    println!("Connecting to servers {servers:?} and mounting FUSE at {mount_point}");
+    let options = vec![
+        MountOption::FSName("fleetfs".to_string()),
+        MountOption::AutoUnmount,
+    ];
+    if direct_io {
+        println!("Using Direct IO");
+    }
+    if write_back {
+        println!("Using write-back caching");
+    }
+    let allow_other = match fuse_allow_other_enabled() {
+        Ok(enabled) => enabled,
+        Err(_) => {
+            eprintln!("Unable to read /etc/fuse.conf");
+            false
+        }
+    };
+
+    let fs = FleetFUSE::new(servers, direct_io, write_back);
+    let notifier = fs.notifier();
+    let mut config = Config::default();
+    config.mount_options = options;
+    config.acl = if allow_other {
+        SessionACL::All
+    } else {
+        SessionACL::Owner
+    };
+    let session = fuser::spawn_mount2(fs, mount_point, &config).unwrap();
+    notifier.set(session.notifier()).ok();
+    session.join().unwrap();
+}
+
+// Parses a comma separated list of IP:PORT
+fn parse_servers(value: &str) -> Result<Vec<SocketAddr>, String> {
+    value
+        .split(',')
+        .map(|server| {
+            server
+                .trim()
+                .parse()
+                .map_err(|_| format!("{server} is not an IP:PORT"))
+        })
+        .collect()
+}
+
+fn command() -> Command {
+    Command::new("FleetFS")
+        .version(crate_version!())
+        .author("Christopher Berner")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .arg(
+            Arg::new("server-ip-port")
+                .long("server-ip-port")
+                .value_name("IP_PORT")
+                .default_value("127.0.0.1:3000")
+                .value_parser(parse_servers)
+                .global(true)
+                .help("Servers for the client commands to connect to. Comma separated list of IP:PORT. Other nodes in the cluster are discovered from them, and failed over to"),
+        )
+        .arg(
+            Arg::new("v")
+                .short('v')
+                .action(ArgAction::Count)
+                .help("Sets the level of verbosity"),
+        )
+        .subcommand(server_command())
+        .subcommand(mount_command())
+        .subcommand(Command::new("fsck").about("Run a filesystem check on the cluster"))
+        .subcommand(
+            Command::new("get-leader").about("Wait for the cluster to elect its leaders"),
+        )
+        .subcommands(file_tool::subcommands())
+}
+
+fn main() -> Result<(), ErrorCode> {
+    let command = command();
+    let args = upgrade_legacy_arguments(&command, std::env::args().collect());
+    let matches = command.get_matches_from(args);
+
+    let verbosity = matches.get_count("v");
+    let log_level = match verbosity {
+        0 => LevelFilter::Error,
+        1 => LevelFilter::Warn,
+        2 => LevelFilter::Info,
+        3 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    env_logger::builder()
+        .format_timestamp_nanos()
+        .filter_level(log_level)
+        .init();
+
+    let servers = matches
+        .get_one::<Vec<SocketAddr>>("server-ip-port")
+        .unwrap()
+        .clone();
+
+    match matches.subcommand() {
+        Some(("server", matches)) => run_server(matches),
+        Some(("mount", matches)) => mount(servers, matches),
+        Some(("fsck", _)) => {
+            let client = NodeClient::new(servers);
+            match client.fsck() {
+                Ok(_) => println!("Filesystem is ok"),
+                Err(e) => {
+                    match e {
+                        ErrorCode::Corrupted => println!("Filesystem corrupted!"),
+                        _ => println!("Filesystem check failed. Try again."),
+                    }
+                    return Err(e);
                 }
-                return Err(e);
             }
         }
-    } else if get_leader {
-        let client = NodeClient::new(server_ip_port);
-        client.filesystem_ready()?;
-        println!("Filesystem ready");
-    } else if mount_point.is_empty() {
-        println!("Starting with peers: {peers:?}");
-        Node::new(&data_dir, bind_address, peers, replicas_per_raft_group).run();
-    } else {
-        println!("Connecting to server {server_ip_port} and mounting FUSE at {mount_point}");
-        let options = vec![
-            MountOption::FSName("fleetfs".to_string()),
-            MountOption::AutoUnmount,
-        ];
-        if direct_io {
-            println!("Using Direct IO");
+        Some(("get-leader", _)) => {
+            let client = NodeClient::new(servers);
+            client.filesystem_ready()?;
+            println!("Filesystem ready");
         }
-        let allow_other = match fuse_allow_other_enabled() {
-            Ok(enabled) => enabled,
-            Err(_) => {
-                eprintln!("Unable to read /etc/fuse.conf");
-                false
+        Some((command, matches)) => {
+            if let Err(error) = file_tool::run(servers, command, matches) {
+                eprintln!("fleetfs {command}: {error}");
+                exit(1);
             }
-        };
-
-        let fs = FleetFUSE::new(server_ip_port, direct_io);
-        let mut config = Config::default();
-        config.mount_options = options;
-        config.acl = if allow_other {
-            SessionACL::All
-        } else {
-            SessionACL::Owner
-        };
-        fuser::mount2(fs, &mount_point, &config).unwrap();
+        }
+        None => unreachable!("A subcommand is required"),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{command, upgrade_legacy_arguments};
+
+    fn upgrade(args: &str) -> String {
+        let args = args.split_whitespace().map(ToString::to_string).collect();
+        upgrade_legacy_arguments(&command(), args).join(" ")
+    }
+
+    #[test]
+    fn legacy_arguments_upgraded() {
+        assert_eq!(upgrade("fleetfs"), "fleetfs");
+        assert_eq!(upgrade("fleetfs -vv"), "fleetfs -vv server");
+        assert_eq!(
+            upgrade("fleetfs --port 3001 --data-dir ls"),
+            "fleetfs server --port 3001 --data-dir ls"
+        );
+        assert_eq!(
+            upgrade("fleetfs -v --server-ip-port 127.0.0.1:3000 --mount-point /mnt"),
+            "fleetfs -v mount --server-ip-port 127.0.0.1:3000 /mnt"
+        );
+        assert_eq!(
+            upgrade("fleetfs --server-ip-port 127.0.0.1:3000 --fsck"),
+            "fleetfs fsck --server-ip-port 127.0.0.1:3000"
+        );
+        assert_eq!(upgrade("fleetfs --get-leader"), "fleetfs get-leader");
+    }
+
+    #[test]
+    fn current_arguments_unchanged() {
+        for args in [
+            "fleetfs --help",
+            "fleetfs help mount",
+            "fleetfs -v server --data-dir /tmp/fleetfs",
+            "fleetfs --server-ip-port 127.0.0.1:3000 ls -l /",
+            "fleetfs --server-ip-port=127.0.0.1:3000 rm -r /dir",
+            "fleetfs get-leader --server-ip-port 127.0.0.1:3000",
+        ] {
+            assert_eq!(upgrade(args), args);
+        }
+    }
+}
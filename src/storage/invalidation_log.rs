@@ -0,0 +1,163 @@
+use futures::channel::oneshot;
+use futures::channel::oneshot::{Receiver, Sender};
+use rand::Rng;
+use std::collections::{HashSet, VecDeque};
+
+// Number of changes retained for clients which are watching for invalidations. Clients which fall
+// further behind than this have to invalidate everything they cached from the raft group
+const MAX_RETAINED_CHANGES: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Change {
+    // The inode's attributes
+    Attributes(u64),
+    // The inode's data, and its attributes
+    Data(u64),
+    // An entry in the directory, and the directory's attributes
+    Entry(u64, String),
+}
+
+pub enum Changes {
+    // Some changes were dropped or happened in a different epoch, so the client has to invalidate
+    // everything
+    Missed,
+    Changed(HashSet<Change>),
+}
+
+// Changes which were made by committed requests, in the order they were applied. The sequence
+// numbers are the consensus slots that the changes were applied in, so they're the same on every
+// replica. The log isn't persisted, so it's identified by a random epoch, and clients holding a
+// sequence number from a different epoch have to invalidate everything
+pub struct InvalidationLog {
+    epoch: u64,
+    changes: VecDeque<(u64, Change)>,
+    // Changes at or before this sequence number have been dropped
+    truncated: u64,
+    // The latest sequence number that has been applied
+    sequence: u64,
+    waiters: Vec<Sender<()>>,
+}
+
+impl InvalidationLog {
+    pub fn new() -> InvalidationLog {
+        InvalidationLog {
+            // Zero is reserved for clients which haven't seen an epoch yet
+            epoch: rand::rng().random_range(1..u64::MAX),
+            changes: VecDeque::new(),
+            truncated: 0,
+            sequence: 0,
+            waiters: vec![],
+        }
+    }
+
+    // The epoch and latest sequence number, which clients resume watching from
+    pub fn position(&self) -> (u64, u64) {
+        (self.epoch, self.sequence)
+    }
+
+    pub fn record(&mut self, sequence: u64, change: Change) {
+        if self.changes.len() == MAX_RETAINED_CHANGES {
+            let (dropped, _) = self.changes.pop_front().unwrap();
+            self.truncated = dropped;
+        }
+        self.changes.push_back((sequence, change));
+    }
+
+    // Called after all the changes in the sequence number have been recorded
+    pub fn advance(&mut self, sequence: u64) {
+        self.sequence = sequence;
+        if self
+            .changes
+            .back()
+            .is_some_and(|(last, _)| *last == sequence)
+        {
+            for waiter in self.waiters.drain(..) {
+                // Ignore errors, since the client may have disconnected
+                waiter.send(()).ok();
+            }
+        }
+    }
+
+    // Returns the changes after the given sequence number, or None if there aren't any yet
+    pub fn changes_since(&self, epoch: u64, sequence: u64) -> Option<Changes> {
+        if epoch != self.epoch || sequence < self.truncated || sequence > self.sequence {
+            return Some(Changes::Missed);
+        }
+
+        let mut changes = HashSet::new();
+        for (change_sequence, change) in self.changes.iter().rev() {
+            if *change_sequence <= sequence {
+                break;
+            }
+            changes.insert(change.clone());
+        }
+        if changes.is_empty() {
+            None
+        } else {
+            Some(Changes::Changed(changes))
+        }
+    }
+
+    // Resolves once new changes are recorded
+    pub fn wait(&mut self) -> Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.waiters.push(sender);
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::invalidation_log::{
+        Change, Changes, InvalidationLog, MAX_RETAINED_CHANGES,
+    };
+    use std::collections::HashSet;
+
+    fn changed(changes: Option<Changes>) -> HashSet<Change> {
+        match changes.unwrap() {
+            Changes::Changed(changes) => changes,
+            Changes::Missed => panic!("Changes were missed"),
+        }
+    }
+
+    #[test]
+    fn changes_since() {
+        let mut log = InvalidationLog::new();
+        // A new client is told to invalidate everything, and then resumes from the current position
+        assert!(matches!(log.changes_since(0, 0), Some(Changes::Missed)));
+        let (epoch, sequence) = log.position();
+        assert!(log.changes_since(epoch, sequence).is_none());
+
+        log.record(1, Change::Attributes(5));
+        log.record(1, Change::Entry(6, "a".to_string()));
+        log.advance(1);
+        log.advance(2);
+        log.record(3, Change::Data(5));
+        log.advance(3);
+        assert_eq!(log.position(), (epoch, 3));
+        assert_eq!(
+            changed(log.changes_since(epoch, 0)),
+            HashSet::from([
+                Change::Attributes(5),
+                Change::Entry(6, "a".to_string()),
+                Change::Data(5)
+            ])
+        );
+        assert_eq!(
+            changed(log.changes_since(epoch, 2)),
+            HashSet::from([Change::Data(5)])
+        );
+        assert!(log.changes_since(epoch, 3).is_none());
+
+        for i in 0..MAX_RETAINED_CHANGES as u64 {
+            log.record(4 + i, Change::Attributes(7));
+            log.advance(4 + i);
+        }
+        // The change at sequence 3 was dropped
+        assert!(matches!(log.changes_since(epoch, 2), Some(Changes::Missed)));
+        assert!(matches!(
+            log.changes_since(epoch, 3),
+            Some(Changes::Changed(_))
+        ));
+    }
+}
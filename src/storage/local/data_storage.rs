@@ -300,8 +300,8 @@ impl<T: PeerClient> DataStorage<T> {
 
 #[cfg(test)]
 mod tests {
-    use crate::ErrorCode;
     use crate::base::CommitId;
+    use crate::base::ErrorCode;
     use crate::client::PeerClient;
     use crate::storage::local::data_storage::{
         BLOCK_SIZE, DataStorage, stores_index, to_global_index, to_local_index_ceiling,
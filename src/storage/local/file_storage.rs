@@ -3,14 +3,16 @@ use std::fs;
 
 use crate::base::node_id_from_address;
 use crate::base::{
-    CommitId, EntryMetadata, ErrorCode, FileKind, OwnedDirectoryEntry, Response, Timestamp,
-    UserContext,
+    CommitId, EntryMetadata, ErrorCode, FileKind, OpenHandleId, OwnedDirectoryEntry, PosixAcl,
+    RangeLock, Response, Timestamp, UserContext, pack_inodes,
 };
 use crate::client::TcpPeerClient;
 use crate::storage::ROOT_INODE;
 use crate::storage::local::data_storage::{BLOCK_SIZE, DataStorage};
 use crate::storage::local::error_helper::into_error_code;
-use crate::storage::local::metadata_storage::{InodeAttributes, MAX_NAME_LENGTH, MetadataStorage};
+use crate::storage::local::metadata_storage::{
+    EntryError, InodeAttributes, MAX_NAME_LENGTH, MetadataStorage, RequestState, stores_data,
+};
 use futures::Future;
 use futures::FutureExt;
 use std::net::SocketAddr;
@@ -32,9 +34,12 @@ fn build_fileattr_response(
         hard_links: attributes.hardlinks,
         user_id: attributes.uid,
         group_id: attributes.gid,
-        device_id: 0,
+        device_id: attributes.rdev,
         block_size: BLOCK_SIZE as u32,
         directory_entries,
+        creation_time: attributes.created,
+        flags: attributes.flags,
+        unique_id: attributes.unique_id,
     }
 }
 
@@ -42,9 +47,21 @@ fn to_fileattr_response(attributes: InodeAttributes, directory_entries: Option<u
     Response::EntryMetadata(build_fileattr_response(attributes, directory_entries))
 }
 
+// The sharding errors are returned as responses, so that they're never mistaken for a failure of
+// the request by anything other than the transaction coordinator
+fn to_entry_response(result: Result<Response, EntryError>) -> Result<Response, ErrorCode> {
+    match result {
+        Ok(response) => Ok(response),
+        Err(EntryError::Failed(error_code)) => Err(error_code),
+        Err(EntryError::Sharded) => Ok(Response::DirectorySharded),
+        Err(EntryError::NeedsSharding) => Ok(Response::DirectoryNeedsSharding),
+    }
+}
+
 pub struct FileStorage {
     data_storage: DataStorage<TcpPeerClient>,
     metadata_storage: MetadataStorage,
+    num_raft_groups: u16,
 }
 
 impl FileStorage {
@@ -68,13 +85,14 @@ impl FileStorage {
         FileStorage {
             data_storage: DataStorage::new(node_id, data_dir.to_str().unwrap(), peer_clients),
             metadata_storage: MetadataStorage::new(raft_group, num_raft_groups, &metadata_dir),
+            num_raft_groups,
         }
     }
 
     pub fn local_data_checksum(&self) -> Result<Vec<u8>, ErrorCode> {
         // TODO: this only checks the integrity of metadata & plain files. Directories are purely
         // stored in the metadata_storage
-        for inode in self.metadata_storage.non_directory_inodes()? {
+        for inode in self.metadata_storage.data_inodes()? {
             if !self.data_storage.file_inode_exists(inode) {
                 return Err(ErrorCode::Corrupted);
             }
@@ -85,10 +103,12 @@ impl FileStorage {
             .map_err(|_| ErrorCode::Uncategorized)
     }
 
-    pub fn statfs(&self) -> Response {
+    pub fn statfs(&self, peers: &[SocketAddr]) -> Response {
         Response::FilesystemInformation {
             block_size: BLOCK_SIZE as u32,
             max_name_length: MAX_NAME_LENGTH,
+            raft_groups: self.num_raft_groups,
+            peers: peers.iter().map(ToString::to_string).collect(),
         }
     }
 
@@ -96,40 +116,57 @@ impl FileStorage {
         &self,
         parent: u64,
         name: &str,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
-        let maybe_inode = self.metadata_storage.lookup(parent, name, context)?;
-
-        if let Some(inode) = maybe_inode {
-            Ok(Response::Inode { id: inode })
-        } else {
-            Err(ErrorCode::DoesNotExist)
-        }
+        to_entry_response(match self.metadata_storage.lookup(parent, name, context) {
+            Ok(Some(inode)) => Ok(Response::Inode { id: inode }),
+            Ok(None) => Err(ErrorCode::DoesNotExist.into()),
+            Err(error) => Err(error),
+        })
     }
 
     pub fn truncate(
         &self,
         inode: u64,
         new_length: u64,
-        context: UserContext,
+        has_write_handle: bool,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
-        self.metadata_storage.truncate(inode, new_length, context)?;
+        self.metadata_storage
+            .truncate(inode, new_length, has_write_handle, context)?;
         self.data_storage.truncate(inode, new_length).unwrap();
 
         Ok(Response::Empty)
     }
 
-    pub fn readdir(&self, inode: u64) -> Result<Response, ErrorCode> {
+    pub fn readdir(
+        &self,
+        inode: u64,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<Response, ErrorCode> {
         let mut entries = vec![];
-        for (inode, filename, file_type) in self.metadata_storage.readdir(inode)? {
+        let (listing, resume_after) = self.metadata_storage.readdir(inode, after, limit)?;
+        for (inode, filename, file_type) in listing {
             entries.push(OwnedDirectoryEntry {
                 inode,
                 name: filename,
                 kind: file_type,
+                attributes: None,
             });
         }
 
-        Ok(Response::DirectoryListing(entries))
+        Ok(Response::DirectoryListing(entries, resume_after))
+    }
+
+    pub fn access(
+        &self,
+        inode: u64,
+        access_mask: i32,
+        context: UserContext<'_>,
+    ) -> Result<Response, ErrorCode> {
+        self.metadata_storage.access(inode, access_mask, context)?;
+        Ok(Response::Empty)
     }
 
     pub fn getattr(&self, inode: u64) -> Result<Response, ErrorCode> {
@@ -142,7 +179,7 @@ impl FileStorage {
         inode: u64,
         atime: Option<Timestamp>,
         mtime: Option<Timestamp>,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
         assert_ne!(inode, ROOT_INODE);
         self.metadata_storage
@@ -154,7 +191,7 @@ impl FileStorage {
         &self,
         inode: u64,
         mode: u32,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
         assert_ne!(inode, ROOT_INODE);
         self.metadata_storage.chmod(inode, mode, context)?;
@@ -166,13 +203,23 @@ impl FileStorage {
         inode: u64,
         uid: Option<u32>,
         gid: Option<u32>,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
         assert_ne!(inode, ROOT_INODE);
         self.metadata_storage.chown(inode, uid, gid, context)?;
         Ok(Response::Empty)
     }
 
+    pub fn set_flags(
+        &self,
+        inode: u64,
+        flags: u32,
+        context: UserContext<'_>,
+    ) -> Result<Response, ErrorCode> {
+        self.metadata_storage.set_flags(inode, flags, context)?;
+        Ok(Response::Empty)
+    }
+
     pub fn fsync(&self, inode: u64) -> Result<Response, ErrorCode> {
         self.data_storage.fsync(inode)?;
         Ok(Response::Empty)
@@ -200,15 +247,24 @@ impl FileStorage {
         Ok(Response::Read { data })
     }
 
-    pub fn write(&self, inode: u64, offset: u64, data: &[u8]) -> Result<Response, ErrorCode> {
-        self.metadata_storage
-            .write(inode, offset, data.len() as u32)?;
+    pub fn write(
+        &self,
+        inode: u64,
+        offset: u64,
+        data: &[u8],
+        uid: u32,
+        append: bool,
+    ) -> Result<Response, ErrorCode> {
+        let offset = self
+            .metadata_storage
+            .write(inode, offset, data.len() as u32, uid, append)?;
         let write_result = self.data_storage.write_local_blocks(inode, offset, data);
         // Reply with the total requested write size, since that's what the FUSE client is expecting, even though this node only wrote some of the bytes
         let total_bytes = data.len() as u32;
         write_result
             .map(move |_| Response::Written {
                 bytes_written: total_bytes,
+                offset,
             })
             .map_err(into_error_code)
     }
@@ -230,12 +286,14 @@ impl FileStorage {
         inode: u64,
         new_parent: u64,
         new_name: &str,
-        context: UserContext,
+        context: UserContext<'_>,
         inode_kind: FileKind,
     ) -> Result<Response, ErrorCode> {
-        self.metadata_storage
-            .create_link(inode, new_parent, new_name, context, inode_kind)?;
-        Ok(Response::Empty)
+        to_entry_response(
+            self.metadata_storage
+                .create_link(inode, new_parent, new_name, context, inode_kind)
+                .map(|_| Response::Empty),
+        )
     }
 
     pub fn replace_link(
@@ -244,12 +302,18 @@ impl FileStorage {
         name: &str,
         new_inode: u64,
         kind: FileKind,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
-        let old_inode = self
-            .metadata_storage
-            .replace_link(parent, name, new_inode, kind, context)?;
-        Ok(Response::Inode { id: old_inode })
+        to_entry_response(
+            self.metadata_storage
+                .replace_link(parent, name, new_inode, kind, context)
+                .map(|old_inode| Response::Inode { id: old_inode }),
+        )
+    }
+
+    // Sets the time at which the following requests are committed
+    pub fn set_commit_time(&self, timestamp: Timestamp) {
+        self.metadata_storage.set_commit_time(timestamp);
     }
 
     pub fn update_parent(&self, inode: u64, new_parent: u64) -> Result<Response, ErrorCode> {
@@ -257,8 +321,38 @@ impl FileStorage {
         Ok(Response::Empty)
     }
 
-    pub fn update_metadata_changed_time(&self, inode: u64) -> Result<Response, ErrorCode> {
-        self.metadata_storage.update_metadata_changed_time(inode)?;
+    pub fn update_metadata_changed_time(
+        &self,
+        inode: u64,
+        modified: bool,
+    ) -> Result<Response, ErrorCode> {
+        self.metadata_storage
+            .update_metadata_changed_time(inode, modified)?;
+        Ok(Response::Empty)
+    }
+
+    pub fn access_time_stale(&self, inode: u64) -> Result<bool, ErrorCode> {
+        self.metadata_storage.access_time_stale(inode)
+    }
+
+    pub fn update_access_times(&self, inodes: &[u64], strict: bool) -> Result<Response, ErrorCode> {
+        self.metadata_storage.update_access_times(inodes, strict)?;
+        Ok(Response::Empty)
+    }
+
+    pub fn directory_shards(&self, inode: u64) -> Result<Response, ErrorCode> {
+        let shards = self.metadata_storage.directory_shards(inode)?;
+        Ok(Response::DirectoryShards {
+            shards: pack_inodes(&shards),
+        })
+    }
+
+    pub fn shard_parent(&self, inode: u64) -> Result<Option<u64>, ErrorCode> {
+        self.metadata_storage.shard_parent(inode)
+    }
+
+    pub fn shard_directory(&self, inode: u64, shards: &[u64]) -> Result<Response, ErrorCode> {
+        self.metadata_storage.shard_directory(inode, shards)?;
         Ok(Response::Empty)
     }
 
@@ -276,7 +370,7 @@ impl FileStorage {
         &self,
         inode: u64,
         key: &str,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
         let attr = self.metadata_storage.get_xattr(inode, key, context)?;
         Ok(Response::Read { data: attr })
@@ -293,7 +387,7 @@ impl FileStorage {
         inode: u64,
         key: &str,
         value: &[u8],
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
         self.metadata_storage
             .set_xattr(inode, key, value, context)?;
@@ -304,7 +398,7 @@ impl FileStorage {
         &self,
         inode: u64,
         key: &str,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
         self.metadata_storage.remove_xattr(inode, key, context)?;
         Ok(Response::Empty)
@@ -315,16 +409,17 @@ impl FileStorage {
         parent: u64,
         name: &str,
         link_inode_and_uid: Option<(u64, u32)>,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Response, ErrorCode> {
         info!("Deleting file");
-        let (deleted_inode, processed) =
+        to_entry_response(
             self.metadata_storage
-                .remove_link(parent, name, link_inode_and_uid, context)?;
-        Ok(Response::RemovedInode {
-            id: deleted_inode,
-            complete: processed,
-        })
+                .remove_link(parent, name, link_inode_and_uid, context)
+                .map(|(deleted_inode, processed)| Response::RemovedInode {
+                    id: deleted_inode,
+                    complete: processed,
+                }),
+        )
     }
 
     pub fn decrement_inode_link_count(
@@ -342,6 +437,98 @@ impl FileStorage {
         Ok(Response::Empty)
     }
 
+    pub fn open_handle(
+        &self,
+        inode: u64,
+        session: u64,
+        handle: u64,
+    ) -> Result<Response, ErrorCode> {
+        self.metadata_storage.open_handle(inode, session, handle)?;
+        Ok(Response::Empty)
+    }
+
+    pub fn has_open_handle(&self, inode: u64, session: u64) -> Result<bool, ErrorCode> {
+        self.metadata_storage.has_open_handle(inode, session)
+    }
+
+    pub fn release_handle(
+        &self,
+        inode: u64,
+        session: u64,
+        handle: u64,
+    ) -> Result<Response, ErrorCode> {
+        if let Some(deleted_inode) = self
+            .metadata_storage
+            .release_handle(inode, session, handle)?
+        {
+            self.data_storage.delete(deleted_inode).unwrap();
+        }
+
+        Ok(Response::Empty)
+    }
+
+    pub fn release_session(&self, session: u64) -> Result<Response, ErrorCode> {
+        for deleted_inode in self.metadata_storage.release_session(session)? {
+            self.data_storage.delete(deleted_inode).unwrap();
+        }
+
+        Ok(Response::Empty)
+    }
+
+    pub fn renew_session(&self, session: u64) -> Result<Response, ErrorCode> {
+        self.metadata_storage.renew_session(session)?;
+        Ok(Response::Empty)
+    }
+
+    pub fn expired_sessions(&self) -> Result<Vec<u64>, ErrorCode> {
+        self.metadata_storage.expired_sessions()
+    }
+
+    pub fn store_range_locks(
+        &self,
+        inode: u64,
+        held: &[RangeLock],
+        waiting: &[RangeLock],
+    ) -> Result<(), ErrorCode> {
+        self.metadata_storage
+            .store_range_locks(inode, held, waiting)
+    }
+
+    pub fn range_locks(&self) -> Result<Vec<(u64, RangeLock, bool)>, ErrorCode> {
+        self.metadata_storage.range_locks()
+    }
+
+    // Returns the result of the request if it was already applied, or Empty if it should be applied
+    // now by the coordinator owner and then finished with finish_request()
+    pub fn begin_request(
+        &self,
+        session: u64,
+        sequence: u64,
+        acknowledged: u64,
+        owner: u64,
+    ) -> Result<Response, ErrorCode> {
+        match self
+            .metadata_storage
+            .begin_request(session, sequence, acknowledged, owner)?
+        {
+            RequestState::New => Ok(Response::Empty),
+            RequestState::InProgress => Err(ErrorCode::RequestInProgress),
+            RequestState::Finished(result) => Ok(Response::RequestResult { result }),
+        }
+    }
+
+    pub fn finish_request(
+        &self,
+        session: u64,
+        sequence: u64,
+        owner: u64,
+        result: &[u8],
+    ) -> Result<Response, ErrorCode> {
+        self.metadata_storage
+            .finish_request(session, sequence, owner, result)?;
+        Ok(Response::Empty)
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn create_inode(
         &self,
@@ -350,12 +537,24 @@ impl FileStorage {
         gid: u32,
         mode: u16,
         kind: FileKind,
+        rdev: u32,
+        open_handle: Option<OpenHandleId>,
+        default_acl: Option<PosixAcl>,
+        shard_of: Option<u64>,
     ) -> Result<Response, ErrorCode> {
-        let (_, attributes) = self
-            .metadata_storage
-            .create_inode(parent, uid, gid, mode, kind)?;
-
-        if kind != FileKind::Directory {
+        let (_, attributes) = self.metadata_storage.create_inode(
+            parent,
+            uid,
+            gid,
+            mode,
+            kind,
+            rdev,
+            open_handle,
+            default_acl,
+            shard_of,
+        )?;
+
+        if stores_data(kind) {
             self.data_storage.truncate(attributes.inode, 0).unwrap();
         }
 
@@ -368,3 +567,100 @@ impl FileStorage {
         Ok(to_fileattr_response(attributes, directory_entries))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{FileKind, Response, SESSION_LEASE_SECONDS, Timestamp, UserContext};
+    use crate::storage::ROOT_INODE;
+    use crate::storage::local::file_storage::FileStorage;
+    use tempfile::tempdir;
+
+    // Creates a file in the root directory, which is open on handle 1 of the session
+    fn create_open_file(storage: &FileStorage, name: &str, session: u64) -> u64 {
+        let context = UserContext::new(0, 0);
+        let Ok(Response::EntryMetadata(attrs)) =
+            storage.create_inode(ROOT_INODE, 0, 0, 0o644, FileKind::File, 0, None, None, None)
+        else {
+            panic!("Failed to create inode");
+        };
+        storage
+            .create_link(attrs.inode, ROOT_INODE, name, context, FileKind::File)
+            .unwrap();
+        storage.write(attrs.inode, 0, b"data", 0, false).unwrap();
+        storage.open_handle(attrs.inode, session, 1).unwrap();
+
+        attrs.inode
+    }
+
+    fn unlink(storage: &FileStorage, name: &str, inode: u64) {
+        let context = UserContext::new(0, 0);
+        storage
+            .remove_link(ROOT_INODE, name, None, context)
+            .unwrap();
+        storage.decrement_inode_link_count(inode, 1).unwrap();
+    }
+
+    fn read(storage: &FileStorage, inode: u64) -> Option<Vec<u8>> {
+        match storage.read_raw(inode, 0, 10) {
+            Ok(Response::Read { data }) => Some(data),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn unlinked_file_readable_until_released() {
+        let dir = tempdir().unwrap();
+        let storage = FileStorage::new(1, 0, 1, dir.path(), &[]);
+        let inode = create_open_file(&storage, "file", 7);
+
+        unlink(&storage, "file", inode);
+        assert!(storage.getattr(inode).is_ok());
+        assert_eq!(read(&storage, inode), Some(b"data".to_vec()));
+
+        storage.release_handle(inode, 7, 1).unwrap();
+        assert!(storage.getattr(inode).is_err());
+        assert_eq!(read(&storage, inode), None);
+    }
+
+    #[test]
+    fn unlinked_file_deleted_when_session_expires() {
+        let dir = tempdir().unwrap();
+        let storage = FileStorage::new(1, 0, 1, dir.path(), &[]);
+        storage.set_commit_time(Timestamp::new(1000, 0));
+        let inode = create_open_file(&storage, "file", 7);
+        unlink(&storage, "file", inode);
+
+        storage.set_commit_time(Timestamp::new(1001 + SESSION_LEASE_SECONDS, 0));
+        assert_eq!(storage.expired_sessions().unwrap(), vec![7]);
+        storage.release_session(7).unwrap();
+        assert!(storage.getattr(inode).is_err());
+        assert_eq!(read(&storage, inode), None);
+    }
+
+    #[test]
+    fn special_files_have_no_data() {
+        let dir = tempdir().unwrap();
+        let storage = FileStorage::new(1, 0, 1, dir.path(), &[]);
+        let context = UserContext::new(0, 0);
+        for (name, kind, rdev) in [
+            ("fifo", FileKind::NamedPipe, 0),
+            ("socket", FileKind::Socket, 0),
+            ("device", FileKind::CharDevice, 0x0103),
+        ] {
+            let Ok(Response::EntryMetadata(attrs)) =
+                storage.create_inode(ROOT_INODE, 0, 0, 0o644, kind, rdev, None, None, None)
+            else {
+                panic!("Failed to create inode");
+            };
+            assert_eq!(attrs.device_id, rdev);
+            storage
+                .create_link(attrs.inode, ROOT_INODE, name, context, kind)
+                .unwrap();
+            assert!(!storage.data_storage.file_inode_exists(attrs.inode));
+            assert!(storage.local_data_checksum().is_ok());
+
+            unlink(&storage, name, attrs.inode);
+            assert!(storage.getattr(attrs.inode).is_err());
+        }
+    }
+}
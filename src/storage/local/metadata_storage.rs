@@ -1,23 +1,61 @@
 use std::cmp::max;
 use std::collections::HashMap;
+use std::ops::Bound;
 use std::path::Path;
 use std::sync::Mutex;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::base::check_access;
-use crate::base::{ErrorCode, FileKind, Timestamp, UserContext};
+use crate::base::{
+    ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR, DirectoryPage, ErrorCode, FS_APPEND_FL, FS_IMMUTABLE_FL,
+    FileKind, OpenHandleId, PosixAcl, RangeLock, RangeLockKind, SESSION_LEASE_SECONDS, Timestamp,
+    UserContext,
+};
 use crate::storage::local::data_storage::BLOCK_SIZE;
 use fuser::INodeNo;
-use redb::{Durability, ReadOnlyTable, ReadableDatabase, ReadableTable, TableDefinition};
+use redb::{Durability, ReadOnlyTable, ReadableDatabase, ReadableTable, Table, TableDefinition};
 use redb_derive::Value;
-use std::time::SystemTime;
 
 pub const ROOT_INODE: u64 = INodeNo::ROOT.0;
 pub const MAX_NAME_LENGTH: u32 = 255;
 pub const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024 * 1024;
+// Upper bound on the entries returned by one readdir() call, to keep responses small
+pub const MAX_LISTING_ENTRIES: u32 = 4096;
+// Directories are split into shards once they have this many entries. Tests use a lower
+// threshold, so that they can split directories quickly
+#[cfg(not(test))]
+const DIRECTORY_SHARD_THRESHOLD: u64 = 16 * 1024;
+#[cfg(test)]
+pub const DIRECTORY_SHARD_THRESHOLD: u64 = 64;
 
 type Inode = u64;
 
+pub enum RequestState {
+    // The request hasn't been seen before, and has now been begun
+    New,
+    // The request was begun, but hasn't finished
+    InProgress,
+    // The request was already applied, with the given encoded result
+    Finished(Vec<u8>),
+}
+
+// Errors from operations on directory entries. The sharding errors route the operation to the
+// directory's shards, and are only returned to transaction coordinators
+#[derive(Debug, PartialEq, Eq)]
+pub enum EntryError {
+    Failed(ErrorCode),
+    // The entry is stored in one of the directory's shards, rather than the directory itself
+    Sharded,
+    // The directory has too many entries, and must be split into shards before more are added
+    NeedsSharding,
+}
+
+impl From<ErrorCode> for EntryError {
+    fn from(error_code: ErrorCode) -> Self {
+        EntryError::Failed(error_code)
+    }
+}
+
 // Stores mapping of directory inodes to their parent
 const PARENTS_TABLE: TableDefinition<u64, u64> = TableDefinition::new("parents");
 
@@ -28,12 +66,83 @@ const PARENTS_TABLE: TableDefinition<u64, u64> = TableDefinition::new("parents")
 const DIRECTORY_TABLE: TableDefinition<(Inode, &str), (Inode, FileKind)> =
     TableDefinition::new("directory");
 
+// Number of entries in each directory's DIRECTORY_TABLE, not counting entries in its shards
+const DIRECTORY_SIZES_TABLE: TableDefinition<Inode, u64> = TableDefinition::new("directory_sizes");
+
+// Maps (directory, shard index) to the shard inode, for directories which have been split into
+// shards. New entries are stored in the shard that their name hashes to, so that large directories
+// are spread across raft groups. Entries created before the split stay in the directory itself
+const DIRECTORY_SHARDS_TABLE: TableDefinition<(Inode, u16), Inode> =
+    TableDefinition::new("directory_shards");
+
+// Maps shard inodes to the directory they're a shard of
+const SHARD_PARENTS_TABLE: TableDefinition<Inode, Inode> = TableDefinition::new("shard_parents");
+
 // Maps the inode & xattr key to an xattr value
 const XATTR_TABLE: TableDefinition<(Inode, &str), &[u8]> = TableDefinition::new("xattrs");
 
+// Open file handles, as (inode, client session, file handle). An inode which has been unlinked is kept
+// until all of its handles are released
+const OPEN_HANDLES_TABLE: TableDefinition<(Inode, u64, u64), ()> =
+    TableDefinition::new("open_handles");
+
+// Maps client sessions to the time their lease was last renewed. Sessions which hold advisory locks
+// or open handles are added when they first do, so that they expire even if they're never renewed
+const SESSIONS_TABLE: TableDefinition<u64, Timestamp> = TableDefinition::new("sessions");
+
+// Advisory locks on each inode, as (inode, index). The held locks come first, followed by the
+// waiting lock requests in the order that they're waiting in
+const RANGE_LOCKS_TABLE: TableDefinition<(Inode, u32), StoredRangeLock> =
+    TableDefinition::new("range_locks");
+
+// Maps inodes to their POSIX access ACL. Inodes whose ACL is fully described by their mode bits
+// don't have an entry
+const ACCESS_ACL_TABLE: TableDefinition<Inode, PosixAcl> = TableDefinition::new("access_acls");
+
+// Maps directories to the default ACL, which is inherited by inodes created in them
+const DEFAULT_ACL_TABLE: TableDefinition<Inode, PosixAcl> = TableDefinition::new("default_acls");
+
 // Maps the inode & xattr key to an xattr value
 const ATTR_TABLE: TableDefinition<Inode, InodeAttributes> = TableDefinition::new("attrs");
 
+// The attribute table, as stored by versions before device numbers, birth times, flags and unique
+// ids were added
+const LEGACY_ATTR_TABLE: TableDefinition<Inode, legacy::InodeAttributes> =
+    TableDefinition::new("attrs");
+
+// Stores the number of unique ids which have been assigned to inodes in this raft group
+const UNIQUE_IDS_TABLE: TableDefinition<(), u64> = TableDefinition::new("unique_ids");
+
+// When the record of a request expires, in seconds, the coordinator that's applying it, and its
+// encoded result once it finished. Until then, the record expires when the coordinator's lease on
+// it does
+type RequestRecord = (i64, u64, Option<&'static [u8]>);
+
+// Records of requests that clients may send again, by (client session, sequence number)
+const REQUEST_RECORDS_TABLE: TableDefinition<(u64, u64), RequestRecord> =
+    TableDefinition::new("request_records");
+
+// Index of the request records by when they expire, so that expired ones can be found
+const REQUEST_EXPIRY_TABLE: TableDefinition<(i64, u64, u64), ()> =
+    TableDefinition::new("request_expiry");
+
+// How long the results of requests are kept for, if the client doesn't acknowledge them sooner.
+// Requests sent again after this are applied again
+const REQUEST_RESULT_RETENTION_SECONDS: i64 = 10 * 60;
+
+// How long a coordinator has to finish a request it began. After this, a retry of the request
+// takes it over, in case the coordinator failed. Shorter than the time that clients retry requests
+// which are in progress for
+const REQUEST_LEASE_SECONDS: i64 = 30;
+
+// Owner of the requests which are begun and finished in the same commit, so are never taken over.
+// Transaction coordinators use other owners
+pub const LOCAL_REQUEST_OWNER: u64 = 0;
+
+// Most expired request records that are dropped by each request that's begun, so that beginning a
+// request doesn't stall on a backlog of them
+const EXPIRED_REQUESTS_PER_BEGIN: usize = 16;
+
 #[derive(Clone, Debug, Value)]
 pub struct InodeAttributes {
     pub inode: Inode,
@@ -47,6 +156,46 @@ pub struct InodeAttributes {
     pub hardlinks: u32,
     pub uid: u32,
     pub gid: u32,
+    // Device number, for character and block devices
+    pub rdev: u32,
+    // Birth time
+    pub created: Timestamp,
+    // Inode flags, such as FS_IMMUTABLE_FL
+    pub flags: u32,
+    // Unlike inode numbers, this is never reused by another inode
+    pub unique_id: u64,
+}
+
+#[derive(Clone, Debug, Value)]
+struct StoredRangeLock {
+    session: u64,
+    owner: u64,
+    pid: u32,
+    start: u64,
+    end: u64,
+    // Otherwise a read lock. Unlocks aren't stored
+    write: bool,
+    // The lock hasn't been granted yet
+    waiting: bool,
+}
+
+mod legacy {
+    use crate::base::{FileKind, Timestamp};
+    use redb_derive::Value;
+
+    #[derive(Clone, Debug, Value)]
+    pub struct InodeAttributes {
+        pub inode: u64,
+        pub size: u64,
+        pub last_accessed: Timestamp,
+        pub last_modified: Timestamp,
+        pub last_metadata_changed: Timestamp,
+        pub kind: FileKind,
+        pub mode: u16,
+        pub hardlinks: u32,
+        pub uid: u32,
+        pub gid: u32,
+    }
 }
 
 impl InodeAttributes {
@@ -83,6 +232,7 @@ fn xattr_access_check(
     key: &str,
     access_mask: i32,
     inode_attrs: &InodeAttributes,
+    acl: Option<&PosixAcl>,
     context: &UserContext,
 ) -> Result<(), ErrorCode> {
     match parse_xattr_namespace(key)? {
@@ -97,15 +247,12 @@ fn xattr_access_check(
             }
         }
         XattrNamespace::SYSTEM => match key {
-            "system.posix_acl_access" => {
-                if !check_access(
-                    inode_attrs.uid,
-                    inode_attrs.gid,
-                    inode_attrs.mode,
-                    context.uid(),
-                    context.gid(),
-                    access_mask,
-                ) {
+            ACL_ACCESS_XATTR | ACL_DEFAULT_XATTR => {
+                // Anyone can read an ACL, but only the owner can change it
+                if access_mask != libc::R_OK
+                    && context.uid() != 0
+                    && context.uid() != inode_attrs.uid
+                {
                     return Err(ErrorCode::OperationNotPermitted);
                 }
             }
@@ -120,8 +267,8 @@ fn xattr_access_check(
                 inode_attrs.uid,
                 inode_attrs.gid,
                 inode_attrs.mode,
-                context.uid(),
-                context.gid(),
+                acl,
+                context,
                 access_mask,
             ) {
                 return Err(ErrorCode::OperationNotPermitted);
@@ -140,31 +287,52 @@ pub struct MetadataStorage {
     // which means that all nodes have the same value for this counter
     next_inode: AtomicU64,
     durability_counter: AtomicU64,
+    raft_group: u64,
     num_raft_groups: u64,
+    // Timestamp of the request being committed, which was chosen when it was proposed so that
+    // all replicas record the same times
+    commit_time: Mutex<Timestamp>,
 }
 
 impl MetadataStorage {
     #[allow(clippy::new_without_default)]
     pub fn new(raft_group: u16, num_raft_groups: u16, metadata_dir: &Path) -> MetadataStorage {
         let db = redb::Database::create(metadata_dir.join("metadata.redb")).unwrap();
+        migrate_legacy_attributes(&db, raft_group as u64, num_raft_groups as u64);
+        migrate_directory_sizes(&db);
         let txn = db.begin_write().unwrap();
         {
             let mut table = txn.open_table(PARENTS_TABLE).unwrap();
             table.insert(&ROOT_INODE, &ROOT_INODE).unwrap();
             txn.open_table(DIRECTORY_TABLE).unwrap();
+            txn.open_table(DIRECTORY_SIZES_TABLE).unwrap();
             txn.open_table(XATTR_TABLE).unwrap();
+            txn.open_table(OPEN_HANDLES_TABLE).unwrap();
+            txn.open_table(SESSIONS_TABLE).unwrap();
+            txn.open_table(RANGE_LOCKS_TABLE).unwrap();
+            txn.open_table(ACCESS_ACL_TABLE).unwrap();
+            txn.open_table(DEFAULT_ACL_TABLE).unwrap();
+            txn.open_table(DIRECTORY_SHARDS_TABLE).unwrap();
+            txn.open_table(SHARD_PARENTS_TABLE).unwrap();
+            txn.open_table(UNIQUE_IDS_TABLE).unwrap();
+            txn.open_table(REQUEST_RECORDS_TABLE).unwrap();
+            txn.open_table(REQUEST_EXPIRY_TABLE).unwrap();
             let mut table = txn.open_table(ATTR_TABLE).unwrap();
             let attrs = InodeAttributes {
                 inode: ROOT_INODE,
                 size: 0,
-                last_accessed: now(),
-                last_modified: now(),
-                last_metadata_changed: now(),
+                last_accessed: Timestamp::now(),
+                last_modified: Timestamp::now(),
+                last_metadata_changed: Timestamp::now(),
                 kind: FileKind::Directory,
                 mode: 0o777,
                 hardlinks: 2,
                 uid: 0,
                 gid: 0,
+                rdev: 0,
+                created: Timestamp::now(),
+                flags: 0,
+                unique_id: 0,
             };
             table.insert(&ROOT_INODE, attrs).unwrap();
         }
@@ -183,7 +351,9 @@ impl MetadataStorage {
             storage: Mutex::new(db),
             next_inode: AtomicU64::new(start_inode),
             durability_counter: AtomicU64::new(0),
+            raft_group: raft_group as u64,
             num_raft_groups: num_raft_groups as u64,
+            commit_time: Mutex::new(Timestamp::now()),
         }
     }
 
@@ -192,14 +362,15 @@ impl MetadataStorage {
         count.is_multiple_of(100)
     }
 
-    pub(super) fn non_directory_inodes(&self) -> Result<Vec<u64>, ErrorCode> {
+    // Returns the inodes which have a data file
+    pub(super) fn data_inodes(&self) -> Result<Vec<u64>, ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let txn = db.begin_read().unwrap();
         let table = txn.open_table(ATTR_TABLE).unwrap();
         let mut result = vec![];
         for item in table.iter().unwrap() {
             let (inode, attrs) = item.unwrap();
-            if attrs.value().kind != FileKind::Directory {
+            if stores_data(attrs.value().kind) {
                 result.push(inode.value());
             }
         }
@@ -211,10 +382,10 @@ impl MetadataStorage {
         &self,
         parent: Inode,
         name: &str,
-        context: UserContext,
-    ) -> Result<Option<Inode>, ErrorCode> {
+        context: UserContext<'_>,
+    ) -> Result<Option<Inode>, EntryError> {
         if name.len() > MAX_NAME_LENGTH as usize {
-            return Err(ErrorCode::NameTooLong);
+            return Err(ErrorCode::NameTooLong.into());
         }
 
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
@@ -225,19 +396,25 @@ impl MetadataStorage {
             .unwrap()
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .value();
+        let acl_table = txn.open_table(ACCESS_ACL_TABLE).unwrap();
         if !check_access(
             parent_attrs.uid,
             parent_attrs.gid,
             parent_attrs.mode,
-            context.uid(),
-            context.gid(),
+            get_acl(&acl_table, parent).as_ref(),
+            &context,
             libc::X_OK,
         ) {
-            return Err(ErrorCode::AccessDenied);
+            return Err(ErrorCode::AccessDenied.into());
         }
 
         let table = txn.open_table(DIRECTORY_TABLE).unwrap();
         let maybe_inode = table.get((parent, name)).unwrap().map(|x| x.value().0);
+        if maybe_inode.is_none()
+            && is_sharded(&txn.open_table(DIRECTORY_SHARDS_TABLE).unwrap(), parent)
+        {
+            return Err(EntryError::Sharded);
+        }
         Ok(maybe_inode)
     }
 
@@ -245,7 +422,7 @@ impl MetadataStorage {
         &self,
         inode: Inode,
         key: &str,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<Vec<u8>, ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let txn = db.begin_read().unwrap();
@@ -253,8 +430,25 @@ impl MetadataStorage {
         let inode_attrs = table
             .get(&inode)
             .unwrap()
-            .ok_or(ErrorCode::InodeDoesNotExist)?;
-        xattr_access_check(key, libc::R_OK, &inode_attrs.value(), &context)?;
+            .ok_or(ErrorCode::InodeDoesNotExist)?
+            .value();
+        let access_acl = get_acl(&txn.open_table(ACCESS_ACL_TABLE).unwrap(), inode);
+        xattr_access_check(key, libc::R_OK, &inode_attrs, access_acl.as_ref(), &context)?;
+
+        // ACLs are stored separately, since they're used in access checks
+        match key {
+            ACL_ACCESS_XATTR => {
+                return access_acl
+                    .map(|acl| acl.to_xattr())
+                    .ok_or(ErrorCode::MissingXattrKey);
+            }
+            ACL_DEFAULT_XATTR => {
+                return get_acl(&txn.open_table(DEFAULT_ACL_TABLE).unwrap(), inode)
+                    .map(|acl| acl.to_xattr())
+                    .ok_or(ErrorCode::MissingXattrKey);
+            }
+            _ => {}
+        }
 
         let table = txn.open_table(XATTR_TABLE).unwrap();
         table
@@ -273,6 +467,12 @@ impl MetadataStorage {
             let (key, _) = item.unwrap();
             keys.push(key.value().1.to_owned());
         }
+        if get_acl(&txn.open_table(ACCESS_ACL_TABLE).unwrap(), inode).is_some() {
+            keys.push(ACL_ACCESS_XATTR.to_owned());
+        }
+        if get_acl(&txn.open_table(DEFAULT_ACL_TABLE).unwrap(), inode).is_some() {
+            keys.push(ACL_DEFAULT_XATTR.to_owned());
+        }
         Ok(keys)
     }
 
@@ -281,7 +481,7 @@ impl MetadataStorage {
         inode: Inode,
         key: &str,
         value: &[u8],
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut txn = db.begin_write().unwrap();
@@ -295,12 +495,35 @@ impl MetadataStorage {
                 .unwrap()
                 .ok_or(ErrorCode::InodeDoesNotExist)?
                 .value();
-            xattr_access_check(key, libc::W_OK, &inode_attrs, &context)?;
-            {
-                let mut table = txn.open_table(XATTR_TABLE).unwrap();
-                table.insert((inode, key), value).unwrap();
+            check_not_protected(&inode_attrs)?;
+            let access_acl = get_acl(&txn.open_table(ACCESS_ACL_TABLE).unwrap(), inode);
+            xattr_access_check(key, libc::W_OK, &inode_attrs, access_acl.as_ref(), &context)?;
+            match key {
+                ACL_ACCESS_XATTR => {
+                    let acl = PosixAcl::from_xattr(value)?;
+                    // The mode bits always mirror the access ACL
+                    inode_attrs.mode = (inode_attrs.mode & !0o777) | acl.mode_bits();
+                    let mut table = txn.open_table(ACCESS_ACL_TABLE).unwrap();
+                    if acl.is_minimal() {
+                        table.remove(&inode).unwrap();
+                    } else {
+                        table.insert(&inode, acl).unwrap();
+                    }
+                }
+                ACL_DEFAULT_XATTR => {
+                    if inode_attrs.kind != FileKind::Directory {
+                        return Err(ErrorCode::AccessDenied);
+                    }
+                    let acl = PosixAcl::from_xattr(value)?;
+                    let mut table = txn.open_table(DEFAULT_ACL_TABLE).unwrap();
+                    table.insert(&inode, acl).unwrap();
+                }
+                _ => {
+                    let mut table = txn.open_table(XATTR_TABLE).unwrap();
+                    table.insert((inode, key), value).unwrap();
+                }
             }
-            inode_attrs.last_metadata_changed = now();
+            inode_attrs.last_metadata_changed = self.now();
             attr_table.insert(&inode, inode_attrs).unwrap();
         }
         txn.commit().unwrap();
@@ -312,7 +535,7 @@ impl MetadataStorage {
         &self,
         inode: Inode,
         key: &str,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut txn = db.begin_write().unwrap();
@@ -326,15 +549,28 @@ impl MetadataStorage {
                 .unwrap()
                 .ok_or(ErrorCode::InodeDoesNotExist)?
                 .value();
-            xattr_access_check(key, libc::W_OK, &inode_attrs, &context)?;
-            {
-                let mut table = txn.open_table(XATTR_TABLE).unwrap();
-                if table.remove((inode, key)).unwrap().is_none() {
-                    // No need to commit the transaction, since nothing was modified
-                    return Err(ErrorCode::MissingXattrKey);
+            check_not_protected(&inode_attrs)?;
+            let access_acl = get_acl(&txn.open_table(ACCESS_ACL_TABLE).unwrap(), inode);
+            xattr_access_check(key, libc::W_OK, &inode_attrs, access_acl.as_ref(), &context)?;
+            let removed = match key {
+                ACL_ACCESS_XATTR => {
+                    let mut table = txn.open_table(ACCESS_ACL_TABLE).unwrap();
+                    table.remove(&inode).unwrap().is_some()
+                }
+                ACL_DEFAULT_XATTR => {
+                    let mut table = txn.open_table(DEFAULT_ACL_TABLE).unwrap();
+                    table.remove(&inode).unwrap().is_some()
+                }
+                _ => {
+                    let mut table = txn.open_table(XATTR_TABLE).unwrap();
+                    table.remove((inode, key)).unwrap().is_some()
                 }
+            };
+            if !removed {
+                // No need to commit the transaction, since nothing was modified
+                return Err(ErrorCode::MissingXattrKey);
             }
-            inode_attrs.last_metadata_changed = now();
+            inode_attrs.last_metadata_changed = self.now();
             attr_table.insert(&inode, inode_attrs).unwrap();
         }
         txn.commit().unwrap();
@@ -342,7 +578,16 @@ impl MetadataStorage {
         Ok(())
     }
 
-    pub fn readdir(&self, inode: Inode) -> Result<Vec<(Inode, String, FileKind)>, ErrorCode> {
+    // Lists up to limit entries with names after the given one. The "." and ".." entries are
+    // only included when starting from the beginning, and count towards the limit, which is at
+    // least 2 so that they fit. Also returns the name to resume the listing after, or None if it
+    // reached the end of the directory
+    pub fn readdir(
+        &self,
+        inode: Inode,
+        after: Option<&str>,
+        limit: u32,
+    ) -> Result<DirectoryPage<(Inode, String, FileKind)>, ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let txn = db.begin_read().unwrap();
         let attr_table = txn.open_table(ATTR_TABLE).unwrap();
@@ -356,15 +601,35 @@ impl MetadataStorage {
             return Err(ErrorCode::InodeDoesNotExist);
         };
 
+        let limit = limit.clamp(2, MAX_LISTING_ENTRIES) as usize;
         let mut result: Vec<(Inode, String, FileKind)> = vec![];
-        for r in directory_table.range((inode, "")..(inode + 1, "")).unwrap() {
+        let start = if let Some(name) = after {
+            Bound::Excluded((inode, name))
+        } else {
+            // TODO: kind of a hack
+            result.push((inode, ".".to_string(), FileKind::Directory));
+            result.push((parent_inode, "..".to_string(), FileKind::Directory));
+            Bound::Included((inode, ""))
+        };
+        let range = directory_table
+            .range::<(Inode, &str)>((start, Bound::Excluded((inode + 1, ""))))
+            .unwrap();
+        let mut resume_after = None;
+        for r in range {
+            if result.len() >= limit {
+                let (_, last, _) = result.last().unwrap();
+                // An empty name resumes after "." and ".."
+                resume_after = Some(if last == "." || last == ".." {
+                    String::new()
+                } else {
+                    last.clone()
+                });
+                break;
+            }
             let (key, value) = r.unwrap();
             result.push((value.value().0, key.value().1.to_string(), value.value().1));
         }
-        // TODO: kind of a hack
-        result.insert(0, (parent_inode, "..".to_string(), FileKind::Directory));
-        result.insert(0, (inode, ".".to_string(), FileKind::Directory));
-        Ok(result)
+        Ok((result, resume_after))
     }
 
     pub fn utimens(
@@ -372,7 +637,7 @@ impl MetadataStorage {
         inode: Inode,
         atime: Option<Timestamp>,
         mtime: Option<Timestamp>,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut txn = db.begin_write().unwrap();
@@ -386,6 +651,7 @@ impl MetadataStorage {
             .unwrap()
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .value();
+        check_not_protected(&inode_attrs)?;
         // Non-owners are only allowed to change atime & mtime to current:
         // http://man7.org/linux/man-pages/man2/utimensat.2.html
         if inode_attrs.uid != context.uid()
@@ -401,8 +667,8 @@ impl MetadataStorage {
                 inode_attrs.uid,
                 inode_attrs.gid,
                 inode_attrs.mode,
-                context.uid(),
-                context.gid(),
+                get_acl(&txn.open_table(ACCESS_ACL_TABLE).unwrap(), inode).as_ref(),
+                &context,
                 libc::W_OK,
             )
         {
@@ -411,16 +677,14 @@ impl MetadataStorage {
 
         if let Some(atime) = atime {
             if atime.nanos == libc::UTIME_NOW as i32 {
-                // TODO: this should be set during proposal. Currently each node set its own timestamp
-                inode_attrs.last_accessed = now();
+                inode_attrs.last_accessed = self.now();
             } else {
                 inode_attrs.last_accessed = atime;
             }
         }
         if let Some(mtime) = mtime {
             if mtime.nanos == libc::UTIME_NOW as i32 {
-                // TODO: this should be set during proposal. Currently each node set its own timestamp
-                inode_attrs.last_modified = now();
+                inode_attrs.last_modified = self.now();
             } else {
                 inode_attrs.last_modified = mtime;
             }
@@ -438,7 +702,7 @@ impl MetadataStorage {
         &self,
         inode: Inode,
         mut mode: u32,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut txn = db.begin_write().unwrap();
@@ -454,13 +718,23 @@ impl MetadataStorage {
         if context.uid() != 0 && inode_attrs.uid != context.uid() {
             return Err(ErrorCode::OperationNotPermitted);
         }
+        check_not_protected(&inode_attrs)?;
 
-        // TODO: suid/sgid not supported
-        mode &= !(libc::S_ISUID | libc::S_ISGID) as u32;
+        // Only members of the file's group may set the setgid bit
+        if context.uid() != 0 && !context.in_group(inode_attrs.gid) {
+            mode &= !libc::S_ISGID as u32;
+        }
         inode_attrs.mode = mode as u16;
-        inode_attrs.last_metadata_changed = now();
+        inode_attrs.last_metadata_changed = self.now();
         table.insert(&inode, inode_attrs).unwrap();
         drop(table);
+        {
+            let mut acl_table = txn.open_table(ACCESS_ACL_TABLE).unwrap();
+            if let Some(mut acl) = get_acl(&acl_table, inode) {
+                acl.set_mode_bits(mode as u16);
+                acl_table.insert(&inode, acl).unwrap();
+            }
+        }
         txn.commit().unwrap();
 
         Ok(())
@@ -471,7 +745,7 @@ impl MetadataStorage {
         inode: Inode,
         uid: Option<u32>,
         gid: Option<u32>,
-        context: UserContext,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut txn = db.begin_write().unwrap();
@@ -484,6 +758,7 @@ impl MetadataStorage {
             .unwrap()
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .value();
+        check_not_protected(&inode_attrs)?;
 
         // Only root can change uid
         if let Some(uid) = uid
@@ -493,8 +768,11 @@ impl MetadataStorage {
         {
             return Err(ErrorCode::OperationNotPermitted);
         }
-        // Only owner may change the group
-        if gid.is_some() && context.uid() != 0 && context.uid() != inode_attrs.uid {
+        // Only owner may change the group, and only to a group they're in
+        if let Some(gid) = gid
+            && context.uid() != 0
+            && (context.uid() != inode_attrs.uid || !context.in_group(gid))
+        {
             return Err(ErrorCode::OperationNotPermitted);
         }
 
@@ -505,7 +783,10 @@ impl MetadataStorage {
             inode_attrs.gid = gid;
         }
         if uid.is_some() || gid.is_some() {
-            inode_attrs.last_metadata_changed = now();
+            if inode_attrs.kind != FileKind::Directory {
+                clear_setid_bits(&mut inode_attrs);
+            }
+            inode_attrs.last_metadata_changed = self.now();
         }
         table.insert(&inode, inode_attrs).unwrap();
         drop(table);
@@ -526,9 +807,10 @@ impl MetadataStorage {
             .unwrap()
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .value();
+        check_not_protected(&inode_attrs)?;
         inode_attrs.hardlinks += 1;
         let old = inode_attrs.last_metadata_changed;
-        inode_attrs.last_metadata_changed = now();
+        inode_attrs.last_metadata_changed = self.now();
         table.insert(&inode, inode_attrs).unwrap();
         drop(table);
         txn.commit().unwrap();
@@ -541,11 +823,11 @@ impl MetadataStorage {
         inode: Inode,
         parent: u64,
         name: &str,
-        context: UserContext,
+        context: UserContext<'_>,
         inode_kind: FileKind,
-    ) -> Result<(), ErrorCode> {
+    ) -> Result<(), EntryError> {
         if self.lookup(parent, name, context)?.is_some() {
-            return Err(ErrorCode::AlreadyExists);
+            return Err(ErrorCode::AlreadyExists.into());
         }
 
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
@@ -560,20 +842,39 @@ impl MetadataStorage {
                 .unwrap()
                 .ok_or(ErrorCode::InodeDoesNotExist)?
                 .value();
+            if parent_attrs.flags & FS_IMMUTABLE_FL != 0 {
+                return Err(ErrorCode::OperationNotPermitted.into());
+            }
             if !check_access(
                 parent_attrs.uid,
                 parent_attrs.gid,
                 parent_attrs.mode,
-                context.uid(),
-                context.gid(),
+                get_acl(&txn.open_table(ACCESS_ACL_TABLE).unwrap(), parent).as_ref(),
+                &context,
                 libc::W_OK,
             ) {
-                return Err(ErrorCode::AccessDenied);
+                return Err(ErrorCode::AccessDenied.into());
+            }
+            let is_shard = txn
+                .open_table(SHARD_PARENTS_TABLE)
+                .unwrap()
+                .get(&parent)
+                .unwrap()
+                .is_some();
+            let mut sizes = txn.open_table(DIRECTORY_SIZES_TABLE).unwrap();
+            let size = sizes
+                .get(&parent)
+                .unwrap()
+                .map(|x| x.value())
+                .unwrap_or_default();
+            if self.num_raft_groups > 1 && !is_shard && size >= DIRECTORY_SHARD_THRESHOLD {
+                return Err(EntryError::NeedsSharding);
             }
-            parent_attrs.last_modified = now();
-            parent_attrs.last_metadata_changed = now();
-            attr_table.insert(&parent, parent_attrs).unwrap();
 
+            parent_attrs.last_modified = self.now();
+            parent_attrs.last_metadata_changed = self.now();
+            attr_table.insert(&parent, parent_attrs).unwrap();
+            sizes.insert(&parent, size + 1).unwrap();
             let mut table = txn.open_table(DIRECTORY_TABLE).unwrap();
             table.insert((parent, name), (inode, inode_kind)).unwrap();
         }
@@ -588,31 +889,42 @@ impl MetadataStorage {
         name: &str,
         new_inode: Inode,
         inode_kind: FileKind,
-        context: UserContext,
-    ) -> Result<u64, ErrorCode> {
+        context: UserContext<'_>,
+    ) -> Result<u64, EntryError> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut txn = db.begin_write().unwrap();
         if !self.durability_tick() {
             txn.set_durability(Durability::None).unwrap();
         }
+        if txn
+            .open_table(DIRECTORY_TABLE)
+            .unwrap()
+            .get((parent, name))
+            .unwrap()
+            .is_none()
+            && is_sharded(&txn.open_table(DIRECTORY_SHARDS_TABLE).unwrap(), parent)
+        {
+            return Err(EntryError::Sharded);
+        }
         let mut attr_table = txn.open_table(ATTR_TABLE).unwrap();
         let mut parent_attrs = attr_table
             .get(&parent)
             .unwrap()
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .value();
+        check_not_protected(&parent_attrs)?;
         if !check_access(
             parent_attrs.uid,
             parent_attrs.gid,
             parent_attrs.mode,
-            context.uid(),
-            context.gid(),
+            get_acl(&txn.open_table(ACCESS_ACL_TABLE).unwrap(), parent).as_ref(),
+            &context,
             libc::W_OK,
         ) {
-            return Err(ErrorCode::AccessDenied);
+            return Err(ErrorCode::AccessDenied.into());
         }
-        parent_attrs.last_modified = now();
-        parent_attrs.last_metadata_changed = now();
+        parent_attrs.last_modified = self.now();
+        parent_attrs.last_metadata_changed = self.now();
         attr_table.insert(&parent, parent_attrs).unwrap();
 
         let old_inode = {
@@ -677,7 +989,11 @@ impl MetadataStorage {
         Ok(())
     }
 
-    pub fn update_metadata_changed_time(&self, inode: u64) -> Result<(), ErrorCode> {
+    pub fn update_metadata_changed_time(
+        &self,
+        inode: u64,
+        modified: bool,
+    ) -> Result<(), ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut txn = db.begin_write().unwrap();
         if !self.durability_tick() {
@@ -690,7 +1006,10 @@ impl MetadataStorage {
                 .unwrap()
                 .ok_or(ErrorCode::InodeDoesNotExist)?
                 .value();
-            inode_attrs.last_metadata_changed = now();
+            inode_attrs.last_metadata_changed = self.now();
+            if modified {
+                inode_attrs.last_modified = self.now();
+            }
             attr_table.insert(&inode, inode_attrs).unwrap();
         }
         txn.commit().unwrap();
@@ -698,11 +1017,134 @@ impl MetadataStorage {
         Ok(())
     }
 
+    pub fn set_flags(
+        &self,
+        inode: Inode,
+        flags: u32,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        if flags & !(FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        {
+            let mut table = txn.open_table(ATTR_TABLE).unwrap();
+            let mut inode_attrs = table
+                .get(&inode)
+                .unwrap()
+                .ok_or(ErrorCode::InodeDoesNotExist)?
+                .value();
+            if context.uid() != 0 && inode_attrs.uid != context.uid() {
+                return Err(ErrorCode::OperationNotPermitted);
+            }
+            // All the supported flags require privileges to change, like CAP_LINUX_IMMUTABLE
+            if context.uid() != 0 && flags != inode_attrs.flags {
+                return Err(ErrorCode::OperationNotPermitted);
+            }
+            inode_attrs.flags = flags;
+            inode_attrs.last_metadata_changed = self.now();
+            table.insert(&inode, inode_attrs).unwrap();
+        }
+        txn.commit().unwrap();
+
+        Ok(())
+    }
+
+    // Returns true if a read should update the inode's access time, in relatime mode
+    pub fn access_time_stale(&self, inode: Inode) -> Result<bool, ErrorCode> {
+        let (attributes, _) = self.get_attributes(inode)?;
+        Ok(relatime_update_needed(&attributes, Timestamp::now()))
+    }
+
+    pub fn update_access_times(&self, inodes: &[Inode], strict: bool) -> Result<(), ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        {
+            let mut attr_table = txn.open_table(ATTR_TABLE).unwrap();
+            for inode in inodes {
+                // The inode may have been deleted since it was read
+                let Some(mut inode_attrs) = attr_table.get(inode).unwrap().map(|x| x.value())
+                else {
+                    continue;
+                };
+                if strict || relatime_update_needed(&inode_attrs, self.now()) {
+                    inode_attrs.last_accessed = self.now();
+                    attr_table.insert(inode, inode_attrs).unwrap();
+                }
+            }
+        }
+        txn.commit().unwrap();
+
+        Ok(())
+    }
+
+    // Returns the directory's shards, or an empty list if it isn't sharded
+    pub fn directory_shards(&self, inode: Inode) -> Result<Vec<Inode>, ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let txn = db.begin_read().unwrap();
+        let table = txn.open_table(DIRECTORY_SHARDS_TABLE).unwrap();
+        let shards = table
+            .range((inode, 0)..=(inode, u16::MAX))
+            .unwrap()
+            .map(|entry| entry.unwrap().1.value())
+            .collect();
+
+        Ok(shards)
+    }
+
+    // Returns the directory that the inode is a shard of, if it's a shard
+    pub fn shard_parent(&self, inode: Inode) -> Result<Option<Inode>, ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let txn = db.begin_read().unwrap();
+        let table = txn.open_table(SHARD_PARENTS_TABLE).unwrap();
+        let parent = table.get(&inode).unwrap().map(|x| x.value());
+
+        Ok(parent)
+    }
+
+    pub fn shard_directory(&self, inode: Inode, shards: &[Inode]) -> Result<(), ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        {
+            let attr_table = txn.open_table(ATTR_TABLE).unwrap();
+            let attrs = attr_table
+                .get(&inode)
+                .unwrap()
+                .ok_or(ErrorCode::InodeDoesNotExist)?
+                .value();
+            if attrs.kind != FileKind::Directory || shards.is_empty() {
+                return Err(ErrorCode::BadRequest);
+            }
+            let mut table = txn.open_table(DIRECTORY_SHARDS_TABLE).unwrap();
+            // Another client may have split the directory concurrently
+            if is_sharded(&table, inode) {
+                return Err(ErrorCode::AlreadyExists);
+            }
+            for (index, shard) in shards.iter().enumerate() {
+                table.insert((inode, index as u16), shard).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+
+        Ok(())
+    }
+
     pub fn truncate(
         &self,
         inode: Inode,
         new_length: u64,
-        context: UserContext,
+        has_write_handle: bool,
+        context: UserContext<'_>,
     ) -> Result<(), ErrorCode> {
         if new_length > MAX_FILE_SIZE {
             return Err(ErrorCode::FileTooLarge);
@@ -720,20 +1162,26 @@ impl MetadataStorage {
                 .unwrap()
                 .ok_or(ErrorCode::InodeDoesNotExist)?
                 .value();
-            if !check_access(
-                inode_attrs.uid,
-                inode_attrs.gid,
-                inode_attrs.mode,
-                context.uid(),
-                context.gid(),
-                libc::W_OK,
-            ) {
+            check_not_protected(&inode_attrs)?;
+            if !has_write_handle
+                && !check_access(
+                    inode_attrs.uid,
+                    inode_attrs.gid,
+                    inode_attrs.mode,
+                    get_acl(&txn.open_table(ACCESS_ACL_TABLE).unwrap(), inode).as_ref(),
+                    &context,
+                    libc::W_OK,
+                )
+            {
                 return Err(ErrorCode::AccessDenied);
             }
 
+            if context.uid() != 0 && inode_attrs.kind == FileKind::File {
+                clear_setid_bits(&mut inode_attrs);
+            }
             inode_attrs.size = new_length;
-            inode_attrs.last_metadata_changed = now();
-            inode_attrs.last_modified = now();
+            inode_attrs.last_metadata_changed = self.now();
+            inode_attrs.last_modified = self.now();
             table.insert(&inode, inode_attrs).unwrap();
         }
         txn.commit().unwrap();
@@ -752,8 +1200,8 @@ impl MetadataStorage {
         name: &str,
         // If provided, will preform "sticky bit" checks for the inode.
         link_inode_and_uid: Option<(u64, u32)>,
-        context: UserContext,
-    ) -> Result<(Inode, bool), ErrorCode> {
+        context: UserContext<'_>,
+    ) -> Result<(Inode, bool), EntryError> {
         let db = self.storage.lock().unwrap();
         let mut txn = db.begin_write().unwrap();
         if !self.durability_tick() {
@@ -766,11 +1214,12 @@ impl MetadataStorage {
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .value();
         let mut dir_table = txn.open_table(DIRECTORY_TABLE).unwrap();
-        let (inode, _) = dir_table
-            .get((parent, name))
-            .unwrap()
-            .ok_or(ErrorCode::DoesNotExist)?
-            .value();
+        let Some((inode, _)) = dir_table.get((parent, name)).unwrap().map(|x| x.value()) else {
+            if is_sharded(&txn.open_table(DIRECTORY_SHARDS_TABLE).unwrap(), parent) {
+                return Err(EntryError::Sharded);
+            }
+            return Err(ErrorCode::DoesNotExist.into());
+        };
 
         if let Some((retrieved_inode, _)) = link_inode_and_uid
             && retrieved_inode != inode
@@ -783,18 +1232,19 @@ impl MetadataStorage {
             return Ok((inode, false));
         }
 
+        check_not_protected(&parent_attrs)?;
         if !check_access(
             parent_attrs.uid,
             parent_attrs.gid,
             parent_attrs.mode,
-            context.uid(),
-            context.gid(),
+            get_acl(&txn.open_table(ACCESS_ACL_TABLE).unwrap(), parent).as_ref(),
+            &context,
             libc::W_OK,
         ) {
             drop(attr_table);
             drop(dir_table);
             txn.abort().unwrap();
-            return Err(ErrorCode::AccessDenied);
+            return Err(ErrorCode::AccessDenied.into());
         }
 
         let uid = context.uid();
@@ -805,7 +1255,7 @@ impl MetadataStorage {
                     drop(attr_table);
                     drop(dir_table);
                     txn.abort().unwrap();
-                    return Err(ErrorCode::AccessDenied);
+                    return Err(ErrorCode::AccessDenied.into());
                 }
             } else {
                 // Sticky bit is on, and we need to check the inode's uid. Tell the client to lock
@@ -817,8 +1267,8 @@ impl MetadataStorage {
             }
         }
 
-        parent_attrs.last_metadata_changed = now();
-        parent_attrs.last_modified = now();
+        parent_attrs.last_metadata_changed = self.now();
+        parent_attrs.last_modified = self.now();
         attr_table.insert(&parent, parent_attrs).unwrap();
         drop(attr_table);
         let (inode, _) = dir_table
@@ -827,12 +1277,30 @@ impl MetadataStorage {
             .ok_or(ErrorCode::DoesNotExist)?
             .value();
         drop(dir_table);
+        {
+            let mut sizes = txn.open_table(DIRECTORY_SIZES_TABLE).unwrap();
+            let size = sizes
+                .get(&parent)
+                .unwrap()
+                .map(|x| x.value())
+                .unwrap_or_default();
+            sizes.insert(&parent, size.saturating_sub(1)).unwrap();
+        }
         txn.commit().unwrap();
 
         Ok((inode, true))
     }
 
-    pub fn write(&self, inode: Inode, offset: u64, length: u32) -> Result<(), ErrorCode> {
+    // Records a write of length bytes at offset, or at the end of the file if append is set.
+    // Returns the offset the data is written at
+    pub fn write(
+        &self,
+        inode: Inode,
+        offset: u64,
+        length: u32,
+        uid: u32,
+        append: bool,
+    ) -> Result<u64, ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut txn = db.begin_write().unwrap();
         if !self.durability_tick() {
@@ -844,18 +1312,28 @@ impl MetadataStorage {
             .unwrap()
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .value();
+        let offset = if append { inode_attrs.size } else { offset };
+        if inode_attrs.flags & FS_IMMUTABLE_FL != 0
+            || (inode_attrs.flags & FS_APPEND_FL != 0 && offset < inode_attrs.size)
+        {
+            return Err(ErrorCode::OperationNotPermitted);
+        }
 
+        if uid != 0 && inode_attrs.kind == FileKind::File {
+            clear_setid_bits(&mut inode_attrs);
+        }
         let current_length = inode_attrs.size;
         inode_attrs.size = max(current_length, u64::from(length) + offset);
-        inode_attrs.last_metadata_changed = now();
-        inode_attrs.last_modified = now();
+        inode_attrs.last_metadata_changed = self.now();
+        inode_attrs.last_modified = self.now();
         table.insert(&inode, inode_attrs).unwrap();
         drop(table);
         txn.commit().unwrap();
 
-        Ok(())
+        Ok(offset)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_inode(
         &self,
         parent: Inode,
@@ -863,6 +1341,10 @@ impl MetadataStorage {
         gid: u32,
         mode: u16,
         kind: FileKind,
+        rdev: u32,
+        open_handle: Option<OpenHandleId>,
+        default_acl: Option<PosixAcl>,
+        shard_of: Option<Inode>,
     ) -> Result<(Inode, InodeAttributes), ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
         let mut txn = db.begin_write().unwrap();
@@ -877,20 +1359,42 @@ impl MetadataStorage {
         } else {
             0
         };
-        // Directories start with a link count of 2, since they have a self link
-        let hardlinks = if kind == FileKind::Directory { 2 } else { 1 };
+        // Directories start with a link count of 2, since they have a self link.
+        // Unnamed inodes start without any links, and are only kept alive by their open handle
+        let hardlinks = if open_handle.is_some() {
+            0
+        } else if kind == FileKind::Directory {
+            2
+        } else {
+            1
+        };
+        // New inodes inherit the parent's default ACL, limited by the requested mode
+        let access_acl = default_acl.as_ref().map(|acl| acl.inherit(mode & 0o777));
+        let mode = if let Some(ref acl) = access_acl {
+            (mode & !0o777) | acl.mode_bits()
+        } else {
+            mode
+        };
+        let unique_id = next_unique_id(
+            &mut txn.open_table(UNIQUE_IDS_TABLE).unwrap(),
+            self.raft_group,
+            self.num_raft_groups,
+        );
         let inode_metadata = InodeAttributes {
             inode,
             size,
-            last_accessed: now(),
-            last_modified: now(),
-            last_metadata_changed: now(),
+            last_accessed: self.now(),
+            last_modified: self.now(),
+            last_metadata_changed: self.now(),
             kind,
-            // TODO: suid/sgid not supported
-            mode: mode & !(libc::S_ISUID | libc::S_ISGID) as u16,
+            mode,
             hardlinks,
             uid,
             gid,
+            rdev,
+            created: self.now(),
+            flags: 0,
+            unique_id,
         };
         attr_table.insert(&inode, &inode_metadata).unwrap();
 
@@ -900,6 +1404,29 @@ impl MetadataStorage {
                 table.insert(&inode, &parent).unwrap();
             }
         }
+        if let Some(acl) = access_acl
+            && !acl.is_minimal()
+        {
+            let mut table = txn.open_table(ACCESS_ACL_TABLE).unwrap();
+            table.insert(&inode, acl).unwrap();
+        }
+        if kind == FileKind::Directory
+            && let Some(acl) = default_acl
+        {
+            let mut table = txn.open_table(DEFAULT_ACL_TABLE).unwrap();
+            table.insert(&inode, acl).unwrap();
+        }
+        if let Some(directory) = shard_of {
+            let mut table = txn.open_table(SHARD_PARENTS_TABLE).unwrap();
+            table.insert(&inode, &directory).unwrap();
+        }
+        if let Some(open_handle) = open_handle {
+            let mut table = txn.open_table(OPEN_HANDLES_TABLE).unwrap();
+            table
+                .insert(&(inode, open_handle.session, open_handle.handle), ())
+                .unwrap();
+            add_session(&txn, open_handle.session, self.now());
+        }
         drop(attr_table);
         txn.commit().unwrap();
         Ok((inode, inode_metadata))
@@ -924,76 +1451,1088 @@ impl MetadataStorage {
             .ok_or(ErrorCode::InodeDoesNotExist)?
             .value();
         inode_attrs.hardlinks -= count;
-        inode_attrs.last_metadata_changed = now();
-        let deleted_inode = if inode_attrs.hardlinks == 0 {
-            let is_directory = inode_attrs.kind == FileKind::Directory;
-            attr_table.remove(&inode).unwrap();
-            {
-                let mut table = txn.open_table(XATTR_TABLE).unwrap();
-                table
-                    .retain_in((inode, "")..(inode + 1, ""), |_, _| false)
-                    .unwrap();
-            }
-            if is_directory {
-                {
-                    let mut table = txn.open_table(PARENTS_TABLE).unwrap();
-                    table.remove(&inode).unwrap();
-                }
-
-                {
-                    let directory_table = txn.open_table(DIRECTORY_TABLE).unwrap();
-                    let mut entries = directory_table.range((inode, "")..(inode + 1, "")).unwrap();
-                    assert!(
-                        entries.next().is_none(),
-                        "Deleted a non-empty directory inode"
-                    );
-                }
-                None
-            } else {
-                // Only delete file contents if this is not a directory (directories don't have data contents)
-                Some(inode)
-            }
+        inode_attrs.last_metadata_changed = self.now();
+        let is_open = has_open_handles(&txn, inode);
+        let deleted_inode = if inode_attrs.hardlinks == 0 && !is_open {
+            drop(attr_table);
+            delete_inode(&txn, &inode_attrs)
         } else {
+            // If the inode is still open it's kept as an orphan, until its last handle is released
             attr_table.insert(&inode, &inode_attrs).unwrap();
+            drop(attr_table);
             None
         };
-        drop(attr_table);
         txn.commit().unwrap();
 
         Ok(deleted_inode)
     }
 
-    pub fn get_attributes(
-        &self,
-        inode: Inode,
-    ) -> Result<(InodeAttributes, Option<u32>), ErrorCode> {
+    pub fn open_handle(&self, inode: Inode, session: u64, handle: u64) -> Result<(), ErrorCode> {
         let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
-        let txn = db.begin_read().unwrap();
-        let attr_table = txn.open_table(ATTR_TABLE).unwrap();
-        let attributes = attr_table
-            .get(&inode)
-            .unwrap()
-            .ok_or(ErrorCode::InodeDoesNotExist)?
-            .value();
-        let table = txn.open_table(DIRECTORY_TABLE).unwrap();
-        let directory_size = if attributes.kind == FileKind::Directory {
-            Some(table.range((inode, "")..(inode + 1, "")).unwrap().count() as u32)
-        } else {
-            None
-        };
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        {
+            let attr_table = txn.open_table(ATTR_TABLE).unwrap();
+            if attr_table.get(&inode).unwrap().is_none() {
+                return Err(ErrorCode::InodeDoesNotExist);
+            }
+        }
+        {
+            let mut table = txn.open_table(OPEN_HANDLES_TABLE).unwrap();
+            table.insert(&(inode, session, handle), ()).unwrap();
+        }
+        add_session(&txn, session, self.now());
+        txn.commit().unwrap();
 
-        Ok((attributes, directory_size))
+        Ok(())
     }
 
-    fn allocate_inode(&self) -> u64 {
-        self.next_inode
-            .fetch_add(self.num_raft_groups, Ordering::SeqCst)
+    pub fn has_open_handle(&self, inode: Inode, session: u64) -> Result<bool, ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let txn = db.begin_read().unwrap();
+        let table = txn.open_table(OPEN_HANDLES_TABLE).unwrap();
+        let mut handles = table
+            .range((inode, session, 0)..=(inode, session, u64::MAX))
+            .unwrap();
+
+        Ok(handles.next().is_some())
     }
-}
 
-fn now() -> Timestamp {
-    let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("System time before unix epoch");
-    Timestamp::new(now.as_secs() as i64, now.subsec_nanos() as i32)
+    // Returns an inode, if that inode's data should be deleted
+    pub fn release_handle(
+        &self,
+        inode: Inode,
+        session: u64,
+        handle: u64,
+    ) -> Result<Option<Inode>, ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        {
+            let mut table = txn.open_table(OPEN_HANDLES_TABLE).unwrap();
+            table.remove(&(inode, session, handle)).unwrap();
+        }
+        let deleted_inode = delete_if_orphaned(&txn, inode);
+        txn.commit().unwrap();
+
+        Ok(deleted_inode)
+    }
+
+    // Releases all the handles opened by the session. Returns the inodes whose data should be deleted
+    pub fn release_session(&self, session: u64) -> Result<Vec<Inode>, ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        let mut released = vec![];
+        {
+            // TODO: this scans all open handles. Should be indexed by session
+            let mut table = txn.open_table(OPEN_HANDLES_TABLE).unwrap();
+            table
+                .retain(|(inode, handle_session, _), _| {
+                    if handle_session == session {
+                        released.push(inode);
+                        false
+                    } else {
+                        true
+                    }
+                })
+                .unwrap();
+        }
+        released.dedup();
+        let deleted_inodes = released
+            .into_iter()
+            .filter_map(|inode| delete_if_orphaned(&txn, inode))
+            .collect();
+        {
+            let mut table = txn.open_table(SESSIONS_TABLE).unwrap();
+            table.remove(&session).unwrap();
+        }
+        txn.commit().unwrap();
+
+        Ok(deleted_inodes)
+    }
+
+    // Records that the session's lease was renewed, at the commit time
+    pub fn renew_session(&self, session: u64) -> Result<(), ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        {
+            let mut table = txn.open_table(SESSIONS_TABLE).unwrap();
+            table.insert(&session, self.now()).unwrap();
+        }
+        txn.commit().unwrap();
+
+        Ok(())
+    }
+
+    // Returns the sessions whose leases have expired as of the commit time. Every replica returns
+    // the same sessions, since they're committed at the same time
+    pub fn expired_sessions(&self) -> Result<Vec<u64>, ErrorCode> {
+        let cutoff = self.now().seconds - SESSION_LEASE_SECONDS;
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let txn = db.begin_read().unwrap();
+        let table = txn.open_table(SESSIONS_TABLE).unwrap();
+        let mut expired = vec![];
+        for item in table.iter().unwrap() {
+            let (session, renewed) = item.unwrap();
+            if renewed.value().seconds < cutoff {
+                expired.push(session.value());
+            }
+        }
+
+        Ok(expired)
+    }
+
+    // Replaces the advisory locks stored for the inode
+    pub fn store_range_locks(
+        &self,
+        inode: Inode,
+        held: &[RangeLock],
+        waiting: &[RangeLock],
+    ) -> Result<(), ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        {
+            let mut table = txn.open_table(RANGE_LOCKS_TABLE).unwrap();
+            table
+                .retain_in((inode, 0)..=(inode, u32::MAX), |_, _| false)
+                .unwrap();
+            let locks = held
+                .iter()
+                .map(|lock| (lock, false))
+                .chain(waiting.iter().map(|lock| (lock, true)));
+            for (index, (lock, waiting)) in locks.enumerate() {
+                let stored = StoredRangeLock {
+                    session: lock.session,
+                    owner: lock.owner,
+                    pid: lock.pid,
+                    start: lock.start,
+                    end: lock.end,
+                    write: lock.kind == RangeLockKind::Write,
+                    waiting,
+                };
+                table.insert(&(inode, index as u32), stored).unwrap();
+            }
+        }
+        for lock in held.iter().chain(waiting) {
+            add_session(&txn, lock.session, self.now());
+        }
+        txn.commit().unwrap();
+
+        Ok(())
+    }
+
+    // Returns the stored advisory locks, as (inode, lock, waiting). Each inode's locks are in the
+    // order they were stored in
+    pub fn range_locks(&self) -> Result<Vec<(Inode, RangeLock, bool)>, ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let txn = db.begin_read().unwrap();
+        let table = txn.open_table(RANGE_LOCKS_TABLE).unwrap();
+        let mut locks = vec![];
+        for item in table.iter().unwrap() {
+            let (key, stored) = item.unwrap();
+            let (inode, _) = key.value();
+            let stored = stored.value();
+            let lock = RangeLock {
+                session: stored.session,
+                owner: stored.owner,
+                pid: stored.pid,
+                start: stored.start,
+                end: stored.end,
+                kind: if stored.write {
+                    RangeLockKind::Write
+                } else {
+                    RangeLockKind::Read
+                },
+            };
+            locks.push((inode, lock, stored.waiting));
+        }
+
+        Ok(locks)
+    }
+
+    // Begins the request on behalf of the coordinator owner, unless it was begun before. A request
+    // whose coordinator's lease expired before it finished is taken over. Results of the session's
+    // requests before acknowledged, and of some of the requests that have expired, are dropped
+    pub fn begin_request(
+        &self,
+        session: u64,
+        sequence: u64,
+        acknowledged: u64,
+        owner: u64,
+    ) -> Result<RequestState, ErrorCode> {
+        let now = self.now().seconds;
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        let state;
+        {
+            let mut records = txn.open_table(REQUEST_RECORDS_TABLE).unwrap();
+            let mut expiry = txn.open_table(REQUEST_EXPIRY_TABLE).unwrap();
+            let mut dropped = vec![];
+            for item in records
+                .range((session, 0)..(session, acknowledged.min(sequence)))
+                .unwrap()
+            {
+                let (key, value) = item.unwrap();
+                let (session, sequence) = key.value();
+                dropped.push((value.value().0, session, sequence));
+            }
+            for item in expiry
+                .range(..=(now, u64::MAX, u64::MAX))
+                .unwrap()
+                .take(EXPIRED_REQUESTS_PER_BEGIN)
+            {
+                dropped.push(item.unwrap().0.value());
+            }
+            for (expires, session, sequence) in dropped {
+                records.remove(&(session, sequence)).unwrap();
+                expiry.remove(&(expires, session, sequence)).unwrap();
+            }
+
+            let record = records.get(&(session, sequence)).unwrap().map(|value| {
+                let (expires, owner, result) = value.value();
+                (expires, owner, result.map(|result| result.to_vec()))
+            });
+            state = match record {
+                Some((_, _, Some(result))) => RequestState::Finished(result),
+                Some((expires, _, None)) if expires > now => RequestState::InProgress,
+                Some((expires, _, None)) => {
+                    // The coordinator's lease expired, so it may have failed
+                    expiry.remove(&(expires, session, sequence)).unwrap();
+                    RequestState::New
+                }
+                None => RequestState::New,
+            };
+            if let RequestState::New = state {
+                let expires = now + REQUEST_LEASE_SECONDS;
+                records
+                    .insert(&(session, sequence), (expires, owner, None))
+                    .unwrap();
+                expiry.insert(&(expires, session, sequence), ()).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+
+        Ok(state)
+    }
+
+    // Records the result of a request, which is returned if it's begun again. Ignored if another
+    // coordinator took over the request
+    pub fn finish_request(
+        &self,
+        session: u64,
+        sequence: u64,
+        owner: u64,
+        result: &[u8],
+    ) -> Result<(), ErrorCode> {
+        let now = self.now().seconds;
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let mut txn = db.begin_write().unwrap();
+        if !self.durability_tick() {
+            txn.set_durability(Durability::None).unwrap();
+        }
+        {
+            let mut records = txn.open_table(REQUEST_RECORDS_TABLE).unwrap();
+            let mut expiry = txn.open_table(REQUEST_EXPIRY_TABLE).unwrap();
+            let record = records
+                .get(&(session, sequence))
+                .unwrap()
+                .map(|value| (value.value().0, value.value().1));
+            // The record may have been dropped, if the request took long enough that the lease
+            // expired, and it's recorded again if no other coordinator took it over
+            match record {
+                Some((_, record_owner)) if record_owner != owner => {
+                    return Ok(());
+                }
+                Some((expires, _)) => {
+                    expiry.remove(&(expires, session, sequence)).unwrap();
+                }
+                None => {}
+            }
+            let expires = now + REQUEST_RESULT_RETENTION_SECONDS;
+            records
+                .insert(&(session, sequence), (expires, owner, Some(result)))
+                .unwrap();
+            expiry.insert(&(expires, session, sequence), ()).unwrap();
+        }
+        txn.commit().unwrap();
+
+        Ok(())
+    }
+
+    pub fn access(
+        &self,
+        inode: Inode,
+        access_mask: i32,
+        context: UserContext<'_>,
+    ) -> Result<(), ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let txn = db.begin_read().unwrap();
+        let attr_table = txn.open_table(ATTR_TABLE).unwrap();
+        let attributes = attr_table
+            .get(&inode)
+            .unwrap()
+            .ok_or(ErrorCode::InodeDoesNotExist)?
+            .value();
+        let acl_table = txn.open_table(ACCESS_ACL_TABLE).unwrap();
+        if check_access(
+            attributes.uid,
+            attributes.gid,
+            attributes.mode,
+            get_acl(&acl_table, inode).as_ref(),
+            &context,
+            access_mask,
+        ) {
+            Ok(())
+        } else {
+            Err(ErrorCode::AccessDenied)
+        }
+    }
+
+    pub fn get_attributes(
+        &self,
+        inode: Inode,
+    ) -> Result<(InodeAttributes, Option<u32>), ErrorCode> {
+        let db = self.storage.lock().map_err(|_| ErrorCode::Corrupted)?;
+        let txn = db.begin_read().unwrap();
+        let attr_table = txn.open_table(ATTR_TABLE).unwrap();
+        let attributes = attr_table
+            .get(&inode)
+            .unwrap()
+            .ok_or(ErrorCode::InodeDoesNotExist)?
+            .value();
+        let sizes = txn.open_table(DIRECTORY_SIZES_TABLE).unwrap();
+        let directory_size = if attributes.kind == FileKind::Directory {
+            Some(
+                sizes
+                    .get(&inode)
+                    .unwrap()
+                    .map(|x| x.value())
+                    .unwrap_or_default() as u32,
+            )
+        } else {
+            None
+        };
+
+        Ok((attributes, directory_size))
+    }
+
+    pub fn set_commit_time(&self, timestamp: Timestamp) {
+        *self.commit_time.lock().unwrap() = timestamp;
+    }
+
+    fn now(&self) -> Timestamp {
+        *self.commit_time.lock().unwrap()
+    }
+
+    fn allocate_inode(&self) -> u64 {
+        self.next_inode
+            .fetch_add(self.num_raft_groups, Ordering::SeqCst)
+    }
+}
+
+// Immutable and append-only inodes can't have their metadata changed, be truncated or be unlinked.
+// Entries can't be removed from immutable and append-only directories
+fn check_not_protected(attributes: &InodeAttributes) -> Result<(), ErrorCode> {
+    if attributes.flags & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+        Err(ErrorCode::OperationNotPermitted)
+    } else {
+        Ok(())
+    }
+}
+
+// Same rule as Linux's relatime: the access time is updated if it's not after the modify or change
+// time, or if it's more than a day old
+fn relatime_update_needed(attributes: &InodeAttributes, now: Timestamp) -> bool {
+    attributes.last_accessed <= attributes.last_modified
+        || attributes.last_accessed <= attributes.last_metadata_changed
+        || now.seconds - attributes.last_accessed.seconds >= 24 * 60 * 60
+}
+
+// Returns a new unique id. Ids are unique across raft groups, since each group assigns ids
+// modulo the number of groups, in the same way as inode numbers
+fn next_unique_id(table: &mut Table<(), u64>, raft_group: u64, num_raft_groups: u64) -> u64 {
+    let assigned = table
+        .get(())
+        .unwrap()
+        .map(|x| x.value())
+        .unwrap_or_default();
+    table.insert((), assigned + 1).unwrap();
+    // Zero is reserved for the root inode
+    (assigned + 1) * num_raft_groups + raft_group
+}
+
+// Rewrites the attributes of a database created before device numbers, birth times, flags and
+// unique ids were stored. Device nodes couldn't be created then, so their device numbers are all 0.
+// The change time is the best available approximation of the birth time
+fn migrate_legacy_attributes(db: &redb::Database, raft_group: u64, num_raft_groups: u64) {
+    let legacy_attributes: Vec<legacy::InodeAttributes> = {
+        let txn = db.begin_read().unwrap();
+        match txn.open_table(LEGACY_ATTR_TABLE) {
+            Ok(table) => table
+                .iter()
+                .unwrap()
+                .map(|entry| entry.unwrap().1.value())
+                .collect(),
+            // Either a new database, or one which is already in the current format
+            Err(_) => return,
+        }
+    };
+
+    let txn = db.begin_write().unwrap();
+    txn.delete_table(LEGACY_ATTR_TABLE).unwrap();
+    {
+        let mut table = txn.open_table(ATTR_TABLE).unwrap();
+        let mut unique_ids = txn.open_table(UNIQUE_IDS_TABLE).unwrap();
+        for old in legacy_attributes {
+            let unique_id = if old.inode == ROOT_INODE {
+                0
+            } else {
+                next_unique_id(&mut unique_ids, raft_group, num_raft_groups)
+            };
+            let attrs = InodeAttributes {
+                inode: old.inode,
+                size: old.size,
+                last_accessed: old.last_accessed,
+                last_modified: old.last_modified,
+                last_metadata_changed: old.last_metadata_changed,
+                kind: old.kind,
+                mode: old.mode,
+                hardlinks: old.hardlinks,
+                uid: old.uid,
+                gid: old.gid,
+                rdev: 0,
+                created: old.last_metadata_changed,
+                flags: 0,
+                unique_id,
+            };
+            table.insert(&old.inode, attrs).unwrap();
+        }
+    }
+    txn.commit().unwrap();
+}
+
+// Counts the entries of each directory, in a database created before the counts were stored
+fn migrate_directory_sizes(db: &redb::Database) {
+    {
+        let txn = db.begin_read().unwrap();
+        match txn.open_table(DIRECTORY_SIZES_TABLE) {
+            Ok(_) => return,
+            // A new database has no directory entries, so there's nothing to count
+            Err(_) if txn.open_table(DIRECTORY_TABLE).is_err() => return,
+            Err(_) => {}
+        }
+    }
+
+    let mut sizes: HashMap<Inode, u64> = HashMap::new();
+    let txn = db.begin_write().unwrap();
+    {
+        let directory_table = txn.open_table(DIRECTORY_TABLE).unwrap();
+        for entry in directory_table.iter().unwrap() {
+            let (parent, _) = entry.unwrap().0.value();
+            *sizes.entry(parent).or_default() += 1;
+        }
+        let mut table = txn.open_table(DIRECTORY_SIZES_TABLE).unwrap();
+        for (inode, size) in sizes {
+            table.insert(&inode, size).unwrap();
+        }
+    }
+    txn.commit().unwrap();
+}
+
+fn get_acl(table: &impl ReadableTable<Inode, PosixAcl>, inode: Inode) -> Option<PosixAcl> {
+    table.get(&inode).unwrap().map(|x| x.value())
+}
+
+// Removes the setuid bit, and the setgid bit if group execute is set. Without group execute the
+// setgid bit marks the file for mandatory locking, rather than granting privileges
+fn clear_setid_bits(inode_attrs: &mut InodeAttributes) {
+    inode_attrs.mode &= !libc::S_ISUID as u16;
+    if inode_attrs.mode & libc::S_IXGRP as u16 != 0 {
+        inode_attrs.mode &= !libc::S_ISGID as u16;
+    }
+}
+
+// Adds the session with a lease renewed at the given time, unless it's already known
+fn add_session(txn: &redb::WriteTransaction, session: u64, now: Timestamp) {
+    let mut table = txn.open_table(SESSIONS_TABLE).unwrap();
+    if table.get(&session).unwrap().is_none() {
+        table.insert(&session, now).unwrap();
+    }
+}
+
+fn is_sharded(table: &impl ReadableTable<(Inode, u16), Inode>, inode: Inode) -> bool {
+    table.get((inode, 0)).unwrap().is_some()
+}
+
+fn has_open_handles(txn: &redb::WriteTransaction, inode: Inode) -> bool {
+    let table = txn.open_table(OPEN_HANDLES_TABLE).unwrap();
+    table
+        .range((inode, 0, 0)..=(inode, u64::MAX, u64::MAX))
+        .unwrap()
+        .next()
+        .is_some()
+}
+
+// Deletes the inode, if it has been unlinked and its last handle has been released.
+// Returns the inode, if its data should be deleted
+fn delete_if_orphaned(txn: &redb::WriteTransaction, inode: Inode) -> Option<Inode> {
+    let attrs = {
+        let attr_table = txn.open_table(ATTR_TABLE).unwrap();
+        attr_table.get(&inode).unwrap()?.value()
+    };
+    if attrs.hardlinks == 0 && !has_open_handles(txn, inode) {
+        delete_inode(txn, &attrs)
+    } else {
+        None
+    }
+}
+
+// Returns the inode, if its data should be deleted
+fn delete_inode(txn: &redb::WriteTransaction, inode_attrs: &InodeAttributes) -> Option<Inode> {
+    let inode = inode_attrs.inode;
+    {
+        let mut attr_table = txn.open_table(ATTR_TABLE).unwrap();
+        attr_table.remove(&inode).unwrap();
+    }
+    {
+        let mut table = txn.open_table(XATTR_TABLE).unwrap();
+        table
+            .retain_in((inode, "")..(inode + 1, ""), |_, _| false)
+            .unwrap();
+    }
+    {
+        let mut table = txn.open_table(ACCESS_ACL_TABLE).unwrap();
+        table.remove(&inode).unwrap();
+        let mut table = txn.open_table(DEFAULT_ACL_TABLE).unwrap();
+        table.remove(&inode).unwrap();
+    }
+    if inode_attrs.kind == FileKind::Directory {
+        {
+            let mut table = txn.open_table(PARENTS_TABLE).unwrap();
+            table.remove(&inode).unwrap();
+            let mut table = txn.open_table(DIRECTORY_SHARDS_TABLE).unwrap();
+            table
+                .retain_in((inode, 0)..=(inode, u16::MAX), |_, _| false)
+                .unwrap();
+            let mut table = txn.open_table(SHARD_PARENTS_TABLE).unwrap();
+            table.remove(&inode).unwrap();
+            let mut table = txn.open_table(DIRECTORY_SIZES_TABLE).unwrap();
+            table.remove(&inode).unwrap();
+        }
+
+        {
+            let directory_table = txn.open_table(DIRECTORY_TABLE).unwrap();
+            let mut entries = directory_table.range((inode, "")..(inode + 1, "")).unwrap();
+            assert!(
+                entries.next().is_none(),
+                "Deleted a non-empty directory inode"
+            );
+        }
+        None
+    } else {
+        stores_data(inode_attrs.kind).then_some(inode)
+    }
+}
+
+// Directories, FIFOs, sockets and device nodes don't have a data file, since their contents aren't
+// stored by the filesystem
+pub(super) fn stores_data(kind: FileKind) -> bool {
+    matches!(kind, FileKind::File | FileKind::Symlink)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{
+        DirectoryPage, ErrorCode, FS_APPEND_FL, FS_IMMUTABLE_FL, FileKind, OpenHandleId, RangeLock,
+        RangeLockKind, SESSION_LEASE_SECONDS, Timestamp, UserContext, pack_groups,
+    };
+    use crate::storage::local::metadata_storage::{
+        DIRECTORY_SHARD_THRESHOLD, DIRECTORY_SIZES_TABLE, EXPIRED_REQUESTS_PER_BEGIN, EntryError,
+        LEGACY_ATTR_TABLE, MetadataStorage, REQUEST_EXPIRY_TABLE, REQUEST_LEASE_SECONDS,
+        REQUEST_RESULT_RETENTION_SECONDS, ROOT_INODE, RequestState, legacy,
+    };
+    use redb::{ReadableDatabase, ReadableTableMetadata};
+    use tempfile::{TempDir, tempdir};
+
+    // Storage for the only raft group, in a temporary directory that's deleted when dropped
+    fn new_storage() -> (MetadataStorage, TempDir) {
+        let dir = tempdir().unwrap();
+        (MetadataStorage::new(0, 1, dir.path()), dir)
+    }
+
+    #[test]
+    fn migrates_legacy_attributes() {
+        let dir = tempdir().unwrap();
+        {
+            let db = redb::Database::create(dir.path().join("metadata.redb")).unwrap();
+            let txn = db.begin_write().unwrap();
+            {
+                let mut table = txn.open_table(LEGACY_ATTR_TABLE).unwrap();
+                let changed = Timestamp::new(1000, 5);
+                let attrs = legacy::InodeAttributes {
+                    inode: ROOT_INODE + 1,
+                    size: 10,
+                    last_accessed: changed,
+                    last_modified: changed,
+                    last_metadata_changed: changed,
+                    kind: FileKind::File,
+                    mode: 0o640,
+                    hardlinks: 1,
+                    uid: 1,
+                    gid: 2,
+                };
+                table.insert(&(ROOT_INODE + 1), attrs).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+
+        let storage = MetadataStorage::new(0, 1, dir.path());
+        let (attrs, _) = storage.get_attributes(ROOT_INODE + 1).unwrap();
+        assert_eq!(attrs.size, 10);
+        assert_eq!(attrs.mode, 0o640);
+        assert_eq!(attrs.created, Timestamp::new(1000, 5));
+        assert_eq!(attrs.flags, 0);
+        assert_ne!(attrs.unique_id, 0);
+    }
+
+    #[test]
+    fn paginated_listing() {
+        let (storage, _dir) = new_storage();
+        let root = UserContext::new(0, 0);
+        for name in ["a", "b", "c"] {
+            let (inode, _) = storage
+                .create_inode(ROOT_INODE, 0, 0, 0o644, FileKind::File, 0, None, None, None)
+                .unwrap();
+            storage
+                .create_link(inode, ROOT_INODE, name, root, FileKind::File)
+                .unwrap();
+        }
+        let names = |(entries, resume_after): DirectoryPage<(u64, String, FileKind)>| {
+            let names: Vec<String> = entries.into_iter().map(|(_, name, _)| name).collect();
+            (names, resume_after)
+        };
+
+        // The limit is raised so that "." and ".." fit, and resuming after them uses an empty name
+        assert_eq!(
+            names(storage.readdir(ROOT_INODE, None, 1).unwrap()),
+            (vec![".".to_string(), "..".to_string()], Some(String::new()))
+        );
+        assert_eq!(
+            names(storage.readdir(ROOT_INODE, Some(""), 2).unwrap()),
+            (
+                vec!["a".to_string(), "b".to_string()],
+                Some("b".to_string())
+            )
+        );
+        // A full page which reaches the end of the directory is the last one
+        assert_eq!(
+            names(storage.readdir(ROOT_INODE, Some("b"), 1).unwrap()),
+            (vec!["c".to_string()], None)
+        );
+    }
+
+    #[test]
+    fn unlinked_inode_lifecycle() {
+        let (storage, _dir) = new_storage();
+        let root = UserContext::new(0, 0);
+        let exists = |inode| storage.get_attributes(inode).is_ok();
+        let create_temporary = |handle| {
+            let open_handle = OpenHandleId { session: 7, handle };
+            storage
+                .create_inode(
+                    ROOT_INODE,
+                    0,
+                    0,
+                    0o644,
+                    FileKind::File,
+                    0,
+                    Some(open_handle),
+                    None,
+                    None,
+                )
+                .unwrap()
+        };
+
+        // An unnamed file is deleted when its handle is released
+        let (temporary, attrs) = create_temporary(1);
+        assert_eq!(attrs.hardlinks, 0);
+        assert_eq!(storage.release_handle(temporary, 7, 1), Ok(Some(temporary)));
+        assert!(!exists(temporary));
+
+        // Unless it was linked into place first
+        let (temporary, _) = create_temporary(2);
+        storage.hardlink_stage0_link_increment(temporary).unwrap();
+        storage
+            .create_link(temporary, ROOT_INODE, "linked", root, FileKind::File)
+            .unwrap();
+        assert_eq!(storage.release_handle(temporary, 7, 2), Ok(None));
+        assert!(exists(temporary));
+
+        // An unlinked file is kept until the session which has it open is released
+        storage.open_handle(temporary, 7, 3).unwrap();
+        assert_eq!(storage.decrement_inode_link_count(temporary, 1), Ok(None));
+        assert!(exists(temporary));
+        assert_eq!(storage.release_session(7), Ok(vec![temporary]));
+        assert!(!exists(temporary));
+    }
+
+    #[test]
+    fn directory_sharding() {
+        let dir = tempdir().unwrap();
+        let storage = MetadataStorage::new(0, 2, dir.path());
+        let root = UserContext::new(0, 0);
+        let create = |parent, kind, shard_of| {
+            storage
+                .create_inode(parent, 0, 0, 0o755, kind, 0, None, None, shard_of)
+                .unwrap()
+                .0
+        };
+        let entries = |inode| storage.get_attributes(inode).unwrap().1;
+        let directory = create(ROOT_INODE, FileKind::Directory, None);
+        storage
+            .create_link(directory, ROOT_INODE, "big", root, FileKind::Directory)
+            .unwrap();
+        let file = create(directory, FileKind::File, None);
+        storage
+            .create_link(file, directory, "old", root, FileKind::File)
+            .unwrap();
+        assert_eq!(entries(directory), Some(1));
+
+        // The directory is split once it reaches the threshold
+        {
+            let db = storage.storage.lock().unwrap();
+            let txn = db.begin_write().unwrap();
+            let mut table = txn.open_table(DIRECTORY_SIZES_TABLE).unwrap();
+            table.insert(&directory, DIRECTORY_SHARD_THRESHOLD).unwrap();
+            drop(table);
+            txn.commit().unwrap();
+        }
+        let new_file = create(directory, FileKind::File, None);
+        assert_eq!(
+            storage.create_link(new_file, directory, "new", root, FileKind::File),
+            Err(EntryError::NeedsSharding)
+        );
+        let shard = create(directory, FileKind::Directory, Some(directory));
+        storage.shard_directory(directory, &[shard]).unwrap();
+        assert_eq!(storage.directory_shards(directory), Ok(vec![shard]));
+        assert_eq!(storage.shard_parent(shard), Ok(Some(directory)));
+
+        // New entries go in the shard, and entries created before the split stay in the directory
+        assert_eq!(
+            storage.create_link(new_file, directory, "new", root, FileKind::File),
+            Err(EntryError::Sharded)
+        );
+        assert_eq!(
+            storage.lookup(directory, "new", root),
+            Err(EntryError::Sharded)
+        );
+        storage
+            .create_link(new_file, shard, "new", root, FileKind::File)
+            .unwrap();
+        assert_eq!(storage.lookup(shard, "new", root), Ok(Some(new_file)));
+        assert_eq!(storage.lookup(directory, "old", root), Ok(Some(file)));
+        assert_eq!(entries(shard), Some(1));
+        assert_eq!(
+            storage.remove_link(directory, "new", None, root),
+            Err(EntryError::Sharded)
+        );
+
+        // Removing the entries empties the directory and its shard, so they can be deleted
+        storage.remove_link(shard, "new", None, root).unwrap();
+        storage.remove_link(directory, "old", None, root).unwrap();
+        assert_eq!(entries(shard), Some(0));
+        assert_eq!(
+            entries(directory),
+            Some(DIRECTORY_SHARD_THRESHOLD as u32 - 1)
+        );
+        storage.decrement_inode_link_count(shard, 2).unwrap();
+        storage.remove_link(ROOT_INODE, "big", None, root).unwrap();
+        storage.decrement_inode_link_count(directory, 2).unwrap();
+        assert!(storage.get_attributes(directory).is_err());
+        assert_eq!(storage.directory_shards(directory), Ok(vec![]));
+    }
+
+    #[test]
+    fn migrates_directory_sizes() {
+        let dir = tempdir().unwrap();
+        let root = UserContext::new(0, 0);
+        {
+            let storage = MetadataStorage::new(0, 1, dir.path());
+            for name in ["a", "b"] {
+                let (inode, _) = storage
+                    .create_inode(ROOT_INODE, 0, 0, 0o644, FileKind::File, 0, None, None, None)
+                    .unwrap();
+                storage
+                    .create_link(inode, ROOT_INODE, name, root, FileKind::File)
+                    .unwrap();
+            }
+            let db = storage.storage.lock().unwrap();
+            let txn = db.begin_write().unwrap();
+            txn.delete_table(DIRECTORY_SIZES_TABLE).unwrap();
+            txn.commit().unwrap();
+        }
+
+        let storage = MetadataStorage::new(0, 1, dir.path());
+        assert_eq!(storage.get_attributes(ROOT_INODE).unwrap().1, Some(2));
+    }
+
+    #[test]
+    fn protected_flags() {
+        let (storage, _dir) = new_storage();
+        let (inode, _) = storage
+            .create_inode(ROOT_INODE, 1, 1, 0o644, FileKind::File, 0, None, None, None)
+            .unwrap();
+        let owner = UserContext::new(1, 1);
+        let root = UserContext::new(0, 0);
+
+        assert_eq!(
+            storage.set_flags(inode, FS_APPEND_FL, owner),
+            Err(ErrorCode::OperationNotPermitted)
+        );
+        storage.set_flags(inode, FS_APPEND_FL, root).unwrap();
+        storage.write(inode, 0, 10, 1, false).unwrap();
+        assert_eq!(
+            storage.write(inode, 5, 10, 1, false),
+            Err(ErrorCode::OperationNotPermitted)
+        );
+        storage.write(inode, 10, 10, 1, false).unwrap();
+        // Appends are written at the end of the file, whatever offset they were sent with
+        assert_eq!(storage.write(inode, 0, 10, 1, true), Ok(20));
+        assert_eq!(
+            storage.chmod(inode, 0o600, owner),
+            Err(ErrorCode::OperationNotPermitted)
+        );
+
+        storage.set_flags(inode, FS_IMMUTABLE_FL, root).unwrap();
+        assert_eq!(
+            storage.write(inode, 20, 10, 1, false),
+            Err(ErrorCode::OperationNotPermitted)
+        );
+        storage.set_flags(inode, 0, root).unwrap();
+        storage.chmod(inode, 0o600, owner).unwrap();
+    }
+
+    #[test]
+    fn request_results() {
+        let (storage, _dir) = new_storage();
+        storage.set_commit_time(Timestamp::new(1000, 0));
+        let begin = |sequence, acknowledged| storage.begin_request(7, sequence, acknowledged, 1);
+
+        assert!(matches!(begin(0, 0), Ok(RequestState::New)));
+        assert!(matches!(begin(0, 0), Ok(RequestState::InProgress)));
+        storage.finish_request(7, 0, 1, b"result").unwrap();
+        assert!(matches!(begin(0, 0), Ok(RequestState::Finished(result)) if result == b"result"));
+
+        // Acknowledging the request drops its result
+        assert!(matches!(begin(1, 1), Ok(RequestState::New)));
+        assert!(matches!(begin(0, 0), Ok(RequestState::New)));
+
+        // Results expire, even if they're never acknowledged
+        storage.finish_request(7, 1, 1, b"result").unwrap();
+        storage.set_commit_time(Timestamp::new(1001 + REQUEST_RESULT_RETENTION_SECONDS, 0));
+        assert!(matches!(begin(2, 0), Ok(RequestState::New)));
+        assert!(matches!(begin(1, 0), Ok(RequestState::New)));
+    }
+
+    #[test]
+    fn request_lease_taken_over() {
+        let (storage, _dir) = new_storage();
+        storage.set_commit_time(Timestamp::new(1000, 0));
+        let begin = |owner| storage.begin_request(7, 0, 0, owner);
+
+        assert!(matches!(begin(1), Ok(RequestState::New)));
+        assert!(matches!(begin(2), Ok(RequestState::InProgress)));
+
+        // The first coordinator didn't finish in time, so the retry takes the request over
+        storage.set_commit_time(Timestamp::new(1000 + REQUEST_LEASE_SECONDS, 0));
+        assert!(matches!(begin(2), Ok(RequestState::New)));
+        assert!(matches!(begin(3), Ok(RequestState::InProgress)));
+        storage.finish_request(7, 0, 1, b"stale").unwrap();
+        assert!(matches!(begin(3), Ok(RequestState::InProgress)));
+        storage.finish_request(7, 0, 2, b"result").unwrap();
+        assert!(matches!(begin(3), Ok(RequestState::Finished(result)) if result == b"result"));
+    }
+
+    #[test]
+    fn expired_requests_dropped_in_batches() {
+        let (storage, _dir) = new_storage();
+        storage.set_commit_time(Timestamp::new(1000, 0));
+        let requests = 2 * EXPIRED_REQUESTS_PER_BEGIN as u64;
+        for session in 0..requests {
+            storage.begin_request(session, 0, 0, 1).unwrap();
+            storage.finish_request(session, 0, 1, b"result").unwrap();
+        }
+        let expiring = || {
+            let db = storage.storage.lock().unwrap();
+            let txn = db.begin_read().unwrap();
+            txn.open_table(REQUEST_EXPIRY_TABLE).unwrap().len().unwrap()
+        };
+        assert_eq!(expiring(), requests);
+
+        storage.set_commit_time(Timestamp::new(1000 + REQUEST_RESULT_RETENTION_SECONDS, 0));
+        storage.begin_request(requests, 0, 0, 1).unwrap();
+        assert_eq!(expiring(), requests + 1 - EXPIRED_REQUESTS_PER_BEGIN as u64);
+        storage.begin_request(requests + 1, 0, 0, 1).unwrap();
+        assert_eq!(expiring(), 2);
+    }
+
+    #[test]
+    fn access_time_modes() {
+        let (storage, _dir) = new_storage();
+        storage.set_commit_time(Timestamp::new(1000, 0));
+        let (inode, _) = storage
+            .create_inode(ROOT_INODE, 0, 0, 0o644, FileKind::File, 0, None, None, None)
+            .unwrap();
+        let accessed = |seconds, strict| {
+            storage.set_commit_time(Timestamp::new(seconds, 0));
+            storage.update_access_times(&[inode], strict).unwrap();
+            storage
+                .get_attributes(inode)
+                .unwrap()
+                .0
+                .last_accessed
+                .seconds
+        };
+
+        // relatime updates the access time if it isn't after the modify time
+        assert_eq!(accessed(1010, false), 1010);
+        assert_eq!(accessed(1020, false), 1010);
+        // or if the file was modified since
+        storage.set_commit_time(Timestamp::new(1030, 0));
+        storage.write(inode, 0, 1, 0, false).unwrap();
+        assert_eq!(accessed(1040, false), 1040);
+        // or if it's more than a day old
+        assert_eq!(accessed(1040 + 24 * 60 * 60 - 1, false), 1040);
+        assert_eq!(accessed(1040 + 24 * 60 * 60, false), 1040 + 24 * 60 * 60);
+
+        // strictatime always updates it
+        assert_eq!(accessed(100_000, true), 100_000);
+        assert_eq!(accessed(100_001, true), 100_001);
+    }
+
+    #[test]
+    fn session_leases() {
+        let (storage, _dir) = new_storage();
+        storage.set_commit_time(Timestamp::new(1000, 0));
+        let lock = RangeLock {
+            session: 7,
+            owner: 1,
+            pid: 2,
+            start: 0,
+            end: 10,
+            kind: RangeLockKind::Write,
+        };
+        let waiter = RangeLock {
+            session: 8,
+            kind: RangeLockKind::Read,
+            ..lock
+        };
+        storage
+            .store_range_locks(ROOT_INODE, &[lock], &[waiter])
+            .unwrap();
+        assert_eq!(
+            storage.range_locks().unwrap(),
+            vec![(ROOT_INODE, lock, false), (ROOT_INODE, waiter, true)]
+        );
+
+        // Only the session which wasn't renewed expires
+        storage.set_commit_time(Timestamp::new(1000 + SESSION_LEASE_SECONDS, 0));
+        storage.renew_session(8).unwrap();
+        assert!(storage.expired_sessions().unwrap().is_empty());
+        storage.set_commit_time(Timestamp::new(1001 + SESSION_LEASE_SECONDS, 0));
+        assert_eq!(storage.expired_sessions().unwrap(), vec![7]);
+
+        storage.release_session(7).unwrap();
+        storage
+            .store_range_locks(ROOT_INODE, &[waiter], &[])
+            .unwrap();
+        assert!(storage.expired_sessions().unwrap().is_empty());
+        assert_eq!(
+            storage.range_locks().unwrap(),
+            vec![(ROOT_INODE, waiter, false)]
+        );
+    }
+
+    #[test]
+    fn supplementary_groups() {
+        let (storage, _dir) = new_storage();
+        let (inode, _) = storage
+            .create_inode(ROOT_INODE, 1, 1, 0o640, FileKind::File, 0, None, None, None)
+            .unwrap();
+        let groups = pack_groups(&[5, 1]);
+        let member = UserContext::with_groups(2, 2, &groups);
+
+        assert_eq!(
+            storage.access(inode, libc::R_OK, UserContext::new(2, 2)),
+            Err(ErrorCode::AccessDenied)
+        );
+        storage.access(inode, libc::R_OK, member).unwrap();
+        assert_eq!(
+            storage.access(inode, libc::W_OK, member),
+            Err(ErrorCode::AccessDenied)
+        );
+
+        // The owner can only change the group to one they're in
+        let owner = UserContext::with_groups(1, 1, &groups);
+        storage.chown(inode, None, Some(5), owner).unwrap();
+        assert_eq!(
+            storage.chown(inode, None, Some(6), owner),
+            Err(ErrorCode::OperationNotPermitted)
+        );
+    }
+
+    #[test]
+    fn setid_bits_cleared() {
+        let (storage, _dir) = new_storage();
+        let mode = |inode| storage.get_attributes(inode).unwrap().0.mode;
+        let (inode, _) = storage
+            .create_inode(
+                ROOT_INODE,
+                1,
+                1,
+                0o6755,
+                FileKind::File,
+                0,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+
+        storage.write(inode, 0, 10, 0, false).unwrap();
+        assert_eq!(mode(inode), 0o6755);
+        storage.write(inode, 0, 10, 2, false).unwrap();
+        assert_eq!(mode(inode), 0o755);
+
+        storage
+            .chmod(inode, 0o6755, UserContext::new(1, 1))
+            .unwrap();
+        storage
+            .chown(inode, None, Some(1), UserContext::new(0, 0))
+            .unwrap();
+        assert_eq!(mode(inode), 0o755);
+
+        // Without group execute, the setgid bit marks the file for mandatory locking, so it's kept
+        storage
+            .chmod(inode, 0o2644, UserContext::new(1, 1))
+            .unwrap();
+        storage.write(inode, 0, 10, 2, false).unwrap();
+        assert_eq!(mode(inode), 0o2644);
+    }
 }
@@ -4,4 +4,6 @@ mod file_storage;
 mod metadata_storage;
 
 pub use file_storage::FileStorage;
-pub use metadata_storage::ROOT_INODE;
+#[cfg(test)]
+pub use metadata_storage::DIRECTORY_SHARD_THRESHOLD;
+pub use metadata_storage::{LOCAL_REQUEST_OWNER, MAX_LISTING_ENTRIES, ROOT_INODE};
@@ -1,121 +1,593 @@
-use crate::base::{AccessType, RequestMetaInfo};
+use crate::base::{AccessType, LockMode, RangeLock, RangeLockKind, RequestMetaInfo};
 use crate::base::{ErrorCode, Request, Response, decode_request};
 use futures::channel::oneshot::Sender;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
-pub enum FileLockType {
-    // File metadata (including directory structure) is locked for writing, but reads are still allowed
-    ExclusiveMetadataWriteConcurrentReadsAllowed,
+// What a lock protects. Directory entries are locked separately from their directory, so that
+// transactions which touch different names in the same directory can run concurrently
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum LockKey {
+    // The inode's metadata, including all of its directory entries if it's a directory
+    Inode(u64),
+    // A single entry in a directory inode
+    Entry(u64, String),
+}
+
+impl LockKey {
+    fn new(inode: u64, entry: Option<&str>) -> LockKey {
+        match entry {
+            None => LockKey::Inode(inode),
+            Some(name) => LockKey::Entry(inode, name.to_string()),
+        }
+    }
+
+    fn inode(&self) -> u64 {
+        match self {
+            LockKey::Inode(inode) | LockKey::Entry(inode, _) => *inode,
+        }
+    }
+}
+
+// What a request needs from the lock table before it can proceed
+struct Claim<'a> {
+    inode: u64,
+    entry: Option<&'a str>,
+    mode: LockMode,
+    // Lock requests acquire the lock. Other requests only need to not conflict with held locks
+    acquire: bool,
+}
+
+impl Claim<'_> {
+    fn from_meta(meta: &RequestMetaInfo) -> Option<Claim<'_>> {
+        let (mode, acquire) = match meta.access_type {
+            AccessType::ReadData => {
+                unreachable!("Read requests aren't implemented for locks yet")
+            }
+            AccessType::ReadMetadata => {
+                unreachable!("Read requests aren't implemented for locks yet")
+            }
+            AccessType::LockMetadata(mode) => (mode, true),
+            AccessType::WriteMetadata => (LockMode::Exclusive, false),
+            AccessType::WriteDataAndMetadata => (LockMode::Exclusive, false),
+            AccessType::NoAccess => return None,
+        };
+
+        Some(Claim {
+            inode: meta.inode?,
+            entry: meta.entry.as_deref(),
+            mode,
+            acquire,
+        })
+    }
+
+    // Returns true if this claim can't proceed while a lock in the given mode is held on key
+    fn conflicts_with(&self, key: &LockKey, mode: LockMode) -> bool {
+        if key.inode() != self.inode {
+            return false;
+        }
+        let either_exclusive = self.mode == LockMode::Exclusive || mode == LockMode::Exclusive;
+        match (self.entry, key) {
+            // A shared lock on the directory only protects its own attributes, so entries can
+            // still be modified, unless the whole directory is locked exclusively
+            (Some(_), LockKey::Inode(_)) => mode == LockMode::Exclusive,
+            (Some(name), LockKey::Entry(_, locked_name)) => name == locked_name && either_exclusive,
+            (None, LockKey::Inode(_)) => either_exclusive,
+            // Entry locks only conflict with locking the whole directory exclusively
+            (None, LockKey::Entry(..)) => self.acquire && self.mode == LockMode::Exclusive,
+        }
+    }
 }
 
 type PendingResponse = Sender<Result<Response, ErrorCode>>;
 
 type PendingRequest = (Vec<u8>, Option<PendingResponse>);
 
-// Lock table for tracking file locks
+// A request waiting for a lock to be released. Lock requests keep the lock they're waiting for,
+// so that later requests can be checked against it without decoding the request
+struct WaitingRequest {
+    lock: Option<(LockKey, LockMode)>,
+    request: PendingRequest,
+}
+
+impl WaitingRequest {
+    fn new(request: PendingRequest) -> WaitingRequest {
+        let lock = match decode_request(&request.0).unwrap() {
+            Request::Lock { inode, name, mode } => Some((LockKey::new(inode, name), mode)),
+            _ => None,
+        };
+        WaitingRequest { lock, request }
+    }
+}
+
+// A SetRangeLock request waiting for conflicting locks to be released, and the response to send once it's granted
+type RangeLockWaiter = (RangeLock, Option<PendingResponse>);
+
+// Lock table for tracking inode and directory entry locks
 #[derive(Default)]
 pub struct LockTable {
-    // Requests that are waiting for the keyed inode to be unlocked
-    pending_requests: HashMap<u64, Vec<PendingRequest>>,
-    // Map of inodes to their lock ids, if they are locked
-    lock_ids: HashMap<u64, (u64, FileLockType)>,
+    // Requests that are waiting for a lock on the keyed inode, or one of its entries, to be released.
+    // In the order that they arrived
+    pending_requests: HashMap<u64, Vec<WaitingRequest>>,
+    // Locks which are currently held on each inode and its entries, and the ids of their holders.
+    // Only shared locks have more than one holder
+    held_locks: HashMap<u64, HashMap<LockKey, (LockMode, Vec<u64>)>>,
+    // Map of lock ids to the key they lock
+    lock_keys: HashMap<u64, LockKey>,
     next_id: u64,
+    // POSIX advisory locks held on each inode. These are only advisory, so they don't block any other requests
+    range_locks: HashMap<u64, Vec<RangeLock>>,
+    // Advisory lock requests waiting on each inode. In the order that they arrived
+    range_lock_waiters: HashMap<u64, Vec<RangeLockWaiter>>,
+    // Inodes whose advisory locks or waiting lock requests have changed, and need to be stored
+    changed_range_locks: HashSet<u64>,
 }
 
 impl LockTable {
     pub fn new() -> LockTable {
         LockTable {
             pending_requests: HashMap::new(),
-            lock_ids: HashMap::new(),
+            held_locks: HashMap::new(),
+            lock_keys: HashMap::new(),
             next_id: 0,
+            range_locks: HashMap::new(),
+            range_lock_waiters: HashMap::new(),
+            changed_range_locks: HashSet::new(),
         }
     }
 
-    // XXX: For debugging, panics if the lock is held, held_lock is present, and they don't match.
+    // Returns true if the claim conflicts with a held lock, or with a lock request that is already
+    // waiting. Waiting lock requests count, so that a stream of shared locks can't starve an
+    // exclusive one
+    fn is_blocked(&self, claim: &Claim, waiting: &[WaitingRequest]) -> bool {
+        if let Some(held) = self.held_locks.get(&claim.inode)
+            && held
+                .iter()
+                .any(|(key, (mode, _))| claim.conflicts_with(key, *mode))
+        {
+            return true;
+        }
+
+        waiting.iter().any(|waiting| {
+            waiting
+                .lock
+                .as_ref()
+                .is_some_and(|(key, mode)| claim.conflicts_with(key, *mode))
+        })
+    }
+
+    // XXX: For debugging, panics if lock_id is present, and it doesn't cover the inode.
     // Since it means the client thinks they have locked this inode, or is misbehaving.
     // This could happen if a lock gets revoked, so shouldn't panic in the future, but it
     // makes debugging easier for now.
-    // Returns true if another client has locked this inode
+    // Returns true if another client has locked this inode, or the directory entry being accessed
     pub fn is_locked(&self, meta: &RequestMetaInfo) -> bool {
-        let inode = if let Some(inode) = meta.inode {
-            inode
-        } else {
+        let Some(inode) = meta.inode else {
             return false;
         };
         if let Some(id) = meta.lock_id {
-            assert_eq!(self.lock_ids[&inode].0, id);
+            assert_eq!(self.lock_keys[&id].inode(), inode);
             return false;
         }
-        if !self.lock_ids.contains_key(&inode) {
+        let Some(claim) = Claim::from_meta(meta) else {
             return false;
-        }
-        let (_, lock_type) = &self.lock_ids[&inode];
-        match *lock_type {
-            FileLockType::ExclusiveMetadataWriteConcurrentReadsAllowed => match meta.access_type {
-                AccessType::ReadData => {
-                    unreachable!("Read requests aren't implemented for locks yet")
-                }
-                AccessType::ReadMetadata => {
-                    unreachable!("Read requests aren't implemented for locks yet")
-                }
-                AccessType::LockMetadata => true,
-                AccessType::WriteMetadata => true,
-                AccessType::WriteDataAndMetadata => true,
-                AccessType::NoAccess => false,
-            },
-        }
+        };
+        let waiting = self
+            .pending_requests
+            .get(&inode)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        self.is_blocked(&claim, waiting)
     }
 
     pub fn wait_for_lock(&mut self, inode: u64, request: PendingRequest) {
-        assert!(self.lock_ids.contains_key(&inode));
         self.pending_requests
             .entry(inode)
             .or_default()
-            .push(request);
+            .push(WaitingRequest::new(request));
     }
 
-    // Returns a lock ID
-    pub fn lock(&mut self, inode: u64) -> u64 {
-        assert!(!self.lock_ids.contains_key(&inode));
+    // Returns a lock ID. The caller must have checked that the lock is not held, with is_locked()
+    pub fn lock(&mut self, inode: u64, entry: Option<&str>, mode: LockMode) -> u64 {
+        let key = LockKey::new(inode, entry);
         let lock_id = self.next_id;
         self.next_id += 1;
-        self.lock_ids.insert(
-            inode,
-            (
-                lock_id,
-                FileLockType::ExclusiveMetadataWriteConcurrentReadsAllowed,
-            ),
-        );
+        let (held_mode, holders) = self
+            .held_locks
+            .entry(inode)
+            .or_default()
+            .entry(key.clone())
+            .or_insert((mode, vec![]));
+        assert!(holders.is_empty() || (*held_mode == LockMode::Shared && mode == LockMode::Shared));
+        holders.push(lock_id);
+        self.lock_keys.insert(lock_id, key);
         lock_id
     }
 
-    // Returns a list of requests that were pending to process.
-    // They may precede without re-checking whether the inode is locked.
-    // Iff the last is a Lock request, a lock ID is returned
-    pub fn unlock(&mut self, inode: u64, lock_id: u64) -> (Vec<PendingRequest>, Option<u64>) {
-        let (existing_lock_id, _) = self.lock_ids.remove(&inode).unwrap();
-        assert_eq!(existing_lock_id, lock_id);
-        if !self.pending_requests.contains_key(&inode) {
-            return (vec![], None);
-        }
-
-        let mut lock_id = None;
-        let mut requests_to_process = self.pending_requests[&inode].len();
-        for (i, (data, _)) in self.pending_requests[&inode].iter().enumerate() {
-            let request = decode_request(data).unwrap();
-            if matches!(request, Request::Lock { .. }) {
-                lock_id = Some(self.lock(inode));
-                requests_to_process = i + 1;
-                break;
+    // Returns the requests that were waiting on the inode, and can now proceed.
+    // Lock requests are returned with the ID of the lock which was granted to them.
+    // Other requests may proceed without re-checking whether the inode is locked.
+    pub fn unlock(&mut self, inode: u64, lock_id: u64) -> Vec<(PendingRequest, Option<u64>)> {
+        let key = self.lock_keys.remove(&lock_id).unwrap();
+        assert_eq!(key.inode(), inode);
+        let held = self.held_locks.get_mut(&inode).unwrap();
+        let (_, holders) = held.get_mut(&key).unwrap();
+        holders.retain(|x| *x != lock_id);
+        if holders.is_empty() {
+            held.remove(&key);
+            if held.is_empty() {
+                self.held_locks.remove(&inode);
             }
         }
 
-        let requests = self
-            .pending_requests
-            .get_mut(&inode)
-            .unwrap()
-            .drain(..requests_to_process)
-            .collect();
-        (requests, lock_id)
+        let Some(pending) = self.pending_requests.remove(&inode) else {
+            return vec![];
+        };
+
+        let mut still_waiting = vec![];
+        let mut ready = vec![];
+        for waiting in pending {
+            let request = decode_request(&waiting.request.0).unwrap();
+            let meta = request.meta_info();
+            let claim = Claim::from_meta(&meta).unwrap();
+            if self.is_blocked(&claim, &still_waiting) {
+                still_waiting.push(waiting);
+            } else if let Request::Lock { inode, name, mode } = request {
+                let granted = self.lock(inode, name, mode);
+                ready.push((waiting.request, Some(granted)));
+            } else {
+                ready.push((waiting.request, None));
+            }
+        }
+        if !still_waiting.is_empty() {
+            self.pending_requests.insert(inode, still_waiting);
+        }
+
+        ready
+    }
+
+    // Returns the first held advisory lock which conflicts with the given one
+    pub fn get_range_lock(&self, inode: u64, lock: &RangeLock) -> Option<RangeLock> {
+        self.range_locks
+            .get(&inode)?
+            .iter()
+            .find(|held| held.conflicts_with(lock))
+            .copied()
+    }
+
+    // Returns the responses which can be sent now. That may include the response to this request,
+    // and to waiting requests which were granted because this one released a lock
+    pub fn set_range_lock(
+        &mut self,
+        inode: u64,
+        lock: RangeLock,
+        wait: bool,
+        response: Option<PendingResponse>,
+    ) -> Vec<(Option<PendingResponse>, Result<Response, ErrorCode>)> {
+        if lock.kind != RangeLockKind::Unlock && self.get_range_lock(inode, &lock).is_some() {
+            if wait {
+                // TODO: detect deadlocks, and return EDEADLK
+                self.range_lock_waiters
+                    .entry(inode)
+                    .or_default()
+                    .push((lock, response));
+                self.changed_range_locks.insert(inode);
+                return vec![];
+            } else {
+                return vec![(response, Err(ErrorCode::WouldBlock))];
+            }
+        }
+
+        self.apply_range_lock(inode, lock);
+        self.changed_range_locks.insert(inode);
+        let mut responses = vec![(response, Ok(Response::Empty))];
+        responses.extend(self.grant_range_lock_waiters(inode));
+
+        responses
+    }
+
+    // Releases all the advisory locks held by the session, and drops its waiting requests.
+    // Returns the responses for waiting requests of other sessions which can now be granted
+    pub fn release_session(
+        &mut self,
+        session: u64,
+    ) -> Vec<(Option<PendingResponse>, Result<Response, ErrorCode>)> {
+        for (inode, waiters) in self.range_lock_waiters.iter_mut() {
+            let before = waiters.len();
+            waiters.retain(|(lock, _)| lock.session != session);
+            if waiters.len() != before {
+                self.changed_range_locks.insert(*inode);
+            }
+        }
+        self.range_lock_waiters
+            .retain(|_, waiters| !waiters.is_empty());
+
+        let mut released = vec![];
+        for (inode, locks) in self.range_locks.iter_mut() {
+            let before = locks.len();
+            locks.retain(|lock| lock.session != session);
+            if locks.len() != before {
+                released.push(*inode);
+                self.changed_range_locks.insert(*inode);
+            }
+        }
+        self.range_locks.retain(|_, locks| !locks.is_empty());
+
+        released
+            .into_iter()
+            .flat_map(|inode| self.grant_range_lock_waiters(inode))
+            .collect()
+    }
+
+    // Drops the owner's waiting requests for the lock's range. Returns the responses to them, which
+    // fail with Interrupted
+    pub fn cancel_range_lock_wait(
+        &mut self,
+        inode: u64,
+        lock: RangeLock,
+    ) -> Vec<(Option<PendingResponse>, Result<Response, ErrorCode>)> {
+        let Some(waiters) = self.range_lock_waiters.remove(&inode) else {
+            return vec![];
+        };
+        let (cancelled, still_waiting): (Vec<_>, Vec<_>) =
+            waiters.into_iter().partition(|(waiting, _)| {
+                waiting.same_owner(&lock) && waiting.start == lock.start && waiting.end == lock.end
+            });
+        if !still_waiting.is_empty() {
+            self.range_lock_waiters.insert(inode, still_waiting);
+        }
+        if !cancelled.is_empty() {
+            self.changed_range_locks.insert(inode);
+        }
+
+        cancelled
+            .into_iter()
+            .map(|(_, response)| (response, Err(ErrorCode::Interrupted)))
+            .collect()
+    }
+
+    // Returns the held advisory locks and waiting lock requests of each inode which changed since
+    // the last call, so that they can be stored
+    #[allow(clippy::type_complexity)]
+    pub fn take_changed_range_locks(&mut self) -> Vec<(u64, Vec<RangeLock>, Vec<RangeLock>)> {
+        self.changed_range_locks
+            .drain()
+            .map(|inode| {
+                let held = self.range_locks.get(&inode).cloned().unwrap_or_default();
+                let waiting = self
+                    .range_lock_waiters
+                    .get(&inode)
+                    .map(|waiters| waiters.iter().map(|(lock, _)| *lock).collect())
+                    .unwrap_or_default();
+                (inode, held, waiting)
+            })
+            .collect()
+    }
+
+    // Restores a stored advisory lock, or waiting lock request. Nothing is waiting for the response
+    // to a restored request, since the client's connection was lost
+    pub fn restore_range_lock(&mut self, inode: u64, lock: RangeLock, waiting: bool) {
+        if waiting {
+            self.range_lock_waiters
+                .entry(inode)
+                .or_default()
+                .push((lock, None));
+        } else {
+            self.range_locks.entry(inode).or_default().push(lock);
+        }
+    }
+
+    // Replaces the owner's locks in the range with the given lock. Unlock removes them
+    fn apply_range_lock(&mut self, inode: u64, lock: RangeLock) {
+        let mut locks = vec![];
+        for existing in self.range_locks.remove(&inode).unwrap_or_default() {
+            if !existing.same_owner(&lock) || !existing.overlaps(&lock) {
+                locks.push(existing);
+                continue;
+            }
+            // Keep the parts of the existing lock which are outside of the new range
+            if existing.start < lock.start {
+                locks.push(RangeLock {
+                    end: lock.start - 1,
+                    ..existing
+                });
+            }
+            if existing.end > lock.end {
+                locks.push(RangeLock {
+                    start: lock.end + 1,
+                    ..existing
+                });
+            }
+        }
+        if lock.kind != RangeLockKind::Unlock {
+            locks.push(lock);
+        }
+        if !locks.is_empty() {
+            self.range_locks.insert(inode, locks);
+        }
+    }
+
+    fn grant_range_lock_waiters(
+        &mut self,
+        inode: u64,
+    ) -> Vec<(Option<PendingResponse>, Result<Response, ErrorCode>)> {
+        let Some(waiters) = self.range_lock_waiters.remove(&inode) else {
+            return vec![];
+        };
+
+        let mut still_waiting = vec![];
+        let mut granted = vec![];
+        for (lock, response) in waiters {
+            if self.get_range_lock(inode, &lock).is_some() {
+                still_waiting.push((lock, response));
+            } else {
+                self.apply_range_lock(inode, lock);
+                granted.push((response, Ok(Response::Empty)));
+            }
+        }
+        if !still_waiting.is_empty() {
+            self.range_lock_waiters.insert(inode, still_waiting);
+        }
+
+        granted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{
+        ErrorCode, LockMode, RangeLock, RangeLockKind, Request, UserContext, encode_request,
+    };
+    use crate::storage::lock_table::LockTable;
+
+    fn create_link(parent: u64, name: &str) -> Vec<u8> {
+        encode_request(&Request::CreateLink {
+            parent,
+            name,
+            inode: 5,
+            kind: crate::base::FileKind::File,
+            lock_id: None,
+            context: UserContext::new(0, 0),
+        })
+    }
+
+    fn lock(inode: u64, name: Option<&str>, mode: LockMode) -> Vec<u8> {
+        encode_request(&Request::Lock { inode, name, mode })
+    }
+
+    fn is_locked(table: &LockTable, data: &[u8]) -> bool {
+        let request = crate::base::decode_request(data).unwrap();
+        table.is_locked(&request.meta_info())
+    }
+
+    #[test]
+    fn shared_directory_lock_allows_other_entries() {
+        let mut table = LockTable::new();
+        table.lock(1, None, LockMode::Shared);
+        let entry_lock = table.lock(1, Some("a"), LockMode::Exclusive);
+
+        assert!(!is_locked(&table, &create_link(1, "b")));
+        assert!(is_locked(&table, &create_link(1, "a")));
+        assert!(!is_locked(&table, &lock(1, None, LockMode::Shared)));
+        assert!(is_locked(&table, &lock(1, None, LockMode::Exclusive)));
+
+        table.wait_for_lock(1, (create_link(1, "a"), None));
+        let ready = table.unlock(1, entry_lock);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].1, None);
+    }
+
+    #[test]
+    fn waiting_exclusive_lock_is_not_starved() {
+        let mut table = LockTable::new();
+        let shared = table.lock(1, None, LockMode::Shared);
+
+        let exclusive = lock(1, None, LockMode::Exclusive);
+        assert!(is_locked(&table, &exclusive));
+        table.wait_for_lock(1, (exclusive, None));
+
+        // A new shared lock has to queue behind the exclusive one
+        let second_shared = lock(1, None, LockMode::Shared);
+        assert!(is_locked(&table, &second_shared));
+        table.wait_for_lock(1, (second_shared, None));
+
+        let ready = table.unlock(1, shared);
+        assert_eq!(ready.len(), 1);
+        let exclusive_id = ready[0].1.unwrap();
+
+        let ready = table.unlock(1, exclusive_id);
+        assert_eq!(ready.len(), 1);
+        assert!(ready[0].1.is_some());
+    }
+
+    #[test]
+    fn locks_only_block_their_own_inode() {
+        let mut table = LockTable::new();
+        let exclusive = table.lock(1, None, LockMode::Exclusive);
+        table.wait_for_lock(1, (lock(1, Some("a"), LockMode::Exclusive), None));
+
+        assert!(is_locked(&table, &create_link(1, "b")));
+        assert!(!is_locked(&table, &create_link(2, "a")));
+        assert!(!is_locked(&table, &lock(2, None, LockMode::Exclusive)));
+
+        let ready = table.unlock(1, exclusive);
+        assert_eq!(ready.len(), 1);
+        // The granted entry lock only blocks its own entry
+        assert!(is_locked(&table, &create_link(1, "a")));
+        assert!(!is_locked(&table, &create_link(1, "b")));
+    }
+
+    fn range_lock(session: u64, start: u64, end: u64, kind: RangeLockKind) -> RangeLock {
+        RangeLock {
+            session,
+            owner: 1,
+            pid: 1,
+            start,
+            end,
+            kind,
+        }
+    }
+
+    #[test]
+    fn range_lock_waits_for_unlock() {
+        let mut table = LockTable::new();
+        let write = range_lock(1, 0, 99, RangeLockKind::Write);
+        assert_eq!(table.set_range_lock(1, write, false, None).len(), 1);
+
+        // Unlocking the middle of the range leaves the two ends locked
+        let unlock = range_lock(1, 10, 19, RangeLockKind::Unlock);
+        table.set_range_lock(1, unlock, false, None);
+        let read = range_lock(2, 10, 19, RangeLockKind::Read);
+        assert_eq!(table.get_range_lock(1, &read), None);
+        let read = range_lock(2, 0, 19, RangeLockKind::Read);
+        assert_eq!(table.get_range_lock(1, &read).unwrap().end, 9);
+
+        let responses = table.set_range_lock(1, read, false, None);
+        assert!(matches!(responses[0].1, Err(ErrorCode::WouldBlock)));
+        assert!(table.set_range_lock(1, read, true, None).is_empty());
+
+        // Releasing the first session grants the waiting lock
+        let responses = table.release_session(1);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].1.is_ok());
+        assert_eq!(table.get_range_lock(1, &write), Some(read));
+    }
+
+    #[test]
+    fn cancelled_range_lock_wait_is_dequeued() {
+        let mut table = LockTable::new();
+        let write = range_lock(1, 0, 99, RangeLockKind::Write);
+        table.set_range_lock(1, write, false, None);
+        let (sender, _receiver) = futures::channel::oneshot::channel();
+        let read = range_lock(2, 0, 9, RangeLockKind::Read);
+        assert!(table.set_range_lock(1, read, true, Some(sender)).is_empty());
+        table.take_changed_range_locks();
+
+        let responses = table.cancel_range_lock_wait(1, read);
+        assert_eq!(responses.len(), 1);
+        assert!(matches!(responses[0].1, Err(ErrorCode::Interrupted)));
+        let changed = table.take_changed_range_locks();
+        assert_eq!(changed, vec![(1, vec![write], vec![])]);
+
+        // The cancelled request isn't granted once the lock is released
+        let unlock = range_lock(1, 0, 99, RangeLockKind::Unlock);
+        assert_eq!(table.set_range_lock(1, unlock, false, None).len(), 1);
+        assert_eq!(table.get_range_lock(1, &write), None);
+    }
+
+    #[test]
+    fn restored_range_lock_waiter_is_granted() {
+        let mut table = LockTable::new();
+        let write = range_lock(1, 0, 99, RangeLockKind::Write);
+        let read = range_lock(2, 0, 9, RangeLockKind::Read);
+        table.restore_range_lock(1, write, false);
+        table.restore_range_lock(1, read, true);
+        assert_eq!(table.get_range_lock(1, &read), Some(write));
+
+        let responses = table.release_session(1);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(table.get_range_lock(1, &write), Some(read));
+        let changed = table.take_changed_range_locks();
+        assert_eq!(changed, vec![(1, vec![read], vec![])]);
     }
 }
@@ -1,15 +1,22 @@
 use crate::base::DistributionRequirement;
+use crate::base::{AtimeMode, LocalContext, RequestMetaInfo, UserContext, pack_inodes};
 use crate::base::{CommitId, FileKind, Request, decode_request, encode_response};
-use crate::base::{ErrorCode, Response};
-use crate::base::{LocalContext, RequestMetaInfo};
+use crate::base::{
+    DirectoryPage, ErrorCode, OwnedDirectoryEntry, Response, response_or_error, unpack_inodes,
+};
 use crate::client::RemoteRaftGroups;
+use crate::storage::local::MAX_LISTING_ENTRIES;
 use crate::storage::message_handlers::fsck_handler::{checksum_request, fsck};
 use crate::storage::message_handlers::transaction_coordinator::{
-    create_transaction, hardlink_transaction, rename_transaction, rmdir_transaction,
-    unlink_transaction,
+    add_shard_entries, create_temporary_transaction, create_transaction, deduplicated_transaction,
+    hardlink_transaction, lookup, rename_transaction, rmdir_transaction, unlink_transaction,
 };
 use crate::storage::raft_group_manager::LocalRaftGroupManager;
+use futures::future::join_all;
+use log::warn;
+use std::collections::HashSet;
 use std::sync::Arc;
+use zerialize::List;
 
 pub fn to_error_response(error_code: ErrorCode) -> Vec<u8> {
     encode_response(&Response::ErrorOccurred(error_code))
@@ -44,6 +51,198 @@ async fn forward_request(
     }
 }
 
+// Fills in the attributes of each directory entry, which are stored in the raft groups of the
+// entries rather than the directory's. Entries that are removed concurrently are left out
+async fn add_entry_attributes(
+    entries: Vec<OwnedDirectoryEntry>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<Vec<OwnedDirectoryEntry>, ErrorCode> {
+    // Each local raft group only needs one read barrier
+    let mut barriers = HashSet::new();
+    for entry in entries.iter() {
+        if raft.inode_stored_locally(entry.inode) {
+            let rgroup = raft.lookup_by_inode(entry.inode);
+            if barriers.insert(rgroup.get_raft_group_id()) {
+                rgroup.read_barrier().await?;
+            }
+        }
+    }
+
+    let attributes = join_all(entries.iter().map(|entry| async move {
+        if raft.inode_stored_locally(entry.inode) {
+            match raft
+                .lookup_by_inode(entry.inode)
+                .file_storage()
+                .getattr(entry.inode)?
+            {
+                Response::EntryMetadata(metadata) => {
+                    add_shard_entries(metadata, raft, remote_rafts).await
+                }
+                _ => Err(ErrorCode::BadResponse),
+            }
+        } else {
+            let request = Request::GetAttr { inode: entry.inode };
+            let response_data = remote_rafts
+                .forward_request(&request)
+                .await
+                .map_err(|_| ErrorCode::Uncategorized)?;
+            let response = response_or_error(&response_data)?;
+            response.as_attr_response().ok_or(ErrorCode::BadResponse)
+        }
+    }))
+    .await;
+
+    let mut result = vec![];
+    for (mut entry, attributes) in entries.into_iter().zip(attributes) {
+        match attributes {
+            Ok(metadata) => {
+                entry.attributes = Some(metadata);
+                result.push(entry);
+            }
+            Err(ErrorCode::InodeDoesNotExist) | Err(ErrorCode::DoesNotExist) => {}
+            Err(error_code) => return Err(error_code),
+        }
+    }
+
+    Ok(result)
+}
+
+// Lists a directory which has been split into shards, by merging the listing of the directory
+// itself with those of its shards. Returns the entries, and the name to resume the listing after
+async fn list_sharded_directory(
+    mut entries: Vec<OwnedDirectoryEntry>,
+    resume_after: Option<String>,
+    shards: Vec<u64>,
+    after: Option<&str>,
+    limit: u32,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<DirectoryPage<OwnedDirectoryEntry>, ErrorCode> {
+    // "." and ".." are only listed at the start, and always come first
+    let dots: Vec<OwnedDirectoryEntry> = if after.is_none() {
+        entries.drain(..2).collect()
+    } else {
+        vec![]
+    };
+    // An empty name resumes after the dots, so that they aren't listed for each shard
+    let after = after.unwrap_or("");
+    let listings = join_all(shards.iter().map(|shard| async move {
+        if raft.inode_stored_locally(*shard) {
+            let rgroup = raft.lookup_by_inode(*shard);
+            rgroup.read_barrier().await?;
+            match rgroup.file_storage().readdir(*shard, Some(after), limit)? {
+                Response::DirectoryListing(entries, resume_after) => Ok((entries, resume_after)),
+                _ => Err(ErrorCode::BadResponse),
+            }
+        } else {
+            let request = Request::ListDir {
+                inode: *shard,
+                after: Some(after),
+                limit,
+                with_attributes: false,
+            };
+            let response_data = remote_rafts
+                .forward_request(&request)
+                .await
+                .map_err(|_| ErrorCode::Uncategorized)?;
+            let response = response_or_error(&response_data)?;
+            let (listing, resume_after) = response
+                .as_directory_listing_response()
+                .ok_or(ErrorCode::BadResponse)?;
+            let entries = listing
+                .iter()
+                .map(|entry| OwnedDirectoryEntry {
+                    inode: entry.inode,
+                    name: entry.name.to_string(),
+                    kind: entry.kind,
+                    attributes: entry.attributes,
+                })
+                .collect();
+            Ok((entries, resume_after.map(str::to_string)))
+        }
+    }))
+    .await;
+    let listings = listings.into_iter().collect::<Result<Vec<_>, _>>()?;
+
+    Ok(merge_shard_listings(
+        dots,
+        entries,
+        resume_after,
+        listings,
+        after,
+        limit,
+    ))
+}
+
+// Merges the listing of a sharded directory with those of its shards, which were all listed after
+// the same name. Returns the entries, and the name to resume the listing after
+fn merge_shard_listings(
+    dots: Vec<OwnedDirectoryEntry>,
+    mut entries: Vec<OwnedDirectoryEntry>,
+    resume_after: Option<String>,
+    shard_listings: Vec<DirectoryPage<OwnedDirectoryEntry>>,
+    after: &str,
+    limit: u32,
+) -> DirectoryPage<OwnedDirectoryEntry> {
+    // Entries past where any of the listings stopped can't be returned yet, because that listing
+    // may have more entries before them
+    let mut stopped_at = resume_after;
+    for (shard_entries, resume_after) in shard_listings {
+        entries.extend(shard_entries);
+        if let Some(name) = resume_after
+            && stopped_at.as_ref().is_none_or(|stopped| name < *stopped)
+        {
+            stopped_at = Some(name);
+        }
+    }
+
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    if let Some(ref stopped) = stopped_at {
+        entries.retain(|entry| entry.name <= *stopped);
+    }
+    let capacity = (limit.clamp(2, MAX_LISTING_ENTRIES) as usize).saturating_sub(dots.len());
+    let resume_after = if entries.len() > capacity || stopped_at.is_some() {
+        entries.truncate(capacity);
+        Some(
+            entries
+                .last()
+                .map_or_else(|| after.to_string(), |entry| entry.name.clone()),
+        )
+    } else {
+        None
+    };
+    let mut result = dots;
+    result.extend(entries);
+
+    (result, resume_after)
+}
+
+// Updates the inode's access time after it was read, according to the access time mode. The read
+// already succeeded, so a failure to update the access time is only logged
+async fn record_access(inode: u64, raft: &LocalRaftGroupManager, context: &LocalContext) {
+    let rgroup = raft.lookup_by_inode(inode);
+    let result = match context.atime_mode {
+        AtimeMode::NoAtime => Ok(()),
+        AtimeMode::Relatime => rgroup.file_storage().access_time_stale(inode).map(|stale| {
+            if stale {
+                rgroup.record_access(inode);
+            }
+        }),
+        AtimeMode::StrictAtime => {
+            let request = Request::UpdateAccessTimes {
+                raft_group: rgroup.get_raft_group_id(),
+                inodes: &pack_inodes(&[inode]),
+                strict: true,
+            };
+            rgroup.propose(&request).await.map(|_| ())
+        }
+    };
+    if let Err(error_code) = result {
+        warn!("Failed to update access time of {inode}: {error_code:?}");
+    }
+}
+
 pub async fn request_router(
     request_data: Vec<u8>,
     raft: Arc<LocalRaftGroupManager>,
@@ -84,9 +283,12 @@ async fn request_router_inner(
 
             Ok(Response::Empty)
         }
-        Request::FilesystemInformation => {
-            Ok(raft.all_groups().next().unwrap().file_storage().statfs())
-        }
+        Request::FilesystemInformation => Ok(raft
+            .all_groups()
+            .next()
+            .unwrap()
+            .file_storage()
+            .statfs(&context.peers)),
         Request::FilesystemCheck => fsck(context.clone(), raft.clone()).await,
         Request::FilesystemChecksum => checksum_request(raft.clone()).await,
         Request::CreateInode { raft_group, .. } => {
@@ -102,22 +304,102 @@ async fn request_router_inner(
         | Request::HardlinkIncrement { inode }
         | Request::DecrementInode { inode, .. }
         | Request::UpdateParent { inode, .. }
-        | Request::UpdateMetadataChangedTime { inode, .. } => {
+        | Request::UpdateMetadataChangedTime { inode, .. }
+        | Request::ShardDirectory { inode, .. }
+        | Request::BeginRequest { inode, .. }
+        | Request::FinishRequest { inode, .. } => {
             // Internal request used during transaction processing
             raft.lookup_by_inode(inode).propose_raw(request_data).await
         }
+        Request::UpdateAccessTimes { raft_group, .. } => {
+            // Internal request used to batch access time updates
+            raft.lookup_by_raft_group(raft_group)
+                .propose_raw(request_data)
+                .await
+        }
+        Request::WatchInvalidations {
+            raft_group,
+            epoch,
+            sequence,
+            session,
+        } => Ok(raft
+            .lookup_by_raft_group(raft_group)
+            .watch_invalidations(epoch, sequence, session)
+            .await),
+        Request::ReleaseSession { raft_group, .. }
+        | Request::RenewSession { raft_group, .. }
+        | Request::ExpireSessions { raft_group } => {
+            raft.lookup_by_raft_group(raft_group)
+                .propose_raw(request_data)
+                .await
+        }
         Request::Write { inode, .. }
-        | Request::Lock { inode }
+        | Request::Lock { inode, .. }
+        | Request::SetRangeLock { inode, .. }
+        | Request::CancelRangeLockWait { inode, .. }
+        | Request::OpenHandle { inode, .. }
+        | Request::ReleaseHandle { inode, .. }
         | Request::Unlock { inode, .. }
         | Request::Fsync { inode }
         | Request::Chmod { inode, .. }
         | Request::Chown { inode, .. }
+        | Request::SetFlags { inode, .. }
         | Request::Truncate { inode, .. }
         | Request::SetXattr { inode, .. }
         | Request::RemoveXattr { inode, .. }
         | Request::Utimens { inode, .. } => {
             raft.lookup_by_inode(inode).propose_raw(request_data).await
         }
+        Request::Deduplicated {
+            session,
+            sequence,
+            acknowledged,
+            request,
+        } => match decode_request(request).map_err(|_| ErrorCode::BadRequest)? {
+            // Checked for duplicates when it's committed
+            Request::Write { inode, .. }
+            | Request::Truncate { inode, .. }
+            | Request::Chown { inode, .. }
+            | Request::Chmod { inode, .. }
+            | Request::Utimens { inode, .. }
+            | Request::SetXattr { inode, .. }
+            | Request::RemoveXattr { inode, .. }
+            | Request::SetFlags { inode, .. }
+            | Request::OpenHandle { inode, .. }
+            | Request::ReleaseHandle { inode, .. }
+            | Request::SetRangeLock {
+                inode, wait: false, ..
+            }
+            | Request::CancelRangeLockWait { inode, .. } => {
+                raft.lookup_by_inode(inode).propose_raw(request_data).await
+            }
+            // Transactions record their result in the raft group of one of the inodes they change
+            Request::Create { parent: owner, .. }
+            | Request::Mkdir { parent: owner, .. }
+            | Request::CreateTemporary { parent: owner, .. }
+            | Request::Unlink { parent: owner, .. }
+            | Request::Rmdir { parent: owner, .. }
+            | Request::Rename { parent: owner, .. }
+            | Request::Hardlink { inode: owner, .. } => {
+                let transaction = Box::pin(request_router_inner(
+                    request.to_vec(),
+                    raft.clone(),
+                    remote_rafts.clone(),
+                    context,
+                ));
+                deduplicated_transaction(
+                    owner,
+                    session,
+                    sequence,
+                    acknowledged,
+                    transaction,
+                    &raft,
+                    &remote_rafts,
+                )
+                .await
+            }
+            _ => Err(ErrorCode::BadRequest),
+        },
         Request::Unlink {
             parent,
             name,
@@ -130,11 +412,14 @@ async fn request_router_inner(
         } => {
             raft.lookup_by_inode(inode).read_barrier().await?;
             let latest_commit = raft.lookup_by_inode(inode).get_latest_local_commit();
-            raft.lookup_by_inode(inode)
+            let response = raft
+                .lookup_by_inode(inode)
                 .file_storage()
                 // TODO: Use the real term, not zero
                 .read(inode, offset, read_size, CommitId::new(0, latest_commit))
-                .await
+                .await?;
+            record_access(inode, &raft, &context).await;
+            Ok(response)
         }
         Request::ReadRaw {
             inode,
@@ -160,14 +445,17 @@ async fn request_router_inner(
             uid,
             gid,
             mode,
+            umask,
+            groups,
         } => {
             create_transaction(
                 parent,
                 name,
-                uid,
-                gid,
+                UserContext::with_groups(uid, gid, groups),
                 mode,
+                umask,
                 FileKind::Directory,
+                0,
                 raft.clone(),
                 remote_rafts.clone(),
             )
@@ -180,14 +468,38 @@ async fn request_router_inner(
             gid,
             mode,
             kind,
+            rdev,
+            umask,
+            groups,
         } => {
             create_transaction(
                 parent,
                 name,
-                uid,
-                gid,
+                UserContext::with_groups(uid, gid, groups),
                 mode,
+                umask,
                 kind,
+                rdev,
+                raft.clone(),
+                remote_rafts.clone(),
+            )
+            .await
+        }
+        Request::CreateTemporary {
+            parent,
+            uid,
+            gid,
+            mode,
+            open_handle,
+            umask,
+            groups,
+        } => {
+            create_temporary_transaction(
+                parent,
+                UserContext::with_groups(uid, gid, groups),
+                mode,
+                umask,
+                open_handle,
                 raft.clone(),
                 remote_rafts.clone(),
             )
@@ -198,10 +510,8 @@ async fn request_router_inner(
             name,
             context,
         } => {
-            raft.lookup_by_inode(parent).read_barrier().await?;
-            raft.lookup_by_inode(parent)
-                .file_storage()
-                .lookup(parent, name, context)
+            let id = lookup(parent, name, context, &raft, &remote_rafts).await?;
+            Ok(Response::Inode { id })
         }
         Request::GetXattr {
             inode,
@@ -235,25 +545,90 @@ async fn request_router_inner(
             new_parent,
             new_name,
             context,
+            flags,
         } => {
             rename_transaction(
                 parent,
                 name,
                 new_parent,
                 new_name,
+                flags,
                 context,
                 raft.clone(),
                 remote_rafts.clone(),
             )
             .await
         }
+        Request::Access {
+            inode,
+            mask,
+            context,
+        } => {
+            raft.lookup_by_inode(inode).read_barrier().await?;
+            raft.lookup_by_inode(inode)
+                .file_storage()
+                .access(inode, mask, context)
+        }
         Request::GetAttr { inode } => {
             raft.lookup_by_inode(inode).read_barrier().await?;
-            raft.lookup_by_inode(inode).file_storage().getattr(inode)
+            match raft.lookup_by_inode(inode).file_storage().getattr(inode)? {
+                Response::EntryMetadata(metadata) => Ok(Response::EntryMetadata(
+                    add_shard_entries(metadata, &raft, &remote_rafts).await?,
+                )),
+                _ => Err(ErrorCode::BadResponse),
+            }
+        }
+        Request::GetRangeLock { inode, lock } => {
+            raft.lookup_by_inode(inode).read_barrier().await?;
+            let conflict = raft.lookup_by_inode(inode).get_range_lock(inode, &lock);
+            Ok(Response::RangeLock { conflict })
+        }
+        Request::ListDir {
+            inode,
+            after,
+            limit,
+            with_attributes,
+        } => {
+            raft.lookup_by_inode(inode).read_barrier().await?;
+            let file_storage = raft.lookup_by_inode(inode).file_storage();
+            let listing = file_storage.readdir(inode, after, limit)?;
+            // Only the first page counts as an access, rather than each page
+            if after.is_none() {
+                record_access(inode, &raft, &context).await;
+            }
+            let Response::DirectoryShards { shards } = file_storage.directory_shards(inode)? else {
+                return Err(ErrorCode::BadResponse);
+            };
+            if !with_attributes && shards.is_empty() {
+                return Ok(listing);
+            }
+            let Response::DirectoryListing(mut entries, mut resume_after) = listing else {
+                return Err(ErrorCode::BadResponse);
+            };
+            if !shards.is_empty() {
+                let shards = unpack_inodes(&shards);
+                (entries, resume_after) = list_sharded_directory(
+                    entries,
+                    resume_after,
+                    shards,
+                    after,
+                    limit,
+                    &raft,
+                    &remote_rafts,
+                )
+                .await?;
+            }
+            if !with_attributes {
+                return Ok(Response::DirectoryListing(entries, resume_after));
+            }
+            let entries = add_entry_attributes(entries, &raft, &remote_rafts).await?;
+            Ok(Response::DirectoryListing(entries, resume_after))
         }
-        Request::ListDir { inode } => {
+        Request::GetDirectoryShards { inode } => {
             raft.lookup_by_inode(inode).read_barrier().await?;
-            raft.lookup_by_inode(inode).file_storage().readdir(inode)
+            raft.lookup_by_inode(inode)
+                .file_storage()
+                .directory_shards(inode)
         }
         Request::ListXattrs { inode } => {
             raft.lookup_by_inode(inode).read_barrier().await?;
@@ -280,3 +655,66 @@ async fn request_router_inner(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{FileKind, OwnedDirectoryEntry};
+    use crate::storage::message_handlers::router::merge_shard_listings;
+
+    fn entries(names: &[&str]) -> Vec<OwnedDirectoryEntry> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(inode, name)| OwnedDirectoryEntry {
+                inode: inode as u64 + 2,
+                name: name.to_string(),
+                kind: FileKind::File,
+                attributes: None,
+            })
+            .collect()
+    }
+
+    fn names(entries: &[OwnedDirectoryEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.name.as_str()).collect()
+    }
+
+    #[test]
+    fn listing_across_shards() {
+        // The shards' entries are merged in order, after "." and ".."
+        let (listing, resume_after) = merge_shard_listings(
+            entries(&[".", ".."]),
+            entries(&["b", "e"]),
+            None,
+            vec![(entries(&["a", "d"]), None), (entries(&["c"]), None)],
+            "",
+            10,
+        );
+        assert_eq!(names(&listing), [".", "..", "a", "b", "c", "d", "e"]);
+        assert_eq!(resume_after, None);
+
+        // Entries after the point where a shard's listing stopped wait for the next page, since
+        // that shard may have entries before them
+        let (listing, resume_after) = merge_shard_listings(
+            vec![],
+            entries(&["b", "e"]),
+            None,
+            vec![(entries(&["a", "c"]), Some("c".to_string()))],
+            "",
+            10,
+        );
+        assert_eq!(names(&listing), ["a", "b", "c"]);
+        assert_eq!(resume_after.as_deref(), Some("c"));
+
+        // A full page resumes after its last entry
+        let (listing, resume_after) = merge_shard_listings(
+            vec![],
+            entries(&["b", "d"]),
+            None,
+            vec![(entries(&["a", "c"]), None)],
+            "",
+            3,
+        );
+        assert_eq!(names(&listing), ["a", "b", "c"]);
+        assert_eq!(resume_after.as_deref(), Some("c"));
+    }
+}
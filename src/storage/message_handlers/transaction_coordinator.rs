@@ -1,13 +1,24 @@
 use crate::base::{
-    EntryMetadata, ErrorCode, FileKind, InodeUidPair, Request, Response, UserContext, WireResponse,
-    encode_response,
+    ACL_DEFAULT_XATTR, FS_APPEND_FL, FS_IMMUTABLE_FL, RENAME_EXCHANGE, RENAME_NOREPLACE,
+    response_or_error,
 };
-use crate::base::{check_access, response_or_error};
+use crate::base::{
+    EntryMetadata, ErrorCode, FileKind, InodeUidPair, LockMode, OpenHandleId, Request, Response,
+    UserContext, WireResponse, encode_response, pack_inodes, unpack_inodes,
+};
+use crate::base::{decode_request_result, encode_request_result};
 use crate::client::RemoteRaftGroups;
+use crate::storage::ROOT_INODE;
 use crate::storage::raft_group_manager::LocalRaftGroupManager;
+use futures::Future;
+use log::error;
 use rand::Rng;
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
+use zerialize::List;
+
+// Upper bound on the number of shards that a large directory is split into
+const MAX_DIRECTORY_SHARDS: u16 = 16;
 
 async fn propose(
     inode: u64,
@@ -30,14 +41,59 @@ async fn propose(
     }
 }
 
+// Applies a transaction at most once, even if the client sends it more than once. Its result is
+// recorded in the raft group of owner, which must be the same each time it's sent
+#[allow(clippy::too_many_arguments)]
+pub async fn deduplicated_transaction(
+    owner: u64,
+    session: u64,
+    sequence: u64,
+    acknowledged: u64,
+    transaction: impl Future<Output = Result<Response, ErrorCode>>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<Response, ErrorCode> {
+    // Identifies this attempt at the transaction, so that a retry can take it over if this one
+    // doesn't finish before its lease expires
+    let coordinator = rand::rng().random_range(1..u64::MAX);
+    let begin = Request::BeginRequest {
+        inode: owner,
+        session,
+        sequence,
+        acknowledged,
+        owner: coordinator,
+    };
+    let response_data = propose(owner, &begin, raft, remote_rafts).await?;
+    if let WireResponse::RequestResult { result } = response_or_error(&response_data)? {
+        // The transaction was already applied
+        return decode_request_result(result);
+    }
+
+    let result = transaction.await;
+    let finish = Request::FinishRequest {
+        inode: owner,
+        session,
+        sequence,
+        result: &encode_request_result(&result),
+        owner: coordinator,
+    };
+    if let Err(error_code) = propose(owner, &finish, raft, remote_rafts).await {
+        // The client will get RequestInProgress if it sends the transaction again, until the
+        // lease expires and the retry takes it over
+        error!("Failed to record result of request {session}:{sequence}: {error_code:?}");
+    }
+
+    result
+}
+
 #[allow(clippy::too_many_arguments)]
 async fn replace_link(
     parent: u64,
     name: &str,
     new_inode: u64,
     kind: FileKind,
-    lock_id: u64,
-    context: UserContext,
+    lock: EntryLock,
+    context: UserContext<'_>,
     raft: &LocalRaftGroupManager,
     remote_rafts: &RemoteRaftGroups,
 ) -> Result<u64, ErrorCode> {
@@ -46,21 +102,258 @@ async fn replace_link(
         name,
         new_inode,
         kind,
-        lock_id: Some(lock_id),
+        lock_id: Some(lock.lock_id),
         context,
     };
 
-    let response_data = propose(parent, &request, raft, remote_rafts).await?;
+    let mut response_data = propose(parent, &request, raft, remote_rafts).await?;
+    if let WireResponse::DirectorySharded = response_or_error(&response_data)? {
+        // The shard is accessed as root, so the directory's flags and permissions are checked here
+        check_can_add_entry(&getattrs(parent, raft, remote_rafts).await?, true)?;
+        check_inode_access(parent, libc::W_OK, context, raft, remote_rafts).await?;
+        let (shard, shard_lock_id) =
+            lock_shard_entry(parent, name, lock.shard_lock, raft, remote_rafts).await?;
+        let request = Request::ReplaceLink {
+            parent: shard,
+            name,
+            new_inode,
+            kind,
+            lock_id: Some(shard_lock_id),
+            context: UserContext::new(0, 0),
+        };
+        let result = propose(shard, &request, raft, remote_rafts).await;
+        release_shard_entry((shard, shard_lock_id), lock.shard_lock, raft, remote_rafts).await?;
+        response_data = result?;
+        update_metadata_changed_time(parent, Some(lock.lock_id), true, raft, remote_rafts).await?;
+    }
     let response = response_or_error(&response_data)?;
     Ok(response.as_inode_response().unwrap())
 }
 
+// Links the inode into the parent directory. If the directory has grown too large it is split
+// into shards first, and once it has been split the link is created in the entry's shard
+#[allow(clippy::too_many_arguments)]
+async fn create_link(
+    parent: u64,
+    name: &str,
+    inode: u64,
+    kind: FileKind,
+    lock: Option<EntryLock>,
+    context: UserContext<'_>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<(), ErrorCode> {
+    let lock_id = lock.map(|lock| lock.lock_id);
+    let request = Request::CreateLink {
+        parent,
+        name,
+        inode,
+        kind,
+        lock_id,
+        context,
+    };
+    loop {
+        let response_data = propose(parent, &request, raft, remote_rafts).await?;
+        match response_or_error(&response_data)? {
+            WireResponse::DirectoryNeedsSharding => {
+                shard_directory(parent, raft, remote_rafts).await?;
+            }
+            WireResponse::DirectorySharded => break,
+            _ => return Ok(()),
+        }
+    }
+
+    // Access to the directory was checked when looking up the entry, except for write access.
+    // The shard itself is only accessed as root, so the directory's flags are checked here too
+    check_can_add_entry(&getattrs(parent, raft, remote_rafts).await?, false)?;
+    check_inode_access(parent, libc::W_OK, context, raft, remote_rafts).await?;
+    let shard_lock = lock.and_then(|lock| lock.shard_lock);
+    let (shard, shard_lock_id) =
+        lock_shard_entry(parent, name, shard_lock, raft, remote_rafts).await?;
+    let request = Request::CreateLink {
+        parent: shard,
+        name,
+        inode,
+        kind,
+        lock_id: Some(shard_lock_id),
+        context: UserContext::new(0, 0),
+    };
+    let result = propose(shard, &request, raft, remote_rafts).await;
+    release_shard_entry((shard, shard_lock_id), shard_lock, raft, remote_rafts).await?;
+    result?;
+    update_metadata_changed_time(parent, lock_id, true, raft, remote_rafts).await
+}
+
+// Returns the shards that the directory has been split into, or an empty list if it hasn't been
+async fn get_directory_shards(
+    inode: u64,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<Vec<u64>, ErrorCode> {
+    if raft.inode_stored_locally(inode) {
+        let rgroup = raft.lookup_by_inode(inode);
+        rgroup.read_barrier().await?;
+
+        match rgroup.file_storage().directory_shards(inode)? {
+            Response::DirectoryShards { shards } => Ok(unpack_inodes(&shards)),
+            _ => Err(ErrorCode::BadResponse),
+        }
+    } else {
+        let response_data = remote_rafts
+            .forward_request(&Request::GetDirectoryShards { inode })
+            .await
+            .map_err(|_| ErrorCode::Uncategorized)?;
+
+        let response = response_or_error(&response_data)?;
+        response
+            .as_directory_shards_response()
+            .ok_or(ErrorCode::BadResponse)
+    }
+}
+
+// Returns the shard that stores the entry with the given name, in a directory which has been split
+async fn entry_shard(
+    parent: u64,
+    name: &str,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<u64, ErrorCode> {
+    let shards = get_directory_shards(parent, raft, remote_rafts).await?;
+    if shards.is_empty() {
+        return Err(ErrorCode::Corrupted);
+    }
+    // FNV-1a, since the hash must be stable across nodes and versions
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in name.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    Ok(shards[(hash % shards.len() as u64) as usize])
+}
+
+// Splits the directory into shards, which are spread over the raft groups following the
+// directory's own group
+async fn shard_directory(
+    inode: u64,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<(), ErrorCode> {
+    let total_groups = remote_rafts.get_total_raft_groups();
+    let shard_count = total_groups.min(MAX_DIRECTORY_SHARDS);
+    let mut shards = vec![];
+    for i in 0..shard_count {
+        let raft_group = ((inode % total_groups as u64) as u16 + i) % total_groups;
+        let create_inode = Request::CreateInode {
+            raft_group,
+            parent: inode,
+            uid: 0,
+            gid: 0,
+            mode: 0o700,
+            kind: FileKind::Directory,
+            open_handle: None,
+            rdev: 0,
+            default_acl: None,
+            shard_of: Some(inode),
+        };
+        let response = remote_rafts
+            .propose_to_specific_group(raft_group, &create_inode)
+            .await
+            .map_err(|_| ErrorCode::Uncategorized)
+            .and_then(|data| {
+                response_or_error(&data)?
+                    .as_attr_response()
+                    .ok_or(ErrorCode::BadResponse)
+            });
+        match response {
+            Ok(attrs) => shards.push(attrs.inode),
+            Err(error_code) => {
+                for shard in shards {
+                    decrement_inode(shard, 2, None, raft, remote_rafts).await;
+                }
+                return Err(error_code);
+            }
+        }
+    }
+
+    let request = Request::ShardDirectory {
+        inode,
+        shards: &pack_inodes(&shards),
+    };
+    match propose(inode, &request, raft, remote_rafts).await {
+        Ok(_) => Ok(()),
+        Err(error_code) => {
+            for shard in shards {
+                decrement_inode(shard, 2, None, raft, remote_rafts).await;
+            }
+            // Another transaction split the directory first
+            if error_code == ErrorCode::AlreadyExists {
+                Ok(())
+            } else {
+                Err(error_code)
+            }
+        }
+    }
+}
+
+// Counts the entries in a sharded directory's shards as its own. The directory must be stored
+// locally, and a read barrier already done on its raft group
+pub async fn add_shard_entries(
+    mut metadata: EntryMetadata,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<EntryMetadata, ErrorCode> {
+    if metadata.kind != FileKind::Directory {
+        return Ok(metadata);
+    }
+    let shards = match raft
+        .lookup_by_inode(metadata.inode)
+        .file_storage()
+        .directory_shards(metadata.inode)?
+    {
+        Response::DirectoryShards { shards } => unpack_inodes(&shards),
+        _ => return Err(ErrorCode::BadResponse),
+    };
+    for shard in shards {
+        let shard_entries = shard_entries(shard, raft, remote_rafts).await?;
+        metadata.directory_entries = metadata
+            .directory_entries
+            .map(|entries| entries + shard_entries);
+    }
+
+    Ok(metadata)
+}
+
+async fn shard_entries(
+    shard: u64,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<u32, ErrorCode> {
+    let metadata = if raft.inode_stored_locally(shard) {
+        let rgroup = raft.lookup_by_inode(shard);
+        rgroup.read_barrier().await?;
+        match rgroup.file_storage().getattr(shard)? {
+            Response::EntryMetadata(metadata) => metadata,
+            _ => return Err(ErrorCode::BadResponse),
+        }
+    } else {
+        let response_data = remote_rafts
+            .forward_request(&Request::GetAttr { inode: shard })
+            .await
+            .map_err(|_| ErrorCode::Uncategorized)?;
+        let response = response_or_error(&response_data)?;
+        response.as_attr_response().ok_or(ErrorCode::BadResponse)?
+    };
+
+    Ok(metadata.directory_entries.unwrap_or_default())
+}
+
 async fn remove_link(
     parent: u64,
     name: &str,
     link_inode_and_uid: Option<(u64, u32)>,
-    lock_id: Option<u64>,
-    context: UserContext,
+    lock: Option<EntryLock>,
+    context: UserContext<'_>,
     raft: &LocalRaftGroupManager,
     remote_rafts: &RemoteRaftGroups,
 ) -> Result<(u64, bool), ErrorCode> {
@@ -68,25 +361,216 @@ async fn remove_link(
         parent,
         name,
         link_inode_and_uid: link_inode_and_uid.map(|(inode, uid)| InodeUidPair::new(inode, uid)),
-        lock_id,
+        lock_id: lock.map(|lock| lock.lock_id),
         context,
     };
     let response_data = propose(parent, &request, raft, remote_rafts).await?;
-    let response = response_or_error(&response_data)?;
-    if let WireResponse::RemovedInode { id, complete } = response {
+    match response_or_error(&response_data)? {
+        WireResponse::RemovedInode { id, complete } => Ok((id, complete)),
+        WireResponse::DirectorySharded => {
+            remove_sharded_link(
+                parent,
+                name,
+                link_inode_and_uid,
+                lock,
+                context,
+                raft,
+                remote_rafts,
+            )
+            .await
+        }
+        _ => unreachable!(),
+    }
+}
+
+// Same as remove_link(), for an entry stored in one of the directory's shards. The shard is only
+// accessed as root, so the access and "sticky bit" checks are performed against the directory here
+async fn remove_sharded_link(
+    parent: u64,
+    name: &str,
+    link_inode_and_uid: Option<(u64, u32)>,
+    lock: Option<EntryLock>,
+    context: UserContext<'_>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<(u64, bool), ErrorCode> {
+    check_inode_access(parent, libc::W_OK, context, raft, remote_rafts).await?;
+    // The shard is only accessed as root, so the directory's flags are checked here
+    let parent_attrs = getattrs(parent, raft, remote_rafts).await?;
+    check_not_protected(&parent_attrs)?;
+
+    let shard_lock = lock.and_then(|lock| lock.shard_lock);
+    let (shard, shard_lock_id) =
+        lock_shard_entry(parent, name, shard_lock, raft, remote_rafts).await?;
+    let result: Result<(u64, bool), ErrorCode> = async {
+        if parent_attrs.mode & libc::S_ISVTX as u16 != 0
+            && context.uid() != 0
+            && context.uid() != parent_attrs.uid
+        {
+            let inode = lookup_entry(shard, name, UserContext::new(0, 0), raft, remote_rafts)
+                .await?
+                .ok_or(ErrorCode::BadResponse)?;
+            match link_inode_and_uid {
+                Some((link_inode, uid)) if link_inode == inode => {
+                    if uid != context.uid() {
+                        return Err(ErrorCode::AccessDenied);
+                    }
+                }
+                // The inode's uid is needed, or the one that was looked up is out of date
+                _ => return Ok((inode, false)),
+            }
+        }
+
+        let request = Request::RemoveLink {
+            parent: shard,
+            name,
+            link_inode_and_uid: link_inode_and_uid
+                .map(|(inode, uid)| InodeUidPair::new(inode, uid)),
+            lock_id: Some(shard_lock_id),
+            context: UserContext::new(0, 0),
+        };
+        let response_data = propose(shard, &request, raft, remote_rafts).await?;
+        let response = response_or_error(&response_data)?;
+        let WireResponse::RemovedInode { id, complete } = response else {
+            unreachable!();
+        };
         Ok((id, complete))
+    }
+    .await;
+    release_shard_entry((shard, shard_lock_id), shard_lock, raft, remote_rafts).await?;
+    let (id, complete) = result?;
+    if complete {
+        let lock_id = lock.map(|lock| lock.lock_id);
+        update_metadata_changed_time(parent, lock_id, true, raft, remote_rafts).await?;
+    }
+
+    Ok((id, complete))
+}
+
+// Immutable and append-only inodes can't be unlinked or renamed
+fn check_not_protected(attributes: &FileOrDirAttrs) -> Result<(), ErrorCode> {
+    if attributes.flags & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0 {
+        Err(ErrorCode::OperationNotPermitted)
     } else {
-        unreachable!();
+        Ok(())
+    }
+}
+
+// Entries can be added to an append-only directory, but existing ones can't be replaced. Nothing
+// can be added to an immutable directory
+fn check_can_add_entry(directory: &FileOrDirAttrs, replaces_entry: bool) -> Result<(), ErrorCode> {
+    if replaces_entry {
+        check_not_protected(directory)
+    } else if directory.flags & FS_IMMUTABLE_FL != 0 {
+        Err(ErrorCode::OperationNotPermitted)
+    } else {
+        Ok(())
     }
 }
 
 // TODO: should return some kind of guard object to prevent dropping the lock_id without unlocking it
+
 async fn lock_inode(
     inode: u64,
+    mode: LockMode,
     raft: &LocalRaftGroupManager,
     remote_rafts: &RemoteRaftGroups,
 ) -> Result<u64, ErrorCode> {
-    let response_data = propose(inode, &Request::Lock { inode }, raft, remote_rafts).await?;
+    let request = Request::Lock {
+        inode,
+        name: None,
+        mode,
+    };
+    let response_data = propose(inode, &request, raft, remote_rafts).await?;
+    let response = response_or_error(&response_data)?;
+    if let WireResponse::Lock { lock_id } = response {
+        Ok(lock_id)
+    } else {
+        unreachable!();
+    }
+}
+
+// An exclusive lock on a single directory entry. If the directory has been split into shards,
+// the entry is locked in its shard too, since requests for it may go straight to the shard
+#[derive(Clone, Copy)]
+struct EntryLock {
+    lock_id: u64,
+    // The shard storing the entry, and the lock_id of the entry in it
+    shard_lock: Option<(u64, u64)>,
+}
+
+// Exclusively locks a single entry in the parent directory. The returned lock may be used to
+// create, replace, or remove that entry. Each lock taken is added to lock_guard as (inode, lock_id)
+async fn lock_entry(
+    parent: u64,
+    name: &str,
+    lock_guard: &Mutex<HashSet<(u64, u64)>>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<EntryLock, ErrorCode> {
+    let lock_id = lock_entry_in_directory(parent, name, raft, remote_rafts).await?;
+    lock_guard.lock().unwrap().insert((parent, lock_id));
+    // Requests which found the directory already split go straight to the shard. Those that
+    // haven't reached the directory yet will wait for the entry lock there instead
+    let shards = get_directory_shards(parent, raft, remote_rafts).await?;
+    let shard_lock = if shards.is_empty() {
+        None
+    } else {
+        let shard = entry_shard(parent, name, raft, remote_rafts).await?;
+        let shard_lock_id = lock_entry_in_directory(shard, name, raft, remote_rafts).await?;
+        lock_guard.lock().unwrap().insert((shard, shard_lock_id));
+        Some((shard, shard_lock_id))
+    };
+
+    Ok(EntryLock {
+        lock_id,
+        shard_lock,
+    })
+}
+
+// Returns the entry's shard and a lock on the entry in it. This is the lock that the caller
+// already holds if there is one, otherwise it's taken here and must be released with
+// release_shard_entry()
+async fn lock_shard_entry(
+    parent: u64,
+    name: &str,
+    held_lock: Option<(u64, u64)>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<(u64, u64), ErrorCode> {
+    if let Some(held_lock) = held_lock {
+        return Ok(held_lock);
+    }
+    let shard = entry_shard(parent, name, raft, remote_rafts).await?;
+    let lock_id = lock_entry_in_directory(shard, name, raft, remote_rafts).await?;
+
+    Ok((shard, lock_id))
+}
+
+async fn release_shard_entry(
+    (shard, lock_id): (u64, u64),
+    held_lock: Option<(u64, u64)>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<(), ErrorCode> {
+    if held_lock.is_some() {
+        return Ok(());
+    }
+    unlock_inode(shard, lock_id, raft, remote_rafts).await
+}
+
+async fn lock_entry_in_directory(
+    parent: u64,
+    name: &str,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<u64, ErrorCode> {
+    let request = Request::Lock {
+        inode: parent,
+        name: Some(name),
+        mode: LockMode::Exclusive,
+    };
+    let response_data = propose(parent, &request, raft, remote_rafts).await?;
     let response = response_or_error(&response_data)?;
     if let WireResponse::Lock { lock_id } = response {
         Ok(lock_id)
@@ -116,13 +600,19 @@ async fn update_parent(
     Ok(())
 }
 
+// Updates the inode's change time, and also its modification time if modified is true
 async fn update_metadata_changed_time(
     inode: u64,
     lock_id: Option<u64>,
+    modified: bool,
     raft: &LocalRaftGroupManager,
     remote_rafts: &RemoteRaftGroups,
 ) -> Result<(), ErrorCode> {
-    let request = Request::UpdateMetadataChangedTime { inode, lock_id };
+    let request = Request::UpdateMetadataChangedTime {
+        inode,
+        lock_id,
+        modified,
+    };
 
     let response_data = propose(inode, &request, raft, remote_rafts).await?;
     response_or_error(&response_data)?
@@ -161,6 +651,8 @@ struct FileOrDirAttrs {
     uid: u32,
     gid: u32,
     directory_entries: u32,
+    // Inode flags, such as FS_IMMUTABLE_FL
+    flags: u32,
 }
 
 impl FileOrDirAttrs {
@@ -173,6 +665,7 @@ impl FileOrDirAttrs {
             uid: response.user_id,
             gid: response.group_id,
             directory_entries: response.directory_entries.unwrap_or_default(),
+            flags: response.flags,
         }
     }
 }
@@ -189,7 +682,10 @@ async fn getattrs(
 
         let response = raft.lookup_by_inode(inode).file_storage().getattr(inode)?;
         match response {
-            Response::EntryMetadata(metadata) => Ok(FileOrDirAttrs::new(&metadata)),
+            Response::EntryMetadata(metadata) => {
+                let metadata = add_shard_entries(metadata, raft, remote_rafts).await?;
+                Ok(FileOrDirAttrs::new(&metadata))
+            }
             _ => Err(ErrorCode::BadResponse),
         }
     } else {
@@ -207,14 +703,135 @@ async fn getattrs(
     }
 }
 
-// TODO: even these read-only RPCs add a significant performance cost. Maybe they can be optimized?
-async fn lookup(
+// Checks access using the inode's mode bits and ACL
+async fn check_inode_access(
+    inode: u64,
+    access_mask: i32,
+    context: UserContext<'_>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<(), ErrorCode> {
+    if raft.inode_stored_locally(inode) {
+        let rgroup = raft.lookup_by_inode(inode);
+        rgroup.read_barrier().await?;
+
+        rgroup.file_storage().access(inode, access_mask, context)?;
+    } else {
+        let request = Request::Access {
+            inode,
+            mask: access_mask,
+            context,
+        };
+
+        let response_data = remote_rafts
+            .forward_request(&request)
+            .await
+            .map_err(|_| ErrorCode::Uncategorized)?;
+        response_or_error(&response_data)?;
+    }
+
+    Ok(())
+}
+
+// Returns the group and mode of a new inode. Inodes created in a setgid directory inherit its
+// group, and new subdirectories also inherit the setgid bit
+fn new_inode_group_and_mode(
+    parent_attrs: &FileOrDirAttrs,
+    context: UserContext<'_>,
+    mut mode: u16,
+    kind: FileKind,
+) -> (u32, u16) {
+    let setgid = libc::S_ISGID as u16;
+    let gid = if parent_attrs.mode & setgid != 0 {
+        if kind == FileKind::Directory {
+            mode |= setgid;
+        }
+        parent_attrs.gid
+    } else {
+        context.gid()
+    };
+    // Only members of the group may create a setgid file, unless it's a mandatory locking file
+    if kind != FileKind::Directory
+        && mode & libc::S_IXGRP as u16 != 0
+        && context.uid() != 0
+        && !context.in_group(gid)
+    {
+        mode &= !setgid;
+    }
+
+    (gid, mode)
+}
+
+// Returns the directory's default ACL in xattr format, if it has one
+async fn get_default_acl(
+    parent: u64,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<Option<Vec<u8>>, ErrorCode> {
+    let result = if raft.inode_stored_locally(parent) {
+        let rgroup = raft.lookup_by_inode(parent);
+        rgroup.read_barrier().await?;
+
+        rgroup
+            .file_storage()
+            .get_xattr(parent, ACL_DEFAULT_XATTR, UserContext::new(0, 0))
+            .map(|response| match response {
+                Response::Read { data } => data,
+                _ => unreachable!(),
+            })
+    } else {
+        let request = Request::GetXattr {
+            inode: parent,
+            key: ACL_DEFAULT_XATTR,
+            context: UserContext::new(0, 0),
+        };
+
+        let response_data = remote_rafts
+            .forward_request(&request)
+            .await
+            .map_err(|_| ErrorCode::Uncategorized)?;
+        response_or_error(&response_data).and_then(|response| {
+            response
+                .as_read_response()
+                .map(|data| data.to_vec())
+                .ok_or(ErrorCode::BadResponse)
+        })
+    };
+
+    match result {
+        Ok(acl) => Ok(Some(acl)),
+        Err(ErrorCode::MissingXattrKey) => Ok(None),
+        Err(error_code) => Err(error_code),
+    }
+}
+
+// Looks up the entry, including in the directory's shards if it has been split
+pub async fn lookup(
     parent: u64,
     name: &str,
-    context: UserContext,
+    context: UserContext<'_>,
     raft: &LocalRaftGroupManager,
     remote_rafts: &RemoteRaftGroups,
 ) -> Result<u64, ErrorCode> {
+    if let Some(inode) = lookup_entry(parent, name, context, raft, remote_rafts).await? {
+        return Ok(inode);
+    }
+    // Execute permission on the directory was already checked
+    let shard = entry_shard(parent, name, raft, remote_rafts).await?;
+    lookup_entry(shard, name, UserContext::new(0, 0), raft, remote_rafts)
+        .await?
+        .ok_or(ErrorCode::BadResponse)
+}
+
+// Returns None if the entry is stored in one of the directory's shards
+// TODO: even these read-only RPCs add a significant performance cost. Maybe they can be optimized?
+async fn lookup_entry(
+    parent: u64,
+    name: &str,
+    context: UserContext<'_>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<Option<u64>, ErrorCode> {
     if raft.inode_stored_locally(parent) {
         let rgroup = raft.lookup_by_inode(parent);
         rgroup.read_barrier().await?;
@@ -225,7 +842,8 @@ async fn lookup(
             .lookup(parent, name, context)?;
 
         match inode_response {
-            Response::Inode { id } => Ok(id),
+            Response::Inode { id } => Ok(Some(id)),
+            Response::DirectorySharded => Ok(None),
             _ => Err(ErrorCode::BadResponse),
         }
     } else {
@@ -238,9 +856,13 @@ async fn lookup(
             .await
             .map_err(|_| ErrorCode::Uncategorized)?;
 
+        // The node storing the directory looks up entries in its shards
         let response = response_or_error(&response_data)?;
 
-        response.as_inode_response().ok_or(ErrorCode::BadResponse)
+        response
+            .as_inode_response()
+            .map(Some)
+            .ok_or(ErrorCode::BadResponse)
     }
 }
 
@@ -266,24 +888,71 @@ async fn decrement_inode(
         .expect("expected Empty");
 }
 
-fn rename_check_access(
+// Returns the parent of the directory, from its ".." entry
+async fn directory_parent(
+    inode: u64,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<u64, ErrorCode> {
+    if raft.inode_stored_locally(inode) {
+        let rgroup = raft.lookup_by_inode(inode);
+        rgroup.read_barrier().await?;
+        match rgroup.file_storage().readdir(inode, None, 2)? {
+            Response::DirectoryListing(entries, _) if entries.len() == 2 => Ok(entries[1].inode),
+            _ => Err(ErrorCode::BadResponse),
+        }
+    } else {
+        let request = Request::ListDir {
+            inode,
+            after: None,
+            limit: 2,
+            with_attributes: false,
+        };
+        let response_data = remote_rafts
+            .forward_request(&request)
+            .await
+            .map_err(|_| ErrorCode::Uncategorized)?;
+        let response = response_or_error(&response_data)?;
+        let (listing, _) = response
+            .as_directory_listing_response()
+            .ok_or(ErrorCode::BadResponse)?;
+        listing
+            .iter()
+            .nth(1)
+            .map(|entry| entry.inode)
+            .ok_or(ErrorCode::BadResponse)
+    }
+}
+
+// Fails if the directory is the destination, or one of its ancestors, since moving it there would
+// disconnect it from the root
+async fn check_not_ancestor<F: Future<Output = Result<u64, ErrorCode>>>(
+    directory: u64,
+    destination: u64,
+    parent_of: impl Fn(u64) -> F,
+) -> Result<(), ErrorCode> {
+    let mut current = destination;
+    while current != ROOT_INODE {
+        if current == directory {
+            return Err(ErrorCode::InvalidArgument);
+        }
+        current = parent_of(current).await?;
+    }
+
+    Ok(())
+}
+
+async fn rename_check_access(
     parent_attrs: &FileOrDirAttrs,
     new_parent_attrs: &FileOrDirAttrs,
     inode_attrs: &FileOrDirAttrs,
     // Attributes and directory entry count if it's a directory
     existing_dest_inode_attrs: &Option<FileOrDirAttrs>,
-    context: UserContext,
+    context: UserContext<'_>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
 ) -> Result<(), ErrorCode> {
-    if !check_access(
-        parent_attrs.uid,
-        parent_attrs.gid,
-        parent_attrs.mode,
-        context.uid(),
-        context.gid(),
-        libc::W_OK,
-    ) {
-        return Err(ErrorCode::AccessDenied);
-    }
+    check_inode_access(parent_attrs.inode, libc::W_OK, context, raft, remote_rafts).await?;
 
     // "Sticky bit" handling
     if parent_attrs.mode & libc::S_ISVTX as u16 != 0
@@ -294,16 +963,14 @@ fn rename_check_access(
         return Err(ErrorCode::AccessDenied);
     }
 
-    if !check_access(
-        new_parent_attrs.uid,
-        new_parent_attrs.gid,
-        new_parent_attrs.mode,
-        context.uid(),
-        context.gid(),
+    check_inode_access(
+        new_parent_attrs.inode,
         libc::W_OK,
-    ) {
-        return Err(ErrorCode::AccessDenied);
-    }
+        context,
+        raft,
+        remote_rafts,
+    )
+    .await?;
 
     // "Sticky bit" handling in new_parent
     if new_parent_attrs.mode & libc::S_ISVTX as u16 != 0
@@ -325,18 +992,8 @@ fn rename_check_access(
 
     // Only move an existing directory to a new parent, if we have write access to it,
     // because that will change the ".." link in it
-    if inode_attrs.kind == FileKind::Directory
-        && parent_attrs.inode != new_parent_attrs.inode
-        && !check_access(
-            inode_attrs.uid,
-            inode_attrs.gid,
-            inode_attrs.mode,
-            context.uid(),
-            context.gid(),
-            libc::W_OK,
-        )
-    {
-        return Err(ErrorCode::AccessDenied);
+    if inode_attrs.kind == FileKind::Directory && parent_attrs.inode != new_parent_attrs.inode {
+        check_inode_access(inode_attrs.inode, libc::W_OK, context, raft, remote_rafts).await?;
     }
 
     Ok(())
@@ -350,16 +1007,23 @@ pub async fn rename_transaction(
     name: &str,
     new_parent: u64,
     new_name: &str,
-    context: UserContext,
+    flags: u32,
+    context: UserContext<'_>,
     raft: Arc<LocalRaftGroupManager>,
     remote_rafts: Arc<RemoteRaftGroups>,
 ) -> Result<Response, ErrorCode> {
+    if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0
+        || flags == RENAME_NOREPLACE | RENAME_EXCHANGE
+    {
+        return Err(ErrorCode::InvalidArgument);
+    }
     let locks: Arc<Mutex<HashSet<(u64, u64)>>> = Arc::new(Mutex::new(HashSet::new()));
     let result = rename_transaction_lock_context(
         parent,
         name,
         new_parent,
         new_name,
+        flags,
         context,
         locks.clone(),
         raft.clone(),
@@ -382,7 +1046,8 @@ async fn rename_transaction_lock_context(
     name: &str,
     new_parent: u64,
     new_name: &str,
-    context: UserContext,
+    flags: u32,
+    context: UserContext<'_>,
     // Pairs of (inode, lock_id)
     lock_guard: Arc<Mutex<HashSet<(u64, u64)>>>,
     raft: Arc<LocalRaftGroupManager>,
@@ -390,19 +1055,25 @@ async fn rename_transaction_lock_context(
 ) -> Result<Response, ErrorCode> {
     // TODO: since we acquire multiple locks in this function it could cause a deadlock.
     // We should acquire them in ascending order of inode
-    let parent_lock_id = lock_inode(parent, &raft, &remote_rafts).await?;
+    // The parents are only locked in shared mode, so that other entries in them can still be
+    // created and removed concurrently. The two entries being modified are locked exclusively
+    let parent_lock_id = lock_inode(parent, LockMode::Shared, &raft, &remote_rafts).await?;
     lock_guard.lock().unwrap().insert((parent, parent_lock_id));
 
-    let new_parent_lock_id = if parent != new_parent {
-        let lock_id = lock_inode(new_parent, &raft, &remote_rafts).await?;
+    if parent != new_parent {
+        let lock_id = lock_inode(new_parent, LockMode::Shared, &raft, &remote_rafts).await?;
         lock_guard.lock().unwrap().insert((new_parent, lock_id));
-        lock_id
+    }
+
+    let entry_lock = lock_entry(parent, name, &lock_guard, &raft, &remote_rafts).await?;
+    let new_entry_lock = if parent != new_parent || name != new_name {
+        lock_entry(new_parent, new_name, &lock_guard, &raft, &remote_rafts).await?
     } else {
-        parent_lock_id
+        entry_lock
     };
 
     let inode = lookup(parent, name, context, &raft, &remote_rafts).await?;
-    let inode_lock_id = lock_inode(inode, &raft, &remote_rafts).await?;
+    let inode_lock_id = lock_inode(inode, LockMode::Exclusive, &raft, &remote_rafts).await?;
     lock_guard.lock().unwrap().insert((inode, inode_lock_id));
 
     let existing_dest_inode =
@@ -416,8 +1087,20 @@ async fn rename_transaction_lock_context(
                 }
             }
         };
+    // The destination entry is locked, in its shard too if the directory has been split, so these
+    // checks can't race with another transaction
+    if flags & RENAME_NOREPLACE != 0 && existing_dest_inode.is_some() {
+        return Err(ErrorCode::AlreadyExists);
+    }
+    if flags & RENAME_EXCHANGE != 0 && existing_dest_inode.is_none() {
+        return Err(ErrorCode::DoesNotExist);
+    }
+    if flags & RENAME_EXCHANGE != 0 && existing_dest_inode == Some(inode) {
+        // Both entries already link to the same inode, so exchanging them is a no-op
+        return Ok(Response::Empty);
+    }
     let existing_inode_lock_id = if let Some(inode) = existing_dest_inode {
-        let lock_id = lock_inode(inode, &raft, &remote_rafts).await?;
+        let lock_id = lock_inode(inode, LockMode::Exclusive, &raft, &remote_rafts).await?;
         lock_guard.lock().unwrap().insert((inode, lock_id));
         Some(lock_id)
     } else {
@@ -433,13 +1116,71 @@ async fn rename_transaction_lock_context(
     } else {
         None
     };
+    check_not_protected(&parent_attrs)?;
+    check_can_add_entry(&new_parent_attrs, existing_dest_inode.is_some())?;
+    check_not_protected(&inode_attrs)?;
+    if let Some(ref attrs) = existing_inode_attrs {
+        check_not_protected(attrs)?;
+    }
+    if parent != new_parent {
+        let parent_of = |inode| directory_parent(inode, &raft, &remote_rafts);
+        if inode_attrs.kind == FileKind::Directory {
+            check_not_ancestor(inode, new_parent, parent_of).await?;
+        }
+        // When exchanging, the destination is moved into the source's parent
+        if flags & RENAME_EXCHANGE != 0
+            && let Some(ref attrs) = existing_inode_attrs
+            && attrs.kind == FileKind::Directory
+        {
+            check_not_ancestor(attrs.inode, parent, parent_of).await?;
+        }
+    }
+    if flags & RENAME_EXCHANGE != 0 {
+        // Both inodes are moved, and neither is unlinked, so check each move separately
+        let existing_inode_attrs = existing_inode_attrs.unwrap();
+        rename_check_access(
+            &parent_attrs,
+            &new_parent_attrs,
+            &inode_attrs,
+            &None,
+            context,
+            &raft,
+            &remote_rafts,
+        )
+        .await?;
+        rename_check_access(
+            &new_parent_attrs,
+            &parent_attrs,
+            &existing_inode_attrs,
+            &None,
+            context,
+            &raft,
+            &remote_rafts,
+        )
+        .await?;
+
+        return exchange_links(
+            (parent, name, entry_lock),
+            (new_parent, new_name, new_entry_lock),
+            (&inode_attrs, inode_lock_id),
+            (&existing_inode_attrs, existing_inode_lock_id.unwrap()),
+            context,
+            &raft,
+            &remote_rafts,
+        )
+        .await;
+    }
+
     rename_check_access(
         &parent_attrs,
         &new_parent_attrs,
         &inode_attrs,
         &existing_inode_attrs,
         context,
-    )?;
+        &raft,
+        &remote_rafts,
+    )
+    .await?;
 
     if let Some(existing_inode) = existing_dest_inode {
         let old_inode = replace_link(
@@ -447,7 +1188,7 @@ async fn rename_transaction_lock_context(
             new_name,
             inode,
             inode_attrs.kind,
-            new_parent_lock_id,
+            new_entry_lock,
             context,
             &raft,
             &remote_rafts,
@@ -461,17 +1202,17 @@ async fn rename_transaction_lock_context(
             decrement_inode(old_inode, 1, existing_inode_lock_id, &raft, &remote_rafts).await;
         }
     } else {
-        let create_link = Request::CreateLink {
-            parent: new_parent,
-            name: new_name,
+        create_link(
+            new_parent,
+            new_name,
             inode,
-            kind: inode_attrs.kind,
-            lock_id: Some(new_parent_lock_id),
+            inode_attrs.kind,
+            Some(new_entry_lock),
             context,
-        };
-
-        let response_data = propose(new_parent, &create_link, &raft, &remote_rafts).await?;
-        response_or_error(&response_data)?;
+            &raft,
+            &remote_rafts,
+        )
+        .await?;
     }
 
     // TODO: this shouldn't be able to fail since we already performed access checks
@@ -479,7 +1220,7 @@ async fn rename_transaction_lock_context(
         parent,
         name,
         Some((inode, inode_attrs.uid)),
-        Some(parent_lock_id),
+        Some(entry_lock),
         context,
         &raft,
         &remote_rafts,
@@ -489,7 +1230,108 @@ async fn rename_transaction_lock_context(
     if inode_attrs.kind == FileKind::Directory {
         update_parent(inode, new_parent, Some(inode_lock_id), &raft, &remote_rafts).await?;
     }
-    update_metadata_changed_time(inode, Some(inode_lock_id), &raft, &remote_rafts).await?;
+    update_metadata_changed_time(inode, Some(inode_lock_id), false, &raft, &remote_rafts).await?;
+
+    Ok(Response::Empty)
+}
+
+// Swaps the inodes that two directory entries link to. The caller must hold locks on both entries and inodes.
+// Entries are passed as (parent, name, entry lock) and inodes as (attributes, inode lock id)
+async fn exchange_links(
+    entry: (u64, &str, EntryLock),
+    other_entry: (u64, &str, EntryLock),
+    inode: (&FileOrDirAttrs, u64),
+    other_inode: (&FileOrDirAttrs, u64),
+    context: UserContext<'_>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<Response, ErrorCode> {
+    let (parent, name, entry_lock) = entry;
+    let (other_parent, other_name, other_entry_lock) = other_entry;
+    let (inode_attrs, inode_lock_id) = inode;
+    let (other_inode_attrs, other_inode_lock_id) = other_inode;
+
+    let replaced = replace_link(
+        other_parent,
+        other_name,
+        inode_attrs.inode,
+        inode_attrs.kind,
+        other_entry_lock,
+        context,
+        raft,
+        remote_rafts,
+    )
+    .await?;
+    assert_eq!(replaced, other_inode_attrs.inode);
+
+    match replace_link(
+        parent,
+        name,
+        other_inode_attrs.inode,
+        other_inode_attrs.kind,
+        entry_lock,
+        context,
+        raft,
+        remote_rafts,
+    )
+    .await
+    {
+        Ok(replaced) => assert_eq!(replaced, inode_attrs.inode),
+        Err(error_code) => {
+            // Rollback the first link, so that the exchange either happens completely or not at all
+            replace_link(
+                other_parent,
+                other_name,
+                other_inode_attrs.inode,
+                other_inode_attrs.kind,
+                other_entry_lock,
+                context,
+                raft,
+                remote_rafts,
+            )
+            .await?;
+            return Err(error_code);
+        }
+    }
+
+    if parent != other_parent {
+        if inode_attrs.kind == FileKind::Directory {
+            update_parent(
+                inode_attrs.inode,
+                other_parent,
+                Some(inode_lock_id),
+                raft,
+                remote_rafts,
+            )
+            .await?;
+        }
+        if other_inode_attrs.kind == FileKind::Directory {
+            update_parent(
+                other_inode_attrs.inode,
+                parent,
+                Some(other_inode_lock_id),
+                raft,
+                remote_rafts,
+            )
+            .await?;
+        }
+    }
+    update_metadata_changed_time(
+        inode_attrs.inode,
+        Some(inode_lock_id),
+        false,
+        raft,
+        remote_rafts,
+    )
+    .await?;
+    update_metadata_changed_time(
+        other_inode_attrs.inode,
+        Some(other_inode_lock_id),
+        false,
+        raft,
+        remote_rafts,
+    )
+    .await?;
 
     Ok(Response::Empty)
 }
@@ -499,49 +1341,21 @@ async fn rename_transaction_lock_context(
 pub async fn rmdir_transaction(
     parent: u64,
     name: &str,
-    context: UserContext,
+    context: UserContext<'_>,
     raft: Arc<LocalRaftGroupManager>,
     remote_rafts: Arc<RemoteRaftGroups>,
 ) -> Result<Response, ErrorCode> {
     let mut inode = lookup(parent, name, context, &raft, &remote_rafts).await?;
     let mut complete = false;
+    let mut shard_locks = vec![];
     while !complete {
         // If the link removal didn't complete successful or with an error, then we need to
         // lock the target inode and lookup its uid to allow processing of "sticky bit"
-        let lock_id = lock_inode(inode, &raft, &remote_rafts).await?;
-        match getattrs(inode, &raft, &remote_rafts).await {
-            Ok(attrs) => {
-                if attrs.directory_entries > 0 {
-                    unlock_inode(inode, lock_id, &raft, &remote_rafts).await?;
-                    return Err(ErrorCode::NotEmpty);
-                }
-                match remove_link(
-                    parent,
-                    name,
-                    Some((inode, attrs.uid)),
-                    None,
-                    context,
-                    &raft,
-                    &remote_rafts,
-                )
-                .await
-                {
-                    Ok((lookup_inode, is_complete)) => {
-                        unlock_inode(inode, lock_id, &raft, &remote_rafts).await?;
-                        inode = lookup_inode;
-                        complete = is_complete;
-                    }
-                    Err(error_code) => {
-                        unlock_inode(inode, lock_id, &raft, &remote_rafts).await?;
-                        return Err(error_code);
-                    }
-                }
-            }
-            Err(error_code) => {
-                unlock_inode(inode, lock_id, &raft, &remote_rafts).await?;
-                return Err(error_code);
-            }
-        }
+        let lock_id = lock_inode(inode, LockMode::Exclusive, &raft, &remote_rafts).await?;
+        let result =
+            remove_directory_link(parent, name, inode, context, &raft, &remote_rafts).await;
+        unlock_inode(inode, lock_id, &raft, &remote_rafts).await?;
+        (inode, complete, shard_locks) = result?;
     }
 
     // TODO: can remove this roundtrip. It's just for debuggability
@@ -554,21 +1368,99 @@ pub async fn rmdir_transaction(
         lock_id: None,
     };
     // TODO: if this fails the inode will leak ;(
-    let response_data = propose(inode, &decrement_link_count, &raft, &remote_rafts).await?;
-    response_or_error(&response_data)?;
+    let result = propose(inode, &decrement_link_count, &raft, &remote_rafts).await;
+    // Requests waiting for the shards fail once they're unlocked, since they no longer exist
+    for (shard, lock_id) in shard_locks {
+        if result.is_ok() {
+            decrement_inode(shard, 2, Some(lock_id), &raft, &remote_rafts).await;
+        }
+        unlock_inode(shard, lock_id, &raft, &remote_rafts).await?;
+    }
+    response_or_error(&result?)?;
 
     Ok(Response::Empty)
 }
 
+// Removes the link to the directory from its parent, if the directory is empty. The directory must
+// be locked. Entries are created in a sharded directory's shards without locking the directory, so
+// the shards are locked before checking that it's empty. Returns the inode that the link refers to,
+// whether it was removed, and if it was, the locks on the shards which are still held
+async fn remove_directory_link(
+    parent: u64,
+    name: &str,
+    inode: u64,
+    context: UserContext<'_>,
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<(u64, bool, Vec<(u64, u64)>), ErrorCode> {
+    let mut shard_locks = vec![];
+    for shard in get_directory_shards(inode, raft, remote_rafts).await? {
+        match lock_inode(shard, LockMode::Exclusive, raft, remote_rafts).await {
+            Ok(lock_id) => shard_locks.push((shard, lock_id)),
+            Err(error_code) => {
+                unlock_shards(&shard_locks, raft, remote_rafts).await?;
+                return Err(error_code);
+            }
+        }
+    }
+
+    let result = async {
+        let attrs = getattrs(inode, raft, remote_rafts).await?;
+        check_not_protected(&attrs)?;
+        if attrs.directory_entries > 0 {
+            return Err(ErrorCode::NotEmpty);
+        }
+        remove_link(
+            parent,
+            name,
+            Some((inode, attrs.uid)),
+            None,
+            context,
+            raft,
+            remote_rafts,
+        )
+        .await
+    }
+    .await;
+
+    match result {
+        Ok((lookup_inode, true)) => Ok((lookup_inode, true, shard_locks)),
+        Ok((lookup_inode, false)) => {
+            unlock_shards(&shard_locks, raft, remote_rafts).await?;
+            Ok((lookup_inode, false, vec![]))
+        }
+        Err(error_code) => {
+            unlock_shards(&shard_locks, raft, remote_rafts).await?;
+            Err(error_code)
+        }
+    }
+}
+
+async fn unlock_shards(
+    shard_locks: &[(u64, u64)],
+    raft: &LocalRaftGroupManager,
+    remote_rafts: &RemoteRaftGroups,
+) -> Result<(), ErrorCode> {
+    for &(shard, lock_id) in shard_locks {
+        unlock_inode(shard, lock_id, raft, remote_rafts).await?;
+    }
+
+    Ok(())
+}
+
 // TODO: persist transaction state, so that it doesn't get lost if the coordinating machine dies
 // in the middle
 pub async fn unlink_transaction(
     parent: u64,
     name: &str,
-    context: UserContext,
+    context: UserContext<'_>,
     raft: Arc<LocalRaftGroupManager>,
     remote_rafts: Arc<RemoteRaftGroups>,
 ) -> Result<Response, ErrorCode> {
+    // TODO: the flags could be changed between this check and the link removal
+    let inode = lookup(parent, name, context, &raft, &remote_rafts).await?;
+    check_not_protected(&getattrs(inode, &raft, &remote_rafts).await?)?;
+
     // Try to remove the link. The result of this might be indeterminate, since "sticky bit"
     // can require that we know the uid of the inode
     let (link_inode, complete) =
@@ -590,9 +1482,13 @@ pub async fn unlink_transaction(
         while !complete {
             // If the link removal didn't complete successful or with an error, then we need to
             // lock the target inode and lookup its uid to allow processing of "sticky bit"
-            let lock_id = lock_inode(inode, &raft, &remote_rafts).await?;
+            let lock_id = lock_inode(inode, LockMode::Exclusive, &raft, &remote_rafts).await?;
             match getattrs(inode, &raft, &remote_rafts).await {
                 Ok(attrs) => {
+                    if let Err(error_code) = check_not_protected(&attrs) {
+                        unlock_inode(inode, lock_id, &raft, &remote_rafts).await?;
+                        return Err(error_code);
+                    }
                     match remove_link(
                         parent,
                         name,
@@ -640,23 +1536,43 @@ pub async fn unlink_transaction(
 pub async fn create_transaction(
     parent: u64,
     name: &str,
-    uid: u32,
-    gid: u32,
+    // The new inode is owned by this user
+    context: UserContext<'_>,
     mode: u16,
+    umask: u16,
     kind: FileKind,
+    rdev: u32,
     raft: Arc<LocalRaftGroupManager>,
     remote_rafts: Arc<RemoteRaftGroups>,
 ) -> Result<Response, ErrorCode> {
+    // Creating device nodes requires CAP_MKNOD, which only root is assumed to have
+    if matches!(kind, FileKind::CharDevice | FileKind::BlockDevice) && context.uid() != 0 {
+        return Err(ErrorCode::OperationNotPermitted);
+    }
+    // The umask is only applied if there's no default ACL to inherit
+    let default_acl = get_default_acl(parent, &raft, &remote_rafts).await?;
+    let mode = if default_acl.is_some() {
+        mode
+    } else {
+        mode & !umask
+    };
+    let parent_attrs = getattrs(parent, &raft, &remote_rafts).await?;
+    let (gid, mode) = new_inode_group_and_mode(&parent_attrs, context, mode, kind);
+
     // First create inode. This effectively begins the transaction.
     // TODO: actually load balance
     let raft_group = rand::rng().random_range(0..remote_rafts.get_total_raft_groups());
     let create_inode = Request::CreateInode {
         raft_group,
         parent,
-        uid,
+        uid: context.uid(),
         gid,
         mode,
         kind,
+        open_handle: None,
+        rdev,
+        default_acl: default_acl.as_deref(),
+        shard_of: None,
     };
 
     // This will be the response back to the client
@@ -676,16 +1592,18 @@ pub async fn create_transaction(
     let client_response = Response::EntryMetadata(created_inode_response);
 
     // Second create the link
-    let create_link = Request::CreateLink {
+    match create_link(
         parent,
         name,
         inode,
         kind,
-        lock_id: None,
-        context: UserContext::new(uid, gid),
-    };
-
-    match propose(parent, &create_link, &raft, &remote_rafts).await {
+        None,
+        context,
+        &raft,
+        &remote_rafts,
+    )
+    .await
+    {
         Ok(_) => {
             // This is the response back to the client
             Ok(client_response)
@@ -705,13 +1623,68 @@ pub async fn create_transaction(
     }
 }
 
+// Creates a file without linking it into the parent. Since there are no links to roll back,
+// this is a single request, once access to the parent has been checked
+#[allow(clippy::too_many_arguments)]
+pub async fn create_temporary_transaction(
+    parent: u64,
+    // The new inode is owned by this user
+    context: UserContext<'_>,
+    mode: u16,
+    umask: u16,
+    open_handle: OpenHandleId,
+    raft: Arc<LocalRaftGroupManager>,
+    remote_rafts: Arc<RemoteRaftGroups>,
+) -> Result<Response, ErrorCode> {
+    check_inode_access(
+        parent,
+        libc::W_OK | libc::X_OK,
+        context,
+        &raft,
+        &remote_rafts,
+    )
+    .await?;
+    let default_acl = get_default_acl(parent, &raft, &remote_rafts).await?;
+    let mode = if default_acl.is_some() {
+        mode
+    } else {
+        mode & !umask
+    };
+    let parent_attrs = getattrs(parent, &raft, &remote_rafts).await?;
+    let (gid, mode) = new_inode_group_and_mode(&parent_attrs, context, mode, FileKind::File);
+
+    // TODO: actually load balance
+    let raft_group = rand::rng().random_range(0..remote_rafts.get_total_raft_groups());
+    let create_inode = Request::CreateInode {
+        raft_group,
+        parent,
+        uid: context.uid(),
+        gid,
+        mode,
+        kind: FileKind::File,
+        open_handle: Some(open_handle),
+        rdev: 0,
+        default_acl: default_acl.as_deref(),
+        shard_of: None,
+    };
+
+    let response_data = remote_rafts
+        .propose_to_specific_group(raft_group, &create_inode)
+        .await
+        .map_err(|_| ErrorCode::Uncategorized)?;
+    let response = response_or_error(&response_data)?;
+    let attrs = response.as_attr_response().ok_or(ErrorCode::BadResponse)?;
+
+    Ok(Response::EntryMetadata(attrs))
+}
+
 // TODO: persist transaction state, so that it doesn't get lost if the coordinating machine dies
 // in the middle
 pub async fn hardlink_transaction(
     inode: u64,
     new_parent: u64,
     new_name: &str,
-    context: UserContext,
+    context: UserContext<'_>,
     raft: Arc<LocalRaftGroupManager>,
     remote_rafts: Arc<RemoteRaftGroups>,
 ) -> Result<Response, ErrorCode> {
@@ -729,31 +1702,25 @@ pub async fn hardlink_transaction(
         _ => return Err(ErrorCode::BadResponse),
     };
 
-    // Second create the new link
-    let create_link = Request::CreateLink {
-        parent: new_parent,
-        name: new_name,
-        inode,
-        kind: attrs.kind,
-        lock_id: None,
-        context,
-    };
-
     let rollback_request = Request::HardlinkRollback {
         inode,
         last_modified_time: rollback,
     };
 
-    match propose(new_parent, &create_link, &raft, &remote_rafts).await {
-        Ok(response_data) => {
-            if response_or_error(&response_data).is_err() {
-                // Rollback the transaction
-                // TODO: if this fails the filesystem is corrupted ;( since the link count
-                // may not have been decremented
-                let response_data = propose(inode, &rollback_request, &raft, &remote_rafts).await?;
-                response_or_error(&response_data)?;
-            }
-
+    // Second create the new link
+    match create_link(
+        new_parent,
+        new_name,
+        inode,
+        attrs.kind,
+        None,
+        context,
+        &raft,
+        &remote_rafts,
+    )
+    .await
+    {
+        Ok(()) => {
             // This is the response back to the client
             Ok(Response::EntryMetadata(attrs))
         }
@@ -767,3 +1734,201 @@ pub async fn hardlink_transaction(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{
+        AtimeMode, ErrorCode, FS_APPEND_FL, FS_IMMUTABLE_FL, FileKind, RENAME_NOREPLACE,
+        UserContext, pack_groups,
+    };
+    use crate::client::NodeClient;
+    use crate::storage::local::DIRECTORY_SHARD_THRESHOLD;
+    use crate::storage::message_handlers::transaction_coordinator::{
+        FileOrDirAttrs, check_can_add_entry, check_not_ancestor, new_inode_group_and_mode,
+    };
+    use crate::storage::{Node, ROOT_INODE};
+    use futures::executor::block_on;
+    use futures::future::ready;
+    use std::collections::HashMap;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::{TempDir, tempdir};
+
+    // Starts a cluster of two nodes, since directories are only split when there is more than one
+    // raft group
+    fn start_cluster() -> (Vec<SocketAddr>, Vec<TempDir>) {
+        let listeners: Vec<TcpListener> = (0..2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let addresses: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        drop(listeners);
+        let mut dirs = vec![];
+        for address in addresses.iter() {
+            let dir = tempdir().unwrap();
+            let peers = addresses
+                .iter()
+                .filter(|x| *x != address)
+                .copied()
+                .collect();
+            let node = Node::new(
+                dir.path().to_str().unwrap(),
+                *address,
+                peers,
+                1,
+                AtimeMode::NoAtime,
+            );
+            thread::spawn(move || node.run());
+            dirs.push(dir);
+        }
+        let client = NodeClient::new(addresses.clone());
+        while client.filesystem_ready().is_err() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        (addresses, dirs)
+    }
+
+    #[test]
+    fn moving_directory_below_itself() {
+        // root -> 2 -> 3 -> 4, and root -> 5
+        let parents: HashMap<u64, u64> = [(2, ROOT_INODE), (3, 2), (4, 3), (5, ROOT_INODE)].into();
+        let parent_of = |inode| ready(parents.get(&inode).copied().ok_or(ErrorCode::DoesNotExist));
+        let check = |directory, destination| {
+            block_on(check_not_ancestor(directory, destination, parent_of))
+        };
+
+        assert_eq!(check(2, 4), Err(ErrorCode::InvalidArgument));
+        assert_eq!(check(3, 3), Err(ErrorCode::InvalidArgument));
+        assert_eq!(check(4, 2), Ok(()));
+        assert_eq!(check(2, 5), Ok(()));
+        assert_eq!(check(5, ROOT_INODE), Ok(()));
+    }
+
+    #[test]
+    fn protected_directory_entries() {
+        let directory = |flags| FileOrDirAttrs {
+            inode: 2,
+            kind: FileKind::Directory,
+            mode: 0o777,
+            hardlinks: 2,
+            uid: 0,
+            gid: 0,
+            directory_entries: 1,
+            flags,
+        };
+        let denied = Err(ErrorCode::OperationNotPermitted);
+
+        assert_eq!(check_can_add_entry(&directory(0), false), Ok(()));
+        assert_eq!(check_can_add_entry(&directory(0), true), Ok(()));
+        // Entries can be added to an append-only directory, such as by renaming into it
+        assert_eq!(check_can_add_entry(&directory(FS_APPEND_FL), false), Ok(()));
+        assert_eq!(check_can_add_entry(&directory(FS_APPEND_FL), true), denied);
+        assert_eq!(
+            check_can_add_entry(&directory(FS_IMMUTABLE_FL), false),
+            denied
+        );
+        assert_eq!(
+            check_can_add_entry(&directory(FS_IMMUTABLE_FL), true),
+            denied
+        );
+    }
+
+    #[test]
+    fn setgid_directory_group_inherited() {
+        let parent = FileOrDirAttrs {
+            inode: 2,
+            kind: FileKind::Directory,
+            mode: 0o2775,
+            hardlinks: 2,
+            uid: 1,
+            gid: 7,
+            directory_entries: 0,
+            flags: 0,
+        };
+        let groups = pack_groups(&[7]);
+        let member = UserContext::with_groups(2, 2, &groups);
+        let other = UserContext::new(3, 3);
+
+        assert_eq!(
+            new_inode_group_and_mode(&parent, other, 0o755, FileKind::Directory),
+            (7, 0o2755)
+        );
+        assert_eq!(
+            new_inode_group_and_mode(&parent, member, 0o2755, FileKind::File),
+            (7, 0o2755)
+        );
+        // Only members of the group may create setgid files
+        assert_eq!(
+            new_inode_group_and_mode(&parent, other, 0o2755, FileKind::File),
+            (7, 0o755)
+        );
+        let parent = FileOrDirAttrs {
+            mode: 0o775,
+            ..parent
+        };
+        assert_eq!(
+            new_inode_group_and_mode(&parent, other, 0o644, FileKind::File),
+            (3, 0o644)
+        );
+    }
+
+    #[test]
+    fn rename_races_create_in_sharded_directory() {
+        let (addresses, _dirs) = start_cluster();
+        let client = NodeClient::new(addresses.clone());
+        let root = UserContext::new(0, 0);
+        let create = |client: &NodeClient, parent, name: &str| {
+            client
+                .create(parent, name, root, 0o644, 0, FileKind::File, 0)
+                .map(|attrs| attrs.inode)
+        };
+        let directory = client
+            .mkdir(ROOT_INODE, "big", root, 0o755, 0)
+            .unwrap()
+            .inode;
+        for i in 0..=DIRECTORY_SHARD_THRESHOLD {
+            create(&client, directory, &format!("file{i}")).unwrap();
+        }
+
+        for i in 0..50 {
+            let flags = if i % 2 == 0 { RENAME_NOREPLACE } else { 0 };
+            let (source, destination) = (format!("source{i}"), format!("destination{i}"));
+            let inode = create(&client, directory, &source).unwrap();
+            let rename = {
+                let client = NodeClient::new(addresses.clone());
+                let (source, destination) = (source.clone(), destination.clone());
+                thread::spawn(move || {
+                    let root = UserContext::new(0, 0);
+                    client.rename(directory, &source, directory, &destination, flags, root)
+                })
+            };
+            let created = create(&client, directory, &destination);
+            let renamed = rename.join().unwrap();
+
+            let client = NodeClient::new(addresses.clone());
+            let linked = client.lookup(directory, &destination, root).unwrap();
+            match (renamed, created) {
+                (Ok(()), Err(error_code)) => {
+                    assert_eq!(error_code, ErrorCode::AlreadyExists);
+                    assert_eq!(linked, inode);
+                }
+                // The rename replaced the created file
+                (Ok(()), Ok(_)) => {
+                    assert_eq!(flags, 0);
+                    assert_eq!(linked, inode);
+                }
+                (Err(error_code), Ok(created)) => {
+                    assert_eq!(flags, RENAME_NOREPLACE);
+                    assert_eq!(error_code, ErrorCode::AlreadyExists);
+                    assert_eq!(linked, created);
+                    assert_eq!(client.lookup(directory, &source, root), Ok(inode));
+                }
+                result => panic!("Unexpected result: {result:?}"),
+            }
+        }
+    }
+}
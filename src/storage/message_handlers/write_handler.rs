@@ -1,5 +1,6 @@
-use crate::base::{ErrorCode, Request, Response};
-use crate::storage::local::FileStorage;
+use crate::base::{ErrorCode, PosixAcl, Request, Response, unpack_inodes};
+use crate::base::{decode_request, decode_request_result, encode_request_result};
+use crate::storage::local::{FileStorage, LOCAL_REQUEST_OWNER};
 
 pub fn commit_write(
     request: &Request<'_>,
@@ -32,6 +33,7 @@ pub fn commit_write(
         | Request::Hardlink { .. }
         | Request::Rename { .. }
         | Request::Create { .. }
+        | Request::CreateTemporary { .. }
         | Request::Unlink { .. }
         | Request::Rmdir { .. } => {
             unreachable!("Transaction coordinator should break these up into internal requests");
@@ -47,16 +49,24 @@ pub fn commit_write(
             gid,
             context,
         } => file_storage.chown(*inode, *uid, *gid, *context),
+        Request::SetFlags {
+            inode,
+            flags,
+            context,
+        } => file_storage.set_flags(*inode, *flags, *context),
         Request::Truncate {
             inode,
             new_length,
             context,
-        } => file_storage.truncate(*inode, *new_length, *context),
+            has_write_handle,
+        } => file_storage.truncate(*inode, *new_length, *has_write_handle, *context),
         Request::Write {
             inode,
             offset,
             data,
-        } => file_storage.write(*inode, *offset, data),
+            uid,
+            append,
+        } => file_storage.write(*inode, *offset, data, *uid, *append),
         Request::RemoveLink {
             parent,
             name,
@@ -91,21 +101,101 @@ pub fn commit_write(
             gid,
             mode,
             kind,
+            open_handle,
+            rdev,
+            default_acl,
+            shard_of,
             ..
-        } => file_storage.create_inode(*parent, *uid, *gid, *mode, *kind),
+        } => {
+            let default_acl = default_acl.map(PosixAcl::from_xattr).transpose()?;
+            file_storage.create_inode(
+                *parent,
+                *uid,
+                *gid,
+                *mode,
+                *kind,
+                *rdev,
+                *open_handle,
+                default_acl,
+                *shard_of,
+            )
+        }
         Request::HardlinkIncrement { inode } => file_storage.hardlink_stage0_link_increment(*inode),
         Request::UpdateParent {
             inode, new_parent, ..
         } => file_storage.update_parent(*inode, *new_parent),
-        Request::UpdateMetadataChangedTime { inode, .. } => {
-            file_storage.update_metadata_changed_time(*inode)
+        Request::UpdateMetadataChangedTime {
+            inode, modified, ..
+        } => file_storage.update_metadata_changed_time(*inode, *modified),
+        Request::UpdateAccessTimes { inodes, strict, .. } => {
+            file_storage.update_access_times(&unpack_inodes(inodes), *strict)
+        }
+        Request::ShardDirectory { inode, shards } => {
+            file_storage.shard_directory(*inode, &unpack_inodes(shards))
         }
         Request::DecrementInode {
             inode,
             decrement_count,
             ..
         } => file_storage.decrement_inode_link_count(*inode, *decrement_count),
-        Request::Lock { .. } | Request::Unlock { .. } => {
+        Request::OpenHandle {
+            inode,
+            session,
+            handle,
+        } => file_storage.open_handle(*inode, *session, *handle),
+        Request::ReleaseHandle {
+            inode,
+            session,
+            handle,
+        } => file_storage.release_handle(*inode, *session, *handle),
+        // The session's advisory locks were already released by the LockTable
+        Request::ReleaseSession { session, .. } => file_storage.release_session(*session),
+        Request::RenewSession { session, .. } => file_storage.renew_session(*session),
+        // Requests to a single raft group are begun and finished in the same commit
+        Request::Deduplicated {
+            session,
+            sequence,
+            acknowledged,
+            request,
+        } => match file_storage.begin_request(
+            *session,
+            *sequence,
+            *acknowledged,
+            LOCAL_REQUEST_OWNER,
+        )? {
+            Response::RequestResult { result } => decode_request_result(&result),
+            _ => {
+                let result = decode_request(request)
+                    .map_err(|_| ErrorCode::BadRequest)
+                    .and_then(|request| commit_write(&request, file_storage));
+                file_storage.finish_request(
+                    *session,
+                    *sequence,
+                    LOCAL_REQUEST_OWNER,
+                    &encode_request_result(&result),
+                )?;
+                result
+            }
+        },
+        Request::BeginRequest {
+            session,
+            sequence,
+            acknowledged,
+            owner,
+            ..
+        } => file_storage.begin_request(*session, *sequence, *acknowledged, *owner),
+        Request::FinishRequest {
+            session,
+            sequence,
+            owner,
+            result,
+            ..
+        } => file_storage.finish_request(*session, *sequence, *owner, result),
+        Request::Lock { .. }
+        | Request::Unlock { .. }
+        | Request::SetRangeLock { .. }
+        | Request::CancelRangeLockWait { .. }
+        | Request::ExpireSessions { .. } => {
             unreachable!("This should have been handled by the LockTable");
         }
         Request::FilesystemReady
@@ -116,9 +206,13 @@ pub fn commit_write(
         | Request::ReadRaw { .. }
         | Request::Lookup { .. }
         | Request::GetAttr { .. }
+        | Request::Access { .. }
         | Request::ListDir { .. }
         | Request::ListXattrs { .. }
         | Request::GetXattr { .. }
+        | Request::GetRangeLock { .. }
+        | Request::GetDirectoryShards { .. }
+        | Request::WatchInvalidations { .. }
         | Request::LatestCommit { .. }
         | Request::RaftGroupLeader { .. }
         | Request::ConsensusMessage { .. } => {
@@ -1,3 +1,4 @@
+mod invalidation_log;
 mod local;
 mod lock_table;
 mod message_handlers;
@@ -5,7 +5,8 @@ use crate::base::LocalContext;
 use crate::base::node_contains_raft_group;
 use crate::base::node_id_from_address;
 use crate::client::{PeerClient, TcpPeerClient};
-use crate::storage::local::FileStorage;
+use crate::storage::invalidation_log::{Change, Changes, InvalidationLog};
+use crate::storage::local::{FileStorage, LOCAL_REQUEST_OWNER};
 use crate::storage::lock_table::LockTable;
 use crate::storage::message_handlers::commit_write;
 use futures::FutureExt;
@@ -15,21 +16,91 @@ use futures::future::{Either, Ready, ready};
 use futures::{Future, TryFutureExt};
 use rand::Rng;
 use raxos::{Action, CommandId, Config, Replica, ReplicaId, Slot};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use crate::base::{ErrorCode, Request, Response, decode_request, encode_request};
+use crate::base::{
+    AccessType, ErrorCode, RangeLock, Request, Response, SESSION_LEASE_SECONDS, Timestamp,
+    decode_request, decode_request_result, encode_request, encode_request_result, pack_inodes,
+};
 
 // Warn when a group retains this much consensus history for a lagging
 // replica (the raft integration warned at 2x its 10MB compaction threshold).
 const RETAINED_WARN_BYTES: usize = 32 * 1024 * 1024;
 
+// How long a client's watch for invalidations waits for changes before returning an empty response
+const INVALIDATION_WATCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often the leader checks for client sessions whose leases have expired
+const SESSION_EXPIRY_INTERVAL: Duration = Duration::from_secs(5);
+
 type PendingResponse = Sender<Result<Response, ErrorCode>>;
 
+// Proposals are prefixed with the version of their format, then the timestamp chosen by the
+// proposing node: seconds then nanos
+const PROPOSAL_VERSION: u8 = 1;
+const PROPOSAL_HEADER_BYTES: usize = 13;
+
+fn encode_proposal(timestamp: Timestamp, request: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(PROPOSAL_HEADER_BYTES + request.len());
+    data.push(PROPOSAL_VERSION);
+    data.extend_from_slice(&timestamp.seconds.to_le_bytes());
+    data.extend_from_slice(&timestamp.nanos.to_le_bytes());
+    data.extend_from_slice(request);
+    data
+}
+
+// Proposals in an unknown format are rejected, rather than applied with a garbage timestamp
+fn decode_proposal(mut data: Vec<u8>) -> Result<(Timestamp, Vec<u8>), ErrorCode> {
+    if data.len() < PROPOSAL_HEADER_BYTES || data[0] != PROPOSAL_VERSION {
+        return Err(ErrorCode::BadRequest);
+    }
+    let request = data.split_off(PROPOSAL_HEADER_BYTES);
+    let seconds = i64::from_le_bytes(data[1..9].try_into().unwrap());
+    let nanos = i32::from_le_bytes(data[9..].try_into().unwrap());
+    Ok((Timestamp::new(seconds, nanos), request))
+}
+
+// Returns the time to commit a proposal at. Proposals from different nodes may be decided in a
+// different order than their timestamps, so the commit time is held back from going backwards
+fn commit_time(proposed: Timestamp, last_commit_time: &mut Timestamp) -> Timestamp {
+    *last_commit_time = proposed.max(*last_commit_time);
+    *last_commit_time
+}
+
+// What the request changes, that clients may have cached. Changes to the entries of a shard are
+// reported against its directory, since that's the inode which clients look entries up in
+fn request_change(request: &Request, file_storage: &FileStorage) -> Option<Change> {
+    let meta = request.meta_info();
+    let inode = meta.inode?;
+    match meta.access_type {
+        AccessType::WriteMetadata => {}
+        AccessType::WriteDataAndMetadata => return Some(Change::Data(inode)),
+        _ => return None,
+    }
+    if let Some(name) = meta.entry {
+        let directory = file_storage.shard_parent(inode).ok().flatten();
+        return Some(Change::Entry(directory.unwrap_or(inode), name));
+    }
+
+    Some(Change::Attributes(inode))
+}
+
+fn send_range_lock_responses(
+    responses: Vec<(Option<PendingResponse>, Result<Response, ErrorCode>)>,
+) {
+    for (sender, response) in responses {
+        if let Some(sender) = sender {
+            // Ignore errors, since the client may have disconnected while it was waiting for a lock
+            sender.send(response).ok();
+        }
+    }
+}
+
 // A member of one replication group, wrapping the raxos consensus replica
 // and applying its decided commands to the local FileStorage.
 pub struct ConsensusNode {
@@ -38,13 +109,26 @@ pub struct ConsensusNode {
     sync_requests: Mutex<Vec<(u64, Sender<()>)>>,
     applied_index: AtomicU64,
     peers: HashMap<u64, TcpPeerClient>,
+    node_id: u64,
     raft_group_id: u16,
     file_storage: FileStorage,
     lock_table: Mutex<LockTable>,
     // Origin of the monotonic clock fed to raxos.
     start: Instant,
+    // When expired sessions were last checked for (nanos since start)
+    last_session_expiry: AtomicU64,
     // Rate limiter for the retained-history warning (nanos of last warn).
     last_retained_warn: AtomicU64,
+    // Hybrid logical clock: the latest timestamp proposed by this node, or applied in this group.
+    // Timestamps are chosen from it when proposing, so that every replica applies the same
+    // times, and they never go backwards within the group even if the nodes' clocks are skewed
+    clock: Mutex<Timestamp>,
+    // The time that the last proposal was committed at. Only changed when applying, so that it's
+    // the same on every replica
+    last_commit_time: Mutex<Timestamp>,
+    // Inodes that were read, whose access times are updated by the next background_tick()
+    accessed_inodes: Mutex<HashSet<u64>>,
+    invalidations: Mutex<InvalidationLog>,
 }
 
 impl ConsensusNode {
@@ -99,6 +183,22 @@ impl ConsensusNode {
             .cloned()
             .collect();
 
+        let file_storage = FileStorage::new(
+            node_id,
+            raft_group_id,
+            num_raft_groups,
+            &path,
+            &peer_addresses,
+        );
+        // Advisory locks are stored, so that they survive restarts
+        let mut lock_table = LockTable::new();
+        for (inode, lock, waiting) in file_storage
+            .range_locks()
+            .expect("Failed to load advisory locks")
+        {
+            lock_table.restore_range_lock(inode, lock, waiting);
+        }
+
         ConsensusNode {
             replica: Mutex::new(replica),
             pending_responses: Mutex::new(HashMap::new()),
@@ -108,17 +208,17 @@ impl ConsensusNode {
                 .iter()
                 .map(|peer| (node_id_from_address(peer), TcpPeerClient::new(*peer)))
                 .collect(),
+            node_id,
             raft_group_id,
-            file_storage: FileStorage::new(
-                node_id,
-                raft_group_id,
-                num_raft_groups,
-                &path,
-                &peer_addresses,
-            ),
-            lock_table: Mutex::new(LockTable::new()),
+            file_storage,
+            lock_table: Mutex::new(lock_table),
             start: Instant::now(),
+            last_session_expiry: AtomicU64::new(0),
             last_retained_warn: AtomicU64::new(0),
+            clock: Mutex::new(Timestamp::new(0, 0)),
+            last_commit_time: Mutex::new(Timestamp::new(0, 0)),
+            accessed_inodes: Mutex::new(HashSet::new()),
+            invalidations: Mutex::new(InvalidationLog::new()),
         }
     }
 
@@ -130,6 +230,10 @@ impl ConsensusNode {
         self.file_storage.local_data_checksum()
     }
 
+    pub fn get_range_lock(&self, inode: u64, lock: &RangeLock) -> Option<RangeLock> {
+        self.lock_table.lock().unwrap().get_range_lock(inode, lock)
+    }
+
     // TODO: remove this method
     pub fn file_storage(&self) -> &FileStorage {
         &self.file_storage
@@ -139,6 +243,12 @@ impl ConsensusNode {
         self.start.elapsed().as_nanos() as u64
     }
 
+    fn next_timestamp(&self) -> Timestamp {
+        let mut clock = self.clock.lock().unwrap();
+        *clock = Timestamp::now().max(clock.next());
+        *clock
+    }
+
     // Runs an input against the consensus replica and carries out the
     // effects it emits. Decided commands are applied while the replica lock
     // is held, which keeps deliveries strictly ordered across concurrently
@@ -213,10 +323,127 @@ impl ConsensusNode {
         }
     }
 
+    // Returns the inodes changed after the given sequence number, waiting for a change if there
+    // haven't been any
+    pub async fn watch_invalidations(&self, epoch: u64, sequence: u64, session: u64) -> Response {
+        let receiver = {
+            let mut invalidations = self.invalidations.lock().unwrap();
+            if let Some(changes) = invalidations.changes_since(epoch, sequence) {
+                return self.invalidations_response(invalidations.position(), changes, session);
+            }
+            invalidations.wait()
+        };
+        // Ignore the timeout, since the client just watches again
+        tokio::time::timeout(INVALIDATION_WATCH_TIMEOUT, receiver)
+            .await
+            .ok();
+
+        let (position, changes) = {
+            let invalidations = self.invalidations.lock().unwrap();
+            let changes = invalidations
+                .changes_since(epoch, sequence)
+                .unwrap_or_else(|| Changes::Changed(HashSet::new()));
+            (invalidations.position(), changes)
+        };
+        self.invalidations_response(position, changes, session)
+    }
+
+    fn invalidations_response(
+        &self,
+        (epoch, sequence): (u64, u64),
+        changes: Changes,
+        session: u64,
+    ) -> Response {
+        let mut inodes = vec![];
+        let mut data_inodes = vec![];
+        let mut entry_parents = vec![];
+        let mut entry_names = vec![];
+        let complete = match changes {
+            Changes::Missed => false,
+            Changes::Changed(changes) => {
+                for change in changes {
+                    match change {
+                        Change::Attributes(inode) => inodes.push(inode),
+                        Change::Data(inode) => {
+                            inodes.push(inode);
+                            // Sessions without the file open don't have its data cached, since
+                            // the kernel drops cached data when a file is opened
+                            if self
+                                .file_storage
+                                .has_open_handle(inode, session)
+                                .unwrap_or(true)
+                            {
+                                data_inodes.push(inode);
+                            }
+                        }
+                        Change::Entry(parent, name) => {
+                            inodes.push(parent);
+                            entry_parents.push(parent);
+                            entry_names.push(name);
+                        }
+                    }
+                }
+                inodes.sort_unstable();
+                inodes.dedup();
+                true
+            }
+        };
+
+        Response::Invalidations {
+            epoch,
+            sequence,
+            complete,
+            inodes: pack_inodes(&inodes),
+            data_inodes: pack_inodes(&data_inodes),
+            entry_parents: pack_inodes(&entry_parents),
+            entry_names,
+        }
+    }
+
+    // Queues an access time update, which is proposed in a batch by background_tick()
+    pub fn record_access(&self, inode: u64) {
+        self.accessed_inodes.lock().unwrap().insert(inode);
+    }
+
     // Should be called once every 100ms to handle background tasks
     pub fn background_tick(&self) {
         self.drive(|replica, now| replica.tick(now));
 
+        let accessed: Vec<u64> = self.accessed_inodes.lock().unwrap().drain().collect();
+        if !accessed.is_empty() {
+            let request = Request::UpdateAccessTimes {
+                raft_group: self.raft_group_id,
+                inodes: &pack_inodes(&accessed),
+                strict: false,
+            };
+            let raft_group_id = self.raft_group_id;
+            tokio::spawn(self.propose(&request).map(move |result| {
+                if let Err(error_code) = result {
+                    warn!("rgroup {raft_group_id}: failed to update access times: {error_code:?}");
+                }
+            }));
+        }
+
+        // Sessions are only expired once this node has been running for a whole lease, so that
+        // clients have had a chance to renew them after the cluster was down
+        let now = self.now();
+        let last_expiry = self.last_session_expiry.load(Ordering::Relaxed);
+        if now > Duration::from_secs(SESSION_LEASE_SECONDS as u64).as_nanos() as u64
+            && now - last_expiry > SESSION_EXPIRY_INTERVAL.as_nanos() as u64
+            && self.replica.lock().unwrap().leader().0 == self.node_id
+        {
+            self.last_session_expiry.store(now, Ordering::Relaxed);
+            let request = Request::ExpireSessions {
+                raft_group: self.raft_group_id,
+            };
+            let raft_group_id = self.raft_group_id;
+            tokio::spawn(self.propose(&request).map(move |result| {
+                if let Err(error_code) = result {
+                    warn!("rgroup {raft_group_id}: failed to expire sessions: {error_code:?}");
+                }
+            }));
+        }
+
         // Retention is unbounded so lagging replicas always remain
         // recoverable (see new()); surface sustained growth, which means
         // some replica has been unreachable for a long time.
@@ -248,35 +475,91 @@ impl ConsensusNode {
 
         let mut lock_table = self.lock_table.lock().unwrap();
 
+        // Advisory locks are entirely managed by the lock table
+        match request {
+            Request::SetRangeLock { inode, lock, wait } => {
+                let responses = lock_table.set_range_lock(inode, lock, wait, pending_response);
+                self.store_range_locks(&mut lock_table);
+                send_range_lock_responses(responses);
+                return vec![];
+            }
+            Request::CancelRangeLockWait { inode, lock } => {
+                let mut responses = lock_table.cancel_range_lock_wait(inode, lock);
+                self.store_range_locks(&mut lock_table);
+                responses.push((pending_response, Ok(Response::Empty)));
+                send_range_lock_responses(responses);
+                return vec![];
+            }
+            Request::ReleaseSession { session, .. } => {
+                let responses = lock_table.release_session(session);
+                self.store_range_locks(&mut lock_table);
+                send_range_lock_responses(responses);
+                // The session's open handles are released by the FileStorage
+                return vec![(request_data, pending_response)];
+            }
+            Request::ExpireSessions { .. } => {
+                let result = self.expire_sessions(&mut lock_table);
+                if let Some(sender) = pending_response {
+                    sender.send(result).ok();
+                }
+                return vec![];
+            }
+            Request::Deduplicated {
+                session,
+                sequence,
+                acknowledged,
+                request: wrapped,
+            } => {
+                if let Ok(
+                    wrapped @ (Request::SetRangeLock { wait: false, .. }
+                    | Request::CancelRangeLockWait { .. }),
+                ) = decode_request(wrapped)
+                {
+                    let result = self.apply_deduplicated_range_lock(
+                        &mut lock_table,
+                        session,
+                        sequence,
+                        acknowledged,
+                        wrapped,
+                    );
+                    if let Some(sender) = pending_response {
+                        sender.send(result).ok();
+                    }
+                    return vec![];
+                }
+            }
+            _ => {}
+        }
+
         let mut to_process = vec![];
         if let Some(inode) = request_meta.inode {
             if lock_table.is_locked(&request_meta) {
                 lock_table.wait_for_lock(inode, (request_data, pending_response));
             } else {
                 match request {
-                    Request::Lock { inode } => {
-                        let lock_id = lock_table.lock(inode);
+                    Request::Lock { inode, name, mode } => {
+                        let lock_id = lock_table.lock(inode, name, mode);
                         if let Some(sender) = pending_response {
                             sender.send(Ok(Response::Lock { lock_id })).ok().unwrap();
                         }
                     }
                     Request::Unlock { inode, lock_id } => {
-                        let (mut requests, new_lock_id) = lock_table.unlock(inode, lock_id);
+                        let requests = lock_table.unlock(inode, lock_id);
                         if let Some(sender) = pending_response {
                             sender.send(Ok(Response::Empty)).ok().unwrap();
                         }
-                        if let Some(id) = new_lock_id {
-                            let (lock_request_data, pending) = requests.pop().unwrap();
-                            let lock_request = decode_request(&lock_request_data).unwrap();
-                            assert!(matches!(lock_request, Request::Lock { .. }));
-                            if let Some(sender) = pending {
-                                sender
-                                    .send(Ok(Response::Lock { lock_id: id }))
-                                    .ok()
-                                    .unwrap();
+                        for ((data, pending), granted_lock_id) in requests {
+                            if let Some(id) = granted_lock_id {
+                                if let Some(sender) = pending {
+                                    sender
+                                        .send(Ok(Response::Lock { lock_id: id }))
+                                        .ok()
+                                        .unwrap();
+                                }
+                            } else {
+                                to_process.push((data, pending));
                             }
                         }
-                        to_process.extend(requests);
                     }
                     _ => {
                         // Default to processing the request
@@ -292,6 +575,67 @@ impl ConsensusNode {
         to_process
     }
 
+    // Applies an advisory lock request which doesn't wait, unless it was applied before, in which
+    // case its original result is returned
+    fn apply_deduplicated_range_lock(
+        &self,
+        lock_table: &mut LockTable,
+        session: u64,
+        sequence: u64,
+        acknowledged: u64,
+        request: Request,
+    ) -> Result<Response, ErrorCode> {
+        if let Response::RequestResult { result } =
+            self.file_storage
+                .begin_request(session, sequence, acknowledged, LOCAL_REQUEST_OWNER)?
+        {
+            return decode_request_result(&result);
+        }
+        let mut responses = match request {
+            Request::SetRangeLock { inode, lock, wait } => {
+                lock_table.set_range_lock(inode, lock, wait, None)
+            }
+            Request::CancelRangeLockWait { inode, lock } => {
+                let mut responses = lock_table.cancel_range_lock_wait(inode, lock);
+                responses.insert(0, (None, Ok(Response::Empty)));
+                responses
+            }
+            _ => unreachable!("Only advisory lock requests are deduplicated by the lock table"),
+        };
+        self.store_range_locks(lock_table);
+        // The first response is for this request
+        let (_, result) = responses.remove(0);
+        send_range_lock_responses(responses);
+        self.file_storage.finish_request(
+            session,
+            sequence,
+            LOCAL_REQUEST_OWNER,
+            &encode_request_result(&result),
+        )?;
+
+        result
+    }
+
+    fn store_range_locks(&self, lock_table: &mut LockTable) {
+        for (inode, held, waiting) in lock_table.take_changed_range_locks() {
+            if let Err(error_code) = self.file_storage.store_range_locks(inode, &held, &waiting) {
+                error!("Failed to store advisory locks of {inode}: {error_code:?}");
+            }
+        }
+    }
+
+    // Releases the advisory locks and open handles of the sessions whose leases have expired
+    fn expire_sessions(&self, lock_table: &mut LockTable) -> Result<Response, ErrorCode> {
+        for session in self.file_storage.expired_sessions()? {
+            info!("rgroup {}: session {session} expired", self.raft_group_id);
+            send_range_lock_responses(lock_table.release_session(session));
+            self.file_storage.release_session(session)?;
+        }
+        self.store_range_locks(lock_table);
+
+        Ok(Response::Empty)
+    }
+
     // Applies one decided slot to the local state machine, resolving the
     // pending client response if this node was the submitter. Runs while the
     // replica lock is held; must not re-enter self.replica.
@@ -305,12 +649,35 @@ impl ConsensusNode {
                 }
                 continue;
             }
+            let (proposed, data) = match decode_proposal(data) {
+                Ok(proposal) => proposal,
+                Err(error_code) => {
+                    error!("Skipping malformed proposal in slot {}", slot.0);
+                    if let Some(sender) = pending_response {
+                        sender.send(Err(error_code)).ok();
+                    }
+                    continue;
+                }
+            };
+            let timestamp = commit_time(proposed, &mut self.last_commit_time.lock().unwrap());
+            {
+                let mut clock = self.clock.lock().unwrap();
+                *clock = timestamp.max(*clock);
+            }
+            // Requests which were waiting for a lock are also committed at this time
+            self.file_storage.set_commit_time(timestamp);
             let to_process = self._process_lock_table(data, pending_response);
 
             for (data, pending_response) in to_process {
                 let request = decode_request(&data).unwrap();
+                let result = commit_write(&request, &self.file_storage);
+                if result.is_ok()
+                    && let Some(change) = request_change(&request, &self.file_storage)
+                {
+                    self.invalidations.lock().unwrap().record(slot.0, change);
+                }
                 if let Some(sender) = pending_response {
-                    match commit_write(&request, &self.file_storage) {
+                    match result {
                         Ok(response) => sender.send(Ok(response)).ok().unwrap(),
                         // TODO: handle this somehow. If not all nodes failed, then the filesystem
                         // is probably corrupted, since some will have applied the write, but not all
@@ -336,7 +703,7 @@ impl ConsensusNode {
                 } else {
                     // Replicas won't have a pending response to reply to, since the node
                     // that submitted the proposal will reply to the client.
-                    if let Err(error_code) = commit_write(&request, &self.file_storage) {
+                    if let Err(error_code) = result {
                         // TODO: handle this somehow. If not all nodes failed, then the filesystem
                         // is probably corrupted, since some will have applied the write, but not all.
                         // There should only be a few types of messages that can fail here. truncate is one,
@@ -363,6 +730,7 @@ impl ConsensusNode {
         }
 
         self.applied_index.store(slot.0, Ordering::SeqCst);
+        self.invalidations.lock().unwrap().advance(slot.0);
 
         // TODO: once drain_filter is stable, it could be used to make this a lot nicer
         let mut sync_requests = self.sync_requests.lock().unwrap();
@@ -387,6 +755,12 @@ impl ConsensusNode {
         &self,
         request: Vec<u8>,
     ) -> impl Future<Output = Result<Response, ErrorCode>> + use<> {
+        // Read barriers are empty, and have nothing to timestamp
+        let request = if request.is_empty() {
+            request
+        } else {
+            encode_proposal(self.next_timestamp(), &request)
+        };
         let (sender, receiver) = oneshot::channel();
         let mut sender = Some(sender);
         self.drive(|replica, now| match replica.submit(now, &request) {
@@ -409,3 +783,60 @@ impl ConsensusNode {
         receiver.map(|x| x.unwrap_or(Err(ErrorCode::Uncategorized)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{ErrorCode, Timestamp};
+    use crate::storage::raft_node::{commit_time, decode_proposal, encode_proposal};
+
+    #[test]
+    fn commit_times_monotonic() {
+        let mut last_commit_time = Timestamp::new(0, 0);
+        let proposed = [
+            Timestamp::new(10, 5),
+            Timestamp::new(9, 0),
+            Timestamp::new(10, 5),
+            Timestamp::new(12, 0),
+            Timestamp::new(11, 999),
+        ];
+        let committed: Vec<Timestamp> = proposed
+            .iter()
+            .map(|timestamp| commit_time(*timestamp, &mut last_commit_time))
+            .collect();
+
+        assert!(committed.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(
+            committed,
+            [
+                Timestamp::new(10, 5),
+                Timestamp::new(10, 5),
+                Timestamp::new(10, 5),
+                Timestamp::new(12, 0),
+                Timestamp::new(12, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_proposals_rejected() {
+        let timestamp = Timestamp::new(1234, 5678);
+        let data = encode_proposal(timestamp, b"request");
+        assert_eq!(
+            decode_proposal(data.clone()),
+            Ok((timestamp, b"request".to_vec()))
+        );
+
+        // Too short to have a timestamp, or in a different format
+        assert_eq!(
+            decode_proposal(data[..5].to_vec()),
+            Err(ErrorCode::BadRequest)
+        );
+        assert_eq!(
+            decode_proposal(b"request".to_vec()),
+            Err(ErrorCode::BadRequest)
+        );
+        let mut other_version = data;
+        other_version[0] = 0;
+        assert_eq!(decode_proposal(other_version), Err(ErrorCode::BadRequest));
+    }
+}
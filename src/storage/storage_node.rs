@@ -5,60 +5,121 @@ use tokio_util::codec::length_delimited;
 
 use log::{debug, error};
 
-use crate::base::node_id_from_address;
+use crate::base::{Request, decode_request, node_id_from_address};
+use crate::client::REQUEST_ID_SIZE;
 use crate::storage::message_handlers::request_router;
 use byteorder::{ByteOrder, LittleEndian};
+use std::future::Future;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
-use crate::base::LocalContext;
+use crate::base::{AtimeMode, LocalContext};
 use crate::client::RemoteRaftGroups;
 use crate::storage::raft_group_manager::LocalRaftGroupManager;
+use futures::SinkExt;
+use futures::channel::mpsc;
 use futures_util::stream::StreamExt;
 use tokio::io::AsyncWriteExt;
+use tokio::task::JoinSet;
+
+// Most requests from one connection that are handled at once. Once this many are in flight, no more
+// are read from the connection until one completes
+const MAX_IN_FLIGHT_REQUESTS: usize = 256;
+
+// Requests which may wait until the client sends another request, such as a lock wait for the
+// unlock. These aren't counted towards MAX_IN_FLIGHT_REQUESTS, since they could fill it and stop the
+// request they're waiting for from being read
+fn waits_for_client(request: &[u8]) -> bool {
+    match decode_request(request) {
+        Ok(Request::SetRangeLock { wait, .. }) => wait,
+        Ok(Request::WatchInvalidations { .. }) => true,
+        Ok(Request::Deduplicated { request, .. }) => waits_for_client(request),
+        _ => false,
+    }
+}
 
 fn spawn_connection_handler(
-    mut socket: TcpStream,
+    socket: TcpStream,
     raft: Arc<LocalRaftGroupManager>,
     remote_raft: Arc<RemoteRaftGroups>,
     context: LocalContext,
 ) {
+    tokio::spawn(serve_connection(socket, move |request| {
+        request_router(request, raft.clone(), remote_raft.clone(), context.clone())
+    }));
+}
+
+// Handles the connection's requests concurrently, responding to each with the ID it was sent with
+async fn serve_connection<H, F>(socket: TcpStream, handler: H)
+where
+    H: Fn(Vec<u8>) -> F,
+    F: Future<Output = Vec<u8>> + Send + 'static,
+{
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = length_delimited::Builder::new()
+        .little_endian()
+        .new_read(reader);
+    // Requests are handled concurrently, so their responses are queued for the writer in the order
+    // they complete
+    let (response_sender, mut responses) = mpsc::channel::<Vec<u8>>(MAX_IN_FLIGHT_REQUESTS);
     tokio::spawn(async move {
-        let (reader, mut writer) = socket.split();
-        let mut reader = length_delimited::Builder::new()
-            .little_endian()
-            .new_read(reader);
-
-        loop {
-            let frame = match reader.next().await {
-                None => return,
-                Some(bytes) => match bytes {
-                    Ok(x) => x,
-                    Err(e) => {
-                        debug!("Client connection closed: {}", e);
-                        return;
-                    }
-                },
-            };
-            let response = request_router(
-                frame.to_vec(),
-                raft.clone(),
-                remote_raft.clone(),
-                context.clone(),
-            )
-            .await;
-            // TODO optimize this to avoid the copy
-            let mut result = vec![0u8; response.len() + 4];
-            result[4..].copy_from_slice(&response);
-            LittleEndian::write_u32(&mut result, response.len() as u32);
-            if let Err(e) = writer.write_all(&result).await {
+        while let Some(response) = responses.next().await {
+            if let Err(e) = writer.write_all(&response).await {
                 debug!("Client connection closed: {}", e);
-                return;
-            };
+                break;
+            }
         }
     });
+    let mut in_flight = JoinSet::new();
+    let mut waiting = JoinSet::new();
+
+    loop {
+        let mut frame = match reader.next().await {
+            None => break,
+            Some(bytes) => match bytes {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!("Client connection closed: {}", e);
+                    break;
+                }
+            },
+        };
+        if frame.len() < REQUEST_ID_SIZE {
+            debug!("Client sent a frame without a request ID");
+            break;
+        }
+        let request = frame.split_off(REQUEST_ID_SIZE);
+        let request_id = frame;
+        // Drop the requests which have completed, since connections may live indefinitely
+        while in_flight.try_join_next().is_some() {}
+        while waiting.try_join_next().is_some() {}
+        let waits = waits_for_client(&request);
+        if !waits && in_flight.len() >= MAX_IN_FLIGHT_REQUESTS {
+            in_flight.join_next().await;
+        }
+        let response = handler(request.to_vec());
+        let mut response_sender = response_sender.clone();
+        let task = async move {
+            let response = response.await;
+            // TODO optimize this to avoid the copy
+            let mut result = vec![0u8; response.len() + REQUEST_ID_SIZE + 4];
+            LittleEndian::write_u32(&mut result, (response.len() + REQUEST_ID_SIZE) as u32);
+            result[4..(REQUEST_ID_SIZE + 4)].copy_from_slice(&request_id);
+            result[(REQUEST_ID_SIZE + 4)..].copy_from_slice(&response);
+            // Waits while the writer is behind. Ignore errors, since the connection may have closed
+            response_sender.send(result).await.ok();
+        };
+        if waits {
+            waiting.spawn(task);
+        } else {
+            in_flight.spawn(task);
+        }
+    }
+    // Requests which are still running are completed, even though their responses can't be sent
+    in_flight.detach_all();
+    waiting.detach_all();
 }
 
 pub struct Node {
@@ -74,6 +135,7 @@ impl Node {
         bind_address: SocketAddr,
         peers: Vec<SocketAddr>,
         replicas_per_raft_group: usize,
+        atime_mode: AtimeMode,
     ) -> Node {
         let data_dir = Path::new(node_dir).join("data");
         #[allow(clippy::expect_fun_call)]
@@ -86,6 +148,7 @@ impl Node {
             peers,
             node_id,
             replicas_per_raft_group,
+            atime_mode,
         );
         // TODO: Make auto adjust based on load. Using same number as nodes is just a heuristic, so that
         // it scales with the cluster
@@ -153,3 +216,136 @@ impl Node {
         runtime.block_on(background_raft);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::base::{RangeLock, RangeLockKind, Request, decode_request, encode_request};
+    use crate::client::REQUEST_ID_SIZE;
+    use crate::storage::storage_node::{MAX_IN_FLIGHT_REQUESTS, serve_connection};
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn frame(request_id: u64, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 4 + REQUEST_ID_SIZE + payload.len()];
+        LittleEndian::write_u32(&mut frame, (REQUEST_ID_SIZE + payload.len()) as u32);
+        LittleEndian::write_u64(&mut frame[4..], request_id);
+        frame[(4 + REQUEST_ID_SIZE)..].copy_from_slice(payload);
+        frame
+    }
+
+    async fn read_frame(stream: &mut TcpStream) -> (u64, Vec<u8>) {
+        let size = stream.read_u32_le().await.unwrap() as usize;
+        let mut frame = vec![0; size];
+        stream.read_exact(&mut frame).await.unwrap();
+        (
+            LittleEndian::read_u64(&frame),
+            frame[REQUEST_ID_SIZE..].to_vec(),
+        )
+    }
+
+    #[test]
+    fn responses_matched_by_request_id() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_io()
+            .enable_time()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                // The first request is slow, so its response is sent after the second one's
+                serve_connection(socket, |request: Vec<u8>| async move {
+                    if request == b"slow" {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                    }
+                    request
+                })
+                .await;
+            });
+
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(&frame(7, b"slow")).await.unwrap();
+            stream.write_all(&frame(8, b"fast")).await.unwrap();
+            assert_eq!(read_frame(&mut stream).await, (8, b"fast".to_vec()));
+            assert_eq!(read_frame(&mut stream).await, (7, b"slow".to_vec()));
+        });
+    }
+
+    #[test]
+    fn lock_waits_not_limited() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_io()
+            .enable_time()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        let lock = |kind| RangeLock {
+            session: 1,
+            owner: 1,
+            pid: 1,
+            start: 0,
+            end: u64::MAX,
+            kind,
+        };
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                // Lock waits are only granted once the lock is released
+                let unlocked = Arc::new(AtomicBool::new(false));
+                serve_connection(socket, move |request: Vec<u8>| {
+                    let unlocked = unlocked.clone();
+                    async move {
+                        match decode_request(&request).unwrap() {
+                            Request::SetRangeLock { wait: true, .. } => {
+                                while !unlocked.load(Ordering::SeqCst) {
+                                    tokio::time::sleep(Duration::from_millis(10)).await;
+                                }
+                            }
+                            _ => unlocked.store(true, Ordering::SeqCst),
+                        }
+                        vec![]
+                    }
+                })
+                .await;
+            });
+
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            let waits = MAX_IN_FLIGHT_REQUESTS as u64 + 1;
+            for request_id in 0..waits {
+                let request = encode_request(&Request::SetRangeLock {
+                    inode: 2,
+                    lock: lock(RangeLockKind::Write),
+                    wait: true,
+                });
+                stream
+                    .write_all(&frame(request_id, &request))
+                    .await
+                    .unwrap();
+            }
+            let unlock = encode_request(&Request::SetRangeLock {
+                inode: 2,
+                lock: lock(RangeLockKind::Unlock),
+                wait: false,
+            });
+            stream.write_all(&frame(waits, &unlock)).await.unwrap();
+
+            let mut responses = vec![];
+            for _ in 0..=waits {
+                let response =
+                    tokio::time::timeout(Duration::from_secs(10), read_frame(&mut stream));
+                responses.push(response.await.expect("Unlock wasn't read").0);
+            }
+            responses.sort_unstable();
+            assert_eq!(responses, (0..=waits).collect::<Vec<u64>>());
+        });
+    }
+}
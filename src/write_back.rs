@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use fleetfs::base::ErrorCode;
+
+// Buffered writes are sent to the servers once a handle has this many bytes buffered
+const WRITE_BACK_LIMIT: usize = 1024 * 1024;
+
+// Writes to a file handle which haven't been sent to the servers yet. Only contiguous writes are
+// merged, so this is a single range of the file
+pub struct WriteBuffer {
+    pub inode: u64,
+    pub offset: u64,
+    pub uid: u32,
+    pub data: Vec<u8>,
+}
+
+struct BufferState {
+    buffer: Option<WriteBuffer>,
+    // The first error from sending the handle's writes while flushing for another operation, such
+    // as a read or getattr() of the inode. It's reported when the handle itself is next flushed, so
+    // that close() and fsync() don't succeed after the data was lost
+    error: Option<ErrorCode>,
+}
+
+struct HandleBuffer {
+    inode: u64,
+    // Held while the handle's writes are sent, so that they can't be reordered with later ones
+    state: Mutex<BufferState>,
+}
+
+// Buffered writes, by file handle. The map is only locked to find a handle's buffer, and not while
+// writes are sent to the servers, so that sending one handle's writes doesn't block the others
+pub struct WriteBuffers {
+    handles: Mutex<HashMap<u64, Arc<HandleBuffer>>>,
+}
+
+impl WriteBuffers {
+    pub fn new() -> WriteBuffers {
+        WriteBuffers {
+            handles: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn handle(&self, inode: u64, handle: u64) -> Arc<HandleBuffer> {
+        let mut handles = self.handles.lock().expect("handles lock is poisoned");
+        handles
+            .entry(handle)
+            .or_insert_with(|| {
+                Arc::new(HandleBuffer {
+                    inode,
+                    state: Mutex::new(BufferState {
+                        buffer: None,
+                        error: None,
+                    }),
+                })
+            })
+            .clone()
+    }
+
+    // Buffers the write, sending the handle's buffer once it's full. If previously buffered writes
+    // have to be sent first and fail, their error is returned
+    pub fn write(
+        &self,
+        inode: u64,
+        handle: u64,
+        offset: u64,
+        data: &[u8],
+        uid: u32,
+        send: impl Fn(&WriteBuffer) -> Result<(), ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        let handle_buffer = self.handle(inode, handle);
+        let mut state = handle_buffer
+            .state
+            .lock()
+            .expect("write buffer lock is poisoned");
+        match state.buffer {
+            Some(ref mut buffer)
+                if buffer.uid == uid && buffer.offset + buffer.data.len() as u64 == offset =>
+            {
+                buffer.data.extend_from_slice(data);
+            }
+            _ => {
+                if let Some(buffer) = state.buffer.take() {
+                    send(&buffer)?;
+                }
+                state.buffer = Some(WriteBuffer {
+                    inode,
+                    offset,
+                    uid,
+                    data: data.to_vec(),
+                });
+            }
+        }
+        if state.buffer.as_ref().unwrap().data.len() >= WRITE_BACK_LIMIT {
+            send(&state.buffer.take().unwrap())?;
+        }
+
+        Ok(())
+    }
+
+    // Sends the handle's buffered writes. Returns the first error from sending them, including
+    // errors from flushes for other operations since the handle was last flushed
+    pub fn flush_handle(
+        &self,
+        handle: u64,
+        send: impl Fn(&WriteBuffer) -> Result<(), ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        let Some(handle_buffer) = self
+            .handles
+            .lock()
+            .expect("handles lock is poisoned")
+            .get(&handle)
+            .cloned()
+        else {
+            return Ok(());
+        };
+        let mut state = handle_buffer
+            .state
+            .lock()
+            .expect("write buffer lock is poisoned");
+        let sent = state.buffer.take().map_or(Ok(()), |buffer| send(&buffer));
+        match state.error.take() {
+            Some(error_code) => Err(error_code),
+            None => sent,
+        }
+    }
+
+    // Flushes the handle, and forgets it
+    pub fn release_handle(
+        &self,
+        handle: u64,
+        send: impl Fn(&WriteBuffer) -> Result<(), ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        let result = self.flush_handle(handle, send);
+        self.handles
+            .lock()
+            .expect("handles lock is poisoned")
+            .remove(&handle);
+
+        result
+    }
+
+    // Sends the buffered writes of every handle of the inode, returning the first error. Errors are
+    // also recorded on the handle, to be reported when it's flushed
+    pub fn flush_inode(
+        &self,
+        inode: u64,
+        send: impl Fn(&WriteBuffer) -> Result<(), ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        self.flush_matching(|buffered_inode| buffered_inode == inode, send)
+    }
+
+    pub fn flush_all(
+        &self,
+        send: impl Fn(&WriteBuffer) -> Result<(), ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        self.flush_matching(|_| true, send)
+    }
+
+    fn flush_matching(
+        &self,
+        filter: impl Fn(u64) -> bool,
+        send: impl Fn(&WriteBuffer) -> Result<(), ErrorCode>,
+    ) -> Result<(), ErrorCode> {
+        let matching: Vec<Arc<HandleBuffer>> = self
+            .handles
+            .lock()
+            .expect("handles lock is poisoned")
+            .values()
+            .filter(|handle_buffer| filter(handle_buffer.inode))
+            .cloned()
+            .collect();
+        let mut result = Ok(());
+        for handle_buffer in matching {
+            let mut state = handle_buffer
+                .state
+                .lock()
+                .expect("write buffer lock is poisoned");
+            if let Some(buffer) = state.buffer.take()
+                && let Err(error_code) = send(&buffer)
+            {
+                state.error.get_or_insert(error_code);
+                if result.is_ok() {
+                    result = Err(error_code);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::write_back::{WRITE_BACK_LIMIT, WriteBuffer, WriteBuffers};
+    use fleetfs::base::ErrorCode;
+    use std::sync::Mutex;
+
+    // Records the writes sent, as (inode, offset, length)
+    fn recorder(
+        sent: &Mutex<Vec<(u64, u64, usize)>>,
+    ) -> impl Fn(&WriteBuffer) -> Result<(), ErrorCode> {
+        |buffer| {
+            sent.lock()
+                .unwrap()
+                .push((buffer.inode, buffer.offset, buffer.data.len()));
+            Ok(())
+        }
+    }
+
+    fn fail(_: &WriteBuffer) -> Result<(), ErrorCode> {
+        Err(ErrorCode::Uncategorized)
+    }
+
+    #[test]
+    fn contiguous_writes_merged() {
+        let buffers = WriteBuffers::new();
+        let sent = Mutex::new(vec![]);
+        buffers.write(1, 10, 0, b"abc", 0, recorder(&sent)).unwrap();
+        buffers.write(1, 10, 3, b"def", 0, recorder(&sent)).unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+
+        // A write elsewhere in the file sends the buffer first
+        buffers.write(1, 10, 100, b"g", 0, recorder(&sent)).unwrap();
+        assert_eq!(*sent.lock().unwrap(), [(1, 0, 6)]);
+        buffers.flush_handle(10, recorder(&sent)).unwrap();
+        assert_eq!(*sent.lock().unwrap(), [(1, 0, 6), (1, 100, 1)]);
+
+        // A full buffer is sent straight away
+        let data = vec![0; WRITE_BACK_LIMIT];
+        buffers.write(2, 11, 0, &data, 0, recorder(&sent)).unwrap();
+        assert_eq!(sent.lock().unwrap().last(), Some(&(2, 0, WRITE_BACK_LIMIT)));
+    }
+
+    #[test]
+    fn flush_errors_reported_to_handle() {
+        let buffers = WriteBuffers::new();
+        let sent = Mutex::new(vec![]);
+        buffers.write(1, 10, 0, b"abc", 0, recorder(&sent)).unwrap();
+        buffers.write(1, 11, 0, b"abc", 0, recorder(&sent)).unwrap();
+        buffers.write(2, 12, 0, b"abc", 0, recorder(&sent)).unwrap();
+
+        // A read of the inode fails, and so do both of its handles when they're closed
+        assert_eq!(buffers.flush_inode(1, fail), Err(ErrorCode::Uncategorized));
+        assert_eq!(
+            buffers.flush_handle(10, recorder(&sent)),
+            Err(ErrorCode::Uncategorized)
+        );
+        assert_eq!(
+            buffers.release_handle(11, recorder(&sent)),
+            Err(ErrorCode::Uncategorized)
+        );
+        // The error is only reported once
+        assert_eq!(buffers.flush_handle(10, recorder(&sent)), Ok(()));
+        assert_eq!(buffers.release_handle(12, recorder(&sent)), Ok(()));
+        assert_eq!(*sent.lock().unwrap(), [(2, 0, 3)]);
+    }
+
+    #[test]
+    fn other_handles_usable_while_sending() {
+        let buffers = WriteBuffers::new();
+        buffers.write(1, 10, 0, b"abc", 0, fail).unwrap();
+        // Sending the first handle's writes doesn't hold up writes to, or flushes of, another one
+        let sent = Mutex::new(vec![]);
+        buffers
+            .flush_handle(10, |_| {
+                buffers.write(2, 11, 0, b"def", 0, recorder(&sent))?;
+                buffers.flush_inode(2, recorder(&sent))
+            })
+            .unwrap();
+        assert_eq!(*sent.lock().unwrap(), [(2, 0, 3)]);
+    }
+}
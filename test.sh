@@ -22,20 +22,20 @@ DATA_DIR3=$(mktemp --directory)
 DIR=$(mktemp --directory)
 DIR2=$(mktemp --directory)
 cargo build
-cargo run -- --port 3300 --data-dir $DATA_DIR --peers 127.0.0.1:3301,127.0.0.1:3302 --redundancy-level "${REDUNDANCY_LEVEL}" &
-cargo run -- --port 3301 --data-dir $DATA_DIR2 --peers 127.0.0.1:3300,127.0.0.1:3302 --redundancy-level "${REDUNDANCY_LEVEL}" &
-cargo run -- --port 3302 --data-dir $DATA_DIR3 --peers 127.0.0.1:3300,127.0.0.1:3301 --redundancy-level "${REDUNDANCY_LEVEL}" &
+cargo run -- server --port 3300 --data-dir $DATA_DIR --peers 127.0.0.1:3301,127.0.0.1:3302 --redundancy-level "${REDUNDANCY_LEVEL}" &
+cargo run -- server --port 3301 --data-dir $DATA_DIR2 --peers 127.0.0.1:3300,127.0.0.1:3302 --redundancy-level "${REDUNDANCY_LEVEL}" &
+cargo run -- server --port 3302 --data-dir $DATA_DIR3 --peers 127.0.0.1:3300,127.0.0.1:3301 --redundancy-level "${REDUNDANCY_LEVEL}" &
 
 # Wait for leader to be elected
-until cargo run -- --server-ip-port 127.0.0.1:3300 --get-leader; do
+until cargo run -- --server-ip-port 127.0.0.1:3300 get-leader; do
     sleep 0.1
 done
 
-cargo run -- --server-ip-port 127.0.0.1:3300 --mount-point $DIR &
+cargo run -- --server-ip-port 127.0.0.1:3300 mount $DIR &
 FUSE_PID=$!
-# Mount the replica with direct IO, so that replication shows up immediately. Otherwise, some tests might fail
-# due to caching in the kernel
-cargo run -- --server-ip-port 127.0.0.1:3301 --mount-point $DIR2 --direct-io &
+# The replica's kernel caches are invalidated by the servers, so changes made through the first mount show up
+# when files are next opened
+cargo run -- --server-ip-port 127.0.0.1:3301 mount $DIR2 &
 sleep 0.5
 
 echo "mounting at $DIR"
@@ -213,14 +213,14 @@ else
     exit
 fi
 
-if cargo run -- --server-ip-port 127.0.0.1:3300 --fsck > /dev/null 2>&1; then
+if cargo run -- --server-ip-port 127.0.0.1:3300 fsck > /dev/null 2>&1; then
     echo -e "$GREEN OK 10 $NC"
 else
     echo -e "$RED FAILED on fsck $NC"
     export TEST_EXIT_STATUS=1
     exit
 fi
-if cargo run -- --server-ip-port 127.0.0.1:3301 --fsck > /dev/null 2>&1; then
+if cargo run -- --server-ip-port 127.0.0.1:3301 fsck > /dev/null 2>&1; then
     echo -e "$GREEN OK 10 replica $NC"
 else
     echo -e "$RED FAILED on fsck replica $NC"
@@ -230,7 +230,7 @@ fi
 
 # Corrupt the filesystem by deletina all the data, but not the metadata
 rm ${DATA_DIR}/data/rgroup_*/data/* || rm ${DATA_DIR2}/data/rgroup_*/data/* || rm ${DATA_DIR3}/data/rgroup_*/data/*
-if ! cargo run -- --server-ip-port 127.0.0.1:3300 --fsck > /dev/null 2>&1; then
+if ! cargo run -- --server-ip-port 127.0.0.1:3300 fsck > /dev/null 2>&1; then
     echo -e "$GREEN OK 11 $NC"
 else
     echo -e "$RED FAILED on fsck corruption detection $NC"